@@ -0,0 +1,164 @@
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Max Wipfli <mail@maxwipfli.ch>
+
+//! Benchmarks of the hot path of the resolver, establishing a baseline for performance work.
+//!
+//! Run with `cargo bench --features testing`. The names of benchmarks to run can be filtered by passing a substring,
+//! e.g. `cargo bench --features testing -- proto`.
+
+use std::{
+    hint::black_box,
+    net::{Ipv4Addr, SocketAddr, UdpSocket as StdUdpSocket},
+    sync::Arc,
+    time::Duration,
+};
+
+use aufloes::{
+    client::mock::MockClient,
+    proto::{
+        edns::{self, EdnsOption},
+        message::{first_question, map_ttls},
+        set_txid_in_binary_message, txid_from_binary_message,
+    },
+    resolver::{self, ResolverConfig, Stats},
+};
+use criterion::{criterion_group, criterion_main, Criterion};
+use hickory_proto::{
+    op::{Message, Query},
+    rr::{rdata::A, RData, Record, RecordType},
+};
+use tokio::{net::UdpSocket, runtime::Runtime, time::timeout};
+
+fn build_query(txid: u16) -> Vec<u8> {
+    let mut query = Message::new();
+    query
+        .set_id(txid)
+        .set_recursion_desired(true)
+        .add_query(Query::query(
+            "www.example.com.".parse().unwrap(),
+            RecordType::A,
+        ));
+    query.to_vec().unwrap()
+}
+
+fn build_response() -> Vec<u8> {
+    let mut response = Message::from_vec(&build_query(0x1234)).unwrap();
+    for i in 1..=8 {
+        response.add_answer(Record::from_rdata(
+            "www.example.com.".parse().unwrap(),
+            300,
+            RData::A(A::new(192, 0, 2, i)),
+        ));
+    }
+    response.to_vec().unwrap()
+}
+
+fn bench_proto(c: &mut Criterion) {
+    let mut query = build_query(0x1234);
+    c.bench_function("proto/txid", |b| {
+        b.iter(|| {
+            let txid = txid_from_binary_message(black_box(&query)).unwrap();
+            set_txid_in_binary_message(black_box(&mut query), txid.wrapping_add(1));
+        })
+    });
+
+    c.bench_function("proto/first_question", |b| {
+        b.iter(|| black_box(first_question(black_box(&query))))
+    });
+
+    let mut response = build_response();
+    c.bench_function("proto/map_ttls", |b| {
+        b.iter(|| map_ttls(black_box(&mut response), |ttl| ttl.max(60)).unwrap())
+    });
+
+    let subnet = [0, 1, 24, 0, 198, 51, 100];
+    c.bench_function("proto/edns_with_options", |b| {
+        b.iter(|| {
+            let options = [EdnsOption {
+                code: edns::ECS_CODE,
+                data: &subnet,
+            }];
+            black_box(edns::with_options(black_box(&query), &options))
+        })
+    });
+}
+
+/// Starts a resolver forwarding to a `MockClient` on a free loopback port, and returns a socket connected to it.
+///
+/// The port is found by binding and dropping a socket, so another process may take it before the resolver binds it.
+/// In that case the resolver stops, and it is started again on another port. The resolver is ready once it answered
+/// a query.
+fn start_resolver(runtime: &Runtime) -> UdpSocket {
+    let query = build_query(0);
+    for _ in 0..10 {
+        let addr = StdUdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+            .and_then(|socket| socket.local_addr())
+            .unwrap();
+        let upstream = MockClient::new();
+        upstream.set_answer("www.example.com.", RData::A(A::new(192, 0, 2, 1)));
+        let resolver = runtime.spawn(async move {
+            resolver::run(
+                Arc::new(upstream),
+                &[addr],
+                ResolverConfig::default(),
+                Arc::new(Stats::default()),
+            )
+            .await
+        });
+
+        let socket = runtime.block_on(async {
+            let socket = UdpSocket::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))
+                .await
+                .unwrap();
+            socket.connect(addr).await.unwrap();
+            let mut buf = [0; 512];
+            while !resolver.is_finished() {
+                socket.send(&query).await.unwrap();
+                // Errors (e.g. ICMP port unreachable before the resolver is bound) are retried as well.
+                if let Ok(Ok(_)) = timeout(Duration::from_millis(100), socket.recv(&mut buf)).await
+                {
+                    return Some(socket);
+                }
+            }
+            None
+        });
+        match socket {
+            Some(socket) => return socket,
+            None => eprintln!(
+                "resolver on {} stopped, retrying on another port: {:?}",
+                addr,
+                runtime.block_on(resolver).unwrap()
+            ),
+        }
+    }
+    panic!("cannot start resolver");
+}
+
+/// Resolves queries end-to-end over loopback, through a resolver forwarding to a `MockClient`.
+fn bench_resolver(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let socket = start_resolver(&runtime);
+    let mut query = build_query(0);
+    // The readiness probes of `start_resolver` use transaction ID 0.
+    let mut txid = 0u16;
+    let mut buf = [0; 512];
+    c.bench_function("resolver/loopback", |b| {
+        b.iter(|| {
+            txid = txid.wrapping_add(1);
+            set_txid_in_binary_message(&mut query, txid);
+            runtime.block_on(async {
+                socket.send(&query).await.unwrap();
+                // Responses to readiness probes that timed out may still arrive and are skipped.
+                loop {
+                    let len = socket.recv(&mut buf).await.unwrap();
+                    if txid_from_binary_message(&buf[..len]) == Some(txid) {
+                        break;
+                    }
+                }
+            });
+        })
+    });
+}
+
+criterion_group!(benches, bench_proto, bench_resolver);
+criterion_main!(benches);
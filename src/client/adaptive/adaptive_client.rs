@@ -0,0 +1,250 @@
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Max Wipfli <mail@maxwipfli.ch>
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use bytes::BytesMut;
+use eyre::{eyre, Result};
+use rand::Rng;
+use tracing::debug;
+
+use crate::client::{client::resolve_with, Client};
+
+/// A client sending each query to the upstream that performed best recently, while occasionally probing the others
+/// (epsilon-greedy), so that traffic gradually shifts to the best upstream, but changes in performance are noticed.
+///
+/// The performance of each upstream is measured by exponentially weighted moving averages (EWMA) of its latency and
+/// success rate. The best upstream is the one with the lowest expected latency per successful answer, i.e. the
+/// latency divided by the success rate. Upstreams without a measured latency are tried first.
+pub struct AdaptiveClient {
+    upstreams: Vec<Upstream>,
+    config: AdaptiveClientConfig,
+}
+
+/// Configuration of an `AdaptiveClient`.
+#[derive(Debug, Clone)]
+pub struct AdaptiveClientConfig {
+    /// Fraction of queries sent to a random upstream instead of the best one, between 0 and 1.
+    pub epsilon: f64,
+
+    /// Weight of each new sample in the moving averages, between 0 (exclusive) and 1. Higher values adapt faster to
+    /// changing performance.
+    pub smoothing: f64,
+}
+
+impl Default for AdaptiveClientConfig {
+    fn default() -> Self {
+        Self {
+            epsilon: 0.1,
+            smoothing: 0.2,
+        }
+    }
+}
+
+struct Upstream {
+    client: Arc<dyn Client>,
+    score: Mutex<Score>,
+}
+
+/// Recent performance of an upstream.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Score {
+    /// Estimated latency of successful requests, or `None` if no request succeeded yet.
+    latency: Option<Duration>,
+    /// Estimated fraction of successful requests.
+    success_rate: f64,
+}
+
+impl Default for Score {
+    fn default() -> Self {
+        Self {
+            latency: None,
+            success_rate: 1.0,
+        }
+    }
+}
+
+impl Score {
+    /// Returns the expected latency per successful answer in seconds (lower is better), or `None` if unknown.
+    fn cost(&self) -> Option<f64> {
+        self.latency
+            .map(|latency| latency.as_secs_f64() / self.success_rate)
+    }
+}
+
+impl AdaptiveClient {
+    /// Creates a new `AdaptiveClient` sending queries to `upstreams`, of which there must be at least one.
+    pub fn new(upstreams: Vec<Arc<dyn Client>>, config: AdaptiveClientConfig) -> Result<Self> {
+        if upstreams.is_empty() {
+            return Err(eyre!("AdaptiveClient requires at least one upstream"));
+        }
+        if !(0.0..=1.0).contains(&config.epsilon) {
+            return Err(eyre!(
+                "AdaptiveClient epsilon must be in [0, 1], got {}",
+                config.epsilon
+            ));
+        }
+        if !(config.smoothing > 0.0 && config.smoothing <= 1.0) {
+            return Err(eyre!(
+                "AdaptiveClient smoothing must be in (0, 1], got {}",
+                config.smoothing
+            ));
+        }
+        let upstreams = upstreams
+            .into_iter()
+            .map(|client| Upstream {
+                client,
+                score: Mutex::new(Score::default()),
+            })
+            .collect();
+        Ok(Self { upstreams, config })
+    }
+
+    /// Returns the index of the upstream to send the next query to.
+    fn select(&self) -> usize {
+        let mut rng = rand::thread_rng();
+        if rng.gen_bool(self.config.epsilon) {
+            return rng.gen_range(0..self.upstreams.len());
+        }
+        let costs = self
+            .upstreams
+            .iter()
+            .map(|upstream| upstream.score.lock().unwrap().cost());
+        // Upstreams with an unknown cost are tried first, then the one with the lowest cost.
+        costs
+            .enumerate()
+            .min_by(|(_, a), (_, b)| match (a, b) {
+                (Some(a), Some(b)) => a.total_cmp(b),
+                (a, b) => a.is_some().cmp(&b.is_some()),
+            })
+            .map_or(0, |(index, _)| index)
+    }
+
+    /// Records the outcome of a request to an upstream.
+    fn record(&self, index: usize, latency: Option<Duration>) {
+        let smoothing = self.config.smoothing;
+        let mut score = self.upstreams[index].score.lock().unwrap();
+        let success = if latency.is_some() { 1.0 } else { 0.0 };
+        score.success_rate = score.success_rate * (1.0 - smoothing) + success * smoothing;
+        if let Some(latency) = latency {
+            score.latency = Some(match score.latency {
+                Some(estimate) => estimate
+                    .mul_f64(1.0 - smoothing)
+                    .saturating_add(latency.mul_f64(smoothing)),
+                None => latency,
+            });
+        }
+    }
+
+    async fn resolve_adaptively(
+        &self,
+        data: BytesMut,
+        deadline: Option<Instant>,
+    ) -> Result<BytesMut> {
+        let index = self.select();
+        let start = Instant::now();
+        let result = resolve_with(&*self.upstreams[index].client, data, deadline).await;
+        match &result {
+            Ok(_) => self.record(index, Some(start.elapsed())),
+            Err(err) => {
+                debug!("AdaptiveClient: upstream #{} failed: {}", index, err);
+                self.record(index, None);
+            }
+        }
+        result
+    }
+}
+
+#[async_trait::async_trait]
+impl Client for AdaptiveClient {
+    async fn resolve_raw(&self, data: BytesMut) -> Result<BytesMut> {
+        self.resolve_adaptively(data, None).await
+    }
+
+    async fn resolve_raw_with_deadline(
+        &self,
+        data: BytesMut,
+        deadline: Instant,
+    ) -> Result<BytesMut> {
+        self.resolve_adaptively(data, Some(deadline)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hickory_proto::rr::{rdata::A, RData};
+
+    use super::*;
+    use crate::client::{client::tests as client_tests, labeled::LabeledClient, mock::MockClient};
+
+    fn build_upstream(index: u8, delay: Duration) -> Arc<LabeledClient> {
+        let upstream = MockClient::new();
+        upstream.set_answer("www.example.com.", RData::A(A::new(192, 0, 2, index)));
+        upstream.set_delay(delay);
+        Arc::new(LabeledClient::new(
+            format!("upstream-{}", index),
+            Arc::new(upstream),
+        ))
+    }
+
+    #[tokio::test]
+    async fn prefer_fastest_upstream() {
+        let fast = build_upstream(1, Duration::ZERO);
+        let slow = build_upstream(2, Duration::from_millis(20));
+        let client = Arc::new(
+            AdaptiveClient::new(
+                vec![slow.clone(), fast.clone()],
+                AdaptiveClientConfig::default(),
+            )
+            .unwrap(),
+        );
+
+        for _ in 0..100 {
+            client_tests::basic_a(client.clone()).await;
+        }
+        let (fast, slow) = (fast.stats().queries, slow.stats().queries);
+        assert_eq!(fast + slow, 100);
+        // Both upstreams are measured first, then about 95% of queries are sent to the fast one.
+        assert!(slow >= 1);
+        assert!(fast >= 80, "fast: {}, slow: {}", fast, slow);
+    }
+
+    #[test]
+    fn select_best_upstream() {
+        let upstreams = (0..3)
+            .map(|index| build_upstream(index, Duration::ZERO) as Arc<dyn Client>)
+            .collect();
+        let config = AdaptiveClientConfig {
+            epsilon: 0.0,
+            ..Default::default()
+        };
+        let client = AdaptiveClient::new(upstreams, config).unwrap();
+        client.record(0, Some(Duration::from_millis(20)));
+        client.record(2, Some(Duration::from_millis(30)));
+        // Upstreams without a measured latency are tried first.
+        assert_eq!(client.select(), 1);
+
+        client.record(1, Some(Duration::from_millis(10)));
+        assert_eq!(client.select(), 1);
+        // Failures make an upstream less attractive, even if it is fast.
+        for _ in 0..4 {
+            client.record(1, None);
+        }
+        let success_rate = client.upstreams[1].score.lock().unwrap().success_rate;
+        assert!((success_rate - 0.4096).abs() < 1e-9, "{}", success_rate);
+        assert_eq!(client.select(), 0);
+    }
+
+    #[test]
+    fn reject_invalid_configs() {
+        assert!(AdaptiveClient::new(Vec::new(), AdaptiveClientConfig::default()).is_err());
+        let upstream = build_upstream(1, Duration::ZERO) as Arc<dyn Client>;
+        for (epsilon, smoothing) in [(1.5, 0.2), (-0.1, 0.2), (0.1, 0.0), (0.1, f64::NAN)] {
+            let config = AdaptiveClientConfig { epsilon, smoothing };
+            assert!(AdaptiveClient::new(vec![upstream.clone()], config).is_err());
+        }
+    }
+}
@@ -0,0 +1,5 @@
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Max Wipfli <mail@maxwipfli.ch>
+
+mod adaptive_client;
+pub use adaptive_client::{AdaptiveClient, AdaptiveClientConfig};
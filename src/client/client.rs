@@ -1,12 +1,50 @@
 // SPDX-License-Identifier: MIT
 // SPDX-FileCopyrightText: 2025 Max Wipfli <mail@maxwipfli.ch>
 
+use std::time::Instant;
+
 use bytes::BytesMut;
-use eyre::Result;
+use eyre::{eyre, Result};
+use hickory_proto::op::Message;
 
 #[async_trait::async_trait]
 pub trait Client: Send + Sync {
     async fn resolve_raw(&self, data: BytesMut) -> Result<BytesMut>;
+
+    /// Resolves a query like `resolve_raw`, but gives up once `deadline` has passed (e.g. because the downstream
+    /// client has stopped waiting for the response by then).
+    ///
+    /// By default, `resolve_raw` is cancelled at the deadline. Clients override this to bound their own timeouts by
+    /// the remaining time instead, or to pass the deadline on to the clients they wrap.
+    async fn resolve_raw_with_deadline(
+        &self,
+        data: BytesMut,
+        deadline: Instant,
+    ) -> Result<BytesMut> {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match tokio::time::timeout(remaining, self.resolve_raw(data)).await {
+            Ok(result) => result,
+            Err(_) => Err(eyre!("deadline exceeded")),
+        }
+    }
+
+    /// Resolves a query, serializing it to and parsing the response from DNS wire format.
+    async fn resolve(&self, query: Message) -> Result<Message> {
+        let response = self.resolve_raw(query.to_vec()?.as_slice().into()).await?;
+        Ok(Message::from_vec(&response)?)
+    }
+}
+
+/// Resolves a query with `client`, bounded by `deadline` (if any).
+pub(crate) async fn resolve_with(
+    client: &dyn Client,
+    data: BytesMut,
+    deadline: Option<Instant>,
+) -> Result<BytesMut> {
+    match deadline {
+        Some(deadline) => client.resolve_raw_with_deadline(data, deadline).await,
+        None => client.resolve_raw(data).await,
+    }
 }
 
 #[cfg(test)]
@@ -15,14 +53,15 @@ pub mod tests {
 
     use bytes::Bytes;
     use hickory_proto::{
-        op::{Message, Query},
-        rr::RecordType,
+        op::Query,
+        rr::{rdata::A, RData, RecordType},
         serialize::binary::BinDecodable,
     };
 
     use super::*;
+    use crate::client::mock::MockClient;
 
-    fn build_request(name: &str, query_type: RecordType) -> BytesMut {
+    pub fn build_request(name: &str, query_type: RecordType) -> BytesMut {
         let mut request = Message::new();
         request.add_query(Query::query(name.parse().unwrap(), query_type));
         request.set_recursion_desired(true);
@@ -32,7 +71,7 @@ pub mod tests {
 
     pub async fn basic_a(client: Arc<dyn Client>) {
         let request_bytes = build_request("www.example.com", RecordType::A);
-        let response_bytes = client.resolve_raw(request_bytes.into()).await.unwrap();
+        let response_bytes = client.resolve_raw(request_bytes).await.unwrap();
         let response = Message::from_bytes(&response_bytes).unwrap();
 
         assert_eq!(response.answer_count(), 1);
@@ -41,10 +80,29 @@ pub mod tests {
 
     pub async fn basic_aaaa(client: Arc<dyn Client>) {
         let request_bytes = build_request("www.example.com", RecordType::AAAA);
-        let response_bytes = client.resolve_raw(request_bytes.into()).await.unwrap();
+        let response_bytes = client.resolve_raw(request_bytes).await.unwrap();
         let response = Message::from_bytes(&response_bytes).unwrap();
 
         assert_eq!(response.answer_count(), 1);
         assert_eq!(response.answers()[0].record_type(), RecordType::AAAA);
     }
+
+    #[tokio::test]
+    async fn resolve() {
+        let client = MockClient::new();
+        client.set_answer("www.example.com.", RData::A(A::new(192, 0, 2, 1)));
+
+        let mut query = Message::new();
+        query.set_id(42).add_query(Query::query(
+            "www.example.com.".parse().unwrap(),
+            RecordType::A,
+        ));
+        let response = client.resolve(query).await.unwrap();
+
+        assert_eq!(response.id(), 42);
+        assert_eq!(
+            response.answers()[0].data(),
+            Some(&RData::A(A::new(192, 0, 2, 1)))
+        );
+    }
 }
@@ -0,0 +1,210 @@
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Max Wipfli <mail@maxwipfli.ch>
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use bytes::BytesMut;
+use eyre::Result;
+use tracing::{debug, info, warn};
+
+use crate::client::{client::resolve_with, Client};
+
+/// A client falling back to a different upstream (e.g. over plain UDP) while the primary upstream (e.g. over
+/// DNS-over-HTTPS) is unreachable.
+///
+/// Only failures to get any response from the primary upstream (e.g. connection or TLS errors, or timeouts) count as
+/// failures, responses with an error rcode are returned as is. After `failure_threshold` consecutive failures, all
+/// queries are sent to the fallback upstream for `recheck_interval`, after which the primary upstream is tried again.
+/// Queries the primary upstream fails are retried on the fallback upstream, so that they are still answered.
+pub struct FallbackClient {
+    primary: Arc<dyn Client>,
+    fallback: Arc<dyn Client>,
+    config: FallbackClientConfig,
+    state: Mutex<FallbackState>,
+}
+
+/// Configuration of a `FallbackClient`.
+#[derive(Debug, Clone)]
+pub struct FallbackClientConfig {
+    /// Number of consecutive failures of the primary upstream after which queries are sent to the fallback upstream.
+    pub failure_threshold: u32,
+
+    /// Time after which the primary upstream is tried again once queries are sent to the fallback upstream.
+    pub recheck_interval: Duration,
+}
+
+impl Default for FallbackClientConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 3,
+            recheck_interval: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Default)]
+struct FallbackState {
+    /// Number of consecutive failures of the primary upstream.
+    failures: u32,
+    /// Time until which queries are sent to the fallback upstream, if fallen back.
+    fallen_back_until: Option<Instant>,
+}
+
+impl FallbackClient {
+    pub fn new(
+        primary: Arc<dyn Client>,
+        fallback: Arc<dyn Client>,
+        config: FallbackClientConfig,
+    ) -> Self {
+        Self {
+            primary,
+            fallback,
+            config,
+            state: Mutex::default(),
+        }
+    }
+
+    /// Returns whether queries are currently sent to the fallback upstream.
+    fn is_fallen_back(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state
+            .fallen_back_until
+            .is_some_and(|until| Instant::now() < until)
+    }
+
+    fn record_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.fallen_back_until.take().is_some() {
+            info!("FallbackClient: primary upstream is reachable again");
+        }
+        state.failures = 0;
+    }
+
+    fn record_failure(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.failures += 1;
+        // While rechecking, a single failure suffices to fall back again.
+        if state.failures >= self.config.failure_threshold || state.fallen_back_until.is_some() {
+            if state.fallen_back_until.is_none() {
+                warn!(
+                    "FallbackClient: primary upstream failed {} times in a row, using fallback upstream for {} s",
+                    state.failures,
+                    self.config.recheck_interval.as_secs()
+                );
+            }
+            state.fallen_back_until = Some(Instant::now() + self.config.recheck_interval);
+        }
+    }
+
+    /// Resolves a query with the primary upstream (unless fallen back), and with the fallback upstream if that fails.
+    /// Both requests are bounded by `deadline` (if any).
+    async fn resolve_with_fallback(
+        &self,
+        data: BytesMut,
+        deadline: Option<Instant>,
+    ) -> Result<BytesMut> {
+        if !self.is_fallen_back() {
+            match resolve_with(&*self.primary, data.clone(), deadline).await {
+                Ok(response) => {
+                    self.record_success();
+                    return Ok(response);
+                }
+                Err(err) => {
+                    debug!(
+                        "FallbackClient: primary upstream failed, retrying on fallback upstream: {}",
+                        err
+                    );
+                    self.record_failure();
+                }
+            }
+        }
+        resolve_with(&*self.fallback, data, deadline).await
+    }
+}
+
+#[async_trait::async_trait]
+impl Client for FallbackClient {
+    async fn resolve_raw(&self, data: BytesMut) -> Result<BytesMut> {
+        self.resolve_with_fallback(data, None).await
+    }
+
+    async fn resolve_raw_with_deadline(
+        &self,
+        data: BytesMut,
+        deadline: Instant,
+    ) -> Result<BytesMut> {
+        self.resolve_with_fallback(data, Some(deadline)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hickory_proto::rr::{rdata::A, RData};
+
+    use super::*;
+    use crate::client::{client::tests as client_tests, mock::MockClient};
+
+    fn build_upstream() -> Arc<MockClient> {
+        let upstream = MockClient::new();
+        upstream.set_answer("www.example.com.", RData::A(A::new(192, 0, 2, 1)));
+        Arc::new(upstream)
+    }
+
+    #[tokio::test]
+    async fn fall_back_when_unreachable() {
+        let primary = build_upstream();
+        primary.set_failing(true);
+        let fallback = build_upstream();
+        let config = FallbackClientConfig {
+            failure_threshold: 2,
+            recheck_interval: Duration::from_millis(100),
+        };
+        let client = Arc::new(FallbackClient::new(
+            primary.clone(),
+            fallback.clone(),
+            config,
+        ));
+
+        // Queries are answered by the fallback upstream, and the primary upstream isn't tried any more after
+        // failing twice.
+        for _ in 0..4 {
+            client_tests::basic_a(client.clone()).await;
+        }
+        assert_eq!(primary.calls(), 2);
+        assert_eq!(fallback.calls(), 4);
+
+        // The primary upstream is tried again after the recheck interval.
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        primary.set_failing(false);
+        client_tests::basic_a(client.clone()).await;
+        client_tests::basic_a(client).await;
+        assert_eq!(primary.calls(), 4);
+        assert_eq!(fallback.calls(), 4);
+    }
+
+    #[tokio::test]
+    async fn reset_failures_on_success() {
+        let primary = build_upstream();
+        let fallback = build_upstream();
+        let config = FallbackClientConfig {
+            failure_threshold: 2,
+            ..Default::default()
+        };
+        let client = Arc::new(FallbackClient::new(
+            primary.clone(),
+            fallback.clone(),
+            config,
+        ));
+
+        // Failures that aren't consecutive don't cause falling back.
+        for failing in [true, false, true, false] {
+            primary.set_failing(failing);
+            client_tests::basic_a(client.clone()).await;
+        }
+        assert_eq!(primary.calls(), 4);
+        assert_eq!(fallback.calls(), 2);
+    }
+}
@@ -0,0 +1,5 @@
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Max Wipfli <mail@maxwipfli.ch>
+
+mod fallback_client;
+pub use fallback_client::{FallbackClient, FallbackClientConfig};
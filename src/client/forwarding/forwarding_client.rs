@@ -0,0 +1,281 @@
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Max Wipfli <mail@maxwipfli.ch>
+
+use std::{collections::HashSet, sync::Arc, time::Instant};
+
+use bytes::BytesMut;
+use eyre::{eyre, Result};
+use hickory_proto::{
+    op::ResponseCode,
+    rr::{Name, RecordType},
+};
+use tracing::debug;
+
+use crate::{
+    client::Client,
+    proto::message::{error_response, first_question},
+};
+
+/// A client forwarding queries to different upstreams depending on the queried domain (conditional forwarding).
+///
+/// Each rule maps a domain to an upstream, and applies to the domain itself and all its subdomains.
+/// If multiple rules match, the one with the longest domain wins. Queries not matching any rule are forwarded to
+/// the default upstream.
+///
+/// Upstreams can be restricted to certain query types, in which case rules only match queries of these types.
+/// Queries whose type isn't allowed on any matching upstream are answered with REFUSED.
+pub struct ForwardingClient {
+    /// Rules, sorted by decreasing number of labels, such that the first matching rule is the most specific one.
+    rules: Vec<ForwardingRule>,
+    default: Arc<dyn Client>,
+    /// Query types allowed on the default upstream. If `None`, all query types are allowed.
+    default_query_types: Option<HashSet<RecordType>>,
+}
+
+/// Rule forwarding queries for a domain (and its subdomains) to an upstream.
+pub struct ForwardingRule {
+    pub domain: Name,
+    /// Query types forwarded by this rule. If `None`, all query types are forwarded.
+    pub query_types: Option<HashSet<RecordType>>,
+    pub upstream: Arc<dyn Client>,
+}
+
+impl ForwardingRule {
+    /// Creates a rule forwarding queries of all types.
+    pub fn new(domain: Name, upstream: Arc<dyn Client>) -> Self {
+        Self {
+            domain,
+            query_types: None,
+            upstream,
+        }
+    }
+
+    fn matches(&self, name: &Name, query_type: RecordType) -> bool {
+        self.domain.zone_of(name) && allows(&self.query_types, query_type)
+    }
+}
+
+fn allows(query_types: &Option<HashSet<RecordType>>, query_type: RecordType) -> bool {
+    query_types
+        .as_ref()
+        .is_none_or(|query_types| query_types.contains(&query_type))
+}
+
+impl ForwardingClient {
+    pub fn new(rules: Vec<ForwardingRule>, default: Arc<dyn Client>) -> Self {
+        let mut rules = rules;
+        rules.sort_by_key(|rule| std::cmp::Reverse(rule.domain.num_labels()));
+        Self {
+            rules,
+            default,
+            default_query_types: None,
+        }
+    }
+
+    /// Restricts the default upstream to the given query types.
+    pub fn with_default_query_types(mut self, query_types: HashSet<RecordType>) -> Self {
+        self.default_query_types = Some(query_types);
+        self
+    }
+
+    /// Selects the upstream for a query for `name` of type `query_type`.
+    /// Returns `None` if no upstream is allowed to handle the query.
+    fn upstream(&self, name: &Name, query_type: RecordType) -> Option<&Arc<dyn Client>> {
+        if let Some(rule) = self
+            .rules
+            .iter()
+            .find(|rule| rule.matches(name, query_type))
+        {
+            debug!(
+                "ForwardingClient: forwarding '{}' ({}) per rule for '{}'",
+                name, query_type, rule.domain
+            );
+            return Some(&rule.upstream);
+        }
+        allows(&self.default_query_types, query_type).then_some(&self.default)
+    }
+
+    /// Forwards a query to its upstream, bounded by `deadline` (if any).
+    async fn forward(&self, data: BytesMut, deadline: Option<Instant>) -> Result<BytesMut> {
+        let Some(question) = first_question(&data) else {
+            return Err(eyre!("ForwardingClient: cannot parse question of query"));
+        };
+        let Some(upstream) = self.upstream(question.name(), question.query_type()) else {
+            debug!(
+                "ForwardingClient: refusing '{}' ({}), as no upstream allows this query type",
+                question.name(),
+                question.query_type()
+            );
+            let response = error_response(&data, ResponseCode::Refused)
+                .ok_or_else(|| eyre!("ForwardingClient: cannot build response to query"))?;
+            return Ok(response.as_slice().into());
+        };
+        match deadline {
+            Some(deadline) => upstream.resolve_raw_with_deadline(data, deadline).await,
+            None => upstream.resolve_raw(data).await,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Client for ForwardingClient {
+    async fn resolve_raw(&self, data: BytesMut) -> Result<BytesMut> {
+        self.forward(data, None).await
+    }
+
+    async fn resolve_raw_with_deadline(
+        &self,
+        data: BytesMut,
+        deadline: Instant,
+    ) -> Result<BytesMut> {
+        self.forward(data, Some(deadline)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hickory_proto::{
+        op::{Message, Query},
+        rr::{rdata::A, RData, RecordType},
+    };
+
+    use super::*;
+    use crate::client::mock::MockClient;
+
+    fn build_query(name: &str) -> BytesMut {
+        build_query_with_type(name, RecordType::A)
+    }
+
+    fn build_query_with_type(name: &str, query_type: RecordType) -> BytesMut {
+        let mut query = Message::new();
+        query.add_query(Query::query(name.parse().unwrap(), query_type));
+        query.to_vec().unwrap().as_slice().into()
+    }
+
+    fn build_upstream(names: &[&str]) -> Arc<MockClient> {
+        let upstream = MockClient::new();
+        for name in names {
+            upstream.set_answer(name, RData::A(A::new(192, 0, 2, 1)));
+        }
+        Arc::new(upstream)
+    }
+
+    #[tokio::test]
+    async fn forward_by_domain() {
+        let names = [
+            "internal.example.",
+            "host.internal.example.",
+            "host.dev.internal.example.",
+            "www.example.com.",
+        ];
+        let internal = build_upstream(&names);
+        let dev = build_upstream(&names);
+        let default = build_upstream(&names);
+        let rules = vec![
+            ForwardingRule::new("internal.example.".parse().unwrap(), internal.clone()),
+            ForwardingRule::new("dev.internal.example.".parse().unwrap(), dev.clone()),
+        ];
+        let client = ForwardingClient::new(rules, default.clone());
+
+        client
+            .resolve_raw(build_query("internal.example."))
+            .await
+            .unwrap();
+        client
+            .resolve_raw(build_query("HOST.internal.example."))
+            .await
+            .unwrap();
+        assert_eq!(internal.calls(), 2);
+
+        // The longest matching domain wins.
+        client
+            .resolve_raw(build_query("host.dev.internal.example."))
+            .await
+            .unwrap();
+        assert_eq!(dev.calls(), 1);
+        assert_eq!(internal.calls(), 2);
+
+        client
+            .resolve_raw(build_query("www.example.com."))
+            .await
+            .unwrap();
+        assert_eq!(default.calls(), 1);
+        assert_eq!(internal.calls(), 2);
+        assert_eq!(dev.calls(), 1);
+    }
+
+    #[tokio::test]
+    async fn no_partial_label_match() {
+        let internal = build_upstream(&[]);
+        let default = build_upstream(&["notinternal.example."]);
+        let rules = vec![ForwardingRule::new(
+            "internal.example.".parse().unwrap(),
+            internal.clone(),
+        )];
+        let client = ForwardingClient::new(rules, default.clone());
+
+        client
+            .resolve_raw(build_query("notinternal.example."))
+            .await
+            .unwrap();
+        assert_eq!(default.calls(), 1);
+        assert_eq!(internal.calls(), 0);
+    }
+
+    #[tokio::test]
+    async fn forward_by_query_type() {
+        let ptr_name = "1.2.0.192.in-addr.arpa.";
+        let local = MockClient::new();
+        local.set_response(ptr_name, RecordType::PTR, Message::new());
+        let local = Arc::new(local);
+        let default = build_upstream(&["www.example.com."]);
+        default.set_response(ptr_name, RecordType::PTR, Message::new());
+        let rules = vec![ForwardingRule {
+            domain: Name::root(),
+            query_types: Some(HashSet::from([RecordType::PTR])),
+            upstream: local.clone(),
+        }];
+        let client = ForwardingClient::new(rules, default.clone());
+
+        client
+            .resolve_raw(build_query_with_type(ptr_name, RecordType::PTR))
+            .await
+            .unwrap();
+        assert_eq!(local.calls(), 1);
+        assert_eq!(default.calls(), 0);
+
+        client
+            .resolve_raw(build_query("www.example.com."))
+            .await
+            .unwrap();
+        assert_eq!(local.calls(), 1);
+        assert_eq!(default.calls(), 1);
+    }
+
+    #[tokio::test]
+    async fn refuse_disallowed_query_type() {
+        let internal = build_upstream(&["host.internal.example."]);
+        let default = build_upstream(&["www.example.com."]);
+        let rules = vec![ForwardingRule {
+            domain: "internal.example.".parse().unwrap(),
+            query_types: Some(HashSet::from([RecordType::A])),
+            upstream: internal.clone(),
+        }];
+        let client = ForwardingClient::new(rules, default.clone())
+            .with_default_query_types(HashSet::from([RecordType::A, RecordType::AAAA]));
+
+        let response = client
+            .resolve_raw(build_query_with_type(
+                "host.internal.example.",
+                RecordType::TXT,
+            ))
+            .await
+            .unwrap();
+
+        let response = Message::from_vec(&response).unwrap();
+        assert_eq!(response.response_code(), ResponseCode::Refused);
+        assert_eq!(response.queries()[0].query_type(), RecordType::TXT);
+        assert_eq!(internal.calls(), 0);
+        assert_eq!(default.calls(), 0);
+    }
+}
@@ -0,0 +1,5 @@
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Max Wipfli <mail@maxwipfli.ch>
+
+mod forwarding_client;
+pub use forwarding_client::{ForwardingClient, ForwardingRule};
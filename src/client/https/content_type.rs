@@ -0,0 +1,67 @@
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Max Wipfli <mail@maxwipfli.ch>
+
+use eyre::{eyre, Result};
+use http::{header, HeaderMap};
+
+/// Media type of DNS messages (RFC 8484, Section 6).
+const DNS_MESSAGE: &str = "application/dns-message";
+
+/// Number of bytes of the body included in errors about unexpected content.
+const SNIPPET_LEN: usize = 64;
+
+/// Checks that a response of a DoH server with the given headers and body contains a DNS message.
+///
+/// Captive portals and proxies may answer with e.g. an HTML page instead, which must not be parsed as DNS message.
+/// The error includes the start of the body to help diagnose where the response came from.
+pub fn check(headers: &HeaderMap, body: &[u8]) -> Result<()> {
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok());
+    let media_type = content_type
+        .and_then(|content_type| content_type.split(';').next())
+        .map(str::trim);
+    if media_type.is_some_and(|media_type| media_type.eq_ignore_ascii_case(DNS_MESSAGE)) {
+        return Ok(());
+    }
+
+    let snippet = String::from_utf8_lossy(&body[..body.len().min(SNIPPET_LEN)]);
+    Err(eyre!(
+        "DoH server responded with Content-Type '{}' instead of '{}' (the response may come from a captive portal \
+         or proxy), response starts with: {:?}",
+        content_type.unwrap_or("<none>"),
+        DNS_MESSAGE,
+        snippet
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use http::HeaderValue;
+
+    use super::*;
+
+    fn headers(content_type: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+        headers
+    }
+
+    #[test]
+    fn dns_message() {
+        assert!(check(&headers("application/dns-message"), &[0; 12]).is_ok());
+        assert!(check(&headers("Application/DNS-Message; foo=bar"), &[0; 12]).is_ok());
+    }
+
+    #[test]
+    fn unexpected_content() {
+        let body = b"<html><body>Please log in to use the Wi-Fi</body></html>";
+        let err = check(&headers("text/html; charset=utf-8"), body).unwrap_err();
+        assert!(err.to_string().contains("text/html"), "{}", err);
+        assert!(err.to_string().contains("Please log in"), "{}", err);
+
+        let err = check(&HeaderMap::new(), &[b'x'; 1000]).unwrap_err();
+        assert!(err.to_string().contains("<none>"), "{}", err);
+        assert!(err.to_string().len() < 300, "{}", err);
+    }
+}
@@ -3,15 +3,30 @@
 
 use std::{
     net::{IpAddr, SocketAddr},
-    time::Duration,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
 };
 
-use bytes::BytesMut;
+use bytes::{Bytes, BytesMut};
 use eyre::{eyre, Result};
-use reqwest::{header, redirect::Policy, Url};
-use tracing::debug;
+use hickory_proto::{
+    op::{Message, Query},
+    rr::{Name, RecordType},
+};
+use reqwest::{
+    header::{self, HeaderMap, HeaderValue},
+    redirect::Policy,
+    ClientBuilder, Proxy, RequestBuilder, Response, Url, Version,
+};
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tracing::{debug, info, warn};
 
-use crate::{client::Client, proto::txid_from_binary_message};
+use super::{content_type, status::send_with_retries};
+use crate::{
+    client::Client,
+    proto::{set_txid_in_binary_message, txid_from_binary_message},
+};
 
 /// A DNS-over-HTTPS (DoH) client.
 ///
@@ -20,15 +35,126 @@ use crate::{client::Client, proto::txid_from_binary_message};
 /// [RFC8484]: https://datatracker.ietf.org/doc/html/rfc8484
 pub struct HttpsClient {
     client: reqwest::Client,
+    /// HTTP version of requests sent using `client`.
+    version: Version,
+    /// HTTP/2 client to fall back to if HTTP/3 requests using `client` fail.
+    fallback_client: Option<reqwest::Client>,
+    /// When the last HTTP/3 request failed, if `fallback_client` is used since. HTTP/3 is tried again once
+    /// `http3_retry_interval` has passed.
+    fallen_back_at: Mutex<Option<Instant>>,
+    http3_retry_interval: Duration,
     url: reqwest::Url,
+    /// Headers set on every request (including the User-Agent).
+    headers: HeaderMap,
+    /// Permits for concurrent requests to the server, if limited.
+    request_permits: Option<Semaphore>,
+    queue_timeout: Duration,
+}
+
+/// HTTP version used to communicate with the DNS-over-HTTPS (DoH) server.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HttpVersion {
+    /// Use HTTP/2 with prior knowledge.
+    #[default]
+    Http2,
+    /// Use HTTP/3 (over QUIC). Requires the `http3` feature.
+    Http3,
+    /// Use HTTP/3 if available, and fall back to HTTP/2 if HTTP/3 requests fail. HTTP/3 is tried again after a
+    /// while, in case the failure was temporary (e.g. UDP blocked on the current network).
+    Auto,
+}
+
+impl FromStr for HttpVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "2" => Ok(Self::Http2),
+            "3" => Ok(Self::Http3),
+            "auto" => Ok(Self::Auto),
+            _ => Err(format!(
+                "invalid HTTP version '{}' (expected '2', '3', or 'auto')",
+                s
+            )),
+        }
+    }
+}
+
+/// Configuration of an `HttpsClient`.
+#[derive(Debug, Clone)]
+pub struct HttpsClientConfig {
+    /// Interval at which HTTP/2 PING frames are sent to keep the connection to the server warm while idle.
+    /// If unset, idle connections are eventually closed and the next query pays for a new handshake.
+    pub keepalive_interval: Option<Duration>,
+
+    /// HTTP version used to communicate with the server.
+    pub http_version: HttpVersion,
+
+    /// Time after which a request to the server is aborted, including connection setup.
+    pub timeout: Duration,
+
+    /// Maximum number of idle connections to the server kept in the connection pool.
+    pub pool_max_idle_per_host: usize,
+
+    /// Time after which idle connections are closed. Idle connections are never closed if `keepalive_interval` is
+    /// set.
+    pub pool_idle_timeout: Duration,
+
+    /// Interval at which TCP keepalive probes are sent on connections to the server. If unset, TCP keepalive is
+    /// disabled.
+    pub tcp_keepalive: Option<Duration>,
+
+    /// User-Agent sent with every request.
+    pub user_agent: String,
+
+    /// Additional headers sent with every request, e.g. to authenticate with a proxy.
+    pub headers: HeaderMap,
+
+    /// URL of the proxy to connect to the server through, e.g. `http://proxy.example.net:3128` or
+    /// `socks5h://127.0.0.1:9050` (which requires the `socks` feature). If unset, the proxy configured by the
+    /// `HTTPS_PROXY` environment variable is used, if any.
+    pub proxy: Option<String>,
+
+    /// Maximum number of concurrent requests to the server, e.g. to stay within the rate limits of providers limiting
+    /// the requests in flight per connection. If unset, the number of requests is not limited.
+    pub max_concurrent_requests: Option<usize>,
+
+    /// Time requests over the `max_concurrent_requests` limit wait for another request to finish, after which they
+    /// fail.
+    pub queue_timeout: Duration,
+}
+
+impl Default for HttpsClientConfig {
+    fn default() -> Self {
+        Self {
+            keepalive_interval: None,
+            http_version: HttpVersion::default(),
+            timeout: Duration::from_secs(10),
+            // HTTP/2 multiplexes requests, so a handful of connections is plenty even under high load.
+            pool_max_idle_per_host: 4,
+            pool_idle_timeout: Duration::from_secs(90),
+            tcp_keepalive: Some(Duration::from_secs(60)),
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            headers: HeaderMap::new(),
+            proxy: None,
+            max_concurrent_requests: None,
+            queue_timeout: Duration::from_secs(10),
+        }
+    }
 }
 
+/// Default User-Agent of `HttpsClient` requests.
+pub(super) const DEFAULT_USER_AGENT: &str =
+    concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
 impl HttpsClient {
     const CONTENT_TYPE_DNS_MESSAGE: &'static str = "application/dns-message";
+    /// Time after falling back to HTTP/2, after which HTTP/3 is tried again.
+    const HTTP3_RETRY_INTERVAL: Duration = Duration::from_secs(300);
 
     /// Create a new `HttpsClient` with the given server URL.
     /// For bootstrap purposes, the IP address of the server can be provided.
-    pub fn new(url: Url, ip: Option<IpAddr>) -> Result<Self> {
+    pub fn new(url: Url, ips: &[IpAddr], config: HttpsClientConfig) -> Result<Self> {
         if url.scheme() != "https" {
             return Err(eyre!(
                 "DohClient cannot be constructed with URL of scheme '{}' (expected 'https')",
@@ -41,71 +167,297 @@ impl HttpsClient {
             ));
         };
 
+        let headers = Self::headers(&config)?;
+        let proxy = config.proxy.as_deref().map(Self::proxy).transpose()?;
+        let request_permits = Self::request_permits(&config)?;
+
+        let h2_client = Self::client_builder(host, ips, &config, proxy.clone())
+            // Prefer HTTP/2.
+            .http2_prior_knowledge()
+            .build()?;
+
+        let (client, version, fallback_client) = match config.http_version {
+            HttpVersion::Http2 => (h2_client, Version::HTTP_2, None),
+            HttpVersion::Http3 => {
+                let h3_client = Self::build_http3_client(Self::client_builder(
+                    host,
+                    ips,
+                    &config,
+                    proxy.clone(),
+                ))?;
+                (h3_client, Version::HTTP_3, None)
+            }
+            HttpVersion::Auto => {
+                match Self::build_http3_client(Self::client_builder(
+                    host,
+                    ips,
+                    &config,
+                    proxy.clone(),
+                )) {
+                    Ok(h3_client) => (h3_client, Version::HTTP_3, Some(h2_client)),
+                    Err(err) => {
+                        warn!(
+                            "HttpsClient: using HTTP/2 for '{}', as HTTP/3 is unavailable: {}",
+                            url, err
+                        );
+                        (h2_client, Version::HTTP_2, None)
+                    }
+                }
+            }
+        };
+
+        Ok(Self {
+            client,
+            version,
+            fallback_client,
+            fallen_back_at: Mutex::new(None),
+            http3_retry_interval: Self::HTTP3_RETRY_INTERVAL,
+            url,
+            headers,
+            request_permits,
+            queue_timeout: config.queue_timeout,
+        })
+    }
+
+    /// Returns the headers to set on every request, i.e. the configured headers and the User-Agent.
+    pub(super) fn headers(config: &HttpsClientConfig) -> Result<HeaderMap> {
+        let mut headers = config.headers.clone();
+        let user_agent = HeaderValue::from_str(&config.user_agent)
+            .map_err(|_| eyre!("DohClient: invalid User-Agent '{}'", config.user_agent))?;
+        headers.insert(header::USER_AGENT, user_agent);
+        Ok(headers)
+    }
+
+    /// Parses the URL of a proxy.
+    pub(super) fn proxy(url: &str) -> Result<Proxy> {
+        let proxy = Proxy::all(url)
+            .map_err(|err| eyre!("DohClient: invalid proxy URL '{}': {}", url, err))?;
+        if cfg!(not(feature = "socks")) && url.starts_with("socks") {
+            return Err(eyre!(
+                "DohClient: SOCKS proxy '{}' is unsupported (requires the `socks` feature)",
+                url
+            ));
+        }
+        Ok(proxy)
+    }
+
+    /// Returns a client builder with the settings common to all HTTP versions.
+    pub(super) fn client_builder(
+        host: &str,
+        ips: &[IpAddr],
+        config: &HttpsClientConfig,
+        proxy: Option<Proxy>,
+    ) -> ClientBuilder {
         let mut client_builder = reqwest::Client::builder()
             // Do not follow redirects.
             .redirect(Policy::none())
-            // Time out requests after 10 seconds.
-            // TODO: Tune this and make it configurable.
-            .timeout(Duration::from_secs(10))
+            .timeout(config.timeout)
             // Do not use plain HTTP.
             .https_only(true)
-            // Prefer HTTP/2.
-            .http2_prior_knowledge();
+            .pool_max_idle_per_host(config.pool_max_idle_per_host)
+            .pool_idle_timeout(config.pool_idle_timeout)
+            .tcp_keepalive(config.tcp_keepalive);
+
+        if let Some(interval) = config.keepalive_interval {
+            client_builder = client_builder
+                .http2_keep_alive_interval(interval)
+                .http2_keep_alive_while_idle(true)
+                // Keep the idle connection in the pool, it is kept alive by the PING frames.
+                .pool_idle_timeout(None);
+        }
+
+        if let Some(proxy) = proxy {
+            client_builder = client_builder.proxy(proxy);
+        }
 
-        if let Some(ip) = ip {
+        if !ips.is_empty() {
             // Resolve server's hostname out-of-band.
             // This may be required to avoid bootstrap issues if this is the configured system resolver.
+            // If there are multiple addresses, connecting fails over between them.
             debug!(
-                "HttpsClient: resolving '{}' to {} to bootstrap DNS",
-                host, ip
+                "HttpsClient: resolving '{}' to {:?} to bootstrap DNS",
+                host, ips
             );
-            client_builder = client_builder.resolve(host, SocketAddr::new(ip, 0));
+            let addrs = ips
+                .iter()
+                .map(|&ip| SocketAddr::new(ip, 0))
+                .collect::<Vec<_>>();
+            client_builder = client_builder.resolve_to_addrs(host, &addrs);
         }
 
-        let client = client_builder.build()?;
+        client_builder
+    }
+
+    #[cfg(feature = "http3")]
+    fn build_http3_client(client_builder: ClientBuilder) -> Result<reqwest::Client> {
+        Ok(client_builder.http3_prior_knowledge().build()?)
+    }
+
+    #[cfg(not(feature = "http3"))]
+    fn build_http3_client(_client_builder: ClientBuilder) -> Result<reqwest::Client> {
+        Err(eyre!(
+            "HttpsClient: HTTP/3 support requires building with the 'http3' feature"
+        ))
+    }
+
+    /// Establishes the connection to the server ahead of the first query, so that it doesn't pay for the handshake.
+    ///
+    /// This resolves the server's own hostname, which the server is likely to have cached.
+    pub async fn warmup(&self) -> Result<()> {
+        let host = self
+            .url
+            .host_str()
+            .ok_or_else(|| eyre!("DohClient: URL doesn't specify host"))?;
+        let name = Name::from_ascii(host.trim_start_matches('[').trim_end_matches(']'))
+            .unwrap_or_else(|_| Name::root());
+        let mut query = Message::new();
+        query
+            .set_recursion_desired(true)
+            .add_query(Query::query(name, RecordType::A));
+        self.resolve(query).await?;
+        debug!("HttpsClient: connection to server warmed up");
+        Ok(())
+    }
+
+    /// Sends a DNS message to the server, falling back to HTTP/2 if configured and the HTTP/3 request fails.
+    async fn send(&self, body: Bytes) -> reqwest::Result<Response> {
+        let Some(fallback_client) = &self.fallback_client else {
+            return self.post(&self.client, self.version, body).await;
+        };
+        if self.should_try_http3() {
+            match self.post(&self.client, self.version, body.clone()).await {
+                Ok(response) => {
+                    if self.fallen_back_at.lock().unwrap().take().is_some() {
+                        info!("HttpsClient: HTTP/3 request succeeded, no longer falling back to HTTP/2");
+                    }
+                    return Ok(response);
+                }
+                Err(err) => {
+                    warn!(
+                        "HttpsClient: HTTP/3 request failed, falling back to HTTP/2 for {:?}: {}",
+                        self.http3_retry_interval, err
+                    );
+                    *self.fallen_back_at.lock().unwrap() = Some(Instant::now());
+                }
+            }
+        }
+        self.post(fallback_client, Version::HTTP_2, body).await
+    }
 
-        Ok(Self { client, url })
+    /// Returns whether to send the next request using HTTP/3, i.e. if it hasn't failed recently. Once the retry
+    /// interval has passed since falling back, HTTP/3 is tried by a single request, while concurrent requests keep
+    /// using HTTP/2 until it succeeded.
+    fn should_try_http3(&self) -> bool {
+        let mut fallen_back_at = self.fallen_back_at.lock().unwrap();
+        match *fallen_back_at {
+            None => true,
+            Some(at) if at.elapsed() >= self.http3_retry_interval => {
+                *fallen_back_at = Some(Instant::now());
+                true
+            }
+            Some(_) => false,
+        }
+    }
+
+    async fn post(
+        &self,
+        client: &reqwest::Client,
+        version: Version,
+        body: Bytes,
+    ) -> reqwest::Result<Response> {
+        self.request(client, version, body).send().await
+    }
+
+    /// Returns the permits for concurrent requests to the server, if limited by the configuration.
+    pub(super) fn request_permits(config: &HttpsClientConfig) -> Result<Option<Semaphore>> {
+        match config.max_concurrent_requests {
+            Some(0) => Err(eyre!(
+                "DohClient cannot be constructed with a concurrency limit of 0"
+            )),
+            max_concurrent_requests => Ok(max_concurrent_requests.map(Semaphore::new)),
+        }
+    }
+
+    async fn acquire_request_permit(&self) -> Result<Option<SemaphorePermit<'_>>> {
+        Self::acquire_permit(self.request_permits.as_ref(), self.queue_timeout).await
+    }
+
+    /// Waits for one of `permits` to send a request to the server, if the number of concurrent requests is limited.
+    /// Fails if none becomes available within `queue_timeout`.
+    pub(super) async fn acquire_permit(
+        permits: Option<&Semaphore>,
+        queue_timeout: Duration,
+    ) -> Result<Option<SemaphorePermit<'_>>> {
+        let Some(permits) = permits else {
+            return Ok(None);
+        };
+        match tokio::time::timeout(queue_timeout, permits.acquire()).await {
+            Ok(permit) => Ok(Some(permit?)),
+            Err(_) => Err(eyre!(
+                "DohClient: timeout waiting for other requests to the server to finish"
+            )),
+        }
+    }
+
+    /// Checks that the response with the given headers and body contains a DNS message, and restores its
+    /// transaction ID to `txid`.
+    fn check_response(headers: &HeaderMap, mut data: BytesMut, txid: u16) -> Result<BytesMut> {
+        content_type::check(headers, &data)?;
+        if txid_from_binary_message(&data).is_none() {
+            return Err(eyre!("DohClient: response is too short"));
+        }
+        // restore transaction ID
+        set_txid_in_binary_message(&mut data, txid);
+        Ok(data)
+    }
+
+    fn request(&self, client: &reqwest::Client, version: Version, body: Bytes) -> RequestBuilder {
+        client
+            .post(self.url.clone())
+            .version(version)
+            .headers(self.headers.clone())
+            .header(header::ACCEPT, Self::CONTENT_TYPE_DNS_MESSAGE)
+            .header(header::CONTENT_TYPE, Self::CONTENT_TYPE_DNS_MESSAGE)
+            .body(body)
     }
 }
 
 #[async_trait::async_trait]
 impl Client for HttpsClient {
     async fn resolve_raw(&self, mut data: BytesMut) -> Result<BytesMut> {
-        let txid = txid_from_binary_message(&data);
+        let txid = txid_from_binary_message(&data)
+            .ok_or_else(|| eyre!("DohClient: query is too short"))?;
 
         // "In order to maximize HTTP cache friendliness, DoH clients [...]
         // SHOULD use a DNS ID of 0 in every DNS request."
         // RFC 8484, Section 4.1
-        data[0] = 0;
-        data[1] = 0;
-
-        let response = self
-            .client
-            .post(self.url.clone())
-            .header(header::ACCEPT, Self::CONTENT_TYPE_DNS_MESSAGE)
-            .header(header::CONTENT_TYPE, Self::CONTENT_TYPE_DNS_MESSAGE)
-            .body(data.freeze())
-            .send()
-            .await?;
+        set_txid_in_binary_message(&mut data, 0);
 
-        // TODO: Perform better error handling, potentially using retries etc.
-        if !response.status().is_success() {
-            return Err(eyre!(
-                "DohClient: server returned non-success status: {}",
-                response.status()
-            ));
-        }
+        let body = data.freeze();
+        // The permit is held until the response has been read completely.
+        let _permit = self.acquire_request_permit().await?;
+        let response = send_with_retries(|| async { Ok(self.send(body.clone()).await?) }).await?;
 
-        let mut data = BytesMut::from(response.bytes().await?);
-        // restore transaction ID
-        data[0..2].copy_from_slice(&txid.to_be_bytes());
-        Ok(data)
+        let headers = response.headers().clone();
+        let data = BytesMut::from(response.bytes().await?);
+        HttpsClient::check_response(&headers, data, txid)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{env, sync::Arc};
+    use std::{
+        env,
+        net::Ipv4Addr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Instant,
+    };
+
+    use reqwest::dns::{Addrs, Name as HostName, Resolve, Resolving};
+    use tokio::net::TcpListener;
 
     use super::*;
     use crate::client::client::tests as client_tests;
@@ -113,13 +465,16 @@ mod tests {
     static TEST_DOH_SERVER_URL: &str = "https://dns10.quad9.net/dns-query";
 
     fn build_client() -> Arc<HttpsClient> {
+        build_client_with_config(HttpsClientConfig::default())
+    }
+
+    fn build_client_with_config(config: HttpsClientConfig) -> Arc<HttpsClient> {
         let server_url = env::var("FERRITE_TEST_DOH_SERVER_URL")
             .unwrap_or(TEST_DOH_SERVER_URL.to_string())
             .parse()
             .unwrap();
         // For testing purposes, we don't need to resolve the server's hostname, as we can rely on the system resolver.
-        let ip = None;
-        let client = HttpsClient::new(server_url, ip).unwrap();
+        let client = HttpsClient::new(server_url, &[], config).unwrap();
         Arc::new(client)
     }
 
@@ -134,4 +489,360 @@ mod tests {
         let client = build_client();
         client_tests::basic_aaaa(client).await;
     }
+
+    /// Resolver counting the hostnames it resolves, i.e. the connections opened by a client using it. If `failing`,
+    /// resolving fails, so that no connection is opened.
+    #[derive(Default)]
+    struct CountingResolver {
+        resolutions: AtomicUsize,
+        failing: bool,
+    }
+
+    impl Resolve for CountingResolver {
+        fn resolve(&self, name: HostName) -> Resolving {
+            self.resolutions.fetch_add(1, Ordering::SeqCst);
+            let failing = self.failing;
+            Box::pin(async move {
+                if failing {
+                    return Err("resolution disabled".into());
+                }
+                let addrs = tokio::net::lookup_host((name.as_str(), 0)).await?;
+                Ok(Box::new(addrs.collect::<Vec<_>>().into_iter()) as Addrs)
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn keepalive_across_idle_period() {
+        let config = HttpsClientConfig {
+            keepalive_interval: Some(Duration::from_secs(1)),
+            // Without keepalive, the connection would be closed while idle.
+            pool_idle_timeout: Duration::from_secs(1),
+            ..Default::default()
+        };
+        let mut client = Arc::try_unwrap(build_client_with_config(config.clone()))
+            .ok()
+            .unwrap();
+        let resolver = Arc::new(CountingResolver::default());
+        let host = client.url.host_str().unwrap().to_string();
+        client.client = HttpsClient::client_builder(&host, &[], &config, None)
+            .http2_prior_knowledge()
+            .dns_resolver(resolver.clone())
+            .build()
+            .unwrap();
+        let client = Arc::new(client);
+
+        client_tests::basic_a(client.clone()).await;
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        client_tests::basic_a(client).await;
+        // The connection was reused, so the hostname was only resolved for the first query.
+        assert_eq!(resolver.resolutions.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn custom_pool_settings() {
+        let client = build_client_with_config(HttpsClientConfig {
+            pool_max_idle_per_host: 1,
+            pool_idle_timeout: Duration::from_secs(5),
+            tcp_keepalive: None,
+            ..Default::default()
+        });
+        client_tests::basic_a(client).await;
+    }
+
+    #[test]
+    fn parse_http_version() {
+        assert_eq!("2".parse(), Ok(HttpVersion::Http2));
+        assert_eq!("3".parse(), Ok(HttpVersion::Http3));
+        assert_eq!("auto".parse(), Ok(HttpVersion::Auto));
+        assert!("1.1".parse::<HttpVersion>().is_err());
+    }
+
+    #[cfg(not(feature = "http3"))]
+    #[test]
+    fn http3_requires_feature() {
+        let config = HttpsClientConfig {
+            http_version: HttpVersion::Http3,
+            ..Default::default()
+        };
+        assert!(HttpsClient::new(TEST_DOH_SERVER_URL.parse().unwrap(), &[], config).is_err());
+
+        let config = HttpsClientConfig {
+            http_version: HttpVersion::Auto,
+            ..Default::default()
+        };
+        let client = HttpsClient::new(TEST_DOH_SERVER_URL.parse().unwrap(), &[], config).unwrap();
+        assert_eq!(client.version, Version::HTTP_2);
+        assert!(client.fallback_client.is_none());
+    }
+
+    #[cfg(feature = "http3")]
+    #[tokio::test]
+    async fn http3_basic_a() {
+        let client = build_client_with_config(HttpsClientConfig {
+            http_version: HttpVersion::Http3,
+            ..Default::default()
+        });
+        client_tests::basic_a(client).await;
+    }
+
+    #[cfg(feature = "http3")]
+    #[tokio::test]
+    async fn auto_basic_a() {
+        let client = build_client_with_config(HttpsClientConfig {
+            http_version: HttpVersion::Auto,
+            ..Default::default()
+        });
+        client_tests::basic_a(client).await;
+    }
+
+    #[tokio::test]
+    async fn retry_http3_after_fallback() {
+        let config = HttpsClientConfig::default();
+        let mut client =
+            HttpsClient::new(TEST_DOH_SERVER_URL.parse().unwrap(), &[], config.clone()).unwrap();
+        // Stand-ins for the HTTP/3 and HTTP/2 clients, which fail without connecting to the server.
+        let build_failing_client = || {
+            let resolver = Arc::new(CountingResolver {
+                failing: true,
+                ..Default::default()
+            });
+            let client = HttpsClient::client_builder("dns10.quad9.net", &[], &config, None)
+                .dns_resolver(resolver.clone())
+                .build()
+                .unwrap();
+            (client, resolver)
+        };
+        let (primary, primary_resolver) = build_failing_client();
+        let (fallback, fallback_resolver) = build_failing_client();
+        client.client = primary;
+        // The stand-in doesn't support HTTP/3, but the version doesn't matter as requests fail before connecting.
+        client.version = Version::HTTP_11;
+        client.fallback_client = Some(fallback);
+        client.http3_retry_interval = Duration::from_millis(50);
+        let attempts = |resolver: &CountingResolver| resolver.resolutions.load(Ordering::SeqCst);
+
+        assert!(client.send(Bytes::new()).await.is_err());
+        assert_eq!(attempts(&primary_resolver), 1);
+        assert_eq!(attempts(&fallback_resolver), 1);
+        assert!(client.fallen_back_at.lock().unwrap().is_some());
+
+        // Further requests only use the fallback client.
+        assert!(client.send(Bytes::new()).await.is_err());
+        assert_eq!(attempts(&primary_resolver), 1);
+        assert_eq!(attempts(&fallback_resolver), 2);
+
+        // Once the retry interval has passed, the primary client is tried again.
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(client.send(Bytes::new()).await.is_err());
+        assert_eq!(attempts(&primary_resolver), 2);
+        assert_eq!(attempts(&fallback_resolver), 3);
+        assert!(client.send(Bytes::new()).await.is_err());
+        assert_eq!(attempts(&primary_resolver), 2);
+    }
+
+    /// Spawns a server on localhost that accepts connections, but never completes the TLS handshake.
+    /// Returns a client for this server with the given timeout.
+    async fn build_unresponsive_client(timeout: Duration) -> HttpsClient {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut connections = Vec::new();
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                connections.push(stream);
+            }
+        });
+        let config = HttpsClientConfig {
+            timeout,
+            ..Default::default()
+        };
+        let url = format!("https://{}/dns-query", server_addr);
+        HttpsClient::new(url.parse().unwrap(), &[], config).unwrap()
+    }
+
+    #[test]
+    fn request_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-auth-token", HeaderValue::from_static("secret"));
+        let config = HttpsClientConfig {
+            user_agent: "test-agent/1.0".to_string(),
+            headers,
+            ..Default::default()
+        };
+        let client = HttpsClient::new(TEST_DOH_SERVER_URL.parse().unwrap(), &[], config).unwrap();
+        let request = client
+            .request(&client.client, client.version, Bytes::new())
+            .build()
+            .unwrap();
+        assert_eq!(request.headers()[header::USER_AGENT], "test-agent/1.0");
+        assert_eq!(request.headers()["x-auth-token"], "secret");
+        assert_eq!(
+            request.headers()[header::CONTENT_TYPE],
+            HttpsClient::CONTENT_TYPE_DNS_MESSAGE
+        );
+
+        let client = build_client();
+        let request = client
+            .request(&client.client, client.version, Bytes::new())
+            .build()
+            .unwrap();
+        assert_eq!(request.headers()[header::USER_AGENT], DEFAULT_USER_AGENT);
+        assert!(DEFAULT_USER_AGENT.starts_with("aufloes/"));
+    }
+
+    #[test]
+    fn reject_short_responses() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static(HttpsClient::CONTENT_TYPE_DNS_MESSAGE),
+        );
+        for len in [0, 1] {
+            let data = BytesMut::from(&[0; 1][..len]);
+            let err = HttpsClient::check_response(&headers, data, 0x1234).unwrap_err();
+            assert!(err.to_string().contains("too short"), "{}", err);
+        }
+
+        let data = BytesMut::from(&[0; 12][..]);
+        let data = HttpsClient::check_response(&headers, data, 0x1234).unwrap();
+        assert_eq!(txid_from_binary_message(&data), Some(0x1234));
+    }
+
+    #[test]
+    fn proxy() {
+        let config = HttpsClientConfig {
+            proxy: Some("http://proxy.example.net:3128".to_string()),
+            ..Default::default()
+        };
+        assert!(HttpsClient::new(TEST_DOH_SERVER_URL.parse().unwrap(), &[], config).is_ok());
+
+        for proxy in ["not a url", "ftp://proxy.example.net"] {
+            let config = HttpsClientConfig {
+                proxy: Some(proxy.to_string()),
+                ..Default::default()
+            };
+            let Err(err) = HttpsClient::new(TEST_DOH_SERVER_URL.parse().unwrap(), &[], config)
+            else {
+                panic!("expected proxy '{}' to be rejected", proxy);
+            };
+            assert!(err.to_string().contains("proxy"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn invalid_user_agent() {
+        let config = HttpsClientConfig {
+            user_agent: "test\nagent".to_string(),
+            ..Default::default()
+        };
+        assert!(HttpsClient::new(TEST_DOH_SERVER_URL.parse().unwrap(), &[], config).is_err());
+    }
+
+    #[tokio::test]
+    async fn multiple_bootstrap_ips() {
+        // Connecting to the first address is refused, so the client has to fail over to the second one.
+        let ips = [
+            Ipv4Addr::LOCALHOST.into(),
+            Ipv4Addr::new(9, 9, 9, 10).into(),
+        ];
+        let client = HttpsClient::new(
+            TEST_DOH_SERVER_URL.parse().unwrap(),
+            &ips,
+            HttpsClientConfig::default(),
+        )
+        .unwrap();
+        client_tests::basic_a(Arc::new(client)).await;
+    }
+
+    #[tokio::test]
+    async fn warmup() {
+        let client = build_client();
+        client.warmup().await.unwrap();
+        client_tests::basic_a(client).await;
+    }
+
+    #[tokio::test]
+    async fn warmup_failure() {
+        let client = build_unresponsive_client(Duration::from_millis(50)).await;
+        assert!(client.warmup().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn configured_timeout() {
+        let client = build_unresponsive_client(Duration::from_millis(50)).await;
+
+        let mut query = Message::new();
+        query.add_query(Query::query(
+            "www.example.com.".parse().unwrap(),
+            RecordType::A,
+        ));
+        let data = query.to_vec().unwrap().as_slice().into();
+        let result = tokio::time::timeout(Duration::from_secs(1), client.resolve_raw(data))
+            .await
+            .unwrap();
+        let err = result.unwrap_err();
+        assert!(err.downcast_ref::<reqwest::Error>().unwrap().is_timeout());
+    }
+
+    #[tokio::test]
+    async fn limit_concurrent_requests() {
+        let mut client = build_unresponsive_client(Duration::from_millis(100)).await;
+        client.request_permits = Some(Semaphore::new(1));
+        let client = Arc::new(client);
+
+        let start = Instant::now();
+        let queries = (0..2)
+            .map(|_| {
+                let client = client.clone();
+                tokio::spawn(async move {
+                    let data = client_tests::build_request("www.example.com.", RecordType::A);
+                    let result = client.resolve_raw(data).await;
+                    (result, start.elapsed())
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut elapsed = Vec::new();
+        for query in queries {
+            let (result, query_elapsed) = query.await.unwrap();
+            let err = result.unwrap_err();
+            assert!(err.downcast_ref::<reqwest::Error>().unwrap().is_timeout());
+            elapsed.push(query_elapsed);
+        }
+        // The second request is only sent once the first one has timed out.
+        elapsed.sort();
+        assert!(elapsed[0] < Duration::from_millis(190), "{:?}", elapsed);
+        assert!(elapsed[1] >= Duration::from_millis(200), "{:?}", elapsed);
+    }
+
+    #[tokio::test]
+    async fn queue_timeout() {
+        let mut client = build_unresponsive_client(Duration::from_secs(1)).await;
+        client.request_permits = Some(Semaphore::new(1));
+        client.queue_timeout = Duration::from_millis(20);
+        let client = Arc::new(client);
+
+        let pending_client = client.clone();
+        let pending = tokio::spawn(async move {
+            let data = client_tests::build_request("www.example.com.", RecordType::A);
+            pending_client.resolve_raw(data).await
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        // Requests fail if no permit becomes available within the queue timeout.
+        let data = client_tests::build_request("www.example.com.", RecordType::A);
+        let err = client.resolve_raw(data).await.unwrap_err();
+        assert!(err.to_string().contains("timeout waiting"), "{}", err);
+        pending.abort();
+    }
+
+    #[test]
+    fn reject_zero_concurrency() {
+        let config = HttpsClientConfig {
+            max_concurrent_requests: Some(0),
+            ..Default::default()
+        };
+        let url = "https://dns.example.net/dns-query".parse().unwrap();
+        assert!(HttpsClient::new(url, &[], config).is_err());
+    }
 }
@@ -0,0 +1,453 @@
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Max Wipfli <mail@maxwipfli.ch>
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    time::Duration,
+};
+
+use bytes::BytesMut;
+use eyre::{eyre, Result};
+use hickory_proto::{
+    op::{Message, MessageType, ResponseCode},
+    rr::{
+        rdata::{A, AAAA, CNAME, MX, TXT},
+        Name, RData, Record, RecordType,
+    },
+};
+use reqwest::{header, Url};
+use serde::Deserialize;
+use tokio::sync::Semaphore;
+use tracing::debug;
+
+use super::{status::send_with_retries, HttpsClient, HttpsClientConfig};
+use crate::{client::Client, proto::message::error_response};
+
+/// A client for the JSON API of DNS-over-HTTPS (DoH) servers, as offered by e.g. Google
+/// (`https://dns.google/resolve`) and Cloudflare (`https://cloudflare-dns.com/dns-query`).
+///
+/// This API is not standardized, and is only meant for networks where the wire format of [RFC8484] is blocked.
+/// Only A, AAAA, MX, and TXT queries are supported, other queries are refused. The authority section of responses
+/// is dropped, as its records are not portably encoded in the JSON API.
+///
+/// [RFC8484]: https://datatracker.ietf.org/doc/html/rfc8484
+pub struct JsonDohClient {
+    client: reqwest::Client,
+    url: Url,
+    /// Permits for concurrent requests to the server, if limited.
+    request_permits: Option<Semaphore>,
+    queue_timeout: Duration,
+}
+
+impl JsonDohClient {
+    const CONTENT_TYPE_DNS_JSON: &'static str = "application/dns-json";
+
+    /// Creates a new `JsonDohClient` sending requests to `url`. For bootstrap purposes, the IP addresses of the
+    /// server can be provided.
+    ///
+    /// The client is configured like an `HttpsClient` (e.g. its timeout, proxy, and headers), except that the HTTP
+    /// version is negotiated with the server.
+    pub fn new(url: Url, ips: &[IpAddr], config: HttpsClientConfig) -> Result<Self> {
+        if url.scheme() != "https" {
+            return Err(eyre!(
+                "JsonDohClient cannot be constructed with URL of scheme '{}' (expected 'https')",
+                url.scheme()
+            ));
+        }
+        let Some(host) = url.host_str() else {
+            return Err(eyre!(
+                "JsonDohClient cannot be constructed with URL that doesn't specify host"
+            ));
+        };
+        let proxy = config
+            .proxy
+            .as_deref()
+            .map(HttpsClient::proxy)
+            .transpose()?;
+        let request_permits = HttpsClient::request_permits(&config)?;
+        let client = HttpsClient::client_builder(host, ips, &config, proxy)
+            .default_headers(HttpsClient::headers(&config)?)
+            .build()?;
+        Ok(Self {
+            client,
+            url,
+            request_permits,
+            queue_timeout: config.queue_timeout,
+        })
+    }
+
+    /// Returns the URL of the request for the given name and query type.
+    fn request_url(&self, name: &Name, query_type: RecordType) -> Url {
+        let mut url = self.url.clone();
+        url.query_pairs_mut()
+            .append_pair("name", &name.to_ascii())
+            .append_pair("type", &query_type.to_string());
+        url
+    }
+}
+
+/// Returns whether queries of the given type are supported.
+fn is_supported(query_type: RecordType) -> bool {
+    matches!(
+        query_type,
+        RecordType::A | RecordType::AAAA | RecordType::MX | RecordType::TXT
+    )
+}
+
+/// A response of the JSON API. Fields not needed to build the DNS response are ignored.
+#[derive(Debug, Deserialize)]
+struct JsonResponse {
+    #[serde(rename = "Status")]
+    status: u16,
+    #[serde(rename = "TC", default)]
+    truncated: bool,
+    #[serde(rename = "RA", default)]
+    recursion_available: bool,
+    #[serde(rename = "AD", default)]
+    authentic_data: bool,
+    #[serde(rename = "CD", default)]
+    checking_disabled: bool,
+    #[serde(rename = "Answer", default)]
+    answers: Vec<JsonRecord>,
+}
+
+/// A record of a response of the JSON API.
+#[derive(Debug, Deserialize)]
+struct JsonRecord {
+    name: String,
+    #[serde(rename = "type")]
+    record_type: u16,
+    #[serde(rename = "TTL")]
+    ttl: u32,
+    data: String,
+}
+
+/// Builds the response to `query` from the JSON response of the DoH server.
+fn build_response(query: &Message, json: &JsonResponse) -> Result<Message> {
+    let mut response = Message::new();
+    response
+        .set_id(query.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(query.op_code())
+        .set_response_code(json.status.into())
+        .set_recursion_desired(query.recursion_desired())
+        .set_recursion_available(json.recursion_available)
+        .set_truncated(json.truncated)
+        .set_authentic_data(json.authentic_data)
+        .set_checking_disabled(json.checking_disabled)
+        .add_queries(query.queries().to_vec());
+
+    for answer in &json.answers {
+        if let Some(record) = parse_record(answer)? {
+            response.add_answer(record);
+        }
+    }
+    Ok(response)
+}
+
+/// Parses a record of the JSON response. Returns `None` for records of unsupported types.
+fn parse_record(record: &JsonRecord) -> Result<Option<Record>> {
+    let invalid = |name| eyre!("JsonDohClient: record with invalid '{}' in response", name);
+
+    let name = Name::from_ascii(&record.name).map_err(|_| invalid("name"))?;
+    let record_type = RecordType::from(record.record_type);
+    let data = record.data.as_str();
+
+    let rdata = match record_type {
+        RecordType::A => data.parse::<Ipv4Addr>().ok().map(|ip| RData::A(A(ip))),
+        RecordType::AAAA => data
+            .parse::<Ipv6Addr>()
+            .ok()
+            .map(|ip| RData::AAAA(AAAA(ip))),
+        RecordType::CNAME => Name::from_ascii(data)
+            .ok()
+            .map(|name| RData::CNAME(CNAME(name))),
+        RecordType::MX => data.split_once(' ').and_then(|(preference, exchange)| {
+            let preference = preference.parse().ok()?;
+            let exchange = Name::from_ascii(exchange.trim()).ok()?;
+            Some(RData::MX(MX::new(preference, exchange)))
+        }),
+        RecordType::TXT => parse_txt(data).map(|strings| RData::TXT(TXT::new(strings))),
+        _ => {
+            debug!(
+                "JsonDohClient: dropping {} record of unsupported type from response",
+                record_type
+            );
+            return Ok(None);
+        }
+    };
+    let rdata = rdata.ok_or_else(|| invalid("data"))?;
+    Ok(Some(Record::from_rdata(name, record.ttl, rdata)))
+}
+
+/// Maximum length of a character string in TXT records.
+const TXT_STRING_LEN: usize = 255;
+
+/// Parses the data of a TXT record, returning its character strings.
+///
+/// Some servers (e.g. Cloudflare) quote the character strings like in zone files, while others (e.g. Google) return
+/// the concatenated text, which is split into character strings of the maximum length.
+fn parse_txt(data: &str) -> Option<Vec<String>> {
+    if !data.starts_with('"') {
+        let strings = data
+            .as_bytes()
+            .chunks(TXT_STRING_LEN)
+            .map(|chunk| String::from_utf8_lossy(chunk).into_owned())
+            .collect::<Vec<_>>();
+        return Some(if strings.is_empty() {
+            vec![String::new()]
+        } else {
+            strings
+        });
+    }
+
+    let mut strings = Vec::new();
+    let mut chars = data.chars();
+    loop {
+        match chars.next() {
+            Some('"') => {}
+            Some(' ') => continue,
+            None => return Some(strings),
+            Some(_) => return None,
+        }
+        let mut string = String::new();
+        loop {
+            match chars.next()? {
+                '"' => break,
+                '\\' => string.push(chars.next()?),
+                c => string.push(c),
+            }
+        }
+        strings.push(string);
+    }
+}
+
+#[async_trait::async_trait]
+impl Client for JsonDohClient {
+    async fn resolve_raw(&self, data: BytesMut) -> Result<BytesMut> {
+        let query = Message::from_vec(&data)
+            .map_err(|err| eyre!("JsonDohClient: cannot parse query: {}", err))?;
+        let [question] = query.queries() else {
+            return Err(eyre!("JsonDohClient: query must contain a single question"));
+        };
+        if !is_supported(question.query_type()) {
+            debug!(
+                "JsonDohClient: refusing unsupported query type {}",
+                question.query_type()
+            );
+            let response = error_response(&data, ResponseCode::Refused)
+                .ok_or_else(|| eyre!("JsonDohClient: cannot build response to query"))?;
+            return Ok(response.as_slice().into());
+        }
+
+        let url = self.request_url(question.name(), question.query_type());
+        // The permit is held until the response has been read completely.
+        let _permit =
+            HttpsClient::acquire_permit(self.request_permits.as_ref(), self.queue_timeout).await?;
+        let response = send_with_retries(|| async {
+            Ok(self
+                .client
+                .get(url.clone())
+                .header(header::ACCEPT, Self::CONTENT_TYPE_DNS_JSON)
+                .send()
+                .await?)
+        })
+        .await?;
+        let body = response.bytes().await?;
+        let json = serde_json::from_slice(&body).map_err(|err| {
+            eyre!(
+                "JsonDohClient: cannot parse response (the server may not support the JSON API): {}",
+                err
+            )
+        })?;
+        let response = build_response(&query, &json)?;
+        Ok(response.to_vec()?.as_slice().into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, sync::Arc};
+
+    use hickory_proto::op::Query;
+
+    use super::*;
+    use crate::client::client::tests as client_tests;
+
+    fn parse(json: &str) -> serde_json::Result<JsonResponse> {
+        serde_json::from_str(json)
+    }
+
+    fn build_query(name: &str, query_type: RecordType) -> Message {
+        let mut query = Message::new();
+        query
+            .set_id(0x1234)
+            .set_recursion_desired(true)
+            .add_query(Query::query(name.parse().unwrap(), query_type));
+        query
+    }
+
+    #[test]
+    fn request_url() {
+        let url = "https://dns.google/resolve".parse().unwrap();
+        let client = JsonDohClient::new(url, &[], HttpsClientConfig::default()).unwrap();
+        let name = "www.example.com.".parse().unwrap();
+        assert_eq!(
+            client.request_url(&name, RecordType::AAAA).as_str(),
+            "https://dns.google/resolve?name=www.example.com.&type=AAAA"
+        );
+        assert!(JsonDohClient::new(
+            "http://dns.google/resolve".parse().unwrap(),
+            &[],
+            HttpsClientConfig::default()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn configured_client() {
+        let url = "https://dns.google/resolve".parse::<Url>().unwrap();
+        let config = HttpsClientConfig {
+            proxy: Some("http://proxy.example.net:3128".to_string()),
+            user_agent: "test-agent/1.0".to_string(),
+            ..Default::default()
+        };
+        let ips = [Ipv4Addr::new(8, 8, 8, 8).into()];
+        assert!(JsonDohClient::new(url.clone(), &ips, config).is_ok());
+
+        let config = HttpsClientConfig {
+            proxy: Some("not a url".to_string()),
+            ..Default::default()
+        };
+        assert!(JsonDohClient::new(url.clone(), &[], config).is_err());
+        let config = HttpsClientConfig {
+            user_agent: "test\nagent".to_string(),
+            ..Default::default()
+        };
+        assert!(JsonDohClient::new(url.clone(), &[], config).is_err());
+        let config = HttpsClientConfig {
+            max_concurrent_requests: Some(0),
+            ..Default::default()
+        };
+        assert!(JsonDohClient::new(url, &[], config).is_err());
+    }
+
+    #[test]
+    fn convert_response() {
+        let query = build_query("www.example.com.", RecordType::A);
+        let json = parse(
+            r#"{"Status": 0, "TC": false, "RD": true, "RA": true, "AD": true, "CD": false,
+                 "Question": [{"name": "www.example.com.", "type": 1}],
+                 "Answer": [
+                     {"name": "www.example.com.", "type": 5, "TTL": 600, "data": "example.com."},
+                     {"name": "example.com.", "type": 1, "TTL": 300, "data": "192.0.2.1"},
+                     {"name": "example.com.", "type": 46, "TTL": 300, "data": "a 8 2 300 ..."}
+                 ]}"#,
+        )
+        .unwrap();
+        let response = build_response(&query, &json).unwrap();
+        assert_eq!(response.id(), 0x1234);
+        assert_eq!(response.message_type(), MessageType::Response);
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert!(response.recursion_desired());
+        assert!(response.recursion_available());
+        assert!(response.authentic_data());
+        assert_eq!(response.queries(), query.queries());
+        let answers = response.answers();
+        assert_eq!(answers.len(), 2);
+        assert_eq!(
+            answers[0].data(),
+            Some(&RData::CNAME(CNAME("example.com.".parse().unwrap())))
+        );
+        assert_eq!(answers[1].ttl(), 300);
+        assert_eq!(answers[1].data(), Some(&RData::A(A::new(192, 0, 2, 1))));
+        // The response can be encoded in wire format.
+        Message::from_vec(&response.to_vec().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn convert_record_types() {
+        let query = build_query("example.com.", RecordType::MX);
+        let json = parse(
+            r#"{"Status": 0, "Answer": [
+                     {"name": "example.com.", "type": 28, "TTL": 60, "data": "2001:db8::1"},
+                     {"name": "example.com.", "type": 15, "TTL": 60, "data": "10 mail.example.com."},
+                     {"name": "example.com.", "type": 16, "TTL": 60, "data": "\"v=spf1 \" \"-all\""},
+                     {"name": "example.com.", "type": 16, "TTL": 60, "data": "v=spf1 -all"}
+                 ]}"#,
+        )
+        .unwrap();
+        let response = build_response(&query, &json).unwrap();
+        let data = response
+            .answers()
+            .iter()
+            .map(|record| record.data().unwrap().clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            data,
+            vec![
+                RData::AAAA(AAAA::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)),
+                RData::MX(MX::new(10, "mail.example.com.".parse().unwrap())),
+                RData::TXT(TXT::new(vec!["v=spf1 ".to_string(), "-all".to_string()])),
+                RData::TXT(TXT::new(vec!["v=spf1 -all".to_string()])),
+            ]
+        );
+
+        assert_eq!(parse_txt(&"x".repeat(300)).unwrap().len(), 2);
+        assert_eq!(parse_txt(r#""a\"b""#).unwrap(), vec!["a\"b".to_string()]);
+        assert_eq!(parse_txt(r#""unterminated"#), None);
+    }
+
+    #[test]
+    fn convert_error_responses() {
+        let query = build_query("nonexistent.example.com.", RecordType::A);
+        let json = parse(
+            r#"{"Status": 3, "Authority": [{"name": "example.com.", "type": 6, "TTL": 3600,
+                 "data": "ns.example.com. hostmaster.example.com. 1 7200 3600 1209600 3600"}]}"#,
+        )
+        .unwrap();
+        let response = build_response(&query, &json).unwrap();
+        assert_eq!(response.response_code(), ResponseCode::NXDomain);
+        assert_eq!(response.answer_count(), 0);
+        assert_eq!(response.name_server_count(), 0);
+
+        assert!(parse(r#"{"error": "bad request"}"#).is_err());
+        assert!(
+            parse(r#"{"Status": 0, "Answer": [{"name": "example.com.", "type": 1}]}"#).is_err()
+        );
+        assert!(parse(r#"{"Status": 01}"#).is_err());
+
+        let json = parse(
+            r#"{"Status": 0, "Answer": [{"name": "example.com.", "type": 1, "TTL": 60, "data": "::1"}]}"#,
+        )
+        .unwrap();
+        assert!(build_response(&query, &json).is_err());
+    }
+
+    #[tokio::test]
+    async fn refuse_unsupported_query_types() {
+        let url = "https://dns.google/resolve".parse().unwrap();
+        let client = JsonDohClient::new(url, &[], HttpsClientConfig::default()).unwrap();
+        let query = build_query("example.com.", RecordType::SRV);
+        let response = client
+            .resolve_raw(query.to_vec().unwrap().as_slice().into())
+            .await
+            .unwrap();
+        let response = Message::from_vec(&response).unwrap();
+        assert_eq!(response.id(), 0x1234);
+        assert_eq!(response.response_code(), ResponseCode::Refused);
+    }
+
+    /// Resolves names using the JSON API of Google, which requires network access.
+    /// Only runs if `FERRITE_TEST_JSON_DOH` is set.
+    #[tokio::test]
+    async fn resolve_with_google() {
+        if env::var_os("FERRITE_TEST_JSON_DOH").is_none() {
+            return;
+        }
+        let url = "https://dns.google/resolve".parse().unwrap();
+        let client = Arc::new(JsonDohClient::new(url, &[], HttpsClientConfig::default()).unwrap());
+        client_tests::basic_a(client.clone()).await;
+        client_tests::basic_aaaa(client).await;
+    }
+}
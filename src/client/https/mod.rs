@@ -1,5 +1,17 @@
 // SPDX-License-Identifier: MIT
 // SPDX-FileCopyrightText: 2025 Max Wipfli <mail@maxwipfli.ch>
 
+mod content_type;
+
 mod https_client;
-pub use https_client::HttpsClient;
+pub use https_client::{HttpVersion, HttpsClient, HttpsClientConfig};
+
+mod json_client;
+pub use json_client::JsonDohClient;
+
+mod status;
+
+#[cfg(unix)]
+mod unix_socket_client;
+#[cfg(unix)]
+pub use unix_socket_client::UnixSocketClient;
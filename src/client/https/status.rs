@@ -0,0 +1,238 @@
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Max Wipfli <mail@maxwipfli.ch>
+
+use std::{future::Future, time::Duration};
+
+use eyre::{eyre, Report, Result};
+use http::{header, HeaderMap, StatusCode};
+use tracing::debug;
+
+/// Maximum number of attempts of a request to a DoH server, including retries after transient errors.
+pub const MAX_ATTEMPTS: u32 = 2;
+
+/// Delay before retrying a request the server failed with a server error (5xx).
+const SERVER_ERROR_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Maximum `Retry-After` delay of rate-limited requests (429) to honor. The downstream client is unlikely to wait for
+/// a response any longer, so requests asked to back off for longer are not retried.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(5);
+
+/// Error for a response of a DoH server with a non-success status.
+#[derive(Debug)]
+pub struct StatusError {
+    pub error: Report,
+    /// Delay after which the request may be retried, or `None` if it must not be retried.
+    pub retry_after: Option<Duration>,
+}
+
+/// Classifies a non-success response status, deciding whether the request is retried.
+///
+/// - 400 (Bad Request) and 415 (Unsupported Media Type) mean the server doesn't understand our requests, which
+///   usually indicates a wrong server URL or configuration. They are not retried.
+/// - 429 (Too Many Requests) is retried after the delay in the `Retry-After` header (in seconds), if any.
+/// - Server errors (5xx) are retried after a short delay.
+/// - Other statuses are not retried.
+pub fn classify(status: StatusCode, headers: &HeaderMap) -> StatusError {
+    match status {
+        StatusCode::BAD_REQUEST | StatusCode::UNSUPPORTED_MEDIA_TYPE => StatusError {
+            error: eyre!(
+                "DoH server rejected the request with status {}, check the server URL and configuration",
+                status
+            ),
+            retry_after: None,
+        },
+        StatusCode::TOO_MANY_REQUESTS => {
+            let retry_after = headers
+                .get(header::RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.trim().parse().ok())
+                .map_or(SERVER_ERROR_RETRY_DELAY, Duration::from_secs);
+            StatusError {
+                error: eyre!(
+                    "DoH server is rate limiting requests (status {}, retry after {} s)",
+                    status,
+                    retry_after.as_secs()
+                ),
+                retry_after: (retry_after <= MAX_RETRY_AFTER).then_some(retry_after),
+            }
+        }
+        status if status.is_server_error() => StatusError {
+            error: eyre!("DoH server failed with status {}", status),
+            retry_after: Some(SERVER_ERROR_RETRY_DELAY),
+        },
+        status => StatusError {
+            error: eyre!("DoH server returned unexpected status {}", status),
+            retry_after: None,
+        },
+    }
+}
+
+/// Response of an HTTP client library.
+pub trait HttpResponse {
+    fn status(&self) -> StatusCode;
+    fn headers(&self) -> &HeaderMap;
+}
+
+impl HttpResponse for reqwest::Response {
+    fn status(&self) -> StatusCode {
+        self.status()
+    }
+
+    fn headers(&self) -> &HeaderMap {
+        self.headers()
+    }
+}
+
+impl<T> HttpResponse for http::Response<T> {
+    fn status(&self) -> StatusCode {
+        self.status()
+    }
+
+    fn headers(&self) -> &HeaderMap {
+        self.headers()
+    }
+}
+
+/// Sends a request using `send` until the server responds with a success status, retrying up to `MAX_ATTEMPTS`
+/// times as decided by `classify`. Errors sending the request are not retried.
+pub async fn send_with_retries<R, F, Fut>(mut send: F) -> Result<R>
+where
+    R: HttpResponse,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<R>>,
+{
+    let mut attempt = 1;
+    loop {
+        let response = send().await?;
+        if response.status().is_success() {
+            return Ok(response);
+        }
+        let StatusError { error, retry_after } = classify(response.status(), response.headers());
+        match retry_after {
+            Some(delay) if attempt < MAX_ATTEMPTS => {
+                debug!("{}, retrying in {} ms", error, delay.as_millis());
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            _ => return Err(error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use http::HeaderValue;
+    use tokio::time::Instant;
+
+    use super::*;
+
+    /// Sends a request with `send_with_retries` to a fake server responding with the given statuses (and `Retry-After`
+    /// headers) in turn. Returns the status of the final response, the number of attempts, and the time spent
+    /// backing off.
+    async fn send_to_fake_server(
+        responses: &[(u16, Option<&'static str>)],
+    ) -> (Result<StatusCode>, usize, Duration) {
+        let attempts = AtomicUsize::new(0);
+        let start = Instant::now();
+        let result = send_with_retries(|| async {
+            let (status, retry_after) = responses[attempts.fetch_add(1, Ordering::SeqCst)];
+            let mut response = http::Response::builder().status(status);
+            if let Some(retry_after) = retry_after {
+                response = response.header(header::RETRY_AFTER, retry_after);
+            }
+            Ok(response.body(()).unwrap())
+        })
+        .await;
+        (
+            result.map(|response| response.status()),
+            attempts.into_inner(),
+            start.elapsed(),
+        )
+    }
+
+    fn retry_after(status: u16, retry_after: Option<&'static str>) -> Option<Duration> {
+        let mut headers = HeaderMap::new();
+        if let Some(retry_after) = retry_after {
+            headers.insert(header::RETRY_AFTER, HeaderValue::from_static(retry_after));
+        }
+        classify(StatusCode::from_u16(status).unwrap(), &headers).retry_after
+    }
+
+    #[test]
+    fn client_errors() {
+        assert_eq!(retry_after(400, None), None);
+        assert_eq!(retry_after(415, None), None);
+        assert_eq!(retry_after(404, None), None);
+        let error = classify(StatusCode::BAD_REQUEST, &HeaderMap::new()).error;
+        assert!(error.to_string().contains("configuration"));
+    }
+
+    #[test]
+    fn rate_limiting() {
+        assert_eq!(retry_after(429, Some("2")), Some(Duration::from_secs(2)));
+        assert_eq!(retry_after(429, None), Some(SERVER_ERROR_RETRY_DELAY));
+        // Dates are not supported.
+        assert_eq!(
+            retry_after(429, Some("Wed, 21 Oct 2015 07:28:00 GMT")),
+            Some(SERVER_ERROR_RETRY_DELAY)
+        );
+        // Backing off for longer is pointless.
+        assert_eq!(retry_after(429, Some("60")), None);
+    }
+
+    #[test]
+    fn server_errors() {
+        assert_eq!(retry_after(500, None), Some(SERVER_ERROR_RETRY_DELAY));
+        assert_eq!(retry_after(503, None), Some(SERVER_ERROR_RETRY_DELAY));
+    }
+
+    #[test]
+    fn other_statuses() {
+        assert_eq!(retry_after(301, None), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retry_transient_errors() {
+        let (result, attempts, delay) = send_to_fake_server(&[(503, None), (200, None)]).await;
+        assert_eq!(result.unwrap(), StatusCode::OK);
+        assert_eq!(attempts, 2);
+        assert_eq!(delay, SERVER_ERROR_RETRY_DELAY);
+
+        let (result, attempts, delay) = send_to_fake_server(&[(429, Some("2")), (200, None)]).await;
+        assert_eq!(result.unwrap(), StatusCode::OK);
+        assert_eq!(attempts, 2);
+        assert_eq!(delay, Duration::from_secs(2));
+
+        // Requests are attempted at most `MAX_ATTEMPTS` times.
+        let (result, attempts, _) =
+            send_to_fake_server(&[(500, None), (502, None), (200, None)]).await;
+        assert!(result.unwrap_err().to_string().contains("502"));
+        assert_eq!(attempts, MAX_ATTEMPTS as usize);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn dont_retry_permanent_errors() {
+        for responses in [
+            &[(400, None), (200, None)],
+            &[(415, None), (200, None)],
+            &[(404, None), (200, None)],
+            &[(429, Some("60")), (200, None)],
+        ] {
+            let (result, attempts, delay) = send_to_fake_server(responses).await;
+            assert!(result.is_err(), "{:?}", responses);
+            assert_eq!(attempts, 1, "{:?}", responses);
+            assert_eq!(delay, Duration::ZERO, "{:?}", responses);
+        }
+
+        let attempts = AtomicUsize::new(0);
+        let result = send_with_retries(|| async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err::<http::Response<()>, _>(eyre!("connection refused"))
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.into_inner(), 1);
+    }
+}
@@ -0,0 +1,360 @@
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Max Wipfli <mail@maxwipfli.ch>
+
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use bytes::{Bytes, BytesMut};
+use eyre::{eyre, Result};
+use h2::{client::SendRequest, RecvStream};
+use http::{header, Method, Request, Response};
+use reqwest::Url;
+use tokio::{net::UnixStream, sync::Mutex, time::timeout};
+use tracing::debug;
+
+use super::{content_type, status::send_with_retries};
+use crate::{
+    client::Client,
+    proto::{set_txid_in_binary_message, txid_from_binary_message},
+};
+
+/// A DNS-over-HTTPS (DoH) client connecting to the server over a Unix domain socket instead of TCP.
+///
+/// This is meant for DoH proxies running alongside the resolver (e.g. as a sidecar), which avoids the overhead of
+/// loopback TCP connections. Requests are sent over HTTP/2 with prior knowledge and without TLS, to the URL the
+/// server would be reached at otherwise (which determines the `:authority` and `:path` of requests).
+pub struct UnixSocketClient {
+    path: PathBuf,
+    url: Url,
+    timeout: Duration,
+    /// Handle to the current HTTP/2 connection, if connected.
+    connection: Mutex<Option<SendRequest<Bytes>>>,
+}
+
+impl UnixSocketClient {
+    const CONTENT_TYPE_DNS_MESSAGE: &'static str = "application/dns-message";
+
+    /// Creates a new `UnixSocketClient` sending requests for `url` to the socket at `path`.
+    /// Requests time out after `timeout`, including connection setup.
+    pub fn new(path: impl AsRef<Path>, url: Url, timeout: Duration) -> Result<Self> {
+        if url.scheme() != "https" {
+            return Err(eyre!(
+                "UnixSocketClient cannot be constructed with URL of scheme '{}' (expected 'https')",
+                url.scheme()
+            ));
+        }
+        Ok(Self {
+            path: path.as_ref().to_owned(),
+            url,
+            timeout,
+            connection: Mutex::default(),
+        })
+    }
+
+    /// Returns a handle to the HTTP/2 connection to the server, connecting if necessary.
+    async fn connection(&self) -> Result<SendRequest<Bytes>> {
+        let mut connection = self.connection.lock().await;
+        if let Some(send_request) = connection.as_ref() {
+            // The handle is only ready if the connection is still alive.
+            match send_request.clone().ready().await {
+                Ok(send_request) => return Ok(send_request),
+                Err(err) => debug!("UnixSocketClient: connection closed: {}", err),
+            }
+        }
+
+        debug!("UnixSocketClient: connecting to {}", self.path.display());
+        let stream = UnixStream::connect(&self.path).await?;
+        let (send_request, h2_connection) = h2::client::handshake(stream).await?;
+        tokio::spawn(async move {
+            if let Err(err) = h2_connection.await {
+                debug!("UnixSocketClient: connection error: {}", err);
+            }
+        });
+        *connection = Some(send_request.clone());
+        Ok(send_request.ready().await?)
+    }
+
+    /// Sends a DNS message to the server.
+    async fn send(&self, body: Bytes) -> Result<Response<RecvStream>> {
+        let mut send_request = self.connection().await?;
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(self.url.as_str())
+            .header(header::ACCEPT, Self::CONTENT_TYPE_DNS_MESSAGE)
+            .header(header::CONTENT_TYPE, Self::CONTENT_TYPE_DNS_MESSAGE)
+            .body(())?;
+        let (response, mut send_stream) = send_request.send_request(request, false)?;
+        send_stream.send_data(body, true)?;
+        Ok(response.await?)
+    }
+
+    /// Sends a DNS message to the server (retrying after transient errors), returning the response body.
+    async fn exchange(&self, body: Bytes) -> Result<BytesMut> {
+        let response = send_with_retries(|| self.send(body.clone())).await?;
+        let headers = response.headers().clone();
+        let mut body = response.into_body();
+        let mut data = BytesMut::new();
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk?;
+            data.extend_from_slice(&chunk);
+            let _ = body.flow_control().release_capacity(chunk.len());
+        }
+        content_type::check(&headers, &data)?;
+        Ok(data)
+    }
+}
+
+#[async_trait::async_trait]
+impl Client for UnixSocketClient {
+    async fn resolve_raw(&self, mut data: BytesMut) -> Result<BytesMut> {
+        let txid = txid_from_binary_message(&data)
+            .ok_or_else(|| eyre!("UnixSocketClient: query is too short"))?;
+
+        // As for `HttpsClient`, use a DNS ID of 0 in every DNS request (RFC 8484, Section 4.1).
+        set_txid_in_binary_message(&mut data, 0);
+
+        let mut data = timeout(self.timeout, self.exchange(data.freeze()))
+            .await
+            .map_err(|_| eyre!("UnixSocketClient: timeout receiving response"))??;
+        if txid_from_binary_message(&data).is_none() {
+            return Err(eyre!("UnixSocketClient: response is too short"));
+        }
+        // restore transaction ID
+        set_txid_in_binary_message(&mut data, txid);
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::VecDeque,
+        env, process,
+        sync::{Arc, Mutex as StdMutex},
+    };
+
+    use hickory_proto::{
+        op::{Message, MessageType},
+        rr::{rdata::A, rdata::AAAA, RData, Record, RecordType},
+    };
+    use http::{Response, StatusCode};
+    use tokio::{net::UnixListener, sync::mpsc};
+
+    use super::*;
+    use crate::client::client::tests as client_tests;
+
+    /// Spawns a DoH server listening on a Unix domain socket, answering every query with a single record of the
+    /// queried type. Returns the socket path and a channel receiving the index of the connection and the URI of every
+    /// request.
+    fn spawn_local_server(name: &str) -> (PathBuf, mpsc::UnboundedReceiver<(usize, String)>) {
+        spawn_failing_local_server(name, Vec::new())
+    }
+
+    /// Spawns a DoH server like `spawn_local_server`, which fails the first requests with the given statuses (and
+    /// `Retry-After` headers).
+    fn spawn_failing_local_server(
+        name: &str,
+        errors: Vec<(StatusCode, Option<&'static str>)>,
+    ) -> (PathBuf, mpsc::UnboundedReceiver<(usize, String)>) {
+        let errors = Arc::new(StdMutex::new(VecDeque::from(errors)));
+        let path = env::temp_dir().join(format!("aufloes-{}-{}.sock", name, process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let (requests_tx, requests_rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            for index in 0.. {
+                let (stream, _) = listener.accept().await.unwrap();
+                let requests_tx = requests_tx.clone();
+                let errors = errors.clone();
+                tokio::spawn(async move {
+                    let mut connection = h2::server::handshake(stream).await.unwrap();
+                    while let Some(Ok((request, mut respond))) = connection.accept().await {
+                        let _ = requests_tx.send((index, request.uri().to_string()));
+
+                        let mut body = request.into_body();
+                        let mut query = Vec::new();
+                        while let Some(chunk) = body.data().await {
+                            query.extend_from_slice(&chunk.unwrap());
+                        }
+                        let error = errors.lock().unwrap().pop_front();
+                        if let Some((status, retry_after)) = error {
+                            let mut http_response = Response::builder().status(status);
+                            if let Some(retry_after) = retry_after {
+                                http_response =
+                                    http_response.header(header::RETRY_AFTER, retry_after);
+                            }
+                            respond
+                                .send_response(http_response.body(()).unwrap(), true)
+                                .unwrap();
+                            continue;
+                        }
+                        let mut response = Message::from_vec(&query).unwrap();
+                        let question = response.queries()[0].clone();
+                        let rdata = match question.query_type() {
+                            RecordType::AAAA => {
+                                RData::AAAA(AAAA::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1))
+                            }
+                            _ => RData::A(A::new(192, 0, 2, 1)),
+                        };
+                        response
+                            .set_message_type(MessageType::Response)
+                            .add_answer(Record::from_rdata(question.name().clone(), 300, rdata));
+
+                        let http_response = Response::builder()
+                            .status(StatusCode::OK)
+                            .header(header::CONTENT_TYPE, "application/dns-message")
+                            .body(())
+                            .unwrap();
+                        let mut send_stream = respond.send_response(http_response, false).unwrap();
+                        send_stream
+                            .send_data(response.to_vec().unwrap().into(), true)
+                            .unwrap();
+                    }
+                });
+            }
+        });
+        (path, requests_rx)
+    }
+
+    fn build_client(path: &Path) -> Arc<UnixSocketClient> {
+        let url = "https://dns.example.net/dns-query".parse().unwrap();
+        Arc::new(UnixSocketClient::new(path, url, Duration::from_secs(1)).unwrap())
+    }
+
+    #[tokio::test]
+    async fn basic_a() {
+        let (path, mut requests) = spawn_local_server("basic-a");
+        client_tests::basic_a(build_client(&path)).await;
+        assert_eq!(
+            requests.recv().await.unwrap(),
+            (0, "https://dns.example.net/dns-query".to_string())
+        );
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn basic_aaaa() {
+        let (path, _requests) = spawn_local_server("basic-aaaa");
+        client_tests::basic_aaaa(build_client(&path)).await;
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn reuse_connection() {
+        let (path, mut requests) = spawn_local_server("reuse");
+        let client = build_client(&path);
+        client_tests::basic_a(client.clone()).await;
+        client_tests::basic_aaaa(client).await;
+        assert_eq!(requests.recv().await.unwrap().0, 0);
+        assert_eq!(requests.recv().await.unwrap().0, 0);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn missing_socket() {
+        let path = env::temp_dir().join(format!("aufloes-missing-{}.sock", process::id()));
+        let client = build_client(&path);
+        assert!(client.resolve_raw(build_query()).await.is_err());
+    }
+
+    fn build_query() -> BytesMut {
+        let mut query = Message::new();
+        query.add_query(hickory_proto::op::Query::query(
+            "www.example.com.".parse().unwrap(),
+            RecordType::A,
+        ));
+        query.to_vec().unwrap().as_slice().into()
+    }
+
+    /// Returns the number of requests received by a server.
+    fn request_count(requests: &mut mpsc::UnboundedReceiver<(usize, String)>) -> usize {
+        std::iter::from_fn(|| requests.try_recv().ok()).count()
+    }
+
+    #[tokio::test]
+    async fn retry_server_errors() {
+        let errors = vec![(StatusCode::SERVICE_UNAVAILABLE, None)];
+        let (path, mut requests) = spawn_failing_local_server("server-error", errors);
+        client_tests::basic_a(build_client(&path)).await;
+        assert_eq!(request_count(&mut requests), 2);
+
+        // Requests are only retried once.
+        let errors = vec![
+            (StatusCode::BAD_GATEWAY, None),
+            (StatusCode::BAD_GATEWAY, None),
+        ];
+        let (path, mut requests) = spawn_failing_local_server("server-errors", errors);
+        let err = build_client(&path)
+            .resolve_raw(build_query())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("502"), "{}", err);
+        assert_eq!(request_count(&mut requests), 2);
+    }
+
+    #[tokio::test]
+    async fn retry_rate_limited_requests() {
+        let errors = vec![(StatusCode::TOO_MANY_REQUESTS, Some("0"))];
+        let (path, mut requests) = spawn_failing_local_server("rate-limited", errors);
+        client_tests::basic_a(build_client(&path)).await;
+        assert_eq!(request_count(&mut requests), 2);
+
+        // Backing off for longer than the downstream waits is pointless.
+        let errors = vec![(StatusCode::TOO_MANY_REQUESTS, Some("3600"))];
+        let (path, mut requests) = spawn_failing_local_server("rate-limited-long", errors);
+        let err = build_client(&path)
+            .resolve_raw(build_query())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("rate limiting"), "{}", err);
+        assert_eq!(request_count(&mut requests), 1);
+    }
+
+    #[tokio::test]
+    async fn no_retry_on_client_errors() {
+        for status in [StatusCode::BAD_REQUEST, StatusCode::UNSUPPORTED_MEDIA_TYPE] {
+            let errors = vec![(status, None), (status, None)];
+            let name = format!("client-error-{}", status.as_u16());
+            let (path, mut requests) = spawn_failing_local_server(&name, errors);
+            let err = build_client(&path)
+                .resolve_raw(build_query())
+                .await
+                .unwrap_err();
+            assert!(err.to_string().contains("configuration"), "{}", err);
+            assert_eq!(request_count(&mut requests), 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn reject_unexpected_content_type() {
+        // Answer every request like a captive portal would.
+        let path = env::temp_dir().join(format!("aufloes-captive-portal-{}.sock", process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut connection = h2::server::handshake(stream).await.unwrap();
+            while let Some(Ok((_, mut respond))) = connection.accept().await {
+                let http_response = Response::builder()
+                    .status(StatusCode::OK)
+                    .header(header::CONTENT_TYPE, "text/html")
+                    .body(())
+                    .unwrap();
+                let mut send_stream = respond.send_response(http_response, false).unwrap();
+                send_stream
+                    .send_data(Bytes::from_static(b"<html>Please log in</html>"), true)
+                    .unwrap();
+            }
+        });
+
+        let err = build_client(&path)
+            .resolve_raw(build_query())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("text/html"), "{}", err);
+        assert!(err.to_string().contains("Please log in"), "{}", err);
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,625 @@
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Max Wipfli <mail@maxwipfli.ch>
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use bytes::BytesMut;
+use eyre::{eyre, Result};
+use hickory_proto::{
+    op::{Message, MessageType, Query, ResponseCode},
+    rr::{Name, RData, RecordType},
+};
+use tracing::debug;
+
+use super::root_hints;
+use crate::{
+    client::{
+        udp::{UdpClient, UdpClientConfig},
+        Client,
+    },
+    proto::message::error_response,
+};
+
+type LookupFuture<'a> = Pin<Box<dyn Future<Output = Result<Message>> + Send + 'a>>;
+
+/// A client resolving queries itself, starting from the root servers, instead of forwarding them to a recursive
+/// resolver.
+///
+/// Resolution uses QNAME minimization (RFC 7816): while looking for the zone containing the queried name, servers are
+/// only sent NS queries for the name truncated to one label below the closest known zone, so only the authoritative
+/// servers of that zone see the full query. CNAMEs are followed, and the addresses of name servers are resolved if
+/// the referral carries no glue.
+///
+/// Only A and AAAA queries are supported, other queries are refused. Authoritative servers are queried over UDP
+/// (IPv4 only), and truncated responses are not retried over TCP.
+pub struct IterativeClient {
+    root_servers: Vec<IpAddr>,
+    servers: Mutex<HashMap<IpAddr, Arc<dyn Client>>>,
+    config: IterativeClientConfig,
+}
+
+/// Configuration of an `IterativeClient`.
+#[derive(Debug, Clone)]
+pub struct IterativeClientConfig {
+    /// Time to wait for the response of an authoritative server, before trying the next one.
+    pub timeout: Duration,
+
+    /// Maximum number of queries sent to authoritative servers to resolve a single query, including the queries for
+    /// name server addresses and CNAME targets.
+    pub max_queries: usize,
+}
+
+impl Default for IterativeClientConfig {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(2),
+            max_queries: 64,
+        }
+    }
+}
+
+impl IterativeClient {
+    pub fn new(config: IterativeClientConfig) -> Self {
+        Self {
+            root_servers: root_hints::addresses()
+                .into_iter()
+                .filter(IpAddr::is_ipv4)
+                .collect(),
+            servers: Mutex::default(),
+            config,
+        }
+    }
+
+    /// Overrides the root servers from the bundled root hints.
+    pub fn with_root_servers(mut self, root_servers: Vec<IpAddr>) -> Self {
+        self.root_servers = root_servers;
+        self
+    }
+
+    /// Uses `client` to query the server at `addr`, instead of plain DNS-over-UDP.
+    #[cfg(test)]
+    fn set_server(&self, addr: IpAddr, client: Arc<dyn Client>) {
+        self.servers.lock().unwrap().insert(addr, client);
+    }
+
+    /// Returns the client for the authoritative server at `addr`, creating it if necessary.
+    async fn server(&self, addr: IpAddr) -> Result<Arc<dyn Client>> {
+        if let Some(client) = self.servers.lock().unwrap().get(&addr) {
+            return Ok(client.clone());
+        }
+        let config = UdpClientConfig {
+            pool_size: 1,
+            timeout: self.config.timeout,
+            ..Default::default()
+        };
+        let client: Arc<dyn Client> =
+            Arc::new(UdpClient::new(SocketAddr::new(addr, 53), config).await?);
+        let mut servers = self.servers.lock().unwrap();
+        Ok(servers.entry(addr).or_insert(client).clone())
+    }
+
+    /// Sends a non-recursive query to each of `servers` in turn, until one of them responds with something other
+    /// than SERVFAIL or REFUSED (which indicates a lame delegation).
+    async fn query(
+        &self,
+        servers: &[IpAddr],
+        name: &Name,
+        query_type: RecordType,
+        budget: &mut usize,
+    ) -> Result<Message> {
+        let mut query = Message::new();
+        query.add_query(Query::query(name.clone(), query_type));
+        let data: BytesMut = query.to_vec()?.as_slice().into();
+
+        let mut last_err = eyre!("IterativeClient: no name servers to query for {}", name);
+        for &addr in servers {
+            if *budget == 0 {
+                return Err(eyre!("IterativeClient: query limit exceeded"));
+            }
+            *budget -= 1;
+
+            debug!(
+                "IterativeClient: querying {} for {} {}",
+                addr, name, query_type
+            );
+            let client = self.server(addr).await?;
+            let response = client
+                .resolve_raw(data.clone())
+                .await
+                .and_then(|response| Ok(Message::from_vec(&response)?));
+            match response {
+                Ok(response)
+                    if matches!(
+                        response.response_code(),
+                        ResponseCode::ServFail | ResponseCode::Refused
+                    ) =>
+                {
+                    last_err = eyre!(
+                        "IterativeClient: {} answered {} for {}",
+                        addr,
+                        response.response_code(),
+                        name
+                    );
+                }
+                Ok(response) => return Ok(response),
+                Err(err) => last_err = err,
+            }
+            debug!("IterativeClient: {}", last_err);
+        }
+        Err(last_err)
+    }
+
+    /// Resolves `name`, starting from the root servers.
+    fn lookup<'a>(
+        &'a self,
+        name: &'a Name,
+        query_type: RecordType,
+        budget: &'a mut usize,
+    ) -> LookupFuture<'a> {
+        Box::pin(async move {
+            let mut zone = Name::root();
+            let mut servers = self.root_servers.clone();
+            // Number of labels of the (minimized) name to query.
+            let mut labels = 1;
+            loop {
+                let minimized = labels < name.num_labels() as usize;
+                let (qname, qtype) = if minimized {
+                    (name.trim_to(labels), RecordType::NS)
+                } else {
+                    (name.clone(), query_type)
+                };
+                let response = self.query(&servers, &qname, qtype, budget).await?;
+                if !minimized
+                    && (response.answer_count() > 0
+                        || response.authoritative()
+                        || response.response_code() != ResponseCode::NoError)
+                {
+                    return self.follow_cname(name, query_type, response, budget).await;
+                }
+
+                match delegation(&response, &zone, &qname) {
+                    Some((child, ns_names)) => {
+                        debug!(
+                            "IterativeClient: following delegation of {} to {:?}",
+                            child, ns_names
+                        );
+                        servers = self
+                            .server_addrs(&response, &child, &ns_names, budget)
+                            .await?;
+                        labels = child.num_labels() as usize + 1;
+                        zone = child;
+                    }
+                    None if !minimized => return Ok(response),
+                    // The name is not a zone cut, continue with one more label.
+                    None if response.response_code() == ResponseCode::NoError => labels += 1,
+                    // Fall back to the full name (as also recommended by RFC 9156), which also gets the NXDOMAIN
+                    // response for the actual question.
+                    None => labels = name.num_labels() as usize,
+                }
+            }
+        })
+    }
+
+    /// Returns the addresses of the name servers `ns_names` of `zone`, from the glue records in `response` or by
+    /// resolving them.
+    async fn server_addrs(
+        &self,
+        response: &Message,
+        zone: &Name,
+        ns_names: &[Name],
+        budget: &mut usize,
+    ) -> Result<Vec<IpAddr>> {
+        let glue = response
+            .additionals()
+            .iter()
+            .filter(|record| ns_names.contains(record.name()))
+            .filter_map(ipv4_addr)
+            .collect::<Vec<_>>();
+        if !glue.is_empty() {
+            return Ok(glue);
+        }
+
+        for ns_name in ns_names {
+            // Name servers within the zone itself cannot be resolved without glue.
+            if zone.zone_of(ns_name) {
+                continue;
+            }
+            match self.lookup(ns_name, RecordType::A, budget).await {
+                Ok(response) => {
+                    let addrs = response
+                        .answers()
+                        .iter()
+                        .filter_map(ipv4_addr)
+                        .collect::<Vec<_>>();
+                    if !addrs.is_empty() {
+                        return Ok(addrs);
+                    }
+                }
+                Err(err) => debug!(
+                    "IterativeClient: cannot resolve name server {}: {}",
+                    ns_name, err
+                ),
+            }
+        }
+        Err(eyre!(
+            "IterativeClient: no addresses for name servers of {}",
+            zone
+        ))
+    }
+
+    /// Follows the CNAME for `name` in `response` (if any), appending the answers for its target to the answers of
+    /// `response`.
+    async fn follow_cname(
+        &self,
+        name: &Name,
+        query_type: RecordType,
+        mut response: Message,
+        budget: &mut usize,
+    ) -> Result<Message> {
+        if response
+            .answers()
+            .iter()
+            .any(|record| record.record_type() == query_type)
+        {
+            return Ok(response);
+        }
+        let target = response
+            .answers()
+            .iter()
+            .find_map(|record| match record.data() {
+                Some(RData::CNAME(cname)) if record.name() == name => Some(cname.0.clone()),
+                _ => None,
+            });
+        let Some(target) = target else {
+            return Ok(response);
+        };
+
+        debug!("IterativeClient: following CNAME {} -> {}", name, target);
+        let mut target_response = self.lookup(&target, query_type, budget).await?;
+        let mut answers = response.take_answers();
+        answers.extend(target_response.take_answers());
+        target_response.add_answers(answers);
+        Ok(target_response)
+    }
+}
+
+#[async_trait::async_trait]
+impl Client for IterativeClient {
+    async fn resolve_raw(&self, data: BytesMut) -> Result<BytesMut> {
+        let query = Message::from_vec(&data)?;
+        let [question] = query.queries() else {
+            return Err(eyre!("IterativeClient: expected exactly one question"));
+        };
+        if !matches!(question.query_type(), RecordType::A | RecordType::AAAA) {
+            debug!(
+                "IterativeClient: refusing unsupported query type {}",
+                question.query_type()
+            );
+            let response = error_response(&data, ResponseCode::Refused)
+                .ok_or_else(|| eyre!("IterativeClient: cannot build response to query"))?;
+            return Ok(response.as_slice().into());
+        }
+
+        let mut budget = self.config.max_queries;
+        let mut response = self
+            .lookup(question.name(), question.query_type(), &mut budget)
+            .await?;
+        response
+            .set_id(query.id())
+            .set_message_type(MessageType::Response)
+            .set_op_code(query.op_code())
+            .set_authoritative(false)
+            .set_recursion_desired(query.recursion_desired())
+            .set_recursion_available(true)
+            .take_queries();
+        response.add_queries(query.queries().to_vec());
+        response.take_additionals();
+        Ok(response.to_vec()?.as_slice().into())
+    }
+}
+
+/// Returns the zone delegated to in `response` to a query for `qname`, and the names of its name servers.
+/// Only delegations to zones below `zone` that contain `qname` are considered, which ensures resolution progresses.
+fn delegation(response: &Message, zone: &Name, qname: &Name) -> Option<(Name, Vec<Name>)> {
+    let mut child: Option<Name> = None;
+    let mut ns_names = Vec::new();
+    for record in response.answers().iter().chain(response.name_servers()) {
+        let Some(RData::NS(ns)) = record.data() else {
+            continue;
+        };
+        let owner = record.name();
+        if owner == zone || !zone.zone_of(owner) || !owner.zone_of(qname) {
+            continue;
+        }
+        if child.get_or_insert_with(|| owner.clone()) == owner {
+            ns_names.push(ns.0.clone());
+        }
+    }
+    child.map(|child| (child, ns_names))
+}
+
+fn ipv4_addr(record: &hickory_proto::rr::Record) -> Option<IpAddr> {
+    match record.data() {
+        Some(RData::A(a)) => Some(IpAddr::V4(a.0)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, net::Ipv4Addr};
+
+    use hickory_proto::rr::{
+        rdata::{A, CNAME, NS, SOA},
+        Record,
+    };
+
+    use super::*;
+    use crate::client::mock::MockClient;
+
+    fn name(name: &str) -> Name {
+        name.parse().unwrap()
+    }
+
+    fn build_query(qname: &str, query_type: RecordType) -> BytesMut {
+        let mut query = Message::new();
+        query
+            .set_id(0x1234)
+            .set_recursion_desired(true)
+            .add_query(Query::query(name(qname), query_type));
+        query.to_vec().unwrap().as_slice().into()
+    }
+
+    /// Builds a referral to the zone `child`, served by `ns` (with glue pointing to `glue`, if given).
+    fn referral(child: &str, ns: &str, glue: Option<Ipv4Addr>) -> Message {
+        let mut response = Message::new();
+        response.add_name_server(Record::from_rdata(
+            name(child),
+            86400,
+            RData::NS(NS(name(ns))),
+        ));
+        if let Some(glue) = glue {
+            response.add_additional(Record::from_rdata(name(ns), 86400, RData::A(A(glue))));
+        }
+        response
+    }
+
+    /// Builds an authoritative response without answers, as sent for names that exist but aren't zone cuts.
+    fn no_data(zone: &str) -> Message {
+        let soa = SOA::new(
+            name(&format!("ns.{}", zone)),
+            name(&format!("hostmaster.{}", zone)),
+            1,
+            3600,
+            600,
+            86400,
+            300,
+        );
+        let mut response = Message::new();
+        response
+            .set_authoritative(true)
+            .add_name_server(Record::from_rdata(name(zone), 300, RData::SOA(soa)));
+        response
+    }
+
+    fn authoritative_answer(qname: &str, rdata: RData) -> Message {
+        let mut response = Message::new();
+        response
+            .set_authoritative(true)
+            .add_answer(Record::from_rdata(name(qname), 300, rdata));
+        response
+    }
+
+    /// Returns the names of all queries received by `server`.
+    fn queried_names(server: &MockClient) -> Vec<String> {
+        server
+            .queries()
+            .iter()
+            .map(|query| {
+                Message::from_vec(query).unwrap().queries()[0]
+                    .name()
+                    .to_string()
+            })
+            .collect()
+    }
+
+    const ROOT: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+    const COM: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 2);
+    const EXAMPLE_COM: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 3);
+    const NET: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 4);
+    const EXAMPLE_NET: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 5);
+
+    /// Builds a client for a hierarchy of mock servers serving the root zone, `com.`, `example.com.`, `net.`, and
+    /// `example.net.` (whose name server has no glue).
+    fn build_client() -> (IterativeClient, HashMap<Ipv4Addr, Arc<MockClient>>) {
+        let root = MockClient::new();
+        root.set_response(
+            "com.",
+            RecordType::NS,
+            referral("com.", "a.gtld.test.", Some(COM)),
+        );
+        root.set_response(
+            "net.",
+            RecordType::NS,
+            referral("net.", "a.gtld.test.", Some(NET)),
+        );
+        let com = MockClient::new();
+        com.set_response(
+            "example.com.",
+            RecordType::NS,
+            referral("example.com.", "ns.example.com.", Some(EXAMPLE_COM)),
+        );
+        let example_com = MockClient::new();
+        example_com.set_response(
+            "www.example.com.",
+            RecordType::A,
+            authoritative_answer("www.example.com.", RData::A(A::new(198, 51, 100, 1))),
+        );
+        example_com.set_response(
+            "alias.example.com.",
+            RecordType::A,
+            authoritative_answer(
+                "alias.example.com.",
+                RData::CNAME(CNAME(name("www.example.net."))),
+            ),
+        );
+        example_com.set_response("sub.example.com.", RecordType::NS, no_data("example.com."));
+        example_com.set_response(
+            "www.sub.example.com.",
+            RecordType::A,
+            authoritative_answer("www.sub.example.com.", RData::A(A::new(198, 51, 100, 3))),
+        );
+        let net = MockClient::new();
+        net.set_response(
+            "example.net.",
+            RecordType::NS,
+            referral("example.net.", "ns.example.com.", None),
+        );
+        let example_net = MockClient::new();
+        example_net.set_response(
+            "www.example.net.",
+            RecordType::A,
+            authoritative_answer("www.example.net.", RData::A(A::new(198, 51, 100, 2))),
+        );
+        // ns.example.com. serves example.net. too.
+        example_com.set_response(
+            "ns.example.com.",
+            RecordType::A,
+            authoritative_answer("ns.example.com.", RData::A(A(EXAMPLE_NET))),
+        );
+
+        let servers = HashMap::from([
+            (ROOT, Arc::new(root)),
+            (COM, Arc::new(com)),
+            (EXAMPLE_COM, Arc::new(example_com)),
+            (NET, Arc::new(net)),
+            (EXAMPLE_NET, Arc::new(example_net)),
+        ]);
+        let client = IterativeClient::new(IterativeClientConfig::default())
+            .with_root_servers(vec![ROOT.into()]);
+        for (&addr, server) in &servers {
+            client.set_server(addr.into(), server.clone());
+        }
+        (client, servers)
+    }
+
+    #[tokio::test]
+    async fn minimize_query_names() {
+        let (client, servers) = build_client();
+        let response = client
+            .resolve_raw(build_query("www.example.com.", RecordType::A))
+            .await
+            .unwrap();
+        let response = Message::from_vec(&response).unwrap();
+        assert_eq!(response.id(), 0x1234);
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert!(!response.authoritative());
+        assert!(response.recursion_available());
+        assert_eq!(response.queries()[0].name(), &name("www.example.com."));
+        assert_eq!(
+            response.answers()[0].data(),
+            Some(&RData::A(A::new(198, 51, 100, 1)))
+        );
+
+        // Each server only saw the labels it needed to.
+        assert_eq!(queried_names(&servers[&ROOT]), ["com."]);
+        assert_eq!(queried_names(&servers[&COM]), ["example.com."]);
+        assert_eq!(queried_names(&servers[&EXAMPLE_COM]), ["www.example.com."]);
+    }
+
+    #[tokio::test]
+    async fn continue_below_non_zone_cuts() {
+        let (client, servers) = build_client();
+        let response = client
+            .resolve_raw(build_query("www.sub.example.com.", RecordType::A))
+            .await
+            .unwrap();
+        let response = Message::from_vec(&response).unwrap();
+        assert_eq!(
+            response.answers()[0].data(),
+            Some(&RData::A(A::new(198, 51, 100, 3)))
+        );
+        assert_eq!(
+            queried_names(&servers[&EXAMPLE_COM]),
+            ["sub.example.com.", "www.sub.example.com."]
+        );
+    }
+
+    #[tokio::test]
+    async fn follow_cname_and_glueless_delegation() {
+        let (client, _) = build_client();
+        let response = client
+            .resolve_raw(build_query("alias.example.com.", RecordType::A))
+            .await
+            .unwrap();
+        let response = Message::from_vec(&response).unwrap();
+        assert_eq!(response.queries()[0].name(), &name("alias.example.com."));
+        let answers = response
+            .answers()
+            .iter()
+            .map(|record| record.data().unwrap().clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            answers,
+            [
+                RData::CNAME(CNAME(name("www.example.net."))),
+                RData::A(A::new(198, 51, 100, 2)),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn query_limit() {
+        let (client, _) = build_client();
+        let client = IterativeClient {
+            config: IterativeClientConfig {
+                max_queries: 2,
+                ..Default::default()
+            },
+            ..client
+        };
+        assert!(client
+            .resolve_raw(build_query("www.example.com.", RecordType::A))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn refuse_unsupported_query_types() {
+        let (client, servers) = build_client();
+        let response = client
+            .resolve_raw(build_query("example.com.", RecordType::MX))
+            .await
+            .unwrap();
+        let response = Message::from_vec(&response).unwrap();
+        assert_eq!(response.response_code(), ResponseCode::Refused);
+        assert_eq!(servers[&ROOT].calls(), 0);
+    }
+
+    /// Resolves a name against the real root servers, which requires network access.
+    /// Only runs if `FERRITE_TEST_ITERATIVE` is set.
+    #[tokio::test]
+    async fn resolve_from_root_servers() {
+        if env::var_os("FERRITE_TEST_ITERATIVE").is_none() {
+            return;
+        }
+        let client = IterativeClient::new(IterativeClientConfig::default());
+        let response = client
+            .resolve_raw(build_query("www.example.com.", RecordType::A))
+            .await
+            .unwrap();
+        let response = Message::from_vec(&response).unwrap();
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert!(response
+            .answers()
+            .iter()
+            .any(|record| record.record_type() == RecordType::A));
+    }
+}
@@ -0,0 +1,7 @@
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Max Wipfli <mail@maxwipfli.ch>
+
+mod iterative_client;
+pub use iterative_client::{IterativeClient, IterativeClientConfig};
+
+mod root_hints;
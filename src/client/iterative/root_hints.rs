@@ -0,0 +1,34 @@
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Max Wipfli <mail@maxwipfli.ch>
+
+use std::net::IpAddr;
+
+/// Root hints, as published by IANA in the `named.root` file.
+const ROOT_HINTS: &str = include_str!("named.root");
+
+/// Returns the addresses of the root servers listed in the bundled root hints.
+pub fn addresses() -> Vec<IpAddr> {
+    ROOT_HINTS
+        .lines()
+        .filter(|line| !line.starts_with(';'))
+        .filter_map(
+            |line| match line.split_whitespace().collect::<Vec<_>>()[..] {
+                [_, _, "A" | "AAAA", addr] => addr.parse().ok(),
+                _ => None,
+            },
+        )
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let addresses = addresses();
+        assert_eq!(addresses.iter().filter(|addr| addr.is_ipv4()).count(), 13);
+        assert_eq!(addresses.iter().filter(|addr| addr.is_ipv6()).count(), 13);
+        assert_eq!(addresses[0], "198.41.0.4".parse::<IpAddr>().unwrap());
+    }
+}
@@ -0,0 +1,166 @@
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Max Wipfli <mail@maxwipfli.ch>
+
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use bytes::BytesMut;
+use eyre::Result;
+use tracing::debug;
+
+use crate::client::{client::resolve_with, Client};
+
+/// A client identifying an upstream by a label (e.g. its URL), which records statistics of the queries it handles.
+///
+/// Wrapping the upstreams of composite clients (e.g. `RoundRobinClient` or `FallbackClient`) in `LabeledClient`s shows
+/// which upstream handled each query, and how the load is distributed across them.
+pub struct LabeledClient {
+    label: String,
+    client: Arc<dyn Client>,
+    queries: AtomicU64,
+    errors: AtomicU64,
+    latency_total_us: AtomicU64,
+    latency_max_us: AtomicU64,
+}
+
+/// Point-in-time statistics of an upstream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpstreamStats {
+    /// Number of queries sent to the upstream.
+    pub queries: u64,
+    /// Number of queries the upstream failed to answer.
+    pub errors: u64,
+    /// Mean time for the upstream to answer a query (excluding failed queries).
+    pub mean_latency: Duration,
+    /// Maximum time for the upstream to answer a query (excluding failed queries).
+    pub max_latency: Duration,
+}
+
+impl LabeledClient {
+    pub fn new(label: impl Into<String>, client: Arc<dyn Client>) -> Self {
+        Self {
+            label: label.into(),
+            client,
+            queries: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            latency_total_us: AtomicU64::new(0),
+            latency_max_us: AtomicU64::new(0),
+        }
+    }
+
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    pub fn stats(&self) -> UpstreamStats {
+        let queries = self.queries.load(Ordering::Relaxed);
+        let answered = queries.saturating_sub(self.errors.load(Ordering::Relaxed));
+        let latency_total_us = self.latency_total_us.load(Ordering::Relaxed);
+        UpstreamStats {
+            queries,
+            errors: self.errors.load(Ordering::Relaxed),
+            mean_latency: Duration::from_micros(
+                latency_total_us.checked_div(answered).unwrap_or(0),
+            ),
+            max_latency: Duration::from_micros(self.latency_max_us.load(Ordering::Relaxed)),
+        }
+    }
+
+    async fn resolve_and_record(
+        &self,
+        data: BytesMut,
+        deadline: Option<Instant>,
+    ) -> Result<BytesMut> {
+        self.queries.fetch_add(1, Ordering::Relaxed);
+        let start = Instant::now();
+        let result = resolve_with(&*self.client, data, deadline).await;
+        let latency = start.elapsed();
+        match &result {
+            Ok(_) => {
+                let latency_us = latency.as_micros().try_into().unwrap_or(u64::MAX);
+                self.latency_total_us
+                    .fetch_add(latency_us, Ordering::Relaxed);
+                self.latency_max_us.fetch_max(latency_us, Ordering::Relaxed);
+                debug!(
+                    "LabeledClient: upstream '{}' answered in {} ms",
+                    self.label,
+                    latency.as_millis()
+                );
+            }
+            Err(err) => {
+                self.errors.fetch_add(1, Ordering::Relaxed);
+                debug!(
+                    "LabeledClient: upstream '{}' failed after {} ms: {}",
+                    self.label,
+                    latency.as_millis(),
+                    err
+                );
+            }
+        }
+        result
+    }
+}
+
+impl fmt::Debug for LabeledClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LabeledClient")
+            .field("label", &self.label)
+            .field("stats", &self.stats())
+            .finish_non_exhaustive()
+    }
+}
+
+#[async_trait::async_trait]
+impl Client for LabeledClient {
+    async fn resolve_raw(&self, data: BytesMut) -> Result<BytesMut> {
+        self.resolve_and_record(data, None).await
+    }
+
+    async fn resolve_raw_with_deadline(
+        &self,
+        data: BytesMut,
+        deadline: Instant,
+    ) -> Result<BytesMut> {
+        self.resolve_and_record(data, Some(deadline)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hickory_proto::rr::{rdata::A, RData, RecordType};
+
+    use super::*;
+    use crate::client::{client::tests as client_tests, mock::MockClient};
+
+    #[tokio::test]
+    async fn record_stats() {
+        let upstream = Arc::new(MockClient::new());
+        upstream.set_answer("www.example.com.", RData::A(A::new(192, 0, 2, 1)));
+        upstream.set_delay(Duration::from_millis(20));
+        let client = Arc::new(LabeledClient::new("udp://192.0.2.53", upstream.clone()));
+        assert_eq!(client.label(), "udp://192.0.2.53");
+
+        client_tests::basic_a(client.clone()).await;
+        client_tests::basic_a(client.clone()).await;
+        upstream.set_failing(true);
+        assert!(client
+            .resolve_raw(client_tests::build_request(
+                "www.example.com.",
+                RecordType::A
+            ))
+            .await
+            .is_err());
+
+        let stats = client.stats();
+        assert_eq!(stats.queries, 3);
+        assert_eq!(stats.errors, 1);
+        assert!(stats.mean_latency >= Duration::from_millis(20));
+        assert!(stats.max_latency >= stats.mean_latency);
+    }
+}
@@ -0,0 +1,5 @@
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Max Wipfli <mail@maxwipfli.ch>
+
+mod labeled_client;
+pub use labeled_client::{LabeledClient, UpstreamStats};
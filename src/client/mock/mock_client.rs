@@ -0,0 +1,229 @@
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Max Wipfli <mail@maxwipfli.ch>
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use bytes::BytesMut;
+use eyre::{eyre, Result};
+use hickory_proto::{
+    op::{Message, MessageType},
+    rr::{Name, RData, Record, RecordType},
+};
+
+use crate::client::Client;
+
+/// A `Client` for tests, which answers queries with canned responses instead of contacting a server.
+///
+/// Responses are keyed by question (name and type). Queries without a configured response fail.
+#[derive(Default)]
+pub struct MockClient {
+    responses: Mutex<HashMap<(Name, RecordType), Message>>,
+    raw_responses: Mutex<HashMap<(Name, RecordType), BytesMut>>,
+    queries: Mutex<Vec<BytesMut>>,
+    calls: AtomicUsize,
+    failing: AtomicBool,
+    delay: Mutex<Option<Duration>>,
+}
+
+impl MockClient {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Configures the response to queries for `name` and `query_type`.
+    /// The transaction ID and question of the response are taken from the query.
+    pub fn set_response(&self, name: &str, query_type: RecordType, response: Message) {
+        let key = (name.parse::<Name>().unwrap().to_lowercase(), query_type);
+        self.responses.lock().unwrap().insert(key, response);
+    }
+
+    /// Configures the response to queries for `name` and `query_type` to be `data`, returned as is (even if it isn't
+    /// a valid message).
+    pub fn set_raw_response(&self, name: &str, query_type: RecordType, data: &[u8]) {
+        let key = (name.parse::<Name>().unwrap().to_lowercase(), query_type);
+        self.raw_responses.lock().unwrap().insert(key, data.into());
+    }
+
+    /// Configures the response to queries for `name`, of the type of `rdata`, to contain a single answer record.
+    pub fn set_answer(&self, name: &str, rdata: RData) {
+        let query_type = rdata.record_type();
+        let mut response = Message::new();
+        response.add_answer(Record::from_rdata(name.parse().unwrap(), 300, rdata));
+        self.set_response(name, query_type, response);
+    }
+
+    /// Makes all further queries fail (or succeed again).
+    pub fn set_failing(&self, failing: bool) {
+        self.failing.store(failing, Ordering::SeqCst);
+    }
+
+    /// Delays all further responses (and failures) by `delay`.
+    pub fn set_delay(&self, delay: Duration) {
+        *self.delay.lock().unwrap() = Some(delay);
+    }
+
+    /// Returns how many times `resolve_raw` was called.
+    pub fn calls(&self) -> usize {
+        self.calls.load(Ordering::SeqCst)
+    }
+
+    /// Returns all queries received so far, in DNS wire format.
+    pub fn queries(&self) -> Vec<BytesMut> {
+        self.queries.lock().unwrap().clone()
+    }
+}
+
+#[async_trait::async_trait]
+impl Client for MockClient {
+    async fn resolve_raw(&self, data: BytesMut) -> Result<BytesMut> {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        self.queries.lock().unwrap().push(data.clone());
+
+        let delay = *self.delay.lock().unwrap();
+        if let Some(delay) = delay {
+            tokio::time::sleep(delay).await;
+        }
+        if self.failing.load(Ordering::SeqCst) {
+            return Err(eyre!("MockClient: configured to fail"));
+        }
+
+        let query = Message::from_vec(&data)?;
+        let [question] = query.queries() else {
+            return Err(eyre!("MockClient: expected exactly one question"));
+        };
+        let key = (question.name().to_lowercase(), question.query_type());
+        if let Some(data) = self.raw_responses.lock().unwrap().get(&key) {
+            return Ok(data.clone());
+        }
+        let Some(mut response) = self.responses.lock().unwrap().get(&key).cloned() else {
+            return Err(eyre!("MockClient: no response configured for {:?}", key));
+        };
+
+        response
+            .set_id(query.id())
+            .set_message_type(MessageType::Response)
+            .set_op_code(query.op_code())
+            .set_recursion_desired(query.recursion_desired())
+            .set_recursion_available(true)
+            .take_queries();
+        response.add_queries(query.queries().to_vec());
+        Ok(response.to_vec()?.as_slice().into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, sync::Arc};
+
+    use hickory_proto::{
+        op::Query,
+        rr::rdata::{A, AAAA},
+    };
+
+    use super::*;
+    use crate::client::client::tests as client_tests;
+
+    fn build_client() -> Arc<MockClient> {
+        let client = MockClient::new();
+        client.set_answer("www.example.com.", RData::A(A::new(192, 0, 2, 1)));
+        client.set_answer(
+            "www.example.com.",
+            RData::AAAA(AAAA::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)),
+        );
+        Arc::new(client)
+    }
+
+    fn build_query(name: &str) -> BytesMut {
+        let mut query = Message::new();
+        query
+            .set_id(0x1234)
+            .add_query(Query::query(name.parse().unwrap(), RecordType::A));
+        query.to_vec().unwrap().as_slice().into()
+    }
+
+    #[tokio::test]
+    async fn basic_a() {
+        let client = build_client();
+        client_tests::basic_a(client).await;
+    }
+
+    #[tokio::test]
+    async fn basic_aaaa() {
+        let client = build_client();
+        client_tests::basic_aaaa(client).await;
+    }
+
+    #[tokio::test]
+    async fn configured_response() {
+        let client = build_client();
+        let query = build_query("WWW.example.com.");
+
+        let response = client.resolve_raw(query.clone()).await.unwrap();
+        let response = Message::from_vec(&response).unwrap();
+        assert_eq!(response.id(), 0x1234);
+        assert_eq!(response.message_type(), MessageType::Response);
+        assert_eq!(
+            response.queries(),
+            Message::from_vec(&query).unwrap().queries()
+        );
+        let Some(RData::A(a)) = response.answers()[0].data() else {
+            panic!("expected A record");
+        };
+        assert_eq!(a.0, Ipv4Addr::new(192, 0, 2, 1));
+
+        assert_eq!(client.calls(), 1);
+        assert_eq!(client.queries(), [query]);
+    }
+
+    #[tokio::test]
+    async fn counts_calls() {
+        let client = build_client();
+        for i in 1..=3 {
+            client
+                .resolve_raw(build_query("www.example.com."))
+                .await
+                .unwrap();
+            assert_eq!(client.calls(), i);
+        }
+        // Failed queries are counted too.
+        assert!(client
+            .resolve_raw(build_query("unknown.example.com."))
+            .await
+            .is_err());
+        assert_eq!(client.calls(), 4);
+    }
+
+    #[tokio::test]
+    async fn failing() {
+        let client = build_client();
+        client.set_failing(true);
+        assert!(client
+            .resolve_raw(build_query("www.example.com."))
+            .await
+            .is_err());
+        client.set_failing(false);
+        assert!(client
+            .resolve_raw(build_query("www.example.com."))
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn delay() {
+        let client = build_client();
+        client.set_delay(Duration::from_millis(50));
+        let stamp = std::time::Instant::now();
+        client
+            .resolve_raw(build_query("www.example.com."))
+            .await
+            .unwrap();
+        assert!(stamp.elapsed() >= Duration::from_millis(50));
+    }
+}
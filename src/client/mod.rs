@@ -4,5 +4,17 @@
 pub mod client;
 pub use client::Client;
 
+pub mod adaptive;
+pub mod fallback;
+pub mod forwarding;
 pub mod https;
+pub mod iterative;
+pub mod labeled;
+#[cfg(any(test, feature = "testing"))]
+pub mod mock;
+pub mod racing;
+pub mod recording;
+pub mod retry;
+pub mod round_robin;
+pub mod tcp;
 pub mod udp;
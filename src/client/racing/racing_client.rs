@@ -0,0 +1,334 @@
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Max Wipfli <mail@maxwipfli.ch>
+
+use std::{
+    pin::pin,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use bytes::BytesMut;
+use eyre::{eyre, Result};
+use tracing::debug;
+
+use crate::client::{client::resolve_with, Client};
+
+/// A client racing each query on the two upstreams with the lowest estimated latency, and returning the first
+/// successful response. The other request is cancelled.
+///
+/// This reduces the tail latency at the cost of one additional upstream request per query. The latency of each
+/// upstream is estimated by an exponentially weighted moving average (EWMA) of the time it took to answer queries.
+/// Upstreams without an estimate yet are raced first, so that all upstreams are measured.
+pub struct RacingClient {
+    upstreams: Vec<Upstream>,
+    config: RacingClientConfig,
+}
+
+/// Configuration of a `RacingClient`.
+#[derive(Debug, Clone)]
+pub struct RacingClientConfig {
+    /// Weight of each new latency sample in the moving average, between 0 (exclusive) and 1. Higher values adapt
+    /// faster to changing latencies.
+    pub smoothing: f64,
+
+    /// Latency recorded for failed requests, so that failing upstreams are raced less often.
+    pub failure_latency: Duration,
+}
+
+impl Default for RacingClientConfig {
+    fn default() -> Self {
+        Self {
+            smoothing: 0.3,
+            failure_latency: Duration::from_secs(5),
+        }
+    }
+}
+
+struct Upstream {
+    client: Arc<dyn Client>,
+    /// Estimated latency, or `None` if the upstream hasn't completed a request yet.
+    latency: Mutex<Option<Duration>>,
+}
+
+impl RacingClient {
+    /// Creates a new `RacingClient` racing queries on `upstreams`, of which there must be at least one. With a single
+    /// upstream, queries are simply sent to it.
+    pub fn new(upstreams: Vec<Arc<dyn Client>>, config: RacingClientConfig) -> Result<Self> {
+        if upstreams.is_empty() {
+            return Err(eyre!("RacingClient requires at least one upstream"));
+        }
+        if !(config.smoothing > 0.0 && config.smoothing <= 1.0) {
+            return Err(eyre!(
+                "RacingClient smoothing must be in (0, 1], got {}",
+                config.smoothing
+            ));
+        }
+        let upstreams = upstreams
+            .into_iter()
+            .map(|client| Upstream {
+                client,
+                latency: Mutex::new(None),
+            })
+            .collect();
+        Ok(Self { upstreams, config })
+    }
+
+    /// Returns the indices of the (up to) two upstreams to race the next query on: those without an estimate first,
+    /// then those with the lowest estimated latency.
+    fn candidates(&self) -> Vec<usize> {
+        let mut indices = (0..self.upstreams.len()).collect::<Vec<_>>();
+        // `None` sorts before any estimate.
+        indices.sort_by_key(|&index| *self.upstreams[index].latency.lock().unwrap());
+        indices.truncate(2);
+        indices
+    }
+
+    /// Adds a latency sample to the estimate of an upstream. Samples are capped at the failure latency, as no request
+    /// counts worse than a failed one.
+    fn record(&self, index: usize, sample: Duration) {
+        let sample = sample.min(self.config.failure_latency);
+        let mut latency = self.upstreams[index].latency.lock().unwrap();
+        *latency = Some(match *latency {
+            // As `smoothing` is at most 1, the estimate never exceeds the largest sample.
+            Some(estimate) => estimate
+                .mul_f64(1.0 - self.config.smoothing)
+                .saturating_add(sample.mul_f64(self.config.smoothing)),
+            None => sample,
+        });
+    }
+
+    /// Records that the request to an upstream was cancelled, as another upstream answered after `elapsed`.
+    ///
+    /// The cancelled request would have taken longer, but it is unknown how much longer. Its estimate is raised
+    /// towards twice the winning latency (or twice its current estimate, if higher), so that upstreams that keep
+    /// losing are raced less often, until the estimates of the others have risen above theirs. As samples are capped,
+    /// the estimate stays bounded however often an upstream loses.
+    fn record_cancelled(&self, index: usize, elapsed: Duration) {
+        let estimate = self.upstreams[index]
+            .latency
+            .lock()
+            .unwrap()
+            .unwrap_or_default();
+        self.record(index, estimate.max(elapsed).saturating_mul(2));
+    }
+
+    /// Resolves a query with a single upstream, recording its latency.
+    async fn resolve_on(
+        &self,
+        index: usize,
+        data: BytesMut,
+        deadline: Option<Instant>,
+    ) -> Result<BytesMut> {
+        let start = Instant::now();
+        let result = resolve_with(&*self.upstreams[index].client, data, deadline).await;
+        match &result {
+            Ok(_) => self.record(index, start.elapsed()),
+            Err(err) => {
+                debug!("RacingClient: upstream #{} failed: {}", index, err);
+                self.record(index, self.config.failure_latency);
+            }
+        }
+        result
+    }
+
+    async fn race(&self, data: BytesMut, deadline: Option<Instant>) -> Result<BytesMut> {
+        let start = Instant::now();
+        let &[first, second] = self.candidates().as_slice() else {
+            return self.resolve_on(0, data, deadline).await;
+        };
+
+        let mut first_request = pin!(self.resolve_on(first, data.clone(), deadline));
+        let mut second_request = pin!(self.resolve_on(second, data, deadline));
+        let (result, other_request, other) = tokio::select! {
+            result = &mut first_request => (result, second_request, second),
+            result = &mut second_request => (result, first_request, first),
+        };
+        if result.is_ok() {
+            // Dropping the other request cancels it.
+            self.record_cancelled(other, start.elapsed());
+            return result;
+        }
+        // If the faster upstream failed, the other one may still answer.
+        other_request.await
+    }
+}
+
+#[async_trait::async_trait]
+impl Client for RacingClient {
+    async fn resolve_raw(&self, data: BytesMut) -> Result<BytesMut> {
+        self.race(data, None).await
+    }
+
+    async fn resolve_raw_with_deadline(
+        &self,
+        data: BytesMut,
+        deadline: Instant,
+    ) -> Result<BytesMut> {
+        self.race(data, Some(deadline)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use hickory_proto::{
+        op::Message,
+        rr::{rdata::A, RData, RecordType},
+    };
+
+    use super::*;
+    use crate::client::{client::tests::build_request, mock::MockClient};
+
+    /// Client counting the requests the wrapped client has completed, i.e. that weren't cancelled.
+    struct CompletionCounter {
+        client: MockClient,
+        completed: AtomicUsize,
+    }
+
+    #[async_trait::async_trait]
+    impl Client for CompletionCounter {
+        async fn resolve_raw(&self, data: BytesMut) -> Result<BytesMut> {
+            let result = self.client.resolve_raw(data).await;
+            self.completed.fetch_add(1, Ordering::SeqCst);
+            result
+        }
+    }
+
+    fn build_upstream(ip: u8, delay: Duration) -> Arc<CompletionCounter> {
+        let client = MockClient::new();
+        client.set_answer("www.example.com.", RData::A(A::new(192, 0, 2, ip)));
+        client.set_delay(delay);
+        Arc::new(CompletionCounter {
+            client,
+            completed: AtomicUsize::new(0),
+        })
+    }
+
+    fn answered_ip(response: &[u8]) -> u8 {
+        let response = Message::from_vec(response).unwrap();
+        match response.answers()[0].data() {
+            Some(RData::A(a)) => a.octets()[3],
+            data => panic!("expected A record, got {:?}", data),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn return_fastest_response() {
+        let slow = build_upstream(1, Duration::from_millis(500));
+        let fast = build_upstream(2, Duration::from_millis(10));
+        let client = RacingClient::new(
+            vec![slow.clone(), fast.clone()],
+            RacingClientConfig::default(),
+        )
+        .unwrap();
+
+        // The mock delays use the paused Tokio clock.
+        let start = tokio::time::Instant::now();
+        let response = client
+            .resolve_raw(build_request("www.example.com.", RecordType::A))
+            .await
+            .unwrap();
+        assert_eq!(answered_ip(&response), 2);
+        assert_eq!(start.elapsed(), Duration::from_millis(10));
+        assert_eq!(slow.client.calls(), 1);
+
+        // The request to the slow upstream was cancelled, so it never completes.
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        assert_eq!(slow.completed.load(Ordering::SeqCst), 0);
+        assert_eq!(fast.completed.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn fall_back_to_other_upstream() {
+        let failing = build_upstream(1, Duration::ZERO);
+        failing.client.set_failing(true);
+        let working = build_upstream(2, Duration::from_millis(10));
+        let client = RacingClient::new(
+            vec![failing.clone(), working.clone()],
+            RacingClientConfig::default(),
+        )
+        .unwrap();
+
+        let response = client
+            .resolve_raw(build_request("www.example.com.", RecordType::A))
+            .await
+            .unwrap();
+        assert_eq!(answered_ip(&response), 2);
+
+        working.client.set_failing(true);
+        assert!(client
+            .resolve_raw(build_request("www.example.com.", RecordType::A))
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn select_fastest_upstreams() {
+        let upstreams = (0..4)
+            .map(|ip| build_upstream(ip, Duration::ZERO) as Arc<dyn Client>)
+            .collect();
+        let client = RacingClient::new(upstreams, RacingClientConfig::default()).unwrap();
+        assert_eq!(client.candidates(), [0, 1]);
+
+        client.record(0, Duration::from_millis(30));
+        client.record(2, Duration::from_millis(10));
+        client.record(3, Duration::from_millis(20));
+        // Upstreams without an estimate are raced first.
+        assert_eq!(client.candidates(), [1, 2]);
+        client.record(1, Duration::from_millis(40));
+        assert_eq!(client.candidates(), [2, 3]);
+
+        // Estimates are moving averages.
+        client.record(2, Duration::from_millis(110));
+        assert_eq!(
+            *client.upstreams[2].latency.lock().unwrap(),
+            Some(Duration::from_millis(40))
+        );
+        assert_eq!(client.candidates(), [3, 0]);
+
+        // Cancelled requests raise the estimate.
+        client.record_cancelled(3, Duration::from_millis(5));
+        assert_eq!(
+            *client.upstreams[3].latency.lock().unwrap(),
+            Some(Duration::from_millis(26))
+        );
+    }
+
+    #[test]
+    fn bound_estimates_of_losing_upstreams() {
+        let upstreams = (0..2)
+            .map(|ip| build_upstream(ip, Duration::ZERO) as Arc<dyn Client>)
+            .collect();
+        let client = RacingClient::new(upstreams, RacingClientConfig::default()).unwrap();
+        client.record(1, Duration::from_millis(50));
+        for _ in 0..5000 {
+            client.record_cancelled(1, Duration::from_millis(10));
+        }
+        let estimate = client.upstreams[1].latency.lock().unwrap().unwrap();
+        assert!(estimate > Duration::from_secs(4), "{:?}", estimate);
+        assert!(estimate <= RacingClientConfig::default().failure_latency);
+        assert_eq!(client.candidates(), [0, 1]);
+
+        // Extreme latencies don't overflow.
+        client.record(0, Duration::MAX);
+        client.record_cancelled(0, Duration::MAX);
+        assert_eq!(
+            *client.upstreams[0].latency.lock().unwrap(),
+            Some(RacingClientConfig::default().failure_latency)
+        );
+    }
+
+    #[test]
+    fn reject_invalid_configs() {
+        assert!(RacingClient::new(Vec::new(), RacingClientConfig::default()).is_err());
+        let upstream = build_upstream(1, Duration::ZERO) as Arc<dyn Client>;
+        for smoothing in [0.0, 1.5, f64::NAN] {
+            let config = RacingClientConfig {
+                smoothing,
+                ..Default::default()
+            };
+            assert!(RacingClient::new(vec![upstream.clone()], config).is_err());
+        }
+    }
+}
@@ -0,0 +1,218 @@
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Max Wipfli <mail@maxwipfli.ch>
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use bytes::BytesMut;
+use eyre::{eyre, Result};
+use tracing::debug;
+
+use crate::{
+    client::Client,
+    proto::{message::first_question, set_txid_in_binary_message, txid_from_binary_message},
+};
+
+/// A client recording queries and responses to a directory of fixtures, or replaying responses from them.
+///
+/// This makes tests and offline development reproducible: a resolution is recorded once against a real upstream,
+/// and later answered from the fixtures without network access. Fixtures are keyed by the question of the query
+/// (e.g. `www.example.com_A_IN`), and the query and response are stored in DNS wire format with a transaction ID of
+/// 0 (in `<key>.query` and `<key>.response`). Replayed responses get the transaction ID of the query.
+pub struct RecordingClient {
+    dir: PathBuf,
+    /// The upstream to record responses of, or `None` to replay them.
+    upstream: Option<Arc<dyn Client>>,
+}
+
+impl RecordingClient {
+    /// Creates a `RecordingClient` forwarding queries to `upstream` and recording them to fixtures in `dir`, which
+    /// must exist. Existing fixtures for the same question are overwritten.
+    pub fn record(upstream: Arc<dyn Client>, dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            upstream: Some(upstream),
+        }
+    }
+
+    /// Creates a `RecordingClient` answering queries from the fixtures in `dir`. Queries without a fixture fail.
+    pub fn replay(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            upstream: None,
+        }
+    }
+
+    /// Returns the key of the fixture for `query`.
+    fn fixture_key(query: &[u8]) -> Result<String> {
+        let question = first_question(query)
+            .ok_or_else(|| eyre!("RecordingClient: cannot parse question of query"))?;
+        let name = question.name().to_lowercase().to_ascii();
+        let name = match name.trim_end_matches('.') {
+            "" => "root".to_string(),
+            // Names may contain arbitrary bytes, which must not escape the directory.
+            name => name
+                .chars()
+                .map(|c| match c {
+                    'a'..='z' | '0'..='9' | '-' | '.' => c,
+                    _ => '_',
+                })
+                .collect(),
+        };
+        Ok(format!(
+            "{}_{}_{}",
+            name,
+            question.query_type(),
+            question.query_class()
+        ))
+    }
+
+    /// Returns the path of the file with the given extension of the fixture with key `key`.
+    fn fixture_path(&self, key: &str, extension: &str) -> PathBuf {
+        self.dir.join(format!("{}.{}", key, extension))
+    }
+
+    async fn record_exchange(
+        &self,
+        upstream: &dyn Client,
+        key: &str,
+        data: BytesMut,
+    ) -> Result<BytesMut> {
+        let mut query = data.clone();
+        set_txid_in_binary_message(&mut query, 0);
+        let response = upstream.resolve_raw(data).await?;
+        let mut recorded = response.clone();
+        set_txid_in_binary_message(&mut recorded, 0);
+
+        debug!("RecordingClient: recording fixture {}", key);
+        write(&self.fixture_path(key, "query"), &query).await?;
+        write(&self.fixture_path(key, "response"), &recorded).await?;
+        Ok(response)
+    }
+
+    async fn replay_exchange(&self, key: &str, txid: u16) -> Result<BytesMut> {
+        let path = self.fixture_path(key, "response");
+        let mut response = BytesMut::from(
+            tokio::fs::read(&path)
+                .await
+                .map_err(|err| {
+                    eyre!(
+                        "RecordingClient: no fixture to replay at '{}': {}",
+                        path.display(),
+                        err
+                    )
+                })?
+                .as_slice(),
+        );
+        if txid_from_binary_message(&response).is_none() {
+            return Err(eyre!(
+                "RecordingClient: fixture '{}' is too short",
+                path.display()
+            ));
+        }
+        set_txid_in_binary_message(&mut response, txid);
+        Ok(response)
+    }
+}
+
+async fn write(path: &Path, data: &[u8]) -> Result<()> {
+    tokio::fs::write(path, data).await.map_err(|err| {
+        eyre!(
+            "RecordingClient: cannot write fixture '{}': {}",
+            path.display(),
+            err
+        )
+    })
+}
+
+#[async_trait::async_trait]
+impl Client for RecordingClient {
+    async fn resolve_raw(&self, data: BytesMut) -> Result<BytesMut> {
+        let txid = txid_from_binary_message(&data)
+            .ok_or_else(|| eyre!("RecordingClient: query is too short"))?;
+        let key = Self::fixture_key(&data)?;
+        match &self.upstream {
+            Some(upstream) => self.record_exchange(&**upstream, &key, data).await,
+            None => self.replay_exchange(&key, txid).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use hickory_proto::{
+        op::{Message, Query},
+        rr::{rdata::A, Name, RData, RecordType},
+    };
+
+    use super::*;
+    use crate::client::{client::tests as client_tests, mock::MockClient};
+
+    fn fixtures_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("aufloes-fixtures-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn record_and_replay() {
+        let dir = fixtures_dir("replay");
+        let upstream = Arc::new(MockClient::new());
+        upstream.set_answer("www.example.com.", RData::A(A::new(192, 0, 2, 1)));
+        let query = client_tests::build_request("www.example.com.", RecordType::A);
+
+        let recorded = RecordingClient::record(upstream.clone(), &dir)
+            .resolve_raw(query.clone())
+            .await
+            .unwrap();
+        assert!(dir.join("www.example.com_A_IN.query").exists());
+        assert!(dir.join("www.example.com_A_IN.response").exists());
+
+        let client = RecordingClient::replay(&dir);
+        let replayed = client.resolve_raw(query.clone()).await.unwrap();
+        assert_eq!(replayed, recorded);
+        assert_eq!(upstream.calls(), 1);
+
+        // The transaction ID of the query is restored, the rest of the response is identical.
+        let mut query = Message::from_vec(&query).unwrap();
+        query.set_id(0x4321);
+        let response = client.resolve(query).await.unwrap();
+        assert_eq!(response.id(), 0x4321);
+        assert_eq!(
+            response.answers(),
+            Message::from_vec(&recorded).unwrap().answers()
+        );
+
+        // Queries without fixture fail.
+        let err = client
+            .resolve_raw(client_tests::build_request(
+                "other.example.com.",
+                RecordType::A,
+            ))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("no fixture"), "{}", err);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn sanitize_fixture_keys() {
+        let key = |name: Name| {
+            let mut query = Message::new();
+            query.add_query(Query::query(name, RecordType::AAAA));
+            RecordingClient::fixture_key(&query.to_vec().unwrap()).unwrap()
+        };
+        assert_eq!(key(Name::root()), "root_AAAA_IN");
+        assert_eq!(
+            key("WWW.Example.com.".parse().unwrap()),
+            "www.example.com_AAAA_IN"
+        );
+        let name = Name::from_labels([&b"../x"[..], b"y/z"]).unwrap();
+        assert!(!key(name).contains('/'));
+    }
+}
@@ -0,0 +1,115 @@
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Max Wipfli <mail@maxwipfli.ch>
+
+use std::{sync::Arc, time::Instant};
+
+use bytes::BytesMut;
+use eyre::Result;
+use hickory_proto::op::ResponseCode;
+use tracing::debug;
+
+use crate::{
+    client::{client::resolve_with, Client},
+    proto::message::response_code,
+};
+
+/// A client retrying queries on a secondary upstream if the primary upstream refuses them.
+///
+/// Upstreams answer with REFUSED or FORMERR if they won't or can't handle a query, in which case the query may well
+/// succeed on a different upstream. The secondary upstream's response is returned as is.
+pub struct RetryClient {
+    primary: Arc<dyn Client>,
+    secondary: Arc<dyn Client>,
+}
+
+impl RetryClient {
+    pub fn new(primary: Arc<dyn Client>, secondary: Arc<dyn Client>) -> Self {
+        Self { primary, secondary }
+    }
+
+    /// Resolves a query with the primary upstream, and retries it with the secondary upstream if necessary. Both
+    /// requests are bounded by `deadline` (if any).
+    async fn resolve_with_retry(
+        &self,
+        data: BytesMut,
+        deadline: Option<Instant>,
+    ) -> Result<BytesMut> {
+        let response = resolve_with(&*self.primary, data.clone(), deadline).await?;
+        match response_code(&response) {
+            Some(rcode @ (ResponseCode::Refused | ResponseCode::FormErr)) => {
+                debug!(
+                    "RetryClient: primary upstream answered {}, retrying on secondary upstream",
+                    rcode
+                );
+                resolve_with(&*self.secondary, data, deadline).await
+            }
+            _ => Ok(response),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Client for RetryClient {
+    async fn resolve_raw(&self, data: BytesMut) -> Result<BytesMut> {
+        self.resolve_with_retry(data, None).await
+    }
+
+    async fn resolve_raw_with_deadline(
+        &self,
+        data: BytesMut,
+        deadline: Instant,
+    ) -> Result<BytesMut> {
+        self.resolve_with_retry(data, Some(deadline)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hickory_proto::{
+        op::{Message, Query},
+        rr::{rdata::A, RData, RecordType},
+    };
+
+    use super::*;
+    use crate::client::mock::MockClient;
+
+    fn build_query(name: &str) -> BytesMut {
+        let mut query = Message::new();
+        query.add_query(Query::query(name.parse().unwrap(), RecordType::A));
+        query.to_vec().unwrap().as_slice().into()
+    }
+
+    #[tokio::test]
+    async fn retry_refused() {
+        let primary = MockClient::new();
+        primary.set_answer("www.example.com.", RData::A(A::new(192, 0, 2, 1)));
+        let mut refused = Message::new();
+        refused.set_response_code(ResponseCode::Refused);
+        primary.set_response("refused.example.com.", RecordType::A, refused);
+        let primary = Arc::new(primary);
+        let secondary = MockClient::new();
+        secondary.set_answer("refused.example.com.", RData::A(A::new(192, 0, 2, 2)));
+        let secondary = Arc::new(secondary);
+        let client = RetryClient::new(primary.clone(), secondary.clone());
+
+        let response = client
+            .resolve_raw(build_query("www.example.com."))
+            .await
+            .unwrap();
+        assert_eq!(Message::from_vec(&response).unwrap().answer_count(), 1);
+        assert_eq!(secondary.calls(), 0);
+
+        let response = client
+            .resolve_raw(build_query("refused.example.com."))
+            .await
+            .unwrap();
+        let response = Message::from_vec(&response).unwrap();
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert_eq!(
+            response.answers()[0].data(),
+            Some(&RData::A(A::new(192, 0, 2, 2)))
+        );
+        assert_eq!(primary.calls(), 2);
+        assert_eq!(secondary.calls(), 1);
+    }
+}
@@ -0,0 +1,112 @@
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Max Wipfli <mail@maxwipfli.ch>
+
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use bytes::BytesMut;
+use eyre::{eyre, Result};
+
+use crate::client::{client::resolve_with, Client};
+
+/// A client distributing queries round-robin across several upstreams, e.g. to spread the load across servers.
+///
+/// Queries are not retried on another upstream if the chosen one fails (use e.g. `FallbackClient` for that).
+pub struct RoundRobinClient {
+    upstreams: Vec<Arc<dyn Client>>,
+    next: AtomicUsize,
+}
+
+impl RoundRobinClient {
+    /// Creates a new `RoundRobinClient` sending queries to `upstreams`, of which there must be at least one.
+    pub fn new(upstreams: Vec<Arc<dyn Client>>) -> Result<Self> {
+        if upstreams.is_empty() {
+            return Err(eyre!("RoundRobinClient requires at least one upstream"));
+        }
+        Ok(Self {
+            upstreams,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// Returns the upstream to send the next query to.
+    fn next_upstream(&self) -> &dyn Client {
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % self.upstreams.len();
+        &*self.upstreams[index]
+    }
+}
+
+#[async_trait::async_trait]
+impl Client for RoundRobinClient {
+    async fn resolve_raw(&self, data: BytesMut) -> Result<BytesMut> {
+        resolve_with(self.next_upstream(), data, None).await
+    }
+
+    async fn resolve_raw_with_deadline(
+        &self,
+        data: BytesMut,
+        deadline: Instant,
+    ) -> Result<BytesMut> {
+        resolve_with(self.next_upstream(), data, Some(deadline)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hickory_proto::rr::{rdata::A, RData};
+
+    use super::*;
+    use crate::client::{client::tests as client_tests, labeled::LabeledClient, mock::MockClient};
+
+    #[tokio::test]
+    async fn distribute_queries() {
+        let upstreams = (0..3)
+            .map(|index| {
+                let upstream = MockClient::new();
+                upstream.set_answer("www.example.com.", RData::A(A::new(192, 0, 2, index)));
+                Arc::new(LabeledClient::new(
+                    format!("upstream-{}", index),
+                    Arc::new(upstream),
+                ))
+            })
+            .collect::<Vec<_>>();
+        let client = Arc::new(
+            RoundRobinClient::new(
+                upstreams
+                    .iter()
+                    .map(|upstream| upstream.clone() as Arc<dyn Client>)
+                    .collect(),
+            )
+            .unwrap(),
+        );
+
+        for _ in 0..7 {
+            client_tests::basic_a(client.clone()).await;
+        }
+        let counts = upstreams
+            .iter()
+            .map(|upstream| (upstream.label().to_string(), upstream.stats().queries))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            counts,
+            [
+                ("upstream-0".to_string(), 3),
+                ("upstream-1".to_string(), 2),
+                ("upstream-2".to_string(), 2)
+            ]
+        );
+        assert!(upstreams
+            .iter()
+            .all(|upstream| upstream.stats().errors == 0));
+    }
+
+    #[test]
+    fn require_upstreams() {
+        assert!(RoundRobinClient::new(Vec::new()).is_err());
+    }
+}
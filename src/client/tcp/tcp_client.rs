@@ -0,0 +1,411 @@
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Max Wipfli <mail@maxwipfli.ch>
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use bytes::BytesMut;
+use eyre::{eyre, Result};
+use rand::Rng;
+use tokio::{
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+    sync::{mpsc, oneshot, Mutex},
+};
+use tracing::{debug, warn};
+
+use crate::{
+    client::Client,
+    proto::{
+        read_tcp_message, set_txid_in_binary_message, txid_from_binary_message, write_tcp_message,
+    },
+};
+
+/// A plain DNS-over-TCP client, e.g. for networks blocking or tampering with DNS over UDP.
+///
+/// Queries are pipelined over a single persistent connection ([RFC7766, Section 6.2.1]), and responses are matched to
+/// queries by their TXID. The connection is established on the first query, and re-established for the next query
+/// after it is closed (e.g. by the server after being idle).
+///
+/// [RFC7766, Section 6.2.1]: https://datatracker.ietf.org/doc/html/rfc7766#section-6.2.1
+pub struct TcpClient {
+    server_addr: SocketAddr,
+    connection: Mutex<Option<Arc<Connection>>>,
+    timeout: Duration,
+}
+
+/// Configuration of a `TcpClient`.
+#[derive(Debug, Clone)]
+pub struct TcpClientConfig {
+    /// Time to wait for the response to a query, including the time to establish the connection.
+    pub timeout: Duration,
+}
+
+impl Default for TcpClientConfig {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// A connection to the server, whose stream is owned by its connection task.
+struct Connection {
+    /// Queries to be written to the stream by the connection task.
+    queries: mpsc::UnboundedSender<BytesMut>,
+    state: Arc<ConnectionState>,
+}
+
+#[derive(Default)]
+struct ConnectionState {
+    pending: std::sync::Mutex<HashMap<u16, oneshot::Sender<BytesMut>>>,
+    closed: AtomicBool,
+}
+
+impl TcpClient {
+    pub fn new(server_addr: SocketAddr, config: TcpClientConfig) -> Self {
+        Self {
+            server_addr,
+            connection: Mutex::default(),
+            timeout: config.timeout,
+        }
+    }
+
+    /// Returns the open connection to the server, establishing a new one if there is none.
+    async fn connection(&self) -> Result<Arc<Connection>> {
+        let mut connection = self.connection.lock().await;
+        if let Some(connection) = &*connection {
+            if !connection.state.closed.load(Ordering::Relaxed) {
+                return Ok(connection.clone());
+            }
+        }
+
+        debug!("TcpClient: connecting to {}", self.server_addr);
+        let stream = TcpStream::connect(self.server_addr)
+            .await
+            .map_err(|err| eyre!("TcpClient: cannot connect to {}: {}", self.server_addr, err))?;
+        // Queries are written with a single write each, which should not be delayed.
+        stream.set_nodelay(true)?;
+        let (queries_tx, queries_rx) = mpsc::unbounded_channel();
+        let state = Arc::new(ConnectionState::default());
+        tokio::spawn(Self::connection_task(
+            stream,
+            self.server_addr,
+            queries_rx,
+            state.clone(),
+        ));
+        Ok(connection
+            .insert(Arc::new(Connection {
+                queries: queries_tx,
+                state,
+            }))
+            .clone())
+    }
+
+    /// Task that writes queries to and reads responses from the stream, resolving pending requests.
+    /// Shuts down when the connection is closed, or when the client is dropped (closing the queries channel).
+    ///
+    /// Writing is done by this task (rather than by the queries themselves), so that cancelled queries cannot leave
+    /// a partially written message on the stream.
+    async fn connection_task(
+        stream: TcpStream,
+        server_addr: SocketAddr,
+        queries: mpsc::UnboundedReceiver<BytesMut>,
+        state: Arc<ConnectionState>,
+    ) {
+        let (reader, writer) = stream.into_split();
+        let result = tokio::select! {
+            result = write_queries(writer, queries) => result,
+            result = read_responses(reader, &state) => result,
+        };
+        match result {
+            Ok(()) => debug!("TcpClient: closing connection to {}", server_addr),
+            Err(err) => debug!("TcpClient: connection to {} closed: {}", server_addr, err),
+        }
+        state.closed.store(true, Ordering::Relaxed);
+        // Dropping the senders fails the queries still waiting for a response.
+        state.pending.lock().unwrap().clear();
+    }
+
+    /// Sends a query and waits up to `timeout` for its response.
+    async fn resolve_with_timeout(
+        &self,
+        mut data: BytesMut,
+        timeout: Duration,
+    ) -> Result<BytesMut> {
+        if txid_from_binary_message(&data).is_none() {
+            return Err(eyre!("TcpClient: query is too short"));
+        }
+        let deadline = tokio::time::Instant::now() + timeout;
+        let connection = tokio::time::timeout_at(deadline, self.connection())
+            .await
+            .map_err(|_| {
+                warn!("TcpClient: timeout connecting to {}", self.server_addr);
+                eyre!("timeout connecting to {}", self.server_addr)
+            })??;
+
+        let (txid, receiver) = connection.state.new_pending_request();
+        set_txid_in_binary_message(&mut data, txid);
+        if connection.queries.send(data).is_err() {
+            connection.state.pending.lock().unwrap().remove(&txid);
+            return Err(eyre!(
+                "TcpClient: connection to {} closed before sending query",
+                self.server_addr
+            ));
+        }
+
+        let result = tokio::time::timeout_at(deadline, receiver).await;
+        match result {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(_)) => Err(eyre!(
+                "TcpClient: connection to {} closed before receiving response",
+                self.server_addr
+            )),
+            Err(_) => {
+                warn!("TcpClient: timeout receiving response");
+                connection.state.pending.lock().unwrap().remove(&txid);
+                Err(eyre!("timeout receiving response"))
+            }
+        }
+    }
+}
+
+impl ConnectionState {
+    /// Finds an unused TXID and inserts it into the pending map.
+    /// Returns the TXID and a receiver for the response.
+    fn new_pending_request(&self) -> (u16, oneshot::Receiver<BytesMut>) {
+        let (sender, receiver) = oneshot::channel();
+        let mut pending = self.pending.lock().unwrap();
+        let mut rng = rand::thread_rng();
+        let txid = loop {
+            let txid = rng.gen();
+            if !pending.contains_key(&txid) {
+                break txid;
+            }
+        };
+        pending.insert(txid, sender);
+        (txid, receiver)
+    }
+}
+
+/// Writes the queries received on `queries` to the stream, until the channel is closed.
+async fn write_queries(
+    mut writer: OwnedWriteHalf,
+    mut queries: mpsc::UnboundedReceiver<BytesMut>,
+) -> Result<()> {
+    while let Some(query) = queries.recv().await {
+        write_tcp_message(&mut writer, &query).await?;
+    }
+    Ok(())
+}
+
+/// Reads responses from the stream and resolves the pending requests they answer, until the stream ends.
+async fn read_responses(mut reader: OwnedReadHalf, state: &ConnectionState) -> Result<()> {
+    loop {
+        let response = read_tcp_message(&mut reader).await?;
+        let Some(txid) = txid_from_binary_message(&response) else {
+            debug!(
+                "TcpClient: ignoring response that is too short ({} bytes)",
+                response.len()
+            );
+            continue;
+        };
+        match state.pending.lock().unwrap().remove(&txid) {
+            Some(sender) => {
+                let _ = sender.send(response);
+            }
+            // e.g. the response to a query that has timed out
+            None => debug!("TcpClient: ignoring response with unknown TXID {}", txid),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Client for TcpClient {
+    async fn resolve_raw(&self, data: BytesMut) -> Result<BytesMut> {
+        self.resolve_with_timeout(data, self.timeout).await
+    }
+
+    async fn resolve_raw_with_deadline(
+        &self,
+        data: BytesMut,
+        deadline: Instant,
+    ) -> Result<BytesMut> {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(eyre!("TcpClient: deadline exceeded before sending query"));
+        }
+        self.resolve_with_timeout(data, self.timeout.min(remaining))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env,
+        net::Ipv4Addr,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use hickory_proto::{
+        op::{Message, MessageType, Query},
+        rr::{rdata::A, RData, Record, RecordType},
+    };
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::client::client::tests as client_tests;
+
+    static TEST_TCP_SERVER_ADDR: &str = "9.9.9.10:53";
+
+    /// Resolves names using a public resolver, which requires network access.
+    /// Only runs if `FERRITE_TEST_TCP_SERVER_ADDR` is set (e.g. to `9.9.9.10:53`).
+    fn build_client() -> Option<Arc<TcpClient>> {
+        let server_addr = env::var("FERRITE_TEST_TCP_SERVER_ADDR").ok()?;
+        let server_addr = match server_addr.as_str() {
+            "" => TEST_TCP_SERVER_ADDR,
+            server_addr => server_addr,
+        };
+        let client = TcpClient::new(server_addr.parse().unwrap(), TcpClientConfig::default());
+        Some(Arc::new(client))
+    }
+
+    /// Spawns a DNS server on localhost answering every query with a single A record, in reverse order of the
+    /// queries of every batch of `batch` queries on a connection. The server closes each connection after
+    /// `max_queries` queries. Returns its address and the number of connections accepted.
+    async fn spawn_local_server(
+        batch: usize,
+        max_queries: usize,
+    ) -> (SocketAddr, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let accepted = connections.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                accepted.fetch_add(1, Ordering::Relaxed);
+                tokio::spawn(async move {
+                    let mut answered = 0;
+                    while answered < max_queries {
+                        let mut responses = Vec::new();
+                        for _ in 0..batch {
+                            let Ok(query) = read_tcp_message(&mut stream).await else {
+                                return;
+                            };
+                            let mut response = Message::from_vec(&query).unwrap();
+                            let name = response.queries()[0].name().clone();
+                            response.set_message_type(MessageType::Response).add_answer(
+                                Record::from_rdata(name, 300, RData::A(A::new(192, 0, 2, 1))),
+                            );
+                            responses.push(response.to_vec().unwrap());
+                        }
+                        for response in responses.iter().rev() {
+                            write_tcp_message(&mut stream, response).await.unwrap();
+                        }
+                        answered += batch;
+                    }
+                });
+            }
+        });
+        (server_addr, connections)
+    }
+
+    fn build_query(name: &str) -> BytesMut {
+        let mut query = Message::new();
+        query.add_query(Query::query(name.parse().unwrap(), RecordType::A));
+        query.to_vec().unwrap().as_slice().into()
+    }
+
+    #[tokio::test]
+    async fn basic_a() {
+        if let Some(client) = build_client() {
+            client_tests::basic_a(client).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn basic_aaaa() {
+        if let Some(client) = build_client() {
+            client_tests::basic_aaaa(client).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn pipeline_queries() {
+        let (server_addr, connections) = spawn_local_server(10, usize::MAX).await;
+        let client = Arc::new(TcpClient::new(server_addr, TcpClientConfig::default()));
+
+        // The server answers out of order, and responses are matched to their queries by TXID.
+        let tasks = (0..100)
+            .map(|i| {
+                let client = client.clone();
+                tokio::spawn(async move {
+                    let name = format!("host{}.example.com.", i);
+                    let response = client.resolve_raw(build_query(&name)).await.unwrap();
+                    let response = Message::from_vec(&response).unwrap();
+                    assert_eq!(response.queries()[0].name().to_string(), name);
+                    assert_eq!(response.answer_count(), 1);
+                })
+            })
+            .collect::<Vec<_>>();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(connections.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn reconnect_after_close() {
+        let (server_addr, connections) = spawn_local_server(1, 2).await;
+        let client = TcpClient::new(server_addr, TcpClientConfig::default());
+
+        for _ in 0..2 {
+            client
+                .resolve_raw(build_query("www.example.com."))
+                .await
+                .unwrap();
+        }
+        // Wait for the client to notice that the server closed the connection.
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        client
+            .resolve_raw(build_query("www.example.com."))
+            .await
+            .unwrap();
+        assert_eq!(connections.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn configured_timeout() {
+        // A server that accepts connections but never answers.
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let config = TcpClientConfig {
+            timeout: Duration::from_millis(50),
+        };
+        let client = TcpClient::new(listener.local_addr().unwrap(), config);
+
+        let result = tokio::time::timeout(
+            Duration::from_secs(1),
+            client.resolve_raw(build_query("www.example.com.")),
+        )
+        .await
+        .unwrap();
+        assert!(result.unwrap_err().to_string().contains("timeout"));
+        assert!(client
+            .resolve_raw_with_deadline(build_query("www.example.com."), Instant::now())
+            .await
+            .unwrap_err()
+            .to_string()
+            .contains("deadline"));
+    }
+}
@@ -0,0 +1,84 @@
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Max Wipfli <mail@maxwipfli.ch>
+
+use std::{
+    collections::HashSet,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+/// Number of buckets of the TXID histogram. Each bucket covers an equal share of the TXID space.
+pub const TXID_BUCKETS: usize = 16;
+
+/// Statistics on the randomization of outgoing queries.
+///
+/// An off-path attacker spoofing responses has to guess both the source port and the TXID of a query. These
+/// statistics allow operators to verify that both are actually randomized.
+#[derive(Debug, Default)]
+pub struct EntropyStats {
+    queries: AtomicU64,
+    source_ports: Mutex<HashSet<u16>>,
+    txid_buckets: [AtomicU64; TXID_BUCKETS],
+}
+
+/// Point-in-time copy of `EntropyStats`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntropySnapshot {
+    /// Number of queries sent.
+    pub queries: u64,
+    /// Number of distinct source ports queries were sent from.
+    pub distinct_source_ports: usize,
+    /// Histogram of the TXIDs of queries sent. For uniformly random TXIDs, all buckets are roughly equal.
+    pub txid_buckets: [u64; TXID_BUCKETS],
+}
+
+impl EntropySnapshot {
+    /// Returns the smallest and largest bucket of the TXID histogram, which are close for uniformly random TXIDs.
+    pub fn txid_spread(&self) -> (u64, u64) {
+        let min = self.txid_buckets.iter().min().copied().unwrap_or(0);
+        let max = self.txid_buckets.iter().max().copied().unwrap_or(0);
+        (min, max)
+    }
+}
+
+impl EntropyStats {
+    /// Records a query sent from `source_port` with transaction ID `txid`.
+    pub fn record(&self, source_port: u16, txid: u16) {
+        self.queries.fetch_add(1, Ordering::Relaxed);
+        self.source_ports.lock().unwrap().insert(source_port);
+        let bucket = txid as usize * TXID_BUCKETS / (u16::MAX as usize + 1);
+        self.txid_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> EntropySnapshot {
+        EntropySnapshot {
+            queries: self.queries.load(Ordering::Relaxed),
+            distinct_source_ports: self.source_ports.lock().unwrap().len(),
+            txid_buckets: std::array::from_fn(|i| self.txid_buckets[i].load(Ordering::Relaxed)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record() {
+        let stats = EntropyStats::default();
+        stats.record(1234, 0x0000);
+        stats.record(1234, 0x0fff);
+        stats.record(5678, 0x1000);
+        stats.record(5678, 0xffff);
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.queries, 4);
+        assert_eq!(snapshot.distinct_source_ports, 2);
+        assert_eq!(snapshot.txid_buckets[0], 2);
+        assert_eq!(snapshot.txid_buckets[1], 1);
+        assert_eq!(snapshot.txid_buckets[TXID_BUCKETS - 1], 1);
+        assert_eq!(snapshot.txid_spread(), (0, 2));
+    }
+}
@@ -1,5 +1,8 @@
 // SPDX-License-Identifier: MIT
 // SPDX-FileCopyrightText: 2025 Max Wipfli <mail@maxwipfli.ch>
 
+mod entropy;
+pub use entropy::{EntropySnapshot, EntropyStats};
+
 mod udp_client;
-pub use udp_client::UdpClient;
+pub use udp_client::{UdpClient, UdpClientConfig};
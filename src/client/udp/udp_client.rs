@@ -3,56 +3,187 @@
 
 use std::{
     collections::HashMap,
+    io,
     net::{Ipv4Addr, Ipv6Addr, SocketAddr},
-    sync::Arc,
-    time::Duration,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
 use bytes::BytesMut;
-use eyre::{eyre, Result};
+use eyre::{eyre, Report, Result};
 use rand::Rng;
 use tokio::{
     net::UdpSocket,
     sync::{oneshot, Mutex},
-    time::timeout,
+    time::MissedTickBehavior,
 };
 use tracing::{debug, warn};
 
-use crate::{client::Client, proto::txid_from_binary_message};
+use super::{EntropySnapshot, EntropyStats};
+use crate::{
+    client::Client,
+    proto::{message::UDP_RECV_BUFFER_LEN, set_txid_in_binary_message, txid_from_binary_message},
+};
 
+/// A plain DNS-over-UDP client.
+///
+/// Queries are distributed round-robin across a pool of sockets, each with its own TXID space and receive task.
+/// Alternatively, each query can be sent from a fresh socket (see `UdpClientConfig::randomize_source_port`).
 pub struct UdpClient {
-    inner: Arc<UdpClientInner>,
-    shutdown_tx: Option<oneshot::Sender<()>>,
+    server_addr: SocketAddr,
+    local_addr: SocketAddr,
+    randomize_source_port: bool,
+    sockets: Vec<Arc<UdpClientInner>>,
+    next_socket: AtomicUsize,
+    shutdown_txs: Vec<oneshot::Sender<()>>,
+    entropy_stats: Arc<EntropyStats>,
+    /// Dropped responses received on sockets created per query (if `randomize_source_port` is set).
+    dropped_responses: DroppedResponses,
+    timeout: Duration,
+}
+
+/// Configuration of a `UdpClient`.
+#[derive(Debug, Clone)]
+pub struct UdpClientConfig {
+    /// Number of sockets used to send queries.
+    pub pool_size: usize,
+
+    /// Time to wait for the response to a query.
+    pub timeout: Duration,
+
+    /// Send each query from a fresh socket bound to a random source port instead of using the pool, which an
+    /// off-path attacker spoofing responses has to guess in addition to the TXID. This comes at the cost of creating
+    /// a socket per query.
+    pub randomize_source_port: bool,
+}
+
+impl Default for UdpClientConfig {
+    fn default() -> Self {
+        Self {
+            pool_size: thread::available_parallelism().map_or(1, |n| n.get()),
+            timeout: Duration::from_secs(5),
+            randomize_source_port: false,
+        }
+    }
 }
 
 impl UdpClient {
-    pub async fn new(server_addr: SocketAddr) -> Result<Self> {
+    /// Minimum interval at which the receive task sweeps pending requests.
+    const MIN_SWEEP_INTERVAL: Duration = Duration::from_millis(10);
+
+    pub async fn new(server_addr: SocketAddr, config: UdpClientConfig) -> Result<Self> {
+        if config.pool_size == 0 {
+            return Err(eyre!(
+                "UdpClient cannot be constructed with a pool size of 0"
+            ));
+        }
         let local_addr = match server_addr {
             SocketAddr::V4(_) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
             SocketAddr::V6(_) => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0),
         };
-        let socket = UdpSocket::bind(local_addr).await?;
-        socket.connect(server_addr).await?;
 
-        let inner = Arc::new(UdpClientInner {
-            socket,
-            pending: Mutex::default(),
-        });
-        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        // The pool is not used if every query gets its own socket.
+        let pool_size = if config.randomize_source_port {
+            0
+        } else {
+            config.pool_size
+        };
+        let mut sockets = Vec::with_capacity(pool_size);
+        let mut shutdown_txs = Vec::with_capacity(pool_size);
+        for _ in 0..pool_size {
+            let socket = Self::connect_socket(local_addr, server_addr).await?;
 
-        tokio::spawn(Self::receive_task(inner.clone(), shutdown_rx));
+            let inner = Arc::new(UdpClientInner {
+                socket,
+                pending: Mutex::default(),
+                dropped_responses: DroppedResponses::default(),
+            });
+            let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+
+            tokio::spawn(Self::receive_task(
+                inner.clone(),
+                config.timeout,
+                shutdown_rx,
+            ));
+
+            sockets.push(inner);
+            shutdown_txs.push(shutdown_tx);
+        }
 
         Ok(Self {
-            inner,
-            shutdown_tx: Some(shutdown_tx),
+            server_addr,
+            local_addr,
+            randomize_source_port: config.randomize_source_port,
+            sockets,
+            next_socket: AtomicUsize::new(0),
+            shutdown_txs,
+            entropy_stats: Arc::default(),
+            dropped_responses: DroppedResponses::default(),
+            timeout: config.timeout,
         })
     }
 
-    /// Finds an unused TXID, inserts it into the pending map.
+    /// Returns statistics on the randomization of source ports and TXIDs of queries sent so far.
+    pub fn entropy_stats(&self) -> EntropySnapshot {
+        self.entropy_stats.snapshot()
+    }
+
+    /// Returns the statistics on the randomization of queries, which keep being updated as queries are sent (e.g.
+    /// to include them in the periodic reports of the resolver).
+    pub fn shared_entropy_stats(&self) -> Arc<EntropyStats> {
+        self.entropy_stats.clone()
+    }
+
+    /// Returns how many responses were dropped because they didn't match any pending query (or were too short to
+    /// tell). Many of them may indicate a misbehaving upstream or an attempt to spoof responses.
+    pub fn dropped_responses(&self) -> u64 {
+        let pooled = self
+            .sockets
+            .iter()
+            .map(|inner| inner.dropped_responses.count())
+            .sum::<u64>();
+        pooled + self.dropped_responses.count()
+    }
+
+    /// Returns the number of queries currently waiting for a response.
+    pub async fn pending_requests(&self) -> usize {
+        let mut count = 0;
+        for inner in &self.sockets {
+            count += inner.pending.lock().await.len();
+        }
+        count
+    }
+
+    /// Binds a socket to `local_addr` and connects it to `server_addr`.
+    async fn connect_socket(local_addr: SocketAddr, server_addr: SocketAddr) -> Result<UdpSocket> {
+        let socket = UdpSocket::bind(local_addr)
+            .await
+            .map_err(|err| socket_error("bind", server_addr, err))?;
+        socket
+            .connect(server_addr)
+            .await
+            .map_err(|err| socket_error("connect", server_addr, err))?;
+        Ok(socket)
+    }
+
+    /// Selects the socket to send the next query on.
+    fn next_socket(&self) -> &Arc<UdpClientInner> {
+        let index = self.next_socket.fetch_add(1, Ordering::Relaxed) % self.sockets.len();
+        &self.sockets[index]
+    }
+
+    /// Finds an unused TXID on the given socket, inserts it into its pending map (expiring after `timeout`).
     /// Returns the TXID and a receiver for the response.
-    async fn new_pending_request(&self) -> (u16, oneshot::Receiver<Result<BytesMut>>) {
+    async fn new_pending_request(
+        inner: &UdpClientInner,
+        timeout: Duration,
+    ) -> (u16, oneshot::Receiver<Result<BytesMut>>) {
         let (sender, receiver) = oneshot::channel();
-        let mut pending = self.inner.pending.lock().await;
+        let mut pending = inner.pending.lock().await;
         let mut rng = rand::thread_rng();
 
         let txid = loop {
@@ -63,21 +194,38 @@ impl UdpClient {
             }
         };
 
-        let prev_value = pending.insert(txid, sender);
+        let request = PendingRequest {
+            sender,
+            expires: Instant::now() + timeout,
+        };
+        let prev_value = pending.insert(txid, request);
         assert!(prev_value.is_none(), "TXID collision");
         (txid, receiver)
     }
 
     /// Task that receives data from the socket and resolves pending requests.
-    /// Shuts down when the `shutdown_rx` channel is closed.
-    async fn receive_task(inner: Arc<UdpClientInner>, mut shutdown_rx: oneshot::Receiver<()>) {
+    /// Every `sweep_interval` (or `MIN_SWEEP_INTERVAL`, if longer), it removes pending requests that have expired or
+    /// were abandoned without being removed (e.g. because the query was cancelled). Shuts down when the `shutdown_rx`
+    /// channel is closed.
+    async fn receive_task(
+        inner: Arc<UdpClientInner>,
+        sweep_interval: Duration,
+        mut shutdown_rx: oneshot::Receiver<()>,
+    ) {
+        // A zero interval would make `interval` panic, e.g. with a timeout of zero.
+        let mut sweep = tokio::time::interval(sweep_interval.max(Self::MIN_SWEEP_INTERVAL));
+        sweep.set_missed_tick_behavior(MissedTickBehavior::Delay);
         loop {
-            let mut buffer = BytesMut::zeroed(1024);
+            let mut buffer = BytesMut::zeroed(UDP_RECV_BUFFER_LEN);
             let result = tokio::select! {
                 _ = &mut shutdown_rx => {
                     debug!("UdpClient: shutting down receive task");
                     break;
                 },
+                _ = sweep.tick() => {
+                    inner.sweep_pending().await;
+                    continue;
+                },
                 result = inner.socket.recv(&mut buffer) => result
             };
 
@@ -88,71 +236,251 @@ impl UdpClient {
             let n = result.unwrap();
             let data = buffer.split_to(n);
 
-            let txid = txid_from_binary_message(&data);
+            let Some(txid) = txid_from_binary_message(&data) else {
+                debug!(
+                    "UdpClient: ignoring response that is too short ({} bytes)",
+                    n
+                );
+                inner.dropped_responses.record();
+                continue;
+            };
 
             let mut pending = inner.pending.lock().await;
-            let Some(sender) = pending.remove(&txid) else {
+            let Some(request) = pending.remove(&txid) else {
                 // Ignore responses that we didn't send a request for.
+                debug!("UdpClient: ignoring response with unknown TXID {}", txid);
+                inner.dropped_responses.record();
                 continue;
             };
-            let _ = sender.send(Ok(data));
+            let _ = request.sender.send(Ok(data));
         }
     }
-}
 
-impl Drop for UdpClient {
-    fn drop(&mut self) {
-        debug!("UdpClient: signalling shutdown to receive task");
-        let _ = self.shutdown_tx.take().unwrap().send(());
-    }
-}
+    /// Sends a query and waits up to `timeout` for its response.
+    async fn resolve_with_timeout(
+        &self,
+        mut data: BytesMut,
+        timeout: Duration,
+    ) -> Result<BytesMut> {
+        if txid_from_binary_message(&data).is_none() {
+            return Err(eyre!("UdpClient: query is too short"));
+        }
+        if self.randomize_source_port {
+            return self.resolve_on_fresh_socket(data, timeout).await;
+        }
 
-#[async_trait::async_trait]
-impl Client for UdpClient {
-    async fn resolve_raw(&self, mut data: BytesMut) -> Result<BytesMut> {
         // Insert pending request before sending.
         // This avoids a data race where the server could theoretically respond before the request is inserted.
         // Another concurrent request could then drop the response as there is no pending request with that ID.
-        let (txid, receiver) = self.new_pending_request().await;
+        let inner = self.next_socket();
+        let (txid, receiver) = Self::new_pending_request(inner, timeout).await;
 
         // set TXID
-        // FIXME: Factor this into a separate function in a "protocol" module.
-        data[0..2].copy_from_slice(&txid.to_be_bytes());
-
-        let timeout_duration = Duration::from_secs(5);
+        set_txid_in_binary_message(&mut data, txid);
 
         // send request
-        self.inner.socket.send(&data).await?;
+        if let Err(err) = inner.socket.send(&data).await {
+            inner.pending.lock().await.remove(&txid);
+            return Err(err.into());
+        }
+        self.entropy_stats
+            .record(inner.socket.local_addr()?.port(), txid);
 
-        match timeout(timeout_duration, receiver).await {
+        match tokio::time::timeout(timeout, receiver).await {
             Ok(Ok(response)) => Ok(response?),
             Ok(Err(err)) => {
                 warn!("UdpClient: error receiving response: {:?}", err);
-                let mut pending = self.inner.pending.lock().await;
+                let mut pending = inner.pending.lock().await;
                 let _ = pending.remove(&txid);
                 Err(eyre!("error receiving response"))
             }
             Err(_) => {
                 warn!("UdpClient: timeout receiving response");
-                let mut pending = self.inner.pending.lock().await;
+                let mut pending = inner.pending.lock().await;
                 let _ = pending.remove(&txid);
                 Err(eyre!("timeout receiving response"))
             }
         }
     }
+
+    /// Sends a query from a new socket, bound to a random source port (chosen by the OS), and waits up to `timeout`
+    /// for its response.
+    ///
+    /// The socket is connected to the server, so only datagrams from the server's address and port are received on
+    /// it, and responses must match the TXID of the query.
+    async fn resolve_on_fresh_socket(
+        &self,
+        mut data: BytesMut,
+        timeout: Duration,
+    ) -> Result<BytesMut> {
+        let socket = Self::connect_socket(self.local_addr, self.server_addr).await?;
+
+        let txid = rand::thread_rng().gen();
+        set_txid_in_binary_message(&mut data, txid);
+        socket.send(&data).await?;
+        self.entropy_stats.record(socket.local_addr()?.port(), txid);
+
+        let receive = async {
+            loop {
+                let mut buffer = BytesMut::zeroed(UDP_RECV_BUFFER_LEN);
+                let n = socket.recv(&mut buffer).await?;
+                let response = buffer.split_to(n);
+                if txid_from_binary_message(&response) == Some(txid) {
+                    return Ok(response);
+                }
+                debug!("UdpClient: ignoring response with unexpected TXID");
+                self.dropped_responses.record();
+            }
+        };
+        match tokio::time::timeout(timeout, receive).await {
+            Ok(result) => result,
+            Err(_) => {
+                warn!("UdpClient: timeout receiving response");
+                Err(eyre!("timeout receiving response"))
+            }
+        }
+    }
+}
+
+impl Drop for UdpClient {
+    fn drop(&mut self) {
+        debug!("UdpClient: signalling shutdown to receive tasks");
+        for shutdown_tx in self.shutdown_txs.drain(..) {
+            let _ = shutdown_tx.send(());
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Client for UdpClient {
+    async fn resolve_raw(&self, data: BytesMut) -> Result<BytesMut> {
+        self.resolve_with_timeout(data, self.timeout).await
+    }
+
+    async fn resolve_raw_with_deadline(
+        &self,
+        data: BytesMut,
+        deadline: Instant,
+    ) -> Result<BytesMut> {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(eyre!("UdpClient: deadline exceeded before sending query"));
+        }
+        self.resolve_with_timeout(data, self.timeout.min(remaining))
+            .await
+    }
+}
+
+/// Describes an error setting up a socket to reach `server_addr`. IPv6 is disabled in some environments (e.g. on some
+/// container platforms), which only shows as an opaque error when binding or connecting IPv6 sockets.
+fn socket_error(operation: &str, server_addr: SocketAddr, err: io::Error) -> Report {
+    match server_addr {
+        SocketAddr::V4(_) => eyre!(
+            "UdpClient: cannot {} socket to reach {}: {}",
+            operation,
+            server_addr,
+            err
+        ),
+        SocketAddr::V6(_) => eyre!(
+            "UdpClient: cannot {} IPv6 socket to reach {}: {} (IPv6 may be unavailable or disabled on this host, \
+             consider using an IPv4 upstream server)",
+            operation,
+            server_addr,
+            err
+        ),
+    }
 }
 
 struct UdpClientInner {
     socket: UdpSocket,
-    pending: Mutex<HashMap<u16, oneshot::Sender<Result<BytesMut>>>>,
+    pending: Mutex<HashMap<u16, PendingRequest>>,
+    dropped_responses: DroppedResponses,
+}
+
+impl UdpClientInner {
+    /// Removes pending requests that have expired or whose receiver has been dropped.
+    async fn sweep_pending(&self) {
+        let now = Instant::now();
+        let mut pending = self.pending.lock().await;
+        let before = pending.len();
+        pending.retain(|_, request| request.expires > now && !request.sender.is_closed());
+        if pending.len() < before {
+            debug!(
+                "UdpClient: removed {} stale pending requests",
+                before - pending.len()
+            );
+        }
+    }
+}
+
+/// A query waiting for its response.
+struct PendingRequest {
+    sender: oneshot::Sender<Result<BytesMut>>,
+    /// Time after which the query has timed out, and the request can be removed.
+    expires: Instant,
+}
+
+/// Counter of responses dropped because they don't match any pending query.
+///
+/// Warns (at most once per `WINDOW`) if at least `SPIKE_THRESHOLD` responses are dropped within `WINDOW`, which
+/// may indicate an attempt to spoof responses.
+#[derive(Default)]
+struct DroppedResponses {
+    count: AtomicU64,
+    window: std::sync::Mutex<Option<DropWindow>>,
+}
+
+struct DropWindow {
+    start: Instant,
+    count: u64,
+    warned: bool,
+}
+
+impl DroppedResponses {
+    const WINDOW: Duration = Duration::from_secs(60);
+    const SPIKE_THRESHOLD: u64 = 10;
+
+    fn record(&self) {
+        let total = self.count.fetch_add(1, Ordering::Relaxed) + 1;
+        let mut window = self.window.lock().unwrap();
+        let window = match window.as_mut() {
+            Some(window) if window.start.elapsed() < Self::WINDOW => window,
+            _ => window.insert(DropWindow {
+                start: Instant::now(),
+                count: 0,
+                warned: false,
+            }),
+        };
+        window.count += 1;
+        if window.count >= Self::SPIKE_THRESHOLD && !window.warned {
+            warn!(
+                "UdpClient: dropped {} responses not matching any query within {} s ({} in total), responses may \
+                 be spoofed",
+                window.count,
+                Self::WINDOW.as_secs(),
+                total
+            );
+            window.warned = true;
+        }
+    }
+
+    fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{env, sync::Arc};
+    use std::{collections::HashSet, env, sync::Arc};
+
+    use hickory_proto::{
+        op::{Message, MessageType, Query},
+        rr::{rdata::A, RData, Record, RecordType},
+    };
+    use tokio::sync::mpsc;
 
     use super::*;
-    use crate::client::client::tests as client_tests;
+    use crate::{client::client::tests as client_tests, proto::edns};
 
     static TEST_UDP_SERVER_ADDR: &str = "9.9.9.10:53";
 
@@ -161,10 +489,49 @@ mod tests {
             .unwrap_or(TEST_UDP_SERVER_ADDR.to_string())
             .parse()
             .unwrap();
-        let client = UdpClient::new(server_addr).await.unwrap();
+        let client = UdpClient::new(server_addr, UdpClientConfig::default())
+            .await
+            .unwrap();
         Arc::new(client)
     }
 
+    /// Spawns a DNS server on localhost answering every query with a single A record.
+    /// Returns its address and a channel receiving the source address of every query.
+    async fn spawn_local_server() -> (SocketAddr, mpsc::UnboundedReceiver<SocketAddr>) {
+        spawn_local_server_with_answers(1).await
+    }
+
+    /// Like `spawn_local_server`, but answering every query with `answers` A records.
+    async fn spawn_local_server_with_answers(
+        answers: u8,
+    ) -> (SocketAddr, mpsc::UnboundedReceiver<SocketAddr>) {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let server_addr = socket.local_addr().unwrap();
+        let (peers_tx, peers_rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let mut buffer = [0; 512];
+                let (n, peer) = socket.recv_from(&mut buffer).await.unwrap();
+                let _ = peers_tx.send(peer);
+                let mut response = Message::from_vec(&buffer[..n]).unwrap();
+                let name = response.queries()[0].name().clone();
+                response.set_message_type(MessageType::Response);
+                for index in 0..answers {
+                    response.add_answer(Record::from_rdata(
+                        name.clone(),
+                        300,
+                        RData::A(A::new(192, 0, 2, index)),
+                    ));
+                }
+                socket
+                    .send_to(&response.to_vec().unwrap(), peer)
+                    .await
+                    .unwrap();
+            }
+        });
+        (server_addr, peers_rx)
+    }
+
     #[tokio::test]
     async fn basic_a() {
         let client = build_client().await;
@@ -176,4 +543,349 @@ mod tests {
         let client = build_client().await;
         client_tests::basic_aaaa(client).await;
     }
+
+    #[tokio::test]
+    async fn pool_concurrent_resolutions() {
+        let (server_addr, mut peers_rx) = spawn_local_server().await;
+        let config = UdpClientConfig {
+            pool_size: 4,
+            ..Default::default()
+        };
+        let client = Arc::new(UdpClient::new(server_addr, config).await.unwrap());
+
+        let tasks = (0..100)
+            .map(|i| {
+                let client = client.clone();
+                tokio::spawn(async move {
+                    let name = format!("host{}.example.com.", i);
+                    let mut query = Message::new();
+                    query.add_query(Query::query(name.parse().unwrap(), RecordType::A));
+                    let data = query.to_vec().unwrap().as_slice().into();
+                    let response = client.resolve_raw(data).await.unwrap();
+                    let response = Message::from_vec(&response).unwrap();
+                    assert_eq!(response.queries()[0].name().to_string(), name);
+                    assert_eq!(response.answer_count(), 1);
+                })
+            })
+            .collect::<Vec<_>>();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        // Queries were distributed across all sockets of the pool.
+        let mut peers = HashSet::new();
+        while let Ok(peer) = peers_rx.try_recv() {
+            peers.insert(peer);
+        }
+        assert_eq!(peers.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn entropy_stats() {
+        let (server_addr, _peers_rx) = spawn_local_server().await;
+        let config = UdpClientConfig {
+            pool_size: 2,
+            ..Default::default()
+        };
+        let client = UdpClient::new(server_addr, config).await.unwrap();
+
+        for _ in 0..10 {
+            let mut query = Message::new();
+            query.add_query(Query::query(
+                "www.example.com.".parse().unwrap(),
+                RecordType::A,
+            ));
+            let data = query.to_vec().unwrap().as_slice().into();
+            client.resolve_raw(data).await.unwrap();
+        }
+
+        let stats = client.entropy_stats();
+        assert_eq!(stats.queries, 10);
+        assert_eq!(stats.distinct_source_ports, 2);
+        assert_eq!(stats.txid_buckets.iter().sum::<u64>(), 10);
+    }
+
+    #[tokio::test]
+    async fn reject_empty_pool() {
+        let config = UdpClientConfig {
+            pool_size: 0,
+            ..Default::default()
+        };
+        let server_addr = TEST_UDP_SERVER_ADDR.parse().unwrap();
+        assert!(UdpClient::new(server_addr, config).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn configured_timeout() {
+        // A server that never answers.
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let config = UdpClientConfig {
+            timeout: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let client = UdpClient::new(socket.local_addr().unwrap(), config)
+            .await
+            .unwrap();
+
+        let mut query = Message::new();
+        query.add_query(Query::query(
+            "www.example.com.".parse().unwrap(),
+            RecordType::A,
+        ));
+        let data = query.to_vec().unwrap().as_slice().into();
+        let result = tokio::time::timeout(Duration::from_secs(1), client.resolve_raw(data))
+            .await
+            .unwrap();
+        assert!(result.unwrap_err().to_string().contains("timeout"));
+    }
+
+    #[tokio::test]
+    async fn deadline() {
+        // A server that never answers.
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let client = UdpClient::new(socket.local_addr().unwrap(), UdpClientConfig::default())
+            .await
+            .unwrap();
+
+        let mut query = Message::new();
+        query.add_query(Query::query(
+            "www.example.com.".parse().unwrap(),
+            RecordType::A,
+        ));
+        let data: BytesMut = query.to_vec().unwrap().as_slice().into();
+
+        // The query isn't sent at all if the deadline has already passed.
+        let result = client
+            .resolve_raw_with_deadline(data.clone(), Instant::now())
+            .await;
+        assert!(result.unwrap_err().to_string().contains("deadline"));
+        let mut buffer = [0; 512];
+        assert!(socket.try_recv(&mut buffer).is_err());
+
+        // The timeout is bounded by the remaining time, instead of the configured 5 seconds.
+        let deadline = Instant::now() + Duration::from_millis(50);
+        let result = tokio::time::timeout(
+            Duration::from_secs(1),
+            client.resolve_raw_with_deadline(data, deadline),
+        )
+        .await
+        .unwrap();
+        assert!(result.unwrap_err().to_string().contains("timeout"));
+    }
+
+    #[tokio::test]
+    async fn receive_large_responses() {
+        let (server_addr, _peers_rx) = spawn_local_server_with_answers(200).await;
+        for randomize_source_port in [false, true] {
+            let config = UdpClientConfig {
+                randomize_source_port,
+                ..Default::default()
+            };
+            let client = UdpClient::new(server_addr, config).await.unwrap();
+            let mut query = Message::new();
+            query.add_query(Query::query(
+                "www.example.com.".parse().unwrap(),
+                RecordType::A,
+            ));
+            let data = query.to_vec().unwrap().as_slice().into();
+            let response = client.resolve_raw(data).await.unwrap();
+            // Larger than the EDNS payload size advertised with --add-edns.
+            assert!(response.len() > usize::from(edns::DEFAULT_UDP_PAYLOAD_SIZE));
+            assert_eq!(Message::from_vec(&response).unwrap().answer_count(), 200);
+        }
+    }
+
+    #[tokio::test]
+    async fn randomize_source_port() {
+        let (server_addr, mut peers_rx) = spawn_local_server().await;
+        let config = UdpClientConfig {
+            randomize_source_port: true,
+            ..Default::default()
+        };
+        let client = UdpClient::new(server_addr, config).await.unwrap();
+
+        for _ in 0..10 {
+            let mut query = Message::new();
+            query.add_query(Query::query(
+                "www.example.com.".parse().unwrap(),
+                RecordType::A,
+            ));
+            let data = query.to_vec().unwrap().as_slice().into();
+            let response = client.resolve_raw(data).await.unwrap();
+            assert_eq!(Message::from_vec(&response).unwrap().answer_count(), 1);
+        }
+
+        let mut peers = HashSet::new();
+        while let Ok(peer) = peers_rx.try_recv() {
+            peers.insert(peer.port());
+        }
+        // Source ports are chosen randomly by the OS, so they may (rarely) repeat.
+        assert!(peers.len() > 1);
+        assert_eq!(client.entropy_stats().distinct_source_ports, peers.len());
+    }
+
+    #[tokio::test]
+    async fn randomize_source_port_ignores_spoofed_responses() {
+        // A server answering from a different port than the query was sent to, as an off-path attacker might.
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let spoofing_socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let server_addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buffer = [0; 512];
+            let (n, peer) = socket.recv_from(&mut buffer).await.unwrap();
+            let mut response = Message::from_vec(&buffer[..n]).unwrap();
+            response.set_message_type(MessageType::Response);
+            spoofing_socket
+                .send_to(&response.to_vec().unwrap(), peer)
+                .await
+                .unwrap();
+        });
+
+        let config = UdpClientConfig {
+            randomize_source_port: true,
+            timeout: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let client = UdpClient::new(server_addr, config).await.unwrap();
+        let mut query = Message::new();
+        query.add_query(Query::query(
+            "www.example.com.".parse().unwrap(),
+            RecordType::A,
+        ));
+        let result = client
+            .resolve_raw(query.to_vec().unwrap().as_slice().into())
+            .await;
+        assert!(result.unwrap_err().to_string().contains("timeout"));
+    }
+
+    #[tokio::test]
+    async fn count_dropped_responses() {
+        let server = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let config = UdpClientConfig {
+            pool_size: 1,
+            ..Default::default()
+        };
+        let client = UdpClient::new(server.local_addr().unwrap(), config)
+            .await
+            .unwrap();
+        let client_addr = client.sockets[0].socket.local_addr().unwrap();
+
+        // Send an unsolicited response and a packet too short to be a message.
+        let mut unsolicited = Message::new();
+        unsolicited
+            .set_id(0xabcd)
+            .set_message_type(MessageType::Response);
+        server
+            .send_to(&unsolicited.to_vec().unwrap(), client_addr)
+            .await
+            .unwrap();
+        server.send_to(&[0; 1], client_addr).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(client.dropped_responses(), 2);
+
+        // Legitimate resolutions are unaffected.
+        tokio::spawn(async move {
+            let mut buffer = [0; 512];
+            let (n, peer) = server.recv_from(&mut buffer).await.unwrap();
+            let mut response = Message::from_vec(&buffer[..n]).unwrap();
+            response.set_message_type(MessageType::Response);
+            server
+                .send_to(&response.to_vec().unwrap(), peer)
+                .await
+                .unwrap();
+        });
+        let mut query = Message::new();
+        query.add_query(Query::query(
+            "www.example.com.".parse().unwrap(),
+            RecordType::A,
+        ));
+        let response = client
+            .resolve_raw(query.to_vec().unwrap().as_slice().into())
+            .await
+            .unwrap();
+        assert_eq!(
+            Message::from_vec(&response).unwrap().message_type(),
+            MessageType::Response
+        );
+        assert_eq!(client.dropped_responses(), 2);
+    }
+
+    #[test]
+    fn describe_ipv6_socket_errors() {
+        let err = || io::Error::from(io::ErrorKind::AddrNotAvailable);
+        let server_addr =
+            SocketAddr::new(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 53).into(), 53);
+        let message = socket_error("bind", server_addr, err()).to_string();
+        assert!(message.contains("cannot bind IPv6 socket"), "{}", message);
+        assert!(message.contains("IPv6 may be unavailable"), "{}", message);
+
+        let server_addr = SocketAddr::new(Ipv4Addr::new(192, 0, 2, 53).into(), 53);
+        let message = socket_error("bind", server_addr, err()).to_string();
+        assert!(!message.contains("IPv6"), "{}", message);
+    }
+
+    #[tokio::test]
+    async fn zero_timeout() {
+        // The server never responds.
+        let server = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let config = UdpClientConfig {
+            timeout: Duration::ZERO,
+            ..Default::default()
+        };
+        let client = UdpClient::new(server.local_addr().unwrap(), config)
+            .await
+            .unwrap();
+
+        let data = client_tests::build_request("www.example.com.", RecordType::A);
+        let result = tokio::time::timeout(Duration::from_secs(1), client.resolve_raw(data))
+            .await
+            .unwrap();
+        assert!(result.unwrap_err().to_string().contains("timeout"));
+
+        // The receive task doesn't panic with a zero sweep interval.
+        let inner = Arc::new(UdpClientInner {
+            socket: UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap(),
+            pending: Mutex::default(),
+            dropped_responses: DroppedResponses::default(),
+        });
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        let task = tokio::spawn(UdpClient::receive_task(inner, Duration::ZERO, shutdown_rx));
+        tokio::time::sleep(UdpClient::MIN_SWEEP_INTERVAL * 3).await;
+        assert!(!task.is_finished());
+        shutdown_tx.send(()).unwrap();
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn sweep_abandoned_requests() {
+        // The server never responds.
+        let server = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let config = UdpClientConfig {
+            pool_size: 2,
+            timeout: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let client = Arc::new(
+            UdpClient::new(server.local_addr().unwrap(), config)
+                .await
+                .unwrap(),
+        );
+
+        // Queries cancelled before timing out don't remove their pending requests themselves.
+        let mut query = Message::new();
+        query.add_query(Query::query(
+            "www.example.com.".parse().unwrap(),
+            RecordType::A,
+        ));
+        let query = BytesMut::from(query.to_vec().unwrap().as_slice());
+        for _ in 0..100 {
+            let result =
+                tokio::time::timeout(Duration::ZERO, client.resolve_raw(query.clone())).await;
+            assert!(result.is_err());
+        }
+        assert!(client.pending_requests().await > 0);
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(client.pending_requests().await, 0);
+    }
 }
@@ -2,5 +2,6 @@
 // SPDX-FileCopyrightText: 2025 Max Wipfli <mail@maxwipfli.ch>
 
 pub mod client;
+pub mod logging;
 pub mod proto;
 pub mod resolver;
@@ -0,0 +1,135 @@
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Max Wipfli <mail@maxwipfli.ch>
+
+//! Formats of log messages, e.g. JSON lines for ingestion by log aggregation systems.
+
+use std::str::FromStr;
+
+use tracing::Subscriber;
+use tracing_subscriber::{fmt::MakeWriter, EnvFilter};
+
+/// Format of log messages.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human-readable text, the default format of `tracing_subscriber`.
+    #[default]
+    Text,
+    /// Like `Text`, but shorter.
+    Compact,
+    /// One JSON object per line, with the timestamp, level, target, and the fields of the event (including the
+    /// message).
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "compact" => Ok(Self::Compact),
+            "json" => Ok(Self::Json),
+            _ => Err(format!(
+                "invalid log format '{}' (expected 'text', 'compact', or 'json')",
+                s
+            )),
+        }
+    }
+}
+
+/// Builds a subscriber which writes the log messages passing the filter to the writer, in the given format.
+pub fn build_subscriber<W>(
+    format: LogFormat,
+    filter: EnvFilter,
+    writer: W,
+) -> Box<dyn Subscriber + Send + Sync>
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(writer);
+    match format {
+        LogFormat::Text => Box::new(builder.finish()),
+        LogFormat::Compact => Box::new(builder.compact().finish()),
+        LogFormat::Json => Box::new(builder.json().finish()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+    };
+
+    use serde_json::{json, Value};
+    use tracing::{info, warn};
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn parse_log_format() {
+        assert_eq!("text".parse(), Ok(LogFormat::Text));
+        assert_eq!("compact".parse(), Ok(LogFormat::Compact));
+        assert_eq!("json".parse(), Ok(LogFormat::Json));
+        assert!("JSON".parse::<LogFormat>().is_err());
+    }
+
+    #[test]
+    fn format_json_lines() {
+        let logs = CapturedLogs::default();
+        let writer = logs.clone();
+        let subscriber = build_subscriber(LogFormat::Json, EnvFilter::new("info"), move || {
+            writer.clone()
+        });
+        tracing::subscriber::with_default(subscriber, || {
+            info!("listening on {}", "127.0.0.1:53");
+            warn!(
+                request = 42,
+                slow = true,
+                "query for \"www.example.com.\"\ttook\n1\u{7} s"
+            );
+        });
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        let lines = logs
+            .lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2, "{}", logs);
+        for line in &lines {
+            let timestamp = line["timestamp"].as_str().unwrap();
+            assert!(timestamp.ends_with('Z'), "{}", line);
+            assert_eq!(line["target"], "aufloes::logging::tests");
+        }
+        assert_eq!(lines[0]["level"], "INFO");
+        assert_eq!(
+            lines[0]["fields"],
+            json!({"message": "listening on 127.0.0.1:53"})
+        );
+        assert_eq!(lines[1]["level"], "WARN");
+        assert_eq!(
+            lines[1]["fields"],
+            json!({
+                "message": "query for \"www.example.com.\"\ttook\n1\u{7} s",
+                "request": 42,
+                "slow": true,
+            })
+        );
+    }
+}
@@ -2,48 +2,842 @@
 // SPDX-FileCopyrightText: 2025 Max Wipfli <mail@maxwipfli.ch>
 
 use std::{
+    collections::HashSet,
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    str::FromStr,
     sync::Arc,
+    time::Duration,
 };
 
-use clap::Parser;
-use eyre::Result;
-use reqwest::Url;
-use tracing::debug;
+use clap::{error::ErrorKind, CommandFactory, Parser, Subcommand};
+use eyre::{eyre, Result};
+use hickory_proto::{
+    op::{Message, Query},
+    rr::{Name, RecordType},
+};
+use ipnet::{IpNet, Ipv6Net};
+use reqwest::{
+    header::{HeaderName, HeaderValue},
+    Url,
+};
+use tracing::{debug, info, warn};
 use tracing_subscriber::EnvFilter;
 
-use aufloes::{client::https::HttpsClient, resolver};
+#[cfg(unix)]
+use aufloes::client::https::UnixSocketClient;
+use aufloes::{
+    client::{
+        adaptive::{AdaptiveClient, AdaptiveClientConfig},
+        fallback::{FallbackClient, FallbackClientConfig},
+        forwarding::{ForwardingClient, ForwardingRule},
+        https::{HttpVersion, HttpsClient, HttpsClientConfig, JsonDohClient},
+        iterative::{IterativeClient, IterativeClientConfig},
+        labeled::LabeledClient,
+        racing::{RacingClient, RacingClientConfig},
+        retry::RetryClient,
+        round_robin::RoundRobinClient,
+        tcp::{TcpClient, TcpClientConfig},
+        udp::{UdpClient, UdpClientConfig},
+        Client,
+    },
+    logging::{build_subscriber, LogFormat},
+    resolver::{self, EcsPolicy, ResolverConfig, ServerIdentity, Stats},
+};
 
 #[derive(Debug, Parser)]
+#[command(args_conflicts_with_subcommands = true)]
 struct Args {
+    /// Be more verbose.
+    #[arg(short, long, global = true)]
+    verbose: bool,
+
+    /// Format of log messages: 'text', 'compact', or 'json' (one JSON object per line, e.g. for log aggregation
+    /// systems).
+    #[arg(long, value_name = "FORMAT", default_value = "text", global = true)]
+    log_format: LogFormat,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    // Arguments of the `serve` command, which is run if no command is given.
+    #[command(flatten)]
+    serve: ServeArgs,
+
+    #[command(flatten)]
+    upstream: Option<UpstreamArgs>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Run the resolver (default).
+    Serve {
+        // Boxed, as the serve arguments make up most of the size of the command.
+        #[command(flatten)]
+        serve: Box<ServeArgs>,
+
+        #[command(flatten)]
+        upstream: UpstreamArgs,
+    },
+    /// Resolve a single query using the upstream server, print the response, and exit.
+    Resolve(ResolveArgs),
+}
+
+#[derive(Debug, clap::Args)]
+struct ServeArgs {
+    /// Validate the arguments (e.g. that the reverse zone file can be loaded), construct the upstream clients, and
+    /// exit without starting the server. Exits with a nonzero status and an error message if invalid.
+    #[arg(long)]
+    check_config: bool,
+
     /// Port to bind UDP server to.
     #[arg(short, long, default_value_t = 53)]
     port: u16,
 
-    /// Be more verbose.
-    #[arg(short, long)]
-    verbose: bool,
+    /// Only listen on the IPv4 localhost address.
+    #[arg(long, conflicts_with = "ipv6_only")]
+    ipv4_only: bool,
+
+    /// Only listen on the IPv6 localhost address.
+    #[arg(long)]
+    ipv6_only: bool,
+
+    /// Answer queries for names ending in the label `__trace` with a TXT record describing how the query was handled.
+    /// Only queries from localhost are traced.
+    #[arg(long)]
+    debug_trace: bool,
+
+    /// Warn if no query has been received for this many seconds, which may indicate a broken listening socket.
+    /// This is only useful for normally busy resolvers.
+    #[arg(long, value_name = "SECONDS")]
+    listener_idle_warning: Option<u64>,
+
+    /// Raise the TTLs of records in upstream responses to at least this many seconds.
+    #[arg(long, value_name = "SECONDS")]
+    min_ttl: Option<u32>,
+
+    /// Lower the TTLs of records in upstream responses to at most this many seconds, so that clients don't cache
+    /// answers for too long. Must not be less than --min-ttl.
+    #[arg(long, value_name = "SECONDS")]
+    max_ttl: Option<u32>,
+
+    /// Set the TTLs of records of a type in upstream responses, regardless of their original TTL and of --min-ttl
+    /// and --max-ttl. Can be specified multiple times or as comma-separated list.
+    /// Example: --ttl-override A=60,AAAA=60
+    #[arg(long = "ttl-override", value_name = "TYPE=SECONDS", value_delimiter = ',', value_parser = parse_ttl_override)]
+    ttl_overrides: Vec<(RecordType, u32)>,
+
+    /// Remove records of this type (e.g. HTTPS) from upstream responses, to work around clients that cannot handle
+    /// them. Can be specified multiple times.
+    #[arg(long = "strip-type", value_name = "TYPE", value_parser = parse_query_type)]
+    strip_types: Vec<RecordType>,
+
+    /// Handling of EDNS Client Subnet (ECS) options in queries: 'strip', 'keep', or 'set=<prefix>'.
+    /// By default, ECS options are stripped to avoid leaking information about the client's network upstream.
+    /// Example: --ecs set=192.0.2.0/24
+    #[arg(long, value_name = "POLICY", default_value = "strip")]
+    ecs: EcsPolicy,
+
+    /// Add an OPT record to queries of clients without EDNS support before forwarding them upstream (and remove it
+    /// from the response), as some upstreams return smaller answers to queries without EDNS.
+    #[arg(long)]
+    add_edns: bool,
+
+    /// Delay each response by a random duration of up to this many milliseconds, making it harder to correlate the
+    /// timing of responses with how they were handled. Disabled by default.
+    #[arg(long, value_name = "MILLISECONDS", default_value_t = 0)]
+    response_jitter_ms: u64,
+
+    /// Warn about queries that take longer than this many milliseconds to answer.
+    /// Per-query logging is only enabled with --verbose, so this can be used to only surface problematic queries.
+    #[arg(long, value_name = "MILLISECONDS")]
+    slow_query_threshold: Option<u64>,
+
+    /// Warn if a request has been pending for more than this many seconds, which may indicate a stuck upstream.
+    #[arg(long, value_name = "SECONDS")]
+    stuck_request_warning: Option<u64>,
+
+    /// Give up on queries this many milliseconds after receiving them, as the client has likely stopped waiting.
+    /// Upstream requests are bounded by the remaining time, in addition to --upstream-timeout.
+    #[arg(long, value_name = "MILLISECONDS")]
+    query_deadline: Option<u64>,
+
+    /// Limit the number of concurrent upstream requests. Requests over the limit are answered with SERVFAIL, unless
+    /// a slot becomes available within --inflight-queue-timeout.
+    #[arg(long, value_name = "N")]
+    max_inflight: Option<usize>,
+
+    /// Time (in milliseconds) that requests over the --max-inflight limit wait for a slot.
+    #[arg(
+        long,
+        value_name = "MILLISECONDS",
+        default_value_t = 0,
+        requires = "max_inflight"
+    )]
+    inflight_queue_timeout: u64,
+
+    /// Only serve queries from this network (or address), and refuse queries from others. Can be specified
+    /// multiple times. By default, queries from all peers are served.
+    /// Example: --allow 192.168.0.0/16 --allow fd00::/8
+    #[arg(long = "allow", value_name = "CIDR", value_parser = parse_allowed_net)]
+    allowlist: Vec<IpNet>,
+
+    /// Log the query rate and latency percentiles (p50, p90, p99) every this many seconds, and the number of queries,
+    /// errors, and latencies of each upstream server if there are several (see --upstream). For DNS-over-UDP upstream
+    /// servers, the number of distinct source ports and the distribution of TXIDs of queries are logged as well, to
+    /// verify that they are randomized.
+    #[arg(long, value_name = "SECONDS")]
+    stats_interval: Option<u64>,
+
+    /// Synthesize AAAA records from A records for names without AAAA records (DNS64), for IPv6-only networks behind
+    /// NAT64. The NAT64 prefix defaults to the well-known prefix 64:ff9b::/96.
+    /// Example: --dns64 or --dns64=2001:db8:64::/96
+    #[arg(
+        long,
+        value_name = "PREFIX",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "64:ff9b::/96"
+    )]
+    dns64: Option<Ipv6Net>,
+
+    /// Like --dns64, but discover the NAT64 prefix at startup by resolving ipv4only.arpa with the upstream server
+    /// (RFC 7050), which must then perform DNS64 itself. Fails to start if no prefix can be discovered.
+    #[arg(long, conflicts_with = "dns64")]
+    dns64_auto: bool,
+
+    /// Capture all queries and responses exchanged with downstream clients to this pcap file (e.g. for Wireshark).
+    /// This is meant for debugging, as it slows down the resolver and records the queries of all clients.
+    #[arg(long, value_name = "PATH")]
+    pcap: Option<PathBuf>,
+
+    /// Answer CHAOS-class TXT queries for `version.bind` and `version.server` with this version string (by default,
+    /// the version of aufloes). This is disabled by default, as it discloses information about the server.
+    /// Example: --chaos-version or --chaos-version="resolver 1.0"
+    #[arg(
+        long,
+        value_name = "VERSION",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = concat!("aufloes ", env!("CARGO_PKG_VERSION"))
+    )]
+    chaos_version: Option<String>,
+
+    /// Answer CHAOS-class TXT queries for `hostname.bind` and `id.server` with this hostname.
+    #[arg(long, value_name = "HOSTNAME")]
+    chaos_hostname: Option<String>,
+
+    /// Answer reverse DNS (PTR) queries for local addresses from this file instead of forwarding them. The file has
+    /// the format of /etc/hosts, i.e. lines with an IP address followed by names. It is reloaded on SIGHUP.
+    #[arg(long, value_name = "PATH")]
+    reverse_zone: Option<PathBuf>,
+
+    /// Answer queries for names in the zone in this zone file (in the standard format of RFC 1035, with an SOA
+    /// record) authoritatively, and forward queries for other names. It is reloaded on SIGHUP.
+    #[arg(long, value_name = "PATH")]
+    local_zone: Option<PathBuf>,
+
+    /// Never forward queries upstream, and answer queries that cannot be answered locally (e.g. CHAOS-class
+    /// queries, see --chaos-version) with REFUSED, as a strictly local server would.
+    #[arg(long)]
+    no_recursion: bool,
+
+    /// Replace query names and client addresses in log messages of the resolver with hashes, so that logs don't
+    /// reveal which names clients look up. Hashes are salted randomly on startup, but stay the same while running,
+    /// so log messages about the same name or client can still be correlated.
+    #[arg(long)]
+    log_privacy: bool,
+
+    /// Identify this instance with this name server identifier (NSID) in responses to queries requesting it, e.g.
+    /// `dig +nsid`. This helps find out which instance answered in anycast or load-balanced setups.
+    #[arg(long, value_name = "ID")]
+    nsid: Option<String>,
+
+    /// Set SO_REUSEPORT on the listening sockets, so that multiple instances of aufloes can listen on the same
+    /// address, and the kernel balances queries between them. This is only supported on Unix.
+    #[arg(long)]
+    reuseport: bool,
 
+    /// Send queries for the same name (e.g. for its A and AAAA records) to the upstream one after another instead of
+    /// concurrently, like the single-request option of glibc, for upstreams that mishandle concurrent queries.
+    #[arg(long)]
+    single_request: bool,
+
+    /// Number of tasks receiving queries concurrently on each listening socket. Raising this may increase the
+    /// throughput at very high query rates, where receiving on a single task becomes the bottleneck.
+    #[arg(long, value_name = "N", default_value = "1")]
+    recv_workers: NonZeroUsize,
+
+    /// Answer liveness (/healthz) and readiness (/readyz) probes over HTTP on this address, e.g. for Kubernetes.
+    /// The resolver is not ready while the upstream fails queries.
+    #[arg(long, value_name = "ADDRESS")]
+    health_addr: Option<SocketAddr>,
+
+    /// Bind the listening socket to this network interface, so that responses egress through it.
+    /// This is only supported on Linux.
+    #[arg(long, value_name = "NAME")]
+    interface: Option<String>,
+}
+
+#[derive(Debug, clap::Args)]
+struct ResolveArgs {
+    #[command(flatten)]
+    upstream: UpstreamArgs,
+
+    /// Name to resolve.
+    name: Name,
+
+    /// Query type.
+    #[arg(default_value = "A", value_parser = parse_query_type)]
+    query_type: RecordType,
+}
+
+// Arguments configuring the upstream servers.
+#[derive(Debug, clap::Args)]
+struct UpstreamArgs {
     /// Upstream server URL
-    /// Currently, only DNS-over-HTTPS (DoH) upstreams are supported.
-    /// Example: https://dnsserver.example.net/dns-query
+    /// Supported are DNS-over-HTTPS (DoH) upstreams and plain DNS-over-UDP or DNS-over-TCP upstreams (which require an
+    /// IP address). With 'iterative:', names are resolved starting from the root servers instead (A and AAAA queries
+    /// only).
+    /// Example: https://dnsserver.example.net/dns-query, udp://192.0.2.53:53 or tcp://192.0.2.53:53
     #[arg(value_parser = parse_url)]
     server: Url,
 
-    /// Upstream server IP address.
+    /// Additional upstream server URL, queries are distributed across all upstream servers according to --strategy.
+    /// Can be specified multiple times. Options like --ip only apply to the main upstream server.
+    /// Example: --upstream udp://192.0.2.54 --upstream udp://192.0.2.55
+    #[arg(long = "upstream", value_name = "URL", value_parser = parse_url)]
+    upstreams: Vec<Url>,
+
+    /// Strategy for distributing queries across the upstream servers (see --upstream): 'round-robin', 'racing' to
+    /// send each query to the two upstream servers with the lowest latency and use the first response, or 'adaptive'
+    /// to send most queries to the upstream server with the lowest latency and error rate, and occasionally probe the
+    /// others.
+    #[arg(long, default_value = "round-robin")]
+    strategy: Strategy,
+
+    /// Upstream server IP addresses (comma-separated), connections fail over between them.
     /// This is required if using DNS-over-HTTPS (DoH) and this resolver is configured as the system resolver.
-    #[arg(long = "ip")]
-    server_ip: Option<IpAddr>,
+    #[arg(long = "ip", value_delimiter = ',')]
+    server_ips: Vec<IpAddr>,
+
+    /// Connect to the DNS-over-HTTPS (DoH) upstream server over this Unix domain socket (using HTTP/2 without TLS),
+    /// e.g. to reach a DoH proxy running alongside. The upstream server URL is still used for the requests.
+    #[arg(long, value_name = "PATH", conflicts_with = "server_ips")]
+    server_uds: Option<PathBuf>,
+
+    /// Interval (in seconds) at which to send keepalive pings to the DNS-over-HTTPS (DoH) upstream while idle.
+    /// This keeps the connection warm for the first query after an idle period.
+    #[arg(long, value_name = "SECONDS")]
+    doh_keepalive_interval: Option<u64>,
+
+    /// Limit the number of concurrent requests to each DNS-over-HTTPS (DoH) upstream, e.g. to stay within the rate
+    /// limits of the provider. Requests over the limit wait for another request to finish (for up to the upstream
+    /// timeout).
+    #[arg(long, value_name = "N")]
+    upstream_concurrency_per_host: Option<NonZeroUsize>,
+
+    /// Use the JSON API of DNS-over-HTTPS (DoH) upstreams (e.g. https://dns.google/resolve) instead of the DNS wire
+    /// format. Only A, AAAA, MX, and TXT queries are supported, other queries are refused. The HTTP version is
+    /// negotiated with the server.
+    #[arg(long, conflicts_with_all = ["server_uds", "http_version"])]
+    doh_json: bool,
+
+    /// HTTP version to use for DNS-over-HTTPS (DoH) upstreams: '2', '3', or 'auto'.
+    /// With 'auto', HTTP/3 is preferred and HTTP/2 is used if HTTP/3 fails, until HTTP/3 is tried again a few minutes
+    /// later. Without HTTP/3 support (the 'http3' feature), '3' is rejected and 'auto' uses HTTP/2 with a warning.
+    #[arg(long, default_value = "2")]
+    http_version: HttpVersion,
+
+    /// User-Agent to send to DNS-over-HTTPS (DoH) upstreams. Defaults to 'aufloes/<version>'.
+    #[arg(long, value_parser = parse_user_agent)]
+    user_agent: Option<String>,
+
+    /// Additional header to send to DNS-over-HTTPS (DoH) upstreams, e.g. to authenticate with a proxy.
+    /// Can be specified multiple times.
+    #[arg(long = "header", value_name = "NAME=VALUE", value_parser = parse_header)]
+    headers: Vec<(HeaderName, HeaderValue)>,
+
+    /// Connect to DNS-over-HTTPS (DoH) upstreams through this proxy, e.g. http://proxy.example.net:3128 or
+    /// socks5h://127.0.0.1:9050 (SOCKS requires the 'socks' feature). Defaults to the HTTPS_PROXY environment
+    /// variable, if set.
+    #[arg(long, value_name = "URL")]
+    proxy: Option<String>,
+
+    /// Time (in milliseconds) after which requests to upstream servers time out.
+    /// Defaults to 5 seconds for DNS-over-UDP and 10 seconds for DNS-over-HTTPS (DoH) upstreams. Must be at least 1.
+    #[arg(long, value_name = "MILLISECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    upstream_timeout: Option<u64>,
+
+    /// Send each query to DNS-over-UDP upstream servers from a new random source port, instead of reusing a pool of
+    /// sockets. This makes spoofing responses harder, at the cost of creating a socket per query.
+    #[arg(long)]
+    randomize_source_port: bool,
+
+    /// Forward queries for a domain (and its subdomains) to a different upstream server (conditional forwarding).
+    /// Can be specified multiple times, the rule with the longest matching domain wins.
+    /// The rule can be restricted to a comma-separated list of query types.
+    /// Example: --forward internal.example=udp://10.0.0.1:53 or --forward .:PTR=udp://192.168.1.1
+    #[arg(long = "forward", value_name = "DOMAIN[:TYPES]=URL", value_parser = parse_forward_rule)]
+    forward_rules: Vec<(Name, Option<HashSet<RecordType>>, Url)>,
+
+    /// Only send queries of these (comma-separated) types to the upstream server, and refuse queries of other types
+    /// that aren't handled by a forwarding rule.
+    /// Example: --server-types A,AAAA,HTTPS
+    #[arg(long, value_name = "TYPES", value_parser = parse_query_types)]
+    server_types: Option<HashSet<RecordType>>,
+
+    /// Retry queries that the upstream server answers with REFUSED or FORMERR once on this secondary upstream server.
+    /// Example: --retry-refused udp://192.0.2.53:53
+    #[arg(long, value_name = "URL", value_parser = parse_url)]
+    retry_refused: Option<Url>,
+
+    /// Send queries to this fallback upstream server (e.g. a plain DNS-over-UDP resolver) while the upstream server
+    /// is unreachable, e.g. because the DNS-over-HTTPS (DoH) connection fails behind a captive portal.
+    /// Example: --fallback udp://192.168.1.1
+    #[arg(long, value_name = "URL", value_parser = parse_url)]
+    fallback: Option<Url>,
+}
+
+/// Strategy for distributing queries across multiple upstream servers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Strategy {
+    /// Send queries to the upstream servers in turn.
+    RoundRobin,
+    /// Send queries to the two fastest upstream servers concurrently.
+    Racing,
+    /// Send queries to the best-performing upstream server, and occasionally to others.
+    Adaptive,
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "round-robin" => Ok(Self::RoundRobin),
+            "racing" => Ok(Self::Racing),
+            "adaptive" => Ok(Self::Adaptive),
+            _ => Err(format!(
+                "invalid strategy '{}' (expected 'round-robin', 'racing', or 'adaptive')",
+                s
+            )),
+        }
+    }
 }
 
 fn parse_url(s: &str) -> Result<Url, String> {
     let url = Url::parse(s).map_err(|e| e.to_string())?;
-    if url.scheme() != "https" {
-        return Err("URL scheme is not 'https' (only DNS-over-HTTPS is supported)".to_string());
+    match url.scheme() {
+        "https" | "iterative" => {}
+        "udp" | "tcp" => {
+            plain_server_addr(&url)?;
+        }
+        scheme => {
+            return Err(format!(
+                "URL scheme '{}' is not supported (expected 'https', 'udp', 'tcp', or 'iterative')",
+                scheme
+            ))
+        }
     }
     Ok(url)
 }
 
+/// Returns the server address of a `udp://<ip>[:<port>]` or `tcp://<ip>[:<port>]` URL.
+fn plain_server_addr(url: &Url) -> Result<SocketAddr, String> {
+    let ip = url
+        .host_str()
+        // IPv6 addresses are enclosed in brackets.
+        .map(|host| host.trim_start_matches('[').trim_end_matches(']'))
+        .and_then(|host| host.parse::<IpAddr>().ok())
+        .ok_or_else(|| {
+            format!(
+                "{} upstream URL must specify an IP address",
+                url.scheme().to_uppercase()
+            )
+        })?;
+    Ok(SocketAddr::new(ip, url.port().unwrap_or(53)))
+}
+
+fn parse_forward_rule(s: &str) -> Result<(Name, Option<HashSet<RecordType>>, Url), String> {
+    let (domain, url) = s
+        .split_once('=')
+        .ok_or("forwarding rule must be of the form DOMAIN[:TYPES]=URL")?;
+    let (domain, query_types) = match domain.split_once(':') {
+        Some((domain, query_types)) => (domain, Some(parse_query_types(query_types)?)),
+        None => (domain, None),
+    };
+    let mut domain = Name::from_ascii(domain).map_err(|e| e.to_string())?;
+    domain.set_fqdn(true);
+    Ok((domain, query_types, parse_url(url)?))
+}
+
+fn parse_user_agent(s: &str) -> Result<String, String> {
+    HeaderValue::from_str(s)
+        .map(|_| s.to_string())
+        .map_err(|_| format!("invalid User-Agent '{}'", s))
+}
+
+fn parse_header(s: &str) -> Result<(HeaderName, HeaderValue), String> {
+    let (name, value) = s
+        .split_once('=')
+        .ok_or("header must be of the form NAME=VALUE")?;
+    let name = HeaderName::from_str(name).map_err(|_| format!("invalid header name '{}'", name))?;
+    let value =
+        HeaderValue::from_str(value).map_err(|_| format!("invalid value for header '{}'", name))?;
+    Ok((name, value))
+}
+
+/// Parses a network in CIDR notation, or a single address.
+fn parse_allowed_net(s: &str) -> Result<IpNet, String> {
+    s.parse::<IpNet>()
+        .or_else(|_| s.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| {
+            format!(
+                "invalid network '{}' (expected CIDR notation or an address)",
+                s
+            )
+        })
+}
+
+fn parse_query_type(s: &str) -> Result<RecordType, String> {
+    s.to_ascii_uppercase()
+        .parse()
+        .map_err(|_| format!("invalid query type '{}'", s))
+}
+
+fn parse_ttl_override(s: &str) -> Result<(RecordType, u32), String> {
+    let (query_type, ttl) = s
+        .split_once('=')
+        .ok_or_else(|| format!("invalid TTL override '{}' (expected TYPE=SECONDS)", s))?;
+    let ttl = ttl
+        .parse()
+        .map_err(|_| format!("invalid TTL '{}' in override '{}'", ttl, s))?;
+    Ok((parse_query_type(query_type)?, ttl))
+}
+
+fn parse_query_types(s: &str) -> Result<HashSet<RecordType>, String> {
+    s.split(',').map(parse_query_type).collect()
+}
+
+/// Builds the client for the upstream server at `url`, which has been validated by `parse_url`.
+/// The IP addresses of DNS-over-HTTPS (DoH) servers can be provided for bootstrap purposes. The randomization
+/// statistics of DNS-over-UDP clients are added to `stats`.
+async fn build_client(
+    url: Url,
+    ips: &[IpAddr],
+    args: &UpstreamArgs,
+    stats: &Stats,
+) -> Result<Arc<dyn Client>> {
+    match url.scheme() {
+        "https" => {
+            let mut config = HttpsClientConfig {
+                keepalive_interval: args.doh_keepalive_interval.map(Duration::from_secs),
+                http_version: args.http_version,
+                headers: args.headers.iter().cloned().collect(),
+                proxy: args.proxy.clone(),
+                max_concurrent_requests: args.upstream_concurrency_per_host.map(NonZeroUsize::get),
+                ..Default::default()
+            };
+            if let Some(user_agent) = &args.user_agent {
+                config.user_agent = user_agent.clone();
+            }
+            if let Some(timeout) = args.upstream_timeout {
+                config.timeout = Duration::from_millis(timeout);
+                config.queue_timeout = config.timeout;
+            }
+            if args.doh_json {
+                return Ok(Arc::new(JsonDohClient::new(url, ips, config)?));
+            }
+            let client = Arc::new(HttpsClient::new(url, ips, config)?);
+            // Establish the connection in the background, failing to do so is not fatal.
+            let warmup_client = client.clone();
+            tokio::spawn(async move {
+                if let Err(err) = warmup_client.warmup().await {
+                    warn!("failed to warm up connection to DoH server: {}", err);
+                }
+            });
+            Ok(client)
+        }
+        "udp" => {
+            let server_addr = plain_server_addr(&url).map_err(|err| eyre!(err))?;
+            let mut config = UdpClientConfig {
+                randomize_source_port: args.randomize_source_port,
+                ..Default::default()
+            };
+            if let Some(timeout) = args.upstream_timeout {
+                config.timeout = Duration::from_millis(timeout);
+            }
+            let client = UdpClient::new(server_addr, config).await?;
+            stats.add_entropy(url.to_string(), client.shared_entropy_stats());
+            Ok(Arc::new(client))
+        }
+        "tcp" => {
+            let server_addr = plain_server_addr(&url).map_err(|err| eyre!(err))?;
+            let mut config = TcpClientConfig::default();
+            if let Some(timeout) = args.upstream_timeout {
+                config.timeout = Duration::from_millis(timeout);
+            }
+            Ok(Arc::new(TcpClient::new(server_addr, config)))
+        }
+        "iterative" => {
+            let mut config = IterativeClientConfig::default();
+            if let Some(timeout) = args.upstream_timeout {
+                config.timeout = Duration::from_millis(timeout);
+            }
+            Ok(Arc::new(IterativeClient::new(config)))
+        }
+        scheme => unreachable!("unsupported URL scheme '{}'", scheme),
+    }
+}
+
+/// Builds the client for the DNS-over-HTTPS (DoH) upstream server reachable over the Unix domain socket at `path`.
+#[cfg(unix)]
+fn build_unix_socket_client(path: &Path, args: &UpstreamArgs) -> Result<Arc<dyn Client>> {
+    let timeout = args
+        .upstream_timeout
+        .map_or(HttpsClientConfig::default().timeout, Duration::from_millis);
+    let client = UnixSocketClient::new(path, args.server.clone(), timeout)?;
+    Ok(Arc::new(client))
+}
+
+#[cfg(not(unix))]
+fn build_unix_socket_client(_path: &Path, _args: &UpstreamArgs) -> Result<Arc<dyn Client>> {
+    Err(eyre!(
+        "Unix domain sockets are only supported on Unix platforms"
+    ))
+}
+
+/// Builds the client for the upstream servers, forwarding to other upstreams according to the forwarding rules.
+/// With multiple upstream servers, the clients of the individual servers are also returned, to report their
+/// statistics.
+async fn build_upstream(
+    args: &UpstreamArgs,
+    stats: &Stats,
+) -> Result<(Arc<dyn Client>, Vec<Arc<LabeledClient>>)> {
+    let main_client = match &args.server_uds {
+        Some(path) => build_unix_socket_client(path, args)?,
+        None => build_client(args.server.clone(), &args.server_ips, args, stats).await?,
+    };
+    let mut labeled_clients = Vec::new();
+    let mut upstream_client = if args.upstreams.is_empty() {
+        main_client
+    } else {
+        labeled_clients.push(Arc::new(LabeledClient::new(
+            args.server.to_string(),
+            main_client,
+        )));
+        for url in &args.upstreams {
+            let client = build_client(url.clone(), &[], args, stats).await?;
+            labeled_clients.push(Arc::new(LabeledClient::new(url.to_string(), client)));
+        }
+        let upstreams = labeled_clients
+            .iter()
+            .map(|client| client.clone() as Arc<dyn Client>)
+            .collect();
+        let client: Arc<dyn Client> = match args.strategy {
+            Strategy::RoundRobin => Arc::new(RoundRobinClient::new(upstreams)?),
+            Strategy::Racing => {
+                Arc::new(RacingClient::new(upstreams, RacingClientConfig::default())?)
+            }
+            Strategy::Adaptive => Arc::new(AdaptiveClient::new(
+                upstreams,
+                AdaptiveClientConfig::default(),
+            )?),
+        };
+        client
+    };
+    if let Some(url) = &args.retry_refused {
+        let secondary = build_client(url.clone(), &[], args, stats).await?;
+        upstream_client = Arc::new(RetryClient::new(upstream_client, secondary));
+    }
+    if let Some(url) = &args.fallback {
+        let fallback = build_client(url.clone(), &[], args, stats).await?;
+        upstream_client = Arc::new(FallbackClient::new(
+            upstream_client,
+            fallback,
+            FallbackClientConfig::default(),
+        ));
+    }
+    if args.forward_rules.is_empty() && args.server_types.is_none() {
+        return Ok((upstream_client, labeled_clients));
+    }
+
+    let mut rules = Vec::new();
+    for (domain, query_types, url) in &args.forward_rules {
+        rules.push(ForwardingRule {
+            domain: domain.clone(),
+            query_types: query_types.clone(),
+            upstream: build_client(url.clone(), &[], args, stats).await?,
+        });
+    }
+    let mut client = ForwardingClient::new(rules, upstream_client);
+    if let Some(server_types) = &args.server_types {
+        client = client.with_default_query_types(server_types.clone());
+    }
+    Ok((Arc::new(client), labeled_clients))
+}
+
+/// Returns the addresses to listen on.
+fn bind_addrs(args: &ServeArgs) -> Vec<SocketAddr> {
+    let mut localhost: Vec<IpAddr> = Vec::new();
+    if !args.ipv6_only {
+        localhost.push(Ipv4Addr::LOCALHOST.into());
+    }
+    if !args.ipv4_only {
+        localhost.push(Ipv6Addr::LOCALHOST.into());
+    }
+    localhost
+        .into_iter()
+        .map(|ip| SocketAddr::new(ip, args.port))
+        .collect()
+}
+
+/// Fails if a DNS-over-UDP upstream server is the resolver itself, which would forward queries to itself forever.
+fn check_upstream_loops(args: &UpstreamArgs, bind_addrs: &[SocketAddr]) -> Result<()> {
+    let urls = [
+        Some(&args.server),
+        args.retry_refused.as_ref(),
+        args.fallback.as_ref(),
+    ]
+    .into_iter()
+    .flatten()
+    .chain(&args.upstreams)
+    .chain(args.forward_rules.iter().map(|(_, _, url)| url));
+    for url in urls.filter(|url| url.scheme() == "udp") {
+        let Ok(server_addr) = plain_server_addr(url) else {
+            continue;
+        };
+        // Packets sent to the unspecified address are delivered to the local host.
+        let is_self = |bind_addr: &SocketAddr| {
+            server_addr == *bind_addr
+                || (server_addr.ip().is_unspecified() && server_addr.port() == bind_addr.port())
+        };
+        if let Some(bind_addr) = bind_addrs.iter().find(|addr| is_self(addr)) {
+            return Err(eyre!(
+                "upstream server {} is the resolver itself (listening on {}), queries would loop",
+                url,
+                bind_addr
+            ));
+        }
+    }
+    Ok(())
+}
+
+async fn serve(args: ServeArgs, upstream: UpstreamArgs) -> Result<()> {
+    if let (Some(min_ttl), Some(max_ttl)) = (args.min_ttl, args.max_ttl) {
+        if min_ttl > max_ttl {
+            return Err(eyre!(
+                "--min-ttl ({}) must not be greater than --max-ttl ({})",
+                min_ttl,
+                max_ttl
+            ));
+        }
+    }
+    let bind_addrs = bind_addrs(&args);
+    check_upstream_loops(&upstream, &bind_addrs)?;
+
+    let stats = Arc::new(Stats::default());
+    let (upstream_client, labeled_clients) = build_upstream(&upstream, &stats).await?;
+    for client in labeled_clients {
+        stats.add_upstream(client);
+    }
+    let check_config = args.check_config;
+    let dns64_auto = args.dns64_auto;
+
+    let mut config = ResolverConfig {
+        debug_trace: args.debug_trace,
+        listener_idle_threshold: args.listener_idle_warning.map(Duration::from_secs),
+        min_ttl: args.min_ttl,
+        max_ttl: args.max_ttl,
+        ttl_overrides: args.ttl_overrides.into_iter().collect(),
+        strip_types: args.strip_types.into_iter().collect(),
+        ecs: args.ecs,
+        add_edns: args.add_edns,
+        response_jitter: (args.response_jitter_ms > 0)
+            .then(|| Duration::from_millis(args.response_jitter_ms)),
+        slow_query_threshold: args.slow_query_threshold.map(Duration::from_millis),
+        stuck_request_threshold: args.stuck_request_warning.map(Duration::from_secs),
+        query_deadline: args.query_deadline.map(Duration::from_millis),
+        max_inflight: args.max_inflight,
+        inflight_queue_timeout: Duration::from_millis(args.inflight_queue_timeout),
+        allowlist: args.allowlist,
+        stats_interval: args.stats_interval.map(Duration::from_secs),
+        identity: (args.chaos_version.is_some() || args.chaos_hostname.is_some()).then_some(
+            ServerIdentity {
+                version: args.chaos_version,
+                hostname: args.chaos_hostname,
+            },
+        ),
+        reverse_zone: args.reverse_zone,
+        local_zone: args.local_zone,
+        interface: args.interface,
+        reuse_port: args.reuseport,
+        dns64: args.dns64,
+        pcap: args.pcap,
+        no_recursion: args.no_recursion,
+        log_privacy: args.log_privacy,
+        health_addr: args.health_addr,
+        nsid: args.nsid,
+        recv_workers: Some(args.recv_workers),
+        single_request: args.single_request,
+    };
+
+    if check_config {
+        resolver::check_config(&config)?;
+        println!(
+            "configuration is valid (listening on {:?}, upstream {})",
+            bind_addrs, upstream.server
+        );
+        return Ok(());
+    }
+
+    if dns64_auto {
+        let prefix = resolver::discover_nat64_prefix(&*upstream_client)
+            .await
+            .map_err(|err| eyre!("cannot discover NAT64 prefix for --dns64-auto: {}", err))?;
+        info!("discovered NAT64 prefix {}", prefix);
+        config.dns64 = Some(prefix);
+    }
+
+    resolver::run(upstream_client, &bind_addrs, config, stats).await
+}
+
+/// Resolves a single query and prints the response in a format similar to `dig`.
+async fn resolve(args: ResolveArgs) -> Result<()> {
+    let (upstream_client, _) = build_upstream(&args.upstream, &Stats::default()).await?;
+
+    let mut name = args.name;
+    name.set_fqdn(true);
+    let mut query = Message::new();
+    query
+        .set_id(rand::random())
+        .set_recursion_desired(true)
+        .add_query(Query::query(name, args.query_type));
+    let response = upstream_client.resolve(query).await?;
+
+    println!(
+        ";; status: {}, answers: {}",
+        response.response_code(),
+        response.answer_count()
+    );
+    for (section, records) in [
+        ("ANSWER", response.answers()),
+        ("AUTHORITY", response.name_servers()),
+    ] {
+        if records.is_empty() {
+            continue;
+        }
+        println!();
+        println!(";; {} SECTION:", section);
+        for record in records {
+            println!("{}", record);
+        }
+    }
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
@@ -54,18 +848,457 @@ async fn main() -> Result<()> {
     } else {
         "info".to_string()
     };
-    let subscriber = tracing_subscriber::fmt()
-        .with_env_filter(EnvFilter::new(env_filter_str))
-        .finish();
-    tracing::subscriber::set_global_default(subscriber)?;
+    tracing::subscriber::set_global_default(build_subscriber(
+        args.log_format,
+        EnvFilter::new(env_filter_str),
+        std::io::stdout,
+    ))?;
     debug!("verbose logging enabled");
 
-    let upstream_client = Arc::new(HttpsClient::new(args.server, args.server_ip)?);
+    match args.command {
+        Some(Command::Serve {
+            serve: serve_args,
+            upstream,
+        }) => serve(*serve_args, upstream).await,
+        Some(Command::Resolve(resolve_args)) => resolve(resolve_args).await,
+        None => match args.upstream {
+            Some(upstream) => serve(args.serve, upstream).await,
+            None => Args::command()
+                .error(
+                    ErrorKind::MissingRequiredArgument,
+                    "the upstream server URL is required",
+                )
+                .exit(),
+        },
+    }
+}
 
-    let localhost = [Ipv4Addr::LOCALHOST.into(), Ipv6Addr::LOCALHOST.into()];
-    let bind_addrs = localhost.map(|ip| SocketAddr::new(ip, args.port));
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    resolver::run(upstream_client, &bind_addrs).await?;
+    #[test]
+    fn parse_serve_by_default() {
+        let args = Args::try_parse_from(["aufloes", "-p", "5353", "udp://192.0.2.53"]).unwrap();
+        assert!(args.command.is_none());
+        assert_eq!(args.log_format, LogFormat::Text);
+        assert_eq!(args.serve.port, 5353);
+        assert_eq!(args.upstream.unwrap().server.as_str(), "udp://192.0.2.53");
+    }
 
-    Ok(())
+    #[test]
+    fn parse_serve() {
+        let args = Args::try_parse_from([
+            "aufloes",
+            "serve",
+            "--min-ttl",
+            "60",
+            "--max-ttl",
+            "3600",
+            "--ttl-override",
+            "a=60,AAAA=60",
+            "--ttl-override",
+            "MX=3600",
+            "--strip-type",
+            "https",
+            "--strip-type",
+            "AAAA",
+            "--retry-refused",
+            "udp://192.0.2.53",
+            "--fallback",
+            "udp://192.168.1.1",
+            "--allow",
+            "192.0.2.0/24",
+            "--allow",
+            "2001:db8::1",
+            "--ip",
+            "192.0.2.1,2001:db8::53",
+            "--user-agent",
+            "test-agent/1.0",
+            "--upstream-concurrency-per-host",
+            "8",
+            "--header",
+            "X-Auth-Token=secret",
+            "--doh-json",
+            "https://dns.example.net/dns-query",
+        ])
+        .unwrap();
+        let Some(Command::Serve { serve, upstream }) = args.command else {
+            panic!("expected serve command");
+        };
+        assert_eq!(serve.port, 53);
+        assert_eq!(serve.min_ttl, Some(60));
+        assert_eq!(serve.max_ttl, Some(3600));
+        assert_eq!(
+            serve.ttl_overrides,
+            [
+                (RecordType::A, 60),
+                (RecordType::AAAA, 60),
+                (RecordType::MX, 3600)
+            ]
+        );
+        assert_eq!(serve.strip_types, [RecordType::HTTPS, RecordType::AAAA]);
+        assert_eq!(
+            serve.allowlist,
+            [
+                "192.0.2.0/24".parse::<IpNet>().unwrap(),
+                "2001:db8::1/128".parse().unwrap()
+            ]
+        );
+        assert_eq!(
+            upstream.server.as_str(),
+            "https://dns.example.net/dns-query"
+        );
+        assert_eq!(
+            upstream.server_ips,
+            [
+                "192.0.2.1".parse::<IpAddr>().unwrap(),
+                "2001:db8::53".parse().unwrap()
+            ]
+        );
+        assert_eq!(upstream.user_agent.as_deref(), Some("test-agent/1.0"));
+        assert_eq!(upstream.upstream_concurrency_per_host, NonZeroUsize::new(8));
+        assert_eq!(
+            upstream.headers,
+            [(
+                HeaderName::from_static("x-auth-token"),
+                HeaderValue::from_static("secret")
+            )]
+        );
+        assert!(upstream.doh_json);
+        assert_eq!(upstream.retry_refused.unwrap().as_str(), "udp://192.0.2.53");
+        assert_eq!(upstream.fallback.unwrap().as_str(), "udp://192.168.1.1");
+    }
+
+    #[test]
+    fn parse_invalid_header() {
+        for header in ["X-Auth-Token", "X Auth=secret", "X-Auth=sec\nret"] {
+            assert!(
+                Args::try_parse_from(["aufloes", "--header", header, "udp://192.0.2.53"]).is_err()
+            );
+        }
+    }
+
+    #[test]
+    fn parse_resolve() {
+        let args = Args::try_parse_from([
+            "aufloes",
+            "resolve",
+            "-v",
+            "udp://192.0.2.53",
+            "www.example.com",
+            "aaaa",
+        ])
+        .unwrap();
+        assert!(args.verbose);
+        let Some(Command::Resolve(resolve_args)) = args.command else {
+            panic!("expected resolve command");
+        };
+        assert_eq!(
+            resolve_args.name,
+            Name::from_ascii("www.example.com").unwrap()
+        );
+        assert_eq!(resolve_args.query_type, RecordType::AAAA);
+
+        let args =
+            Args::try_parse_from(["aufloes", "resolve", "iterative:", "example.com"]).unwrap();
+        let Some(Command::Resolve(resolve_args)) = args.command else {
+            panic!("expected resolve command");
+        };
+        assert_eq!(resolve_args.upstream.server.scheme(), "iterative");
+
+        let args =
+            Args::try_parse_from(["aufloes", "resolve", "udp://192.0.2.53", "www.example.com"])
+                .unwrap();
+        let Some(Command::Resolve(resolve_args)) = args.command else {
+            panic!("expected resolve command");
+        };
+        assert_eq!(resolve_args.query_type, RecordType::A);
+    }
+
+    #[test]
+    fn parse_dns64() {
+        let args = Args::try_parse_from(["aufloes", "--dns64", "udp://192.0.2.53"]).unwrap();
+        assert_eq!(args.serve.dns64, Some("64:ff9b::/96".parse().unwrap()));
+
+        let args =
+            Args::try_parse_from(["aufloes", "--dns64=2001:db8:64::/48", "udp://192.0.2.53"])
+                .unwrap();
+        assert_eq!(args.serve.dns64, Some("2001:db8:64::/48".parse().unwrap()));
+
+        let args = Args::try_parse_from(["aufloes", "--dns64-auto", "udp://192.0.2.53"]).unwrap();
+        assert!(args.serve.dns64_auto);
+        assert_eq!(args.serve.dns64, None);
+        assert!(
+            Args::try_parse_from(["aufloes", "--dns64", "--dns64-auto", "udp://192.0.2.53"])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn parse_chaos_version() {
+        let args =
+            Args::try_parse_from(["aufloes", "--chaos-version", "udp://192.0.2.53"]).unwrap();
+        assert_eq!(
+            args.serve.chaos_version.as_deref(),
+            Some(concat!("aufloes ", env!("CARGO_PKG_VERSION")))
+        );
+        assert_eq!(args.upstream.unwrap().server.as_str(), "udp://192.0.2.53");
+
+        let args = Args::try_parse_from(["aufloes", "--chaos-version=custom", "udp://192.0.2.53"])
+            .unwrap();
+        assert_eq!(args.serve.chaos_version.as_deref(), Some("custom"));
+    }
+
+    #[test]
+    fn address_families() {
+        let bind_addrs_for = |flags: &[&str]| {
+            let args = Args::try_parse_from(
+                ["aufloes", "-p", "5353"]
+                    .iter()
+                    .chain(flags)
+                    .chain(&["udp://192.0.2.53"]),
+            )
+            .unwrap();
+            bind_addrs(&args.serve)
+        };
+        let v4 = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 5353);
+        let v6 = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 5353);
+
+        assert_eq!(bind_addrs_for(&[]), [v4, v6]);
+        assert_eq!(bind_addrs_for(&["--ipv4-only"]), [v4]);
+        assert_eq!(bind_addrs_for(&["--ipv6-only"]), [v6]);
+        assert!(Args::try_parse_from([
+            "aufloes",
+            "--ipv4-only",
+            "--ipv6-only",
+            "udp://192.0.2.53"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn reject_upstream_loops() {
+        let check = |args: &[&str]| {
+            let args = Args::try_parse_from(["aufloes", "-p", "5353"].iter().chain(args)).unwrap();
+            check_upstream_loops(&args.upstream.unwrap(), &bind_addrs(&args.serve))
+        };
+        assert!(check(&["udp://127.0.0.1:5353"]).is_err());
+        assert!(check(&["udp://[::1]:5353"]).is_err());
+        assert!(check(&["udp://0.0.0.0:5353"]).is_err());
+        assert!(check(&["--fallback", "udp://127.0.0.1:5353", "udp://192.0.2.53"]).is_err());
+        assert!(check(&["--upstream", "udp://127.0.0.1:5353", "udp://192.0.2.53"]).is_err());
+        assert!(check(&[
+            "--forward",
+            "home.arpa=udp://[::1]:5353",
+            "udp://192.0.2.53"
+        ])
+        .is_err());
+
+        assert!(check(&["udp://127.0.0.1:53"]).is_ok());
+        assert!(check(&["udp://127.0.0.53:5353"]).is_ok());
+        assert!(check(&["--ipv4-only", "udp://[::1]:5353"]).is_ok());
+        assert!(check(&["https://127.0.0.1:5353/dns-query"]).is_ok());
+    }
+
+    #[test]
+    fn parse_tcp_upstream() {
+        let args = Args::try_parse_from(["aufloes", "tcp://[2001:db8::53]:5353"]).unwrap();
+        let url = args.upstream.unwrap().server;
+        assert_eq!(
+            plain_server_addr(&url).unwrap(),
+            "[2001:db8::53]:5353".parse().unwrap()
+        );
+        let err = Args::try_parse_from(["aufloes", "tcp://dns.example.net"]).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("TCP upstream URL must specify an IP address"),
+            "{}",
+            err
+        );
+    }
+
+    #[tokio::test]
+    async fn build_json_upstream() {
+        let args = Args::try_parse_from([
+            "aufloes",
+            "--doh-json",
+            "--ip",
+            "8.8.8.8",
+            "--proxy",
+            "not a url",
+            "https://dns.google/resolve",
+        ])
+        .unwrap();
+        // The settings of DoH upstreams apply to the JSON API as well.
+        let err = build_upstream(&args.upstream.unwrap(), &Stats::default())
+            .await
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("proxy"), "{}", err);
+
+        assert!(Args::try_parse_from([
+            "aufloes",
+            "--doh-json",
+            "--http-version",
+            "3",
+            "https://dns.google/resolve"
+        ])
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn build_multiple_upstreams() {
+        let args = Args::try_parse_from([
+            "aufloes",
+            "--upstream",
+            "udp://192.0.2.54",
+            "--upstream",
+            "tcp://192.0.2.55:5353",
+            "--strategy",
+            "round-robin",
+            "udp://192.0.2.53",
+        ])
+        .unwrap();
+        let upstream = args.upstream.unwrap();
+        assert_eq!(upstream.strategy, Strategy::RoundRobin);
+        let stats = Stats::default();
+        let (_, labeled_clients) = build_upstream(&upstream, &stats).await.unwrap();
+        let labels = labeled_clients
+            .iter()
+            .map(|client| client.label())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            labels,
+            [
+                "udp://192.0.2.53",
+                "udp://192.0.2.54",
+                "tcp://192.0.2.55:5353"
+            ]
+        );
+        // The randomization of queries to DNS-over-UDP upstreams is reported.
+        let entropy_labels = stats
+            .snapshot()
+            .entropy
+            .into_iter()
+            .map(|(label, _)| label)
+            .collect::<Vec<_>>();
+        assert_eq!(entropy_labels, ["udp://192.0.2.53", "udp://192.0.2.54"]);
+
+        let args = Args::try_parse_from([
+            "aufloes",
+            "--strategy",
+            "racing",
+            "--upstream",
+            "udp://192.0.2.54",
+            "udp://192.0.2.53",
+        ])
+        .unwrap();
+        let upstream = args.upstream.unwrap();
+        assert_eq!(upstream.strategy, Strategy::Racing);
+        let (_, labeled_clients) = build_upstream(&upstream, &Stats::default()).await.unwrap();
+        assert_eq!(labeled_clients.len(), 2);
+        let args = Args::try_parse_from([
+            "aufloes",
+            "--strategy",
+            "adaptive",
+            "--upstream",
+            "udp://192.0.2.54",
+            "udp://192.0.2.53",
+        ])
+        .unwrap();
+        assert_eq!(args.upstream.unwrap().strategy, Strategy::Adaptive);
+
+        // A single upstream server doesn't need to be distinguished.
+        let args = Args::try_parse_from(["aufloes", "udp://192.0.2.53"]).unwrap();
+        let (_, labeled_clients) = build_upstream(&args.upstream.unwrap(), &Stats::default())
+            .await
+            .unwrap();
+        assert!(labeled_clients.is_empty());
+    }
+
+    #[tokio::test]
+    async fn check_config() {
+        let args = Args::try_parse_from([
+            "aufloes",
+            "--check-config",
+            "--reverse-zone",
+            "/nonexistent/hosts",
+            "udp://192.0.2.53",
+        ])
+        .unwrap();
+        assert!(serve(args.serve, args.upstream.unwrap()).await.is_err());
+
+        // The server isn't started, so the port doesn't need to be available.
+        let args =
+            Args::try_parse_from(["aufloes", "--check-config", "-p", "53", "udp://192.0.2.53"])
+                .unwrap();
+        serve(args.serve, args.upstream.unwrap()).await.unwrap();
+
+        let err =
+            Args::try_parse_from(["aufloes", "--check-config", "udp:/192.0.2.53:abc"]).unwrap_err();
+        assert!(err.to_string().contains("invalid value"), "{}", err);
+    }
+
+    #[test]
+    fn parse_log_format() {
+        let args = Args::try_parse_from([
+            "aufloes",
+            "serve",
+            "--log-format",
+            "json",
+            "udp://192.0.2.53",
+        ])
+        .unwrap();
+        assert_eq!(args.log_format, LogFormat::Json);
+        let args = Args::try_parse_from([
+            "aufloes",
+            "resolve",
+            "--log-format=compact",
+            "udp://192.0.2.53",
+            "www.example.com",
+        ])
+        .unwrap();
+        assert_eq!(args.log_format, LogFormat::Compact);
+        assert!(
+            Args::try_parse_from(["aufloes", "--log-format", "xml", "udp://192.0.2.53"]).is_err()
+        );
+    }
+
+    #[test]
+    fn reject_invalid_args() {
+        assert!(Args::try_parse_from(["aufloes", "resolve", "udp://192.0.2.53"]).is_err());
+        assert!(Args::try_parse_from(["aufloes", "-p", "5353"]).is_err());
+        assert!(Args::try_parse_from([
+            "aufloes",
+            "--upstream-concurrency-per-host",
+            "0",
+            "https://dns.example.net/dns-query"
+        ])
+        .is_err());
+        assert!(
+            Args::try_parse_from(["aufloes", "--upstream-timeout", "0", "udp://192.0.2.53"])
+                .is_err()
+        );
+        assert!(Args::try_parse_from([
+            "aufloes",
+            "resolve",
+            "udp://192.0.2.53",
+            "www.example.com",
+            "BOGUS"
+        ])
+        .is_err());
+        assert!(
+            Args::try_parse_from(["aufloes", "--strategy", "random", "udp://192.0.2.53"]).is_err()
+        );
+        for ttl_override in ["A", "A=-1", "BOGUS=60"] {
+            assert!(Args::try_parse_from([
+                "aufloes",
+                "--ttl-override",
+                ttl_override,
+                "udp://192.0.2.53"
+            ])
+            .is_err());
+        }
+    }
 }
@@ -0,0 +1,518 @@
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Max Wipfli <mail@maxwipfli.ch>
+
+//! Helpers for Extension Mechanisms for DNS (EDNS(0)), as defined in [RFC6891].
+//!
+//! [RFC6891]: https://datatracker.ietf.org/doc/html/rfc6891
+
+use std::{net::IpAddr, str::FromStr};
+
+use super::message::{records, RecordLocation, Section};
+
+/// Resource record type of the OPT pseudo-record.
+pub const OPT_TYPE: u16 = 41;
+
+/// EDNS option code of Extended DNS Errors.
+pub const EDE_CODE: u16 = 15;
+
+/// EDNS option code of EDNS Client Subnet.
+pub const ECS_CODE: u16 = 8;
+
+/// EDNS option code of the Name Server Identifier (NSID), as defined in [RFC5001].
+///
+/// [RFC5001]: https://datatracker.ietf.org/doc/html/rfc5001
+pub const NSID_CODE: u16 = 3;
+
+/// UDP payload size advertised in OPT records added to messages, as recommended by the DNS Flag Day 2020.
+pub const DEFAULT_UDP_PAYLOAD_SIZE: u16 = 1232;
+
+/// An EDNS option within a message encoded in DNS wire format.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EdnsOption<'a> {
+    pub code: u16,
+    pub data: &'a [u8],
+}
+
+/// An Extended DNS Error, as defined in [RFC8914].
+///
+/// [RFC8914]: https://datatracker.ietf.org/doc/html/rfc8914
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtendedError {
+    pub info_code: u16,
+    pub extra_text: String,
+}
+
+impl ExtendedError {
+    /// INFO-CODE of errors that don't match any other code.
+    pub const OTHER: u16 = 0;
+    /// INFO-CODE of queries for names blocked by the server's policy.
+    pub const BLOCKED: u16 = 15;
+    /// INFO-CODE of queries the server is configured not to answer (e.g. from clients not in an allowlist).
+    pub const PROHIBITED: u16 = 18;
+    /// INFO-CODE of queries a server that is not authoritative for the name (and doesn't recurse) cannot answer.
+    pub const NOT_AUTHORITATIVE: u16 = 20;
+    /// INFO-CODE of queries of a kind (e.g. an opcode) the server doesn't support.
+    pub const NOT_SUPPORTED: u16 = 21;
+    /// INFO-CODE of queries that failed due to network errors (e.g. reaching the upstream server).
+    pub const NETWORK_ERROR: u16 = 23;
+
+    pub fn new(info_code: u16, extra_text: impl Into<String>) -> Self {
+        Self {
+            info_code,
+            extra_text: extra_text.into(),
+        }
+    }
+
+    /// Encodes the error as EDE option data.
+    pub fn to_option_data(&self) -> Vec<u8> {
+        let mut data = self.info_code.to_be_bytes().to_vec();
+        data.extend(self.extra_text.as_bytes());
+        data
+    }
+}
+
+/// A client subnet, as carried in the EDNS Client Subnet (ECS) option defined in [RFC7871].
+///
+/// [RFC7871]: https://datatracker.ietf.org/doc/html/rfc7871
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientSubnet {
+    pub addr: IpAddr,
+    pub prefix_len: u8,
+}
+
+impl ClientSubnet {
+    /// Encodes the subnet as ECS option data, with scope prefix length 0 as required in queries.
+    pub fn to_option_data(&self) -> Vec<u8> {
+        let (family, addr) = match self.addr {
+            IpAddr::V4(addr) => (1u16, addr.octets().to_vec()),
+            IpAddr::V6(addr) => (2u16, addr.octets().to_vec()),
+        };
+        let mut data = family.to_be_bytes().to_vec();
+        data.extend([self.prefix_len, 0]);
+        // "ADDRESS [...] MUST be truncated to the number of bits indicated by the SOURCE PREFIX-LENGTH field"
+        // RFC 7871, Section 6
+        let len = (self.prefix_len as usize).div_ceil(8);
+        data.extend(&addr[..len]);
+        let trailing_bits = self.prefix_len % 8;
+        if trailing_bits != 0 {
+            *data.last_mut().unwrap() &= 0xff << (8 - trailing_bits);
+        }
+        data
+    }
+}
+
+impl FromStr for ClientSubnet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = s
+            .split_once('/')
+            .ok_or_else(|| format!("invalid subnet '{}' (expected ADDRESS/PREFIX)", s))?;
+        let addr = addr.parse::<IpAddr>().map_err(|e| e.to_string())?;
+        let prefix_len = prefix_len.parse::<u8>().map_err(|e| e.to_string())?;
+        let max_prefix_len = if addr.is_ipv4() { 32 } else { 128 };
+        if prefix_len > max_prefix_len {
+            return Err(format!(
+                "invalid prefix length {} for subnet '{}'",
+                prefix_len, s
+            ));
+        }
+        Ok(Self { addr, prefix_len })
+    }
+}
+
+/// Fixed fields of an OPT record, which are carried in its CLASS and TTL fields.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OptHeader {
+    /// Maximum UDP payload size of the sender.
+    pub udp_payload_size: u16,
+    /// Upper 8 bits of the extended RCODE.
+    pub extended_rcode: u8,
+    pub version: u8,
+    /// Whether the sender is DNSSEC-aware (DO bit).
+    pub dnssec_ok: bool,
+}
+
+/// Locates the OPT record of a message encoded in DNS wire format.
+/// Returns `None` if the message has no OPT record or is malformed.
+pub fn find_opt(message: &[u8]) -> Option<RecordLocation> {
+    records(message)?
+        .into_iter()
+        .find(|record| record.section == Section::Additional && record.rtype == OPT_TYPE)
+}
+
+/// Parses the fixed fields of the OPT record of a message encoded in DNS wire format.
+/// Returns `None` if the message has no OPT record or is malformed.
+pub fn opt_header(message: &[u8]) -> Option<OptHeader> {
+    let opt = find_opt(message)?;
+    let ttl = &message[opt.ttl_offset..opt.ttl_offset + 4];
+    Some(OptHeader {
+        udp_payload_size: opt.class,
+        extended_rcode: ttl[0],
+        version: ttl[1],
+        dnssec_ok: ttl[2] & 0x80 != 0,
+    })
+}
+
+/// Returns the EDNS options of a message encoded in DNS wire format, in the order they appear.
+/// Returns `None` if the message has no OPT record or is malformed.
+pub fn options(message: &[u8]) -> Option<Vec<EdnsOption<'_>>> {
+    let opt = find_opt(message)?;
+    let mut rdata = &message[opt.rdata];
+    let mut options = Vec::new();
+    while !rdata.is_empty() {
+        let code = u16::from_be_bytes([*rdata.first()?, *rdata.get(1)?]);
+        let len = u16::from_be_bytes([*rdata.get(2)?, *rdata.get(3)?]) as usize;
+        let data = rdata.get(4..4 + len)?;
+        options.push(EdnsOption { code, data });
+        rdata = &rdata[4 + len..];
+    }
+    Some(options)
+}
+
+/// Returns a copy of a message encoded in DNS wire format with the EDNS options replaced by `options`.
+/// If the message has no OPT record, one is added. Returns `None` if the message is malformed.
+pub fn with_options(message: &[u8], options: &[EdnsOption]) -> Option<Vec<u8>> {
+    let rdata = encode_options(options);
+    let rdlength = u16::try_from(rdata.len()).ok()?.to_be_bytes();
+    let mut result = Vec::with_capacity(message.len() + rdata.len());
+    if let Some(opt) = find_opt(message) {
+        result.extend(&message[..opt.rdata.start - 2]);
+        result.extend(rdlength);
+        result.extend(rdata);
+        result.extend(&message[opt.rdata.end..]);
+    } else {
+        // Make sure the message is well-formed before appending to it.
+        records(message)?;
+        result.extend(message);
+        // root name, type, class (UDP payload size), TTL (extended RCODE and flags), RDLENGTH
+        result.push(0);
+        result.extend(OPT_TYPE.to_be_bytes());
+        result.extend(DEFAULT_UDP_PAYLOAD_SIZE.to_be_bytes());
+        result.extend(0u32.to_be_bytes());
+        result.extend(rdlength);
+        result.extend(rdata);
+        let arcount = u16::from_be_bytes([result[10], result[11]]).checked_add(1)?;
+        result[10..12].copy_from_slice(&arcount.to_be_bytes());
+    }
+    Some(result)
+}
+
+/// Returns a copy of a message encoded in DNS wire format with the OPT record removed.
+/// Returns `None` if the message has no OPT record or is malformed.
+pub fn without_opt(message: &[u8]) -> Option<Vec<u8>> {
+    let opt = find_opt(message)?;
+    let mut result = Vec::with_capacity(message.len() - opt.range.len());
+    result.extend(&message[..opt.range.start]);
+    result.extend(&message[opt.range.end..]);
+    let arcount = u16::from_be_bytes([result[10], result[11]]) - 1;
+    result[10..12].copy_from_slice(&arcount.to_be_bytes());
+    Some(result)
+}
+
+fn encode_options(options: &[EdnsOption]) -> Vec<u8> {
+    let mut data = Vec::new();
+    for option in options {
+        data.extend(option.code.to_be_bytes());
+        data.extend((option.data.len() as u16).to_be_bytes());
+        data.extend(option.data);
+    }
+    data
+}
+
+/// Returns a copy of a message encoded in DNS wire format with `error` appended to its EDNS options.
+/// If the message has no OPT record, one is added. Returns `None` if the message is malformed.
+pub fn with_extended_error(message: &[u8], error: &ExtendedError) -> Option<Vec<u8>> {
+    let data = error.to_option_data();
+    let mut options = options(message).unwrap_or_default();
+    options.push(EdnsOption {
+        code: EDE_CODE,
+        data: &data,
+    });
+    with_options(message, &options)
+}
+
+/// Returns whether a message encoded in DNS wire format requests the server's NSID, i.e. has an empty NSID option.
+pub fn requests_nsid(message: &[u8]) -> bool {
+    options(message)
+        .unwrap_or_default()
+        .iter()
+        .any(|option| option.code == NSID_CODE && option.data.is_empty())
+}
+
+/// Returns a copy of a message encoded in DNS wire format with its NSID option set to `nsid`, replacing any existing
+/// NSID option. If the message has no OPT record, one is added. Returns `None` if the message is malformed.
+pub fn with_nsid(message: &[u8], nsid: &[u8]) -> Option<Vec<u8>> {
+    let mut options = options(message).unwrap_or_default();
+    options.retain(|option| option.code != NSID_CODE);
+    options.push(EdnsOption {
+        code: NSID_CODE,
+        data: nsid,
+    });
+    with_options(message, &options)
+}
+
+/// Returns the Extended DNS Errors contained in a message encoded in DNS wire format.
+pub fn extended_errors(message: &[u8]) -> Vec<ExtendedError> {
+    options(message)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|option| option.code == EDE_CODE && option.data.len() >= 2)
+        .map(|option| ExtendedError {
+            info_code: u16::from_be_bytes([option.data[0], option.data[1]]),
+            extra_text: String::from_utf8_lossy(&option.data[2..]).into_owned(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+pub mod tests {
+    use hickory_proto::{
+        op::{Message, Query},
+        rr::RecordType,
+    };
+
+    use super::*;
+
+    /// Appends an OPT record with the given options to a message encoded in DNS wire format.
+    pub fn append_opt(message: &mut Vec<u8>, options: &[(u16, &[u8])]) {
+        let rdata = options
+            .iter()
+            .flat_map(|(code, data)| {
+                let mut option = code.to_be_bytes().to_vec();
+                option.extend((data.len() as u16).to_be_bytes());
+                option.extend(*data);
+                option
+            })
+            .collect::<Vec<_>>();
+
+        // root name, type, class (UDP payload size), TTL (extended RCODE and flags), RDLENGTH
+        message.push(0);
+        message.extend(OPT_TYPE.to_be_bytes());
+        message.extend(1232u16.to_be_bytes());
+        message.extend(0u32.to_be_bytes());
+        message.extend((rdata.len() as u16).to_be_bytes());
+        message.extend(rdata);
+
+        let arcount = u16::from_be_bytes([message[10], message[11]]) + 1;
+        message[10..12].copy_from_slice(&arcount.to_be_bytes());
+    }
+
+    fn build_query() -> Vec<u8> {
+        let mut query = Message::new();
+        query.add_query(Query::query(
+            "www.example.com.".parse().unwrap(),
+            RecordType::A,
+        ));
+        query.to_vec().unwrap()
+    }
+
+    #[test]
+    fn set_nsid() {
+        let mut query = build_query();
+        assert!(!requests_nsid(&query));
+        let response = with_nsid(&query, b"ns1").unwrap();
+        assert_eq!(
+            options(&response).unwrap(),
+            [EdnsOption {
+                code: NSID_CODE,
+                data: b"ns1"
+            }]
+        );
+        append_opt(&mut query, &[(NSID_CODE, &[])]);
+        assert!(requests_nsid(&query));
+
+        // An NSID received from the upstream is replaced, other options are kept.
+        let mut response = build_query();
+        append_opt(&mut response, &[(NSID_CODE, b"upstream"), (12, &[0; 4])]);
+        let response = with_nsid(&response, b"ns1").unwrap();
+        assert_eq!(
+            options(&response).unwrap(),
+            [
+                EdnsOption {
+                    code: 12,
+                    data: &[0; 4]
+                },
+                EdnsOption {
+                    code: NSID_CODE,
+                    data: b"ns1"
+                }
+            ]
+        );
+        // Only empty NSID options are requests.
+        assert!(!requests_nsid(&response));
+    }
+
+    #[test]
+    fn add_extended_error() {
+        let error = ExtendedError::new(ExtendedError::BLOCKED, "blocked by policy");
+
+        let message = with_extended_error(&build_query(), &error).unwrap();
+        assert_eq!(extended_errors(&message), std::slice::from_ref(&error));
+
+        // Existing options are kept.
+        let mut message = build_query();
+        append_opt(&mut message, &[(12, &[0; 4])]);
+        let message = with_extended_error(&message, &error).unwrap();
+        let options = options(&message).unwrap();
+        assert_eq!(options.len(), 2);
+        assert_eq!(options[0].code, 12);
+        assert_eq!(extended_errors(&message), [error]);
+    }
+
+    #[test]
+    fn no_opt() {
+        let message = build_query();
+        assert_eq!(find_opt(&message), None);
+        assert_eq!(options(&message), None);
+        assert!(extended_errors(&message).is_empty());
+    }
+
+    #[test]
+    fn parse_options() {
+        let mut message = build_query();
+        append_opt(
+            &mut message,
+            &[
+                (12, &[0; 4]),
+                (EDE_CODE, b"\x00\x06bogus"),
+                (EDE_CODE, b"\x00\x16"),
+            ],
+        );
+
+        let options = options(&message).unwrap();
+        assert_eq!(options.len(), 3);
+        assert_eq!(
+            options[0],
+            EdnsOption {
+                code: 12,
+                data: &[0; 4]
+            }
+        );
+
+        assert_eq!(
+            extended_errors(&message),
+            [
+                ExtendedError {
+                    info_code: 6,
+                    extra_text: "bogus".to_string(),
+                },
+                ExtendedError {
+                    info_code: 22,
+                    extra_text: String::new(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_opt_header() {
+        let mut message = build_query();
+        append_opt(&mut message, &[]);
+        let opt = find_opt(&message).unwrap();
+        // extended RCODE 1, version 0, DO bit
+        message[opt.ttl_offset..opt.ttl_offset + 4].copy_from_slice(&[1, 0, 0x80, 0]);
+
+        assert_eq!(
+            opt_header(&message),
+            Some(OptHeader {
+                udp_payload_size: 1232,
+                extended_rcode: 1,
+                version: 0,
+                dnssec_ok: true,
+            })
+        );
+        assert_eq!(options(&message), Some(Vec::new()));
+        assert_eq!(opt_header(&build_query()), None);
+    }
+
+    #[test]
+    fn remove_opt() {
+        let query = build_query();
+        let mut message = query.clone();
+        append_opt(&mut message, &[(12, &[0; 4])]);
+        assert_eq!(without_opt(&message), Some(query.clone()));
+        assert_eq!(without_opt(&query), None);
+    }
+
+    #[test]
+    fn replace_options() {
+        let mut message = build_query();
+        append_opt(
+            &mut message,
+            &[(ECS_CODE, &[0, 1, 24, 0, 192, 0, 2]), (12, &[0; 4])],
+        );
+
+        let retained = options(&message)
+            .unwrap()
+            .into_iter()
+            .filter(|option| option.code != ECS_CODE)
+            .collect::<Vec<_>>();
+        let replaced = with_options(&message, &retained).unwrap();
+
+        assert_eq!(
+            options(&replaced).unwrap(),
+            [EdnsOption {
+                code: 12,
+                data: &[0; 4]
+            }]
+        );
+        assert_eq!(opt_header(&replaced), opt_header(&message));
+        assert_eq!(replaced.len(), message.len() - 11);
+    }
+
+    #[test]
+    fn add_opt() {
+        let message = build_query();
+        let data = [1, 2, 3];
+        let replaced = with_options(
+            &message,
+            &[EdnsOption {
+                code: 12,
+                data: &data,
+            }],
+        )
+        .unwrap();
+
+        assert_eq!(
+            options(&replaced).unwrap(),
+            [EdnsOption {
+                code: 12,
+                data: &data
+            }]
+        );
+        assert_eq!(
+            opt_header(&replaced).unwrap().udp_payload_size,
+            DEFAULT_UDP_PAYLOAD_SIZE
+        );
+        assert_eq!(with_options(&message[..message.len() - 1], &[]), None);
+    }
+
+    #[test]
+    fn encode_client_subnet() {
+        let subnet = "192.0.2.129/25".parse::<ClientSubnet>().unwrap();
+        assert_eq!(subnet.to_option_data(), [0, 1, 25, 0, 192, 0, 2, 128]);
+
+        let subnet = "2001:db8::/32".parse::<ClientSubnet>().unwrap();
+        assert_eq!(
+            subnet.to_option_data(),
+            [0, 2, 32, 0, 0x20, 0x01, 0x0d, 0xb8]
+        );
+
+        let subnet = "0.0.0.0/0".parse::<ClientSubnet>().unwrap();
+        assert_eq!(subnet.to_option_data(), [0, 1, 0, 0]);
+
+        assert!("192.0.2.0/33".parse::<ClientSubnet>().is_err());
+        assert!("192.0.2.0".parse::<ClientSubnet>().is_err());
+    }
+
+    #[test]
+    fn truncated_option() {
+        let mut message = build_query();
+        append_opt(&mut message, &[(12, &[0; 4])]);
+        // Shorten the option length field past RDATA of the OPT record.
+        let opt = find_opt(&message).unwrap();
+        message[opt.rdata.start + 3] = 5;
+        assert_eq!(options(&message), None);
+    }
+}
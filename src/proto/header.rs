@@ -0,0 +1,161 @@
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Max Wipfli <mail@maxwipfli.ch>
+
+/// Header of a DNS message, as defined in [RFC1035], Section 4.1.1.
+///
+/// [RFC1035]: https://datatracker.ietf.org/doc/html/rfc1035
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub id: u16,
+    pub flags: u16,
+    pub qdcount: u16,
+    pub ancount: u16,
+    pub nscount: u16,
+    pub arcount: u16,
+}
+
+impl Header {
+    /// Length of the header in DNS wire format.
+    pub const LEN: usize = 12;
+
+    pub const FLAG_QR: u16 = 0x8000;
+    pub const FLAG_TC: u16 = 0x0200;
+    pub const FLAG_RD: u16 = 0x0100;
+    pub const FLAG_RA: u16 = 0x0080;
+    pub const FLAG_AD: u16 = 0x0020;
+    pub const FLAG_CD: u16 = 0x0010;
+    pub const OPCODE_MASK: u16 = 0x7800;
+    pub const RCODE_MASK: u16 = 0x000f;
+
+    /// Parses the header of a message encoded in DNS wire format.
+    /// Returns `None` if the message is too short to contain a header.
+    pub fn parse(message: &[u8]) -> Option<Self> {
+        let header = message.get(..Self::LEN)?;
+        let field = |i: usize| u16::from_be_bytes([header[2 * i], header[2 * i + 1]]);
+        Some(Self {
+            id: field(0),
+            flags: field(1),
+            qdcount: field(2),
+            ancount: field(3),
+            nscount: field(4),
+            arcount: field(5),
+        })
+    }
+
+    /// Opcode of a standard query.
+    pub const OPCODE_QUERY: u8 = 0;
+
+    /// Returns the opcode of the message.
+    pub fn opcode(&self) -> u8 {
+        ((self.flags & Self::OPCODE_MASK) >> 11) as u8
+    }
+
+    /// Returns the (lower 4 bits of the) response code of the message.
+    pub fn rcode(&self) -> u8 {
+        (self.flags & Self::RCODE_MASK) as u8
+    }
+
+    /// Encodes the header in DNS wire format.
+    pub fn to_bytes(&self) -> [u8; Self::LEN] {
+        let mut bytes = [0; Self::LEN];
+        let fields = [
+            self.id,
+            self.flags,
+            self.qdcount,
+            self.ancount,
+            self.nscount,
+            self.arcount,
+        ];
+        for (chunk, field) in bytes.chunks_exact_mut(2).zip(fields) {
+            chunk.copy_from_slice(&field.to_be_bytes());
+        }
+        bytes
+    }
+
+    /// Whether the message is a response (QR bit set) rather than a query.
+    pub fn is_response(&self) -> bool {
+        self.flags & Self::FLAG_QR != 0
+    }
+
+    /// Whether the data in the response has been authenticated by a validating resolver (AD bit, [RFC4035,
+    /// Section 3.2.3]). In queries, it indicates that the client understands the AD bit ([RFC6840, Section 5.7]).
+    ///
+    /// [RFC4035, Section 3.2.3]: https://datatracker.ietf.org/doc/html/rfc4035#section-3.2.3
+    /// [RFC6840, Section 5.7]: https://datatracker.ietf.org/doc/html/rfc6840#section-5.7
+    pub fn authentic_data(&self) -> bool {
+        self.flags & Self::FLAG_AD != 0
+    }
+
+    /// Whether DNSSEC validation is disabled for the query (CD bit, [RFC4035, Section 3.2.2]), i.e. the client
+    /// validates responses itself and also wants to receive data that fails validation.
+    ///
+    /// [RFC4035, Section 3.2.2]: https://datatracker.ietf.org/doc/html/rfc4035#section-3.2.2
+    pub fn checking_disabled(&self) -> bool {
+        self.flags & Self::FLAG_CD != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let message = [
+            0x12, 0x34, 0x81, 0x80, 0x00, 0x01, 0x00, 0x02, 0x00, 0x03, 0x00, 0x04, 0xff,
+        ];
+        let header = Header::parse(&message).unwrap();
+        assert_eq!(
+            header,
+            Header {
+                id: 0x1234,
+                flags: 0x8180,
+                qdcount: 1,
+                ancount: 2,
+                nscount: 3,
+                arcount: 4,
+            }
+        );
+        assert!(header.is_response());
+        assert_eq!(header.rcode(), 0);
+        assert_eq!(header.to_bytes(), message[..Header::LEN]);
+    }
+
+    #[test]
+    fn parse_query() {
+        let message = [0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        let header = Header::parse(&message).unwrap();
+        assert!(!header.is_response());
+        assert_eq!(header.opcode(), Header::OPCODE_QUERY);
+        assert!(!header.authentic_data());
+        assert!(!header.checking_disabled());
+    }
+
+    #[test]
+    fn parse_dnssec_flags() {
+        // AD and CD bits set
+        let message = [0x12, 0x34, 0x01, 0x30, 0, 1, 0, 0, 0, 0, 0, 0];
+        let header = Header::parse(&message).unwrap();
+        assert!(header.authentic_data());
+        assert!(header.checking_disabled());
+
+        // only CD bit set
+        let message = [0x12, 0x34, 0x01, 0x10, 0, 1, 0, 0, 0, 0, 0, 0];
+        let header = Header::parse(&message).unwrap();
+        assert!(!header.authentic_data());
+        assert!(header.checking_disabled());
+    }
+
+    #[test]
+    fn parse_opcode() {
+        // UPDATE (opcode 5) with RD bit set
+        let message = [0x12, 0x34, 0x29, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        assert_eq!(Header::parse(&message).unwrap().opcode(), 5);
+    }
+
+    #[test]
+    fn parse_too_short() {
+        assert_eq!(Header::parse(&[]), None);
+        assert_eq!(Header::parse(&[0; 11]), None);
+    }
+}
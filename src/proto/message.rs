@@ -0,0 +1,568 @@
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Max Wipfli <mail@maxwipfli.ch>
+
+use std::{collections::HashSet, ops::Range};
+
+use bytes::BytesMut;
+use hickory_proto::{
+    op::{Message, Query, ResponseCode},
+    rr::{Record, RecordType},
+    serialize::binary::{BinDecodable, BinDecoder},
+};
+
+use super::{
+    edns::{self, ExtendedError},
+    Header,
+};
+
+/// Section of a DNS message containing resource records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Section {
+    Answer,
+    Authority,
+    Additional,
+}
+
+/// Location of a resource record within a message encoded in DNS wire format.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordLocation {
+    pub section: Section,
+    /// Range of the whole record, starting at its owner name.
+    pub range: Range<usize>,
+    pub rtype: u16,
+    pub class: u16,
+    /// Offset of the 4-byte TTL field.
+    pub ttl_offset: usize,
+    pub rdata: Range<usize>,
+}
+
+/// Returns the offset just past the (possibly compressed) domain name starting at `offset`.
+fn skip_name(message: &[u8], mut offset: usize) -> Option<usize> {
+    loop {
+        let len = *message.get(offset)? as usize;
+        match len & 0xc0 {
+            0x00 if len == 0 => return Some(offset + 1),
+            0x00 => offset += 1 + len,
+            // A compression pointer ends the name.
+            0xc0 => {
+                message.get(offset + 1)?;
+                return Some(offset + 2);
+            }
+            _ => return None,
+        }
+    }
+}
+
+/// Returns the offset of the end of the question section of a message encoded in DNS wire format.
+/// Returns `None` if the message is malformed.
+pub fn question_end(message: &[u8]) -> Option<usize> {
+    let header = Header::parse(message)?;
+    let mut offset = Header::LEN;
+    for _ in 0..header.qdcount {
+        // name, type, class
+        offset = skip_name(message, offset)? + 4;
+    }
+    (offset <= message.len()).then_some(offset)
+}
+
+/// Parses the first question of a message encoded in DNS wire format.
+/// Returns `None` if the message has no question or is malformed.
+pub fn first_question(message: &[u8]) -> Option<Query> {
+    if Header::parse(message)?.qdcount == 0 {
+        return None;
+    }
+    // Decode from the start of the message, so compression pointers are resolved correctly.
+    let mut decoder = BinDecoder::new(message);
+    decoder.read_slice(Header::LEN).ok()?;
+    Query::read(&mut decoder).ok()
+}
+
+/// Locates all resource records of a message encoded in DNS wire format.
+/// Returns `None` if the message is malformed.
+pub fn records(message: &[u8]) -> Option<Vec<RecordLocation>> {
+    let header = Header::parse(message)?;
+    let sections = [
+        (Section::Answer, header.ancount),
+        (Section::Authority, header.nscount),
+        (Section::Additional, header.arcount),
+    ];
+
+    let mut offset = question_end(message)?;
+    let mut records = Vec::new();
+    for (section, count) in sections {
+        for _ in 0..count {
+            let start = offset;
+            offset = skip_name(message, offset)?;
+            let fields = message.get(offset..offset + 10)?;
+            let field = |i: usize| u16::from_be_bytes([fields[i], fields[i + 1]]);
+            let rdata_start = offset + 10;
+            let rdata_end = rdata_start + field(8) as usize;
+            if rdata_end > message.len() {
+                return None;
+            }
+            records.push(RecordLocation {
+                section,
+                range: start..rdata_end,
+                rtype: field(0),
+                class: field(2),
+                ttl_offset: offset + 4,
+                rdata: rdata_start..rdata_end,
+            });
+            offset = rdata_end;
+        }
+    }
+    Some(records)
+}
+
+/// Returns the response code of a message encoded in DNS wire format, including the upper bits carried in the OPT
+/// record (if any). Returns `None` if the message is too short to contain a header.
+pub fn response_code(message: &[u8]) -> Option<ResponseCode> {
+    let header = Header::parse(message)?;
+    let extended_rcode = edns::opt_header(message).map_or(0, |opt| opt.extended_rcode);
+    Some(ResponseCode::from(extended_rcode, header.rcode()))
+}
+
+/// Builds a response to a query encoded in DNS wire format that only carries the given error code.
+/// The transaction ID, opcode, RD and CD bits, and question section are copied from the query (the CD bit as required
+/// by RFC 4035, Section 3.2.2).
+/// Returns `None` if the question section of the query is malformed.
+pub fn error_response(query: &[u8], rcode: ResponseCode) -> Option<Vec<u8>> {
+    let query_header = Header::parse(query)?;
+    let question_end = question_end(query)?;
+    let header = Header {
+        id: query_header.id,
+        flags: Header::FLAG_QR
+            | Header::FLAG_RA
+            | (query_header.flags & (Header::OPCODE_MASK | Header::FLAG_RD | Header::FLAG_CD))
+            | (rcode.low() as u16 & Header::RCODE_MASK),
+        qdcount: query_header.qdcount,
+        ancount: 0,
+        nscount: 0,
+        arcount: 0,
+    };
+    let mut response = header.to_bytes().to_vec();
+    response.extend(&query[Header::LEN..question_end]);
+    Some(response)
+}
+
+/// Builds a response to a query encoded in DNS wire format like `error_response`, which additionally carries an
+/// Extended DNS Error ([RFC8914]) explaining the error if the query has an OPT record. Peers without EDNS support
+/// must not receive an OPT record (RFC 6891, Section 7).
+///
+/// [RFC8914]: https://datatracker.ietf.org/doc/html/rfc8914
+pub fn error_response_with_reason(
+    query: &[u8],
+    rcode: ResponseCode,
+    error: &ExtendedError,
+) -> Option<Vec<u8>> {
+    let response = error_response(query, rcode)?;
+    if edns::find_opt(query).is_none() {
+        return Some(response);
+    }
+    Some(edns::with_extended_error(&response, error).unwrap_or(response))
+}
+
+/// Rewrites the TTLs of all answer and authority records of a message encoded in DNS wire format in place.
+/// Returns `None` (leaving the message unchanged) if the message is malformed.
+pub fn map_ttls(message: &mut [u8], f: impl Fn(u32) -> u32) -> Option<()> {
+    map_ttls_by_type(message, |_, ttl| f(ttl))
+}
+
+/// Like `map_ttls`, but `f` also receives the type of each record, so that TTLs can be rewritten depending on it.
+pub fn map_ttls_by_type(message: &mut [u8], f: impl Fn(RecordType, u32) -> u32) -> Option<()> {
+    for record in records(message)? {
+        if record.section == Section::Additional {
+            continue;
+        }
+        let ttl_field = &mut message[record.ttl_offset..record.ttl_offset + 4];
+        let ttl = u32::from_be_bytes(ttl_field.try_into().unwrap());
+        ttl_field.copy_from_slice(&f(RecordType::from(record.rtype), ttl).to_be_bytes());
+    }
+    Some(())
+}
+
+/// Maximum size of messages sent over UDP to peers that don't support EDNS (RFC 1035, Section 4.2.1).
+pub const MAX_UDP_MESSAGE_LEN: usize = 512;
+
+/// Size of buffers receiving UDP datagrams, which fits any datagram. With EDNS, messages over UDP can be larger than
+/// `MAX_UDP_MESSAGE_LEN` (e.g. `edns::DEFAULT_UDP_PAYLOAD_SIZE`, or whatever the peer advertises).
+pub const UDP_RECV_BUFFER_LEN: usize = u16::MAX as usize;
+
+/// Truncates a message encoded in DNS wire format to at most `max_len` bytes if it is longer, and sets the TC bit to
+/// signal that the full message must be retrieved over TCP.
+///
+/// The answer records that fit are kept, authority and additional records are removed. Returns `None` (leaving the
+/// message unchanged) if the message is malformed or its question section doesn't fit.
+pub fn truncate(message: &mut BytesMut, max_len: usize) -> Option<()> {
+    if message.len() <= max_len {
+        return Some(());
+    }
+    let question_end = question_end(message)?;
+    if question_end > max_len {
+        return None;
+    }
+    let answers = records(message)?
+        .into_iter()
+        .take_while(|record| record.section == Section::Answer && record.range.end <= max_len)
+        .collect::<Vec<_>>();
+    let end = answers
+        .last()
+        .map_or(question_end, |record| record.range.end);
+
+    let mut header = Header::parse(message)?;
+    header.flags |= Header::FLAG_TC;
+    header.ancount = answers.len() as u16;
+    header.nscount = 0;
+    header.arcount = 0;
+    message.truncate(end);
+    message[..Header::LEN].copy_from_slice(&header.to_bytes());
+    Some(())
+}
+
+/// Removes all records of the given types from a message encoded in DNS wire format, updating the record counts.
+/// Returns `None` (leaving the message unchanged) if the message is malformed.
+///
+/// The message is only re-serialized if it contains records to remove, as removing records in place could break name
+/// compression pointers into them.
+pub fn strip_record_types(message: &mut BytesMut, types: &HashSet<RecordType>) -> Option<()> {
+    let strip = records(message)?
+        .iter()
+        .any(|record| types.contains(&RecordType::from(record.rtype)));
+    if !strip {
+        return Some(());
+    }
+
+    let mut parsed = Message::from_vec(message).ok()?;
+    let retain = |records: Vec<Record>| {
+        records
+            .into_iter()
+            .filter(|record| !types.contains(&record.record_type()))
+            .collect::<Vec<_>>()
+    };
+    let answers = retain(parsed.take_answers());
+    let name_servers = retain(parsed.take_name_servers());
+    let additionals = retain(parsed.take_additionals());
+    parsed.insert_answers(answers);
+    parsed.insert_name_servers(name_servers);
+    parsed.insert_additionals(additionals);
+    *message = parsed.to_vec().ok()?.as_slice().into();
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use hickory_proto::{
+        op::MessageType,
+        rr::{
+            rdata::{A, AAAA, NS},
+            Name, RData,
+        },
+    };
+
+    use super::*;
+
+    #[test]
+    fn locate_records() {
+        let name = "www.example.com.".parse().unwrap();
+        let mut message = Message::new();
+        message
+            .add_query(Query::query(name, RecordType::A))
+            .add_answer(Record::from_rdata(
+                "www.example.com.".parse().unwrap(),
+                300,
+                RData::A(A::new(192, 0, 2, 1)),
+            ))
+            .add_name_server(Record::from_rdata(
+                "example.com.".parse().unwrap(),
+                3600,
+                RData::NS(NS("ns.example.com.".parse().unwrap())),
+            ));
+        let data = message.to_vec().unwrap();
+
+        let records = records(&data).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].section, Section::Answer);
+        assert_eq!(records[0].rtype, u16::from(RecordType::A));
+        assert_eq!(&data[records[0].rdata.clone()], [192, 0, 2, 1]);
+        assert_eq!(
+            data[records[0].ttl_offset..records[0].ttl_offset + 4],
+            300u32.to_be_bytes()
+        );
+        assert_eq!(records[1].section, Section::Authority);
+        assert_eq!(records[1].rtype, u16::from(RecordType::NS));
+        assert_eq!(records[1].range.end, data.len());
+    }
+
+    #[test]
+    fn parse_first_question() {
+        let query = Query::query("www.example.com.".parse().unwrap(), RecordType::AAAA);
+        let mut message = Message::new();
+        message.add_query(query.clone());
+        assert_eq!(first_question(&message.to_vec().unwrap()), Some(query));
+
+        let message = Message::new();
+        assert_eq!(first_question(&message.to_vec().unwrap()), None);
+    }
+
+    #[test]
+    fn root_questions() {
+        for query_type in [RecordType::NS, RecordType::SOA, RecordType::A] {
+            let query = Query::query(Name::root(), query_type);
+            let mut message = Message::new();
+            message.add_query(query.clone());
+            let data = message.to_vec().unwrap();
+
+            // The root name is encoded as a single zero-length label.
+            assert_eq!(data.len(), Header::LEN + 1 + 4);
+            assert_eq!(question_end(&data), Some(data.len()));
+            assert_eq!(records(&data), Some(Vec::new()));
+            let question = first_question(&data).unwrap();
+            assert!(question.name().is_root());
+            assert_eq!(question, query);
+        }
+    }
+
+    #[test]
+    fn root_owner_name() {
+        let mut message = Message::new();
+        message
+            .add_query(Query::query(Name::root(), RecordType::NS))
+            .add_answer(Record::from_rdata(
+                Name::root(),
+                518400,
+                RData::NS(NS("a.root-servers.net.".parse().unwrap())),
+            ));
+        let data = message.to_vec().unwrap();
+
+        let records = records(&data).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].rtype, u16::from(RecordType::NS));
+        assert_eq!(records[0].range.end, data.len());
+    }
+
+    #[test]
+    fn rewrite_ttls() {
+        let mut message = Message::new();
+        message
+            .add_query(Query::query(
+                "www.example.com.".parse().unwrap(),
+                RecordType::A,
+            ))
+            .add_answer(Record::from_rdata(
+                "www.example.com.".parse().unwrap(),
+                1,
+                RData::A(A::new(192, 0, 2, 1)),
+            ))
+            .add_name_server(Record::from_rdata(
+                "example.com.".parse().unwrap(),
+                3600,
+                RData::NS(NS("ns.example.com.".parse().unwrap())),
+            ))
+            .add_additional(Record::from_rdata(
+                "ns.example.com.".parse().unwrap(),
+                5,
+                RData::A(A::new(192, 0, 2, 53)),
+            ));
+        let mut data = message.to_vec().unwrap();
+
+        map_ttls(&mut data, |ttl| ttl.max(60)).unwrap();
+
+        let message = Message::from_vec(&data).unwrap();
+        assert_eq!(message.answers()[0].ttl(), 60);
+        assert_eq!(message.name_servers()[0].ttl(), 3600);
+        // Additional records are left untouched.
+        assert_eq!(message.additionals()[0].ttl(), 5);
+
+        map_ttls_by_type(&mut data, |rtype, ttl| match rtype {
+            RecordType::NS => 86400,
+            _ => ttl,
+        })
+        .unwrap();
+        let message = Message::from_vec(&data).unwrap();
+        assert_eq!(message.answers()[0].ttl(), 60);
+        assert_eq!(message.name_servers()[0].ttl(), 86400);
+    }
+
+    #[test]
+    fn strip_records() {
+        let name = "www.example.com.".parse::<Name>().unwrap();
+        let mut message = Message::new();
+        message
+            .add_query(Query::query(name.clone(), RecordType::ANY))
+            .add_answer(Record::from_rdata(
+                name.clone(),
+                300,
+                RData::A(A::new(192, 0, 2, 1)),
+            ))
+            .add_answer(Record::from_rdata(
+                name.clone(),
+                300,
+                RData::AAAA(AAAA::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)),
+            ))
+            .add_answer(Record::from_rdata(
+                name,
+                300,
+                RData::A(A::new(192, 0, 2, 2)),
+            ));
+        let data = message.to_vec().unwrap();
+        let types = HashSet::from([RecordType::AAAA]);
+
+        let mut stripped = BytesMut::from(data.as_slice());
+        strip_record_types(&mut stripped, &types).unwrap();
+        let stripped = Message::from_vec(&stripped).unwrap();
+        assert_eq!(stripped.answer_count(), 2);
+        assert!(stripped
+            .answers()
+            .iter()
+            .all(|record| record.record_type() == RecordType::A));
+        assert_eq!(stripped.queries(), message.queries());
+
+        // Messages without records to strip are left untouched.
+        let mut unchanged = BytesMut::from(data.as_slice());
+        strip_record_types(&mut unchanged, &HashSet::from([RecordType::HTTPS])).unwrap();
+        assert_eq!(unchanged, data);
+
+        let mut malformed = BytesMut::from(&data[..data.len() - 1]);
+        assert_eq!(strip_record_types(&mut malformed, &types), None);
+        assert_eq!(malformed, data[..data.len() - 1]);
+    }
+
+    #[test]
+    fn truncate_message() {
+        let name = "www.example.com.".parse::<Name>().unwrap();
+        let mut message = Message::new();
+        message.add_query(Query::query(name.clone(), RecordType::A));
+        for i in 0..40 {
+            message.add_answer(Record::from_rdata(
+                name.clone(),
+                300,
+                RData::A(A::new(192, 0, 2, i)),
+            ));
+        }
+        message.add_additional(Record::from_rdata(
+            "ns.example.com.".parse().unwrap(),
+            300,
+            RData::A(A::new(192, 0, 2, 53)),
+        ));
+        let data = message.to_vec().unwrap();
+        assert!(data.len() > MAX_UDP_MESSAGE_LEN);
+
+        let mut truncated = BytesMut::from(data.as_slice());
+        truncate(&mut truncated, MAX_UDP_MESSAGE_LEN).unwrap();
+        assert!(truncated.len() <= MAX_UDP_MESSAGE_LEN);
+        let truncated = Message::from_vec(&truncated).unwrap();
+        assert!(truncated.truncated());
+        assert!(truncated.answer_count() > 0 && truncated.answer_count() < 40);
+        assert_eq!(
+            truncated.answers(),
+            &message.answers()[..truncated.answers().len()]
+        );
+        assert_eq!(truncated.additional_count(), 0);
+
+        // Messages that fit are left untouched.
+        let mut unchanged = BytesMut::from(data.as_slice());
+        truncate(&mut unchanged, data.len()).unwrap();
+        assert_eq!(unchanged, data);
+    }
+
+    #[test]
+    fn build_error_response_with_reason() {
+        let error = ExtendedError::new(ExtendedError::PROHIBITED, "not allowed");
+        let mut query = Message::new();
+        query.set_id(0x1234).add_query(Query::query(
+            "www.example.com.".parse().unwrap(),
+            RecordType::A,
+        ));
+        let data = query.to_vec().unwrap();
+
+        // Peers without EDNS support don't receive an OPT record.
+        let response = error_response_with_reason(&data, ResponseCode::Refused, &error).unwrap();
+        assert_eq!(
+            response,
+            error_response(&data, ResponseCode::Refused).unwrap()
+        );
+
+        let mut data = data;
+        edns::tests::append_opt(&mut data, &[]);
+        let response = error_response_with_reason(&data, ResponseCode::Refused, &error).unwrap();
+        assert_eq!(edns::extended_errors(&response), [error]);
+        let response = Message::from_vec(&response).unwrap();
+        assert_eq!(response.id(), 0x1234);
+        assert_eq!(response.response_code(), ResponseCode::Refused);
+    }
+
+    #[test]
+    fn build_error_response() {
+        let mut query = Message::new();
+        query
+            .set_id(0x1234)
+            .set_recursion_desired(true)
+            .set_checking_disabled(true)
+            .add_query(Query::query(
+                "www.example.com.".parse().unwrap(),
+                RecordType::A,
+            ))
+            .add_additional(Record::from_rdata(
+                "www.example.com.".parse().unwrap(),
+                0,
+                RData::A(A::new(192, 0, 2, 1)),
+            ));
+        let data = query.to_vec().unwrap();
+
+        let response = error_response(&data, ResponseCode::ServFail).unwrap();
+
+        let response = Message::from_vec(&response).unwrap();
+        assert_eq!(response.id(), 0x1234);
+        assert_eq!(response.message_type(), MessageType::Response);
+        assert_eq!(response.response_code(), ResponseCode::ServFail);
+        assert!(response.recursion_desired());
+        assert!(response.checking_disabled());
+        assert_eq!(response.queries(), query.queries());
+        assert_eq!(response.additional_count(), 0);
+
+        assert_eq!(
+            error_response(&data[..data.len() / 2], ResponseCode::ServFail),
+            None
+        );
+    }
+
+    #[test]
+    fn parse_response_code() {
+        let mut response = Message::new();
+        response.set_response_code(ResponseCode::Refused);
+        assert_eq!(
+            response_code(&response.to_vec().unwrap()),
+            Some(ResponseCode::Refused)
+        );
+
+        // BADCOOKIE (23) is an extended response code, whose upper bits are carried in the OPT record.
+        let mut response = Message::new();
+        response
+            .set_response_code(ResponseCode::BADCOOKIE)
+            .set_edns(Default::default());
+        assert_eq!(
+            response_code(&response.to_vec().unwrap()),
+            Some(ResponseCode::BADCOOKIE)
+        );
+
+        assert_eq!(response_code(&[0; 11]), None);
+    }
+
+    #[test]
+    fn malformed() {
+        let mut query = Message::new();
+        query.add_query(Query::query(
+            "www.example.com.".parse().unwrap(),
+            RecordType::A,
+        ));
+        let data = query.to_vec().unwrap();
+
+        assert_eq!(question_end(&data), Some(data.len()));
+        // truncated question
+        assert_eq!(question_end(&data[..data.len() - 1]), None);
+        // claims an answer record that isn't there
+        let mut data = data;
+        data[7] = 1;
+        assert_eq!(records(&data), None);
+    }
+}
@@ -1,9 +1,37 @@
 // SPDX-License-Identifier: MIT
 // SPDX-FileCopyrightText: 2025 Max Wipfli <mail@maxwipfli.ch>
 
+pub mod edns;
+mod header;
+pub use header::Header;
+pub mod message;
+mod tcp;
+pub use tcp::{read_tcp_message, write_tcp_message, MAX_TCP_MESSAGE_LEN};
+
 /// Extracts the transaction ID from a message encoded in DNS wire format.
-pub fn txid_from_binary_message(message: &[u8]) -> u16 {
-    let mut txid = [0u8; 2];
-    txid.copy_from_slice(&message[0..2]);
-    u16::from_be_bytes(txid)
+/// Returns `None` if the message is too short to contain a header.
+pub fn txid_from_binary_message(message: &[u8]) -> Option<u16> {
+    Header::parse(message).map(|header| header.id)
+}
+
+/// Sets the transaction ID of a message encoded in DNS wire format.
+///
+/// Only the ID field of the header is modified, the rest of the message (including any OPT record) is left intact.
+pub fn set_txid_in_binary_message(message: &mut [u8], txid: u16) {
+    message[0..2].copy_from_slice(&txid.to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn txid() {
+        let mut message = [0; Header::LEN];
+        set_txid_in_binary_message(&mut message, 0x1234);
+        assert_eq!(message[..2], [0x12, 0x34]);
+        assert_eq!(txid_from_binary_message(&message), Some(0x1234));
+        assert_eq!(txid_from_binary_message(&message[..1]), None);
+        assert_eq!(txid_from_binary_message(&[]), None);
+    }
 }
@@ -0,0 +1,113 @@
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Max Wipfli <mail@maxwipfli.ch>
+
+//! Framing of DNS messages over TCP (and other stream transports), where each message is prefixed with its length
+//! as a 2-byte unsigned integer in network byte order ([RFC1035, Section 4.2.2]).
+//!
+//! [RFC1035, Section 4.2.2]: https://datatracker.ietf.org/doc/html/rfc1035#section-4.2.2
+
+use std::io::ErrorKind;
+
+use bytes::BytesMut;
+use eyre::{eyre, Result};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Maximum length of a DNS message sent over TCP, as limited by the length prefix.
+pub const MAX_TCP_MESSAGE_LEN: usize = u16::MAX as usize;
+
+/// Reads a length-prefixed DNS message from `reader`.
+///
+/// Fails if the stream ends before the length prefix or the message is read completely.
+pub async fn read_tcp_message<R: AsyncRead + Unpin>(reader: &mut R) -> Result<BytesMut> {
+    let len = match reader.read_u16().await {
+        Ok(len) => len as usize,
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
+            return Err(eyre!("stream ended before length prefix of DNS message"))
+        }
+        Err(err) => return Err(err.into()),
+    };
+    let mut message = BytesMut::zeroed(len);
+    match reader.read_exact(&mut message).await {
+        Ok(_) => Ok(message),
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => Err(eyre!(
+            "stream ended before DNS message of {} bytes was read completely",
+            len
+        )),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Writes `message` to `writer`, prefixed with its length.
+///
+/// Fails if the message is longer than `MAX_TCP_MESSAGE_LEN`. The length prefix and message are written with a
+/// single write where possible, so they aren't split across TCP segments unnecessarily.
+pub async fn write_tcp_message<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    message: &[u8],
+) -> Result<()> {
+    let len: u16 = message.len().try_into().map_err(|_| {
+        eyre!(
+            "DNS message of {} bytes is too long for TCP (at most {} bytes)",
+            message.len(),
+            MAX_TCP_MESSAGE_LEN
+        )
+    })?;
+    let mut frame = Vec::with_capacity(2 + message.len());
+    frame.extend_from_slice(&len.to_be_bytes());
+    frame.extend_from_slice(message);
+    writer.write_all(&frame).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn round_trip() {
+        for len in [0, 1, 512, MAX_TCP_MESSAGE_LEN] {
+            let message = (0..len).map(|i| i as u8).collect::<Vec<_>>();
+            let mut stream = Vec::new();
+            write_tcp_message(&mut stream, &message).await.unwrap();
+            assert_eq!(stream.len(), 2 + len);
+            assert_eq!(stream[..2], (len as u16).to_be_bytes());
+
+            let mut reader = stream.as_slice();
+            assert_eq!(read_tcp_message(&mut reader).await.unwrap(), message);
+            assert!(reader.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn multiple_messages() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        let writer = tokio::spawn(async move {
+            for i in 0..3u8 {
+                write_tcp_message(&mut client, &[i; 100]).await.unwrap();
+            }
+        });
+        // Messages longer than the buffer of the duplex stream are read in multiple parts.
+        for i in 0..3u8 {
+            assert_eq!(read_tcp_message(&mut server).await.unwrap(), [i; 100][..]);
+        }
+        writer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn reject_too_long_message() {
+        let mut stream = Vec::new();
+        let message = vec![0; MAX_TCP_MESSAGE_LEN + 1];
+        assert!(write_tcp_message(&mut stream, &message).await.is_err());
+        assert!(stream.is_empty());
+    }
+
+    #[tokio::test]
+    async fn truncated_stream() {
+        for stream in [&[][..], &[0x00], &[0x00, 0x04, 0xab, 0xcd]] {
+            let mut reader = stream;
+            let err = read_tcp_message(&mut reader).await.unwrap_err();
+            assert!(err.to_string().contains("stream ended"), "{}", err);
+        }
+    }
+}
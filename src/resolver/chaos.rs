@@ -0,0 +1,145 @@
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Max Wipfli <mail@maxwipfli.ch>
+
+use bytes::BytesMut;
+use eyre::Result;
+use hickory_proto::{
+    op::{Message, MessageType},
+    rr::{rdata::TXT, DNSClass, Name, RData, Record, RecordType},
+};
+
+/// Names of CHAOS-class TXT records conventionally holding the server version.
+const VERSION_NAMES: &[&str] = &["version.bind.", "version.server."];
+
+/// Names of CHAOS-class TXT records conventionally holding the server hostname.
+const HOSTNAME_NAMES: &[&str] = &["hostname.bind.", "id.server."];
+
+/// Information about the resolver itself, served in CHAOS-class TXT records.
+#[derive(Debug, Clone, Default)]
+pub struct ServerIdentity {
+    /// Version string served for `version.bind` and `version.server`.
+    pub version: Option<String>,
+    /// Hostname served for `hostname.bind` and `id.server`.
+    pub hostname: Option<String>,
+}
+
+impl ServerIdentity {
+    /// Returns the text to answer a CHAOS-class TXT query for `name` with, if any.
+    fn lookup(&self, name: &Name) -> Option<&str> {
+        // Names are compared case-insensitively.
+        let matches = |names: &[&str]| {
+            names
+                .iter()
+                .any(|candidate| Name::from_ascii(candidate).unwrap() == *name)
+        };
+        if matches(VERSION_NAMES) {
+            self.version.as_deref()
+        } else if matches(HOSTNAME_NAMES) {
+            self.hostname.as_deref()
+        } else {
+            None
+        }
+    }
+}
+
+/// Answers CHAOS-class TXT queries for the server version or hostname.
+/// Returns `None` if the query is not such a query, or the requested information is not configured.
+pub fn answer(query: &Message, identity: &ServerIdentity) -> Option<Result<BytesMut>> {
+    let [question] = query.queries() else {
+        return None;
+    };
+    if question.query_class() != DNSClass::CH || question.query_type() != RecordType::TXT {
+        return None;
+    }
+    let text = identity.lookup(question.name())?;
+
+    let mut record = Record::from_rdata(
+        question.name().clone(),
+        0,
+        RData::TXT(TXT::new(vec![text.to_string()])),
+    );
+    record.set_dns_class(DNSClass::CH);
+    let mut response = Message::new();
+    response
+        .set_id(query.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(query.op_code())
+        .set_recursion_desired(query.recursion_desired())
+        .set_checking_disabled(query.checking_disabled())
+        .set_authoritative(true)
+        .add_query(question.clone())
+        .add_answer(record);
+    Some(
+        response
+            .to_vec()
+            .map(|response| response.as_slice().into())
+            .map_err(Into::into),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use hickory_proto::op::Query;
+
+    use super::*;
+
+    fn query(name: &str, class: DNSClass, query_type: RecordType) -> Message {
+        let mut query_message = Message::new();
+        let mut query = Query::query(name.parse().unwrap(), query_type);
+        query.set_query_class(class);
+        query_message.add_query(query);
+        query_message
+    }
+
+    fn identity() -> ServerIdentity {
+        ServerIdentity {
+            version: Some("aufloes 1.2.3".to_string()),
+            hostname: None,
+        }
+    }
+
+    #[test]
+    fn answer_version() {
+        for name in ["version.bind.", "VERSION.server."] {
+            let query = query(name, DNSClass::CH, RecordType::TXT);
+            let response = answer(&query, &identity()).unwrap().unwrap();
+            let response = Message::from_vec(&response).unwrap();
+            let answer = &response.answers()[0];
+            assert_eq!(answer.dns_class(), DNSClass::CH);
+            let Some(RData::TXT(txt)) = answer.data() else {
+                panic!("expected TXT record");
+            };
+            assert_eq!(
+                txt.txt_data(),
+                [b"aufloes 1.2.3".to_vec().into_boxed_slice()]
+            );
+        }
+    }
+
+    #[test]
+    fn ignore_other_queries() {
+        let identity = identity();
+        // IN-class queries are resolved as usual.
+        assert!(answer(
+            &query("version.bind.", DNSClass::IN, RecordType::TXT),
+            &identity
+        )
+        .is_none());
+        assert!(answer(
+            &query("version.bind.", DNSClass::CH, RecordType::A),
+            &identity
+        )
+        .is_none());
+        assert!(answer(
+            &query("www.example.com.", DNSClass::CH, RecordType::TXT),
+            &identity
+        )
+        .is_none());
+        // The hostname is not configured.
+        assert!(answer(
+            &query("hostname.bind.", DNSClass::CH, RecordType::TXT),
+            &identity
+        )
+        .is_none());
+    }
+}
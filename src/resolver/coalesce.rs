@@ -0,0 +1,97 @@
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Max Wipfli <mail@maxwipfli.ch>
+
+use std::{collections::HashMap, future::Future, sync::Mutex};
+
+use bytes::{Bytes, BytesMut};
+use eyre::{eyre, Result};
+use tokio::sync::oneshot;
+
+/// Key identifying identical queries: the query in DNS wire format, excluding the transaction ID.
+///
+/// As the key includes the header flags and the OPT record, queries for the same name whose responses may differ
+/// (e.g. with and without the DO or CD bit set) are never coalesced.
+type QuestionKey = Bytes;
+
+type Waiter = oneshot::Sender<Result<BytesMut, String>>;
+
+/// Coalesces concurrent identical queries, so that only one of them is sent to the upstream server.
+///
+/// The first query for a given key resolves it, and all identical queries arriving while it is in flight wait for
+/// (a copy of) its response instead of issuing their own upstream request.
+#[derive(Default)]
+pub struct Coalescer {
+    inflight: Mutex<HashMap<QuestionKey, Vec<Waiter>>>,
+}
+
+impl Coalescer {
+    /// Resolves `query` with `resolve`, unless an identical query is already in flight, in which case its response
+    /// is awaited instead. The transaction ID of the returned response is unspecified.
+    pub async fn resolve<F>(&self, query: &[u8], resolve: F) -> Result<BytesMut>
+    where
+        F: Future<Output = Result<BytesMut>>,
+    {
+        let key = QuestionKey::copy_from_slice(query.get(2..).unwrap_or_default());
+
+        let receiver = {
+            let mut inflight = self.inflight.lock().unwrap();
+            match inflight.get_mut(&key) {
+                Some(waiters) => {
+                    let (sender, receiver) = oneshot::channel();
+                    waiters.push(sender);
+                    Some(receiver)
+                }
+                None => {
+                    inflight.insert(key.clone(), Vec::new());
+                    None
+                }
+            }
+        };
+        if let Some(receiver) = receiver {
+            return match receiver.await {
+                Ok(result) => result.map_err(|err| eyre!(err)),
+                Err(_) => Err(eyre!("coalesced upstream request was cancelled")),
+            };
+        }
+
+        // Removes the entry even if this future is dropped, so waiters don't wait forever.
+        let guard = InflightGuard {
+            coalescer: self,
+            key,
+        };
+        let result = resolve.await;
+        for waiter in guard.finish() {
+            let _ = waiter.send(match &result {
+                Ok(response) => Ok(response.clone()),
+                Err(err) => Err(err.to_string()),
+            });
+        }
+        result
+    }
+}
+
+struct InflightGuard<'a> {
+    coalescer: &'a Coalescer,
+    key: QuestionKey,
+}
+
+impl InflightGuard<'_> {
+    /// Removes the in-flight entry, returning its waiters.
+    fn finish(self) -> Vec<Waiter> {
+        let waiters = self.take_waiters();
+        std::mem::forget(self);
+        waiters
+    }
+
+    fn take_waiters(&self) -> Vec<Waiter> {
+        let mut inflight = self.coalescer.inflight.lock().unwrap();
+        inflight.remove(&self.key).unwrap_or_default()
+    }
+}
+
+impl Drop for InflightGuard<'_> {
+    fn drop(&mut self) {
+        // Dropping the waiters signals cancellation to them.
+        self.take_waiters();
+    }
+}
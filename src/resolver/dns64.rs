@@ -0,0 +1,286 @@
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Max Wipfli <mail@maxwipfli.ch>
+
+//! Synthesis of AAAA records from A records for IPv6-only clients behind NAT64 (DNS64, [RFC 6147]), and discovery
+//! of the NAT64 prefix ([RFC 7050]).
+//!
+//! [RFC 6147]: https://datatracker.ietf.org/doc/html/rfc6147
+//! [RFC 7050]: https://datatracker.ietf.org/doc/html/rfc7050
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use bytes::BytesMut;
+use eyre::{eyre, Result};
+use hickory_proto::{
+    op::{Message, Query, ResponseCode},
+    rr::{rdata::AAAA, DNSClass, RData, Record, RecordType},
+};
+use ipnet::Ipv6Net;
+use tracing::warn;
+
+use crate::client::Client;
+
+/// Prefix lengths supported for embedding IPv4 addresses (RFC 6052, Section 2.2).
+pub const PREFIX_LENS: &[u8] = &[32, 40, 48, 56, 64, 96];
+
+/// Embeds `ipv4` into the NAT64 `prefix`, whose length must be one of `PREFIX_LENS`.
+pub fn embed(prefix: &Ipv6Net, ipv4: Ipv4Addr) -> Ipv6Addr {
+    debug_assert!(PREFIX_LENS.contains(&prefix.prefix_len()));
+    let mut octets = prefix.network().octets();
+    let mut index = usize::from(prefix.prefix_len() / 8);
+    for octet in ipv4.octets() {
+        // Bits 64 to 71 of the address must be zero.
+        if index == 8 {
+            index += 1;
+        }
+        octets[index] = octet;
+        index += 1;
+    }
+    octets.into()
+}
+
+/// Name with only A records, whose AAAA records synthesized by a DNS64 server reveal its NAT64 prefix
+/// (RFC 7050, Section 2.1).
+const IPV4ONLY_ARPA: &str = "ipv4only.arpa.";
+
+/// The IPv4 addresses of `ipv4only.arpa` (RFC 7050, Section 2.2).
+const IPV4ONLY_ADDRS: [Ipv4Addr; 2] =
+    [Ipv4Addr::new(192, 0, 0, 170), Ipv4Addr::new(192, 0, 0, 171)];
+
+/// Returns the NAT64 prefix of `ipv6`, if it embeds one of the IPv4 addresses of `ipv4only.arpa` at any of the
+/// supported prefix lengths.
+fn extract_prefix(ipv6: Ipv6Addr) -> Option<Ipv6Net> {
+    PREFIX_LENS.iter().find_map(|&len| {
+        let prefix = Ipv6Net::new(ipv6, len).ok()?.trunc();
+        IPV4ONLY_ADDRS
+            .iter()
+            .any(|&ipv4| embed(&prefix, ipv4) == ipv6)
+            .then_some(prefix)
+    })
+}
+
+/// Discovers the NAT64 prefix by resolving the AAAA records of `ipv4only.arpa` with `upstream`, which must be a
+/// DNS64 server (RFC 7050, Section 3). If the upstream uses several prefixes, the first one is returned.
+pub async fn discover_nat64_prefix(upstream: &dyn Client) -> Result<Ipv6Net> {
+    let mut query = Message::new();
+    query
+        .set_id(rand::random())
+        .set_recursion_desired(true)
+        .add_query(Query::query(
+            IPV4ONLY_ARPA.parse().unwrap(),
+            RecordType::AAAA,
+        ));
+    let response = upstream
+        .resolve(query)
+        .await
+        .map_err(|err| eyre!("cannot resolve {}: {}", IPV4ONLY_ARPA, err))?;
+    let mut prefixes = response
+        .answers()
+        .iter()
+        .filter_map(|record| match record.data() {
+            Some(RData::AAAA(aaaa)) => extract_prefix(aaaa.0),
+            _ => None,
+        });
+    let prefix = prefixes.next().ok_or_else(|| {
+        eyre!(
+            "upstream returned no AAAA records of {} with a NAT64 prefix (is it a DNS64 server?)",
+            IPV4ONLY_ARPA
+        )
+    })?;
+    for other in prefixes.filter(|other| *other != prefix) {
+        warn!(
+            "upstream uses several NAT64 prefixes, ignoring {} in favor of {}",
+            other, prefix
+        );
+    }
+    Ok(prefix)
+}
+
+/// Returns the A query to synthesize AAAA records from, if `query` is a AAAA query and `response` indicates that
+/// the name exists but has no AAAA records.
+pub fn a_query(query: &[u8], response: &[u8]) -> Option<BytesMut> {
+    let mut query = Message::from_vec(query).ok()?;
+    let [question] = query.queries() else {
+        return None;
+    };
+    if question.query_type() != RecordType::AAAA || question.query_class() != DNSClass::IN {
+        return None;
+    }
+    let response = Message::from_vec(response).ok()?;
+    if response.response_code() != ResponseCode::NoError
+        || response
+            .answers()
+            .iter()
+            .any(|record| record.record_type() == RecordType::AAAA)
+    {
+        return None;
+    }
+
+    let mut question = question.clone();
+    question.set_query_type(RecordType::A);
+    query.take_queries();
+    query.add_query(question);
+    Some(query.to_vec().ok()?.as_slice().into())
+}
+
+/// Synthesizes the response to the AAAA `query` from the response to the corresponding A query, replacing its A
+/// records by AAAA records in `prefix`. Returns `None` if there are no A records to synthesize from.
+pub fn synthesize(query: &[u8], a_response: &[u8], prefix: &Ipv6Net) -> Option<BytesMut> {
+    let query = Message::from_vec(query).ok()?;
+    let mut response = Message::from_vec(a_response).ok()?;
+    if response.response_code() != ResponseCode::NoError {
+        return None;
+    }
+
+    let mut synthesized = false;
+    let answers = response
+        .take_answers()
+        .into_iter()
+        .map(|record| match record.data() {
+            Some(RData::A(a)) => {
+                synthesized = true;
+                let rdata = RData::AAAA(AAAA::from(embed(prefix, a.0)));
+                let mut aaaa = Record::from_rdata(record.name().clone(), record.ttl(), rdata);
+                aaaa.set_dns_class(record.dns_class());
+                aaaa
+            }
+            _ => record,
+        })
+        .collect::<Vec<_>>();
+    if !synthesized {
+        return None;
+    }
+
+    response.insert_answers(answers);
+    response.take_queries();
+    response.add_queries(query.queries().to_vec());
+    response.set_id(query.id());
+    Some(response.to_vec().ok()?.as_slice().into())
+}
+
+#[cfg(test)]
+mod tests {
+    use hickory_proto::{
+        op::{MessageType, Query},
+        rr::{
+            rdata::{A, CNAME},
+            Name,
+        },
+    };
+
+    use super::*;
+
+    #[test]
+    fn embed_addresses() {
+        // Examples from RFC 6052, Section 2.4.
+        let ipv4 = Ipv4Addr::new(192, 0, 2, 33);
+        let cases = [
+            ("2001:db8::/32", "2001:db8:c000:221::"),
+            ("2001:db8:100::/40", "2001:db8:1c0:2:21::"),
+            ("2001:db8:122::/48", "2001:db8:122:c000:2:2100::"),
+            ("2001:db8:122:300::/56", "2001:db8:122:3c0:0:221::"),
+            ("2001:db8:122:344::/64", "2001:db8:122:344:c0:2:2100:0"),
+            ("2001:db8:122:344::/96", "2001:db8:122:344::192.0.2.33"),
+            ("64:ff9b::/96", "64:ff9b::192.0.2.33"),
+        ];
+        for (prefix, expected) in cases {
+            let prefix = prefix.parse().unwrap();
+            assert_eq!(embed(&prefix, ipv4), expected.parse::<Ipv6Addr>().unwrap());
+        }
+    }
+
+    fn build_query(query_type: RecordType) -> Vec<u8> {
+        let mut query = Message::new();
+        query.set_id(0x1234).add_query(Query::query(
+            "www.example.com.".parse().unwrap(),
+            query_type,
+        ));
+        query.to_vec().unwrap()
+    }
+
+    fn build_response(query: &[u8], answers: Vec<Record>) -> Vec<u8> {
+        let mut response = Message::from_vec(query).unwrap();
+        response
+            .set_message_type(MessageType::Response)
+            .insert_answers(answers);
+        response.to_vec().unwrap()
+    }
+
+    #[test]
+    fn extract_prefixes() {
+        for (ipv6, prefix) in [
+            ("64:ff9b::192.0.0.170", "64:ff9b::/96"),
+            ("64:ff9b::192.0.0.171", "64:ff9b::/96"),
+            ("2001:db8:c000:aa::", "2001:db8::/32"),
+            ("2001:db8:122:344:c0:0:aa00:0", "2001:db8:122:344::/64"),
+        ] {
+            assert_eq!(
+                extract_prefix(ipv6.parse().unwrap()),
+                Some(prefix.parse().unwrap())
+            );
+        }
+        assert_eq!(extract_prefix("64:ff9b::192.0.2.1".parse().unwrap()), None);
+        assert_eq!(extract_prefix("2001:db8::1".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn synthesize_from_a_records() {
+        let query = build_query(RecordType::AAAA);
+        let response = build_response(&query, Vec::new());
+        let a_query = a_query(&query, &response).unwrap();
+        let a_query = Message::from_vec(&a_query).unwrap();
+        assert_eq!(a_query.id(), 0x1234);
+        assert_eq!(a_query.queries()[0].query_type(), RecordType::A);
+
+        let name = "www.example.com.".parse::<Name>().unwrap();
+        let alias = "alias.example.com.".parse::<Name>().unwrap();
+        let a_response = build_response(
+            &a_query.to_vec().unwrap(),
+            vec![
+                Record::from_rdata(name, 600, RData::CNAME(CNAME(alias.clone()))),
+                Record::from_rdata(alias, 300, RData::A(A::new(192, 0, 2, 1))),
+            ],
+        );
+        let prefix = "64:ff9b::/96".parse().unwrap();
+        let response = synthesize(&query, &a_response, &prefix).unwrap();
+        let response = Message::from_vec(&response).unwrap();
+        assert_eq!(response.id(), 0x1234);
+        assert_eq!(response.queries()[0].query_type(), RecordType::AAAA);
+        assert_eq!(response.answer_count(), 2);
+        assert_eq!(response.answers()[0].record_type(), RecordType::CNAME);
+        assert_eq!(response.answers()[1].ttl(), 300);
+        assert_eq!(
+            response.answers()[1].data(),
+            Some(&RData::AAAA(AAAA::new(
+                0x64, 0xff9b, 0, 0, 0, 0, 0xc000, 0x201
+            )))
+        );
+    }
+
+    #[test]
+    fn only_synthesize_without_aaaa_records() {
+        let query = build_query(RecordType::AAAA);
+        let response = build_response(
+            &query,
+            vec![Record::from_rdata(
+                "www.example.com.".parse().unwrap(),
+                300,
+                RData::AAAA(AAAA::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)),
+            )],
+        );
+        assert!(a_query(&query, &response).is_none());
+
+        let mut nxdomain = Message::from_vec(&build_response(&query, Vec::new())).unwrap();
+        nxdomain.set_response_code(ResponseCode::NXDomain);
+        assert!(a_query(&query, &nxdomain.to_vec().unwrap()).is_none());
+
+        let query = build_query(RecordType::A);
+        assert!(a_query(&query, &build_response(&query, Vec::new())).is_none());
+
+        // Without A records, there is nothing to synthesize from.
+        let query = build_query(RecordType::AAAA);
+        let a_response = build_response(&build_query(RecordType::A), Vec::new());
+        let prefix = "64:ff9b::/96".parse().unwrap();
+        assert!(synthesize(&query, &a_response, &prefix).is_none());
+    }
+}
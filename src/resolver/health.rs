@@ -0,0 +1,192 @@
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Max Wipfli <mail@maxwipfli.ch>
+
+//! HTTP endpoint for liveness and readiness probes, e.g. of container orchestrators like Kubernetes.
+//!
+//! `GET /healthz` answers 200 while the process is running. `GET /readyz` answers 200 if the resolver is ready to
+//! answer queries, i.e. its sockets are bound and the upstream is healthy, and 503 otherwise.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use eyre::{eyre, Result};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tracing::debug;
+
+/// Number of consecutive failed upstream requests after which the upstream is considered unhealthy.
+const FAILURE_THRESHOLD: u32 = 3;
+
+/// Maximum length of the request line and headers of probe requests.
+const MAX_REQUEST_LEN: usize = 4096;
+
+/// Time after which connections of probes that haven't sent a complete request are closed.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Health of the resolver, as reported to readiness probes.
+///
+/// The upstream is considered unhealthy after `FAILURE_THRESHOLD` consecutive failed requests, and healthy again
+/// after the next successful one.
+#[derive(Debug, Default)]
+pub struct Health {
+    sockets_bound: AtomicBool,
+    upstream_failures: AtomicU32,
+}
+
+impl Health {
+    /// Records that the sockets of the resolver are bound.
+    pub fn record_sockets_bound(&self) {
+        self.sockets_bound.store(true, Ordering::Relaxed);
+    }
+
+    /// Records the outcome of an upstream request.
+    pub fn record_upstream(&self, success: bool) {
+        if success {
+            self.upstream_failures.store(0, Ordering::Relaxed);
+        } else {
+            self.upstream_failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns whether the resolver is ready to answer queries.
+    pub fn is_ready(&self) -> bool {
+        self.sockets_bound.load(Ordering::Relaxed)
+            && self.upstream_failures.load(Ordering::Relaxed) < FAILURE_THRESHOLD
+    }
+}
+
+/// Task that answers liveness and readiness probes on `listener`.
+pub async fn serve(listener: TcpListener, health: Arc<Health>) -> Result<()> {
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let health = health.clone();
+        tokio::spawn(async move {
+            if let Err(err) = handle_probe(stream, &health).await {
+                debug!(
+                    "health endpoint: cannot answer probe from {}: {}",
+                    peer, err
+                );
+            }
+        });
+    }
+}
+
+async fn handle_probe(mut stream: TcpStream, health: &Health) -> Result<()> {
+    let mut request = Vec::new();
+    let read = async {
+        let mut buffer = [0; 1024];
+        while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+            let n = stream.read(&mut buffer).await?;
+            if n == 0 || request.len() + n > MAX_REQUEST_LEN {
+                return Err(eyre!("incomplete or too long request"));
+            }
+            request.extend_from_slice(&buffer[..n]);
+        }
+        Ok(())
+    };
+    tokio::time::timeout(REQUEST_TIMEOUT, read)
+        .await
+        .map_err(|_| eyre!("timeout reading request"))??;
+
+    let request_line = request.split(|&b| b == b'\r').next().unwrap_or_default();
+    let mut fields = request_line.split(|&b| b == b' ');
+    let (method, path) = (fields.next(), fields.next());
+    let (status, body) = match (method, path) {
+        (Some(b"GET" | b"HEAD"), Some(b"/healthz")) => ("200 OK", "ok\n"),
+        (Some(b"GET" | b"HEAD"), Some(b"/readyz")) if health.is_ready() => ("200 OK", "ready\n"),
+        (Some(b"GET" | b"HEAD"), Some(b"/readyz")) => ("503 Service Unavailable", "not ready\n"),
+        (Some(b"GET" | b"HEAD"), _) => ("404 Not Found", "not found\n"),
+        _ => ("405 Method Not Allowed", "method not allowed\n"),
+    };
+    let mut response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        body.len()
+    );
+    if method != Some(b"HEAD") {
+        response.push_str(body);
+    }
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use super::*;
+
+    async fn spawn_endpoint(health: Arc<Health>) -> SocketAddr {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, health));
+        addr
+    }
+
+    /// Sends a request for `path` and returns the status line and body of the response.
+    async fn get(addr: SocketAddr, method: &str, path: &str) -> (String, String) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!("{} {} HTTP/1.1\r\nHost: localhost\r\n\r\n", method, path);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head.lines().next().unwrap().to_string();
+        (status, body.to_string())
+    }
+
+    #[tokio::test]
+    async fn readiness() {
+        let health = Arc::new(Health::default());
+        let addr = spawn_endpoint(health.clone()).await;
+
+        // The process is alive, but not ready until the sockets are bound.
+        assert_eq!(get(addr, "GET", "/healthz").await.0, "HTTP/1.1 200 OK");
+        assert_eq!(
+            get(addr, "GET", "/readyz").await.0,
+            "HTTP/1.1 503 Service Unavailable"
+        );
+        health.record_sockets_bound();
+        assert_eq!(
+            get(addr, "GET", "/readyz").await,
+            ("HTTP/1.1 200 OK".to_string(), "ready\n".to_string())
+        );
+
+        // The upstream becomes unhealthy after consecutive failures, and healthy again after a success.
+        for _ in 0..FAILURE_THRESHOLD {
+            health.record_upstream(false);
+        }
+        assert_eq!(
+            get(addr, "GET", "/readyz").await.0,
+            "HTTP/1.1 503 Service Unavailable"
+        );
+        assert_eq!(get(addr, "GET", "/healthz").await.0, "HTTP/1.1 200 OK");
+        health.record_upstream(true);
+        assert_eq!(get(addr, "GET", "/readyz").await.0, "HTTP/1.1 200 OK");
+    }
+
+    #[tokio::test]
+    async fn other_requests() {
+        let addr = spawn_endpoint(Arc::default()).await;
+        assert_eq!(
+            get(addr, "HEAD", "/healthz").await,
+            ("HTTP/1.1 200 OK".to_string(), String::new())
+        );
+        assert_eq!(
+            get(addr, "GET", "/metrics").await.0,
+            "HTTP/1.1 404 Not Found"
+        );
+        assert_eq!(
+            get(addr, "POST", "/healthz").await.0,
+            "HTTP/1.1 405 Method Not Allowed"
+        );
+    }
+}
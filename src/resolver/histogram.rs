@@ -0,0 +1,115 @@
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Max Wipfli <mail@maxwipfli.ch>
+
+use std::time::Duration;
+
+/// Upper bound of the first bucket, in microseconds.
+const MIN_BUCKET_US: f64 = 64.0;
+
+/// Number of buckets per doubling of latency, i.e. each bucket is about 19% wider than the previous one.
+const BUCKETS_PER_DOUBLING: f64 = 4.0;
+
+/// Number of buckets, with the last one (around 67 s) catching all higher latencies.
+const BUCKETS: usize = 81;
+
+/// Histogram of latencies, with exponentially growing buckets.
+///
+/// Percentiles are reported as the upper bound of the bucket they fall in, so they overestimate the actual latency
+/// by up to one bucket width (about 19%).
+#[derive(Debug, Clone)]
+pub struct LatencyHistogram {
+    buckets: [u64; BUCKETS],
+    count: u64,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self {
+            buckets: [0; BUCKETS],
+            count: 0,
+        }
+    }
+}
+
+impl LatencyHistogram {
+    pub fn record(&mut self, latency: Duration) {
+        let latency_us = latency.as_micros() as f64;
+        let index = if latency_us <= MIN_BUCKET_US {
+            0
+        } else {
+            ((latency_us / MIN_BUCKET_US).log2() * BUCKETS_PER_DOUBLING).ceil() as usize
+        };
+        self.buckets[index.min(BUCKETS - 1)] += 1;
+        self.count += 1;
+    }
+
+    /// Returns the number of recorded latencies.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Returns the latency below which the fraction `quantile` (between 0 and 1) of recorded latencies fall, or
+    /// `None` if no latencies were recorded.
+    pub fn percentile(&self, quantile: f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((quantile * self.count as f64).ceil() as u64).clamp(1, self.count);
+        let mut seen = 0;
+        let index = self
+            .buckets
+            .iter()
+            .position(|&n| {
+                seen += n;
+                seen >= rank
+            })
+            .unwrap_or(BUCKETS - 1);
+        let upper_bound_us = MIN_BUCKET_US * 2f64.powf(index as f64 / BUCKETS_PER_DOUBLING);
+        Some(Duration::from_micros(upper_bound_us as u64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_between(latency: Option<Duration>, min_ms: u64, max_ms: u64) {
+        let latency = latency.unwrap();
+        assert!(
+            latency >= Duration::from_millis(min_ms) && latency <= Duration::from_millis(max_ms),
+            "{:?} not within [{}, {}] ms",
+            latency,
+            min_ms,
+            max_ms
+        );
+    }
+
+    #[test]
+    fn percentiles() {
+        let mut histogram = LatencyHistogram::default();
+        assert_eq!(histogram.percentile(0.5), None);
+
+        for _ in 0..90 {
+            histogram.record(Duration::from_millis(10));
+        }
+        for _ in 0..9 {
+            histogram.record(Duration::from_millis(100));
+        }
+        histogram.record(Duration::from_secs(1000));
+
+        assert_eq!(histogram.count(), 100);
+        assert_between(histogram.percentile(0.5), 10, 12);
+        assert_between(histogram.percentile(0.9), 10, 12);
+        assert_between(histogram.percentile(0.99), 100, 120);
+        // Latencies beyond the last bucket are capped.
+        assert_between(histogram.percentile(1.0), 60_000, 70_000);
+        assert_between(histogram.percentile(0.0), 10, 12);
+    }
+
+    #[test]
+    fn small_latencies() {
+        let mut histogram = LatencyHistogram::default();
+        histogram.record(Duration::ZERO);
+        assert_eq!(histogram.percentile(0.5), Some(Duration::from_micros(64)));
+    }
+}
@@ -0,0 +1,118 @@
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Max Wipfli <mail@maxwipfli.ch>
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::time::{sleep, Instant};
+use tracing::warn;
+
+/// Tracker of in-flight requests, warning if a request has been pending for an unusually long time.
+///
+/// This may indicate a stuck upstream or a backpressure problem.
+pub struct InflightTracker {
+    threshold: Duration,
+    /// Start time of in-flight requests, by request ID.
+    requests: Mutex<BTreeMap<u64, Instant>>,
+    warnings: AtomicU64,
+}
+
+/// Guard removing a request from the in-flight requests when it is dropped.
+pub struct InflightGuard<'a> {
+    tracker: &'a InflightTracker,
+    id: u64,
+}
+
+impl Drop for InflightGuard<'_> {
+    fn drop(&mut self) {
+        self.tracker.requests.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl InflightTracker {
+    pub fn new(threshold: Duration) -> Self {
+        Self {
+            threshold,
+            requests: Mutex::default(),
+            warnings: AtomicU64::new(0),
+        }
+    }
+
+    /// Records that the request with the given ID is in flight until the returned guard is dropped.
+    pub fn track(&self, id: u64) -> InflightGuard<'_> {
+        self.requests.lock().unwrap().insert(id, Instant::now());
+        InflightGuard { tracker: self, id }
+    }
+
+    /// Returns the ID and age of the oldest in-flight request.
+    pub fn oldest(&self) -> Option<(u64, Duration)> {
+        let requests = self.requests.lock().unwrap();
+        // Request IDs are assigned in order, so the request with the lowest ID is the oldest.
+        requests
+            .first_key_value()
+            .map(|(id, stamp)| (*id, stamp.elapsed()))
+    }
+
+    /// Returns how many times the tracker has warned about stuck requests.
+    #[cfg(test)]
+    pub fn warnings(&self) -> u64 {
+        self.warnings.load(Ordering::Relaxed)
+    }
+
+    /// Task that periodically checks the age of the oldest in-flight request. Warns once per stuck request.
+    pub async fn run(self: Arc<Self>) {
+        let mut warned_for = None;
+        loop {
+            sleep(self.threshold / 2).await;
+            let Some((id, age)) = self.oldest() else {
+                continue;
+            };
+            if age > self.threshold && warned_for != Some(id) {
+                warn!(
+                    "request #{}: pending for {} s, the upstream may be stuck",
+                    id,
+                    age.as_secs()
+                );
+                self.warnings.fetch_add(1, Ordering::Relaxed);
+                warned_for = Some(id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn warn_on_stuck_request() {
+        let tracker = Arc::new(InflightTracker::new(Duration::from_secs(10)));
+        tokio::spawn(tracker.clone().run());
+        assert_eq!(tracker.oldest(), None);
+
+        let stuck = tracker.track(0);
+        sleep(Duration::from_secs(5)).await;
+        let other = tracker.track(1);
+        assert_eq!(tracker.oldest(), Some((0, Duration::from_secs(5))));
+        drop(other);
+        assert_eq!(tracker.warnings(), 0);
+
+        sleep(Duration::from_secs(12)).await;
+        assert_eq!(tracker.warnings(), 1);
+        // Only warns once per stuck request.
+        sleep(Duration::from_secs(60)).await;
+        assert_eq!(tracker.warnings(), 1);
+        assert_eq!(tracker.oldest(), Some((0, Duration::from_secs(77))));
+
+        drop(stuck);
+        assert_eq!(tracker.oldest(), None);
+        sleep(Duration::from_secs(60)).await;
+        assert_eq!(tracker.warnings(), 1);
+    }
+}
@@ -0,0 +1,314 @@
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Max Wipfli <mail@maxwipfli.ch>
+
+//! Authoritative answers from a local zone file (e.g. for a home network), so that the resolver can serve a small
+//! zone itself while forwarding all other queries.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::Path,
+};
+
+use bytes::BytesMut;
+use eyre::{eyre, Result};
+use hickory_proto::{
+    op::{Message, MessageType, ResponseCode},
+    rr::{DNSClass, Name, RData, Record, RecordType},
+    serialize::txt::Parser,
+};
+
+/// Maximum number of CNAME records followed within the zone when answering a query.
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// Records of a zone loaded from a zone file, answered authoritatively.
+#[derive(Debug, Clone)]
+pub struct LocalZone {
+    /// Name of the zone, i.e. the owner of its SOA record.
+    apex: Name,
+    soa: Record,
+    /// Records by (lowercase) owner name and type.
+    records: HashMap<(Name, RecordType), Vec<Record>>,
+    /// Names existing in the zone, i.e. owner names and their ancestors up to the apex (empty non-terminals).
+    names: HashSet<Name>,
+}
+
+impl LocalZone {
+    /// Loads the zone from a zone file in the format of RFC 1035 (Section 5), which must contain exactly one SOA
+    /// record at the apex of the zone.
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .map_err(|err| eyre!("cannot read local zone '{}': {}", path.display(), err))?;
+        Self::parse(&contents, path)
+            .map_err(|err| eyre!("invalid local zone '{}': {}", path.display(), err))
+    }
+
+    fn parse(contents: &str, path: &Path) -> Result<Self> {
+        let (_, record_sets) = Parser::new(contents, Some(path.to_path_buf()), None).parse()?;
+        let records = record_sets
+            .values()
+            .flat_map(|record_set| record_set.records_without_rrsigs().cloned())
+            .collect::<Vec<_>>();
+
+        let mut soas = records
+            .iter()
+            .filter(|record| record.record_type() == RecordType::SOA);
+        let (Some(soa), None) = (soas.next(), soas.next()) else {
+            return Err(eyre!("zone must contain exactly one SOA record"));
+        };
+        let soa = soa.clone();
+        let apex = soa.name().to_lowercase();
+
+        let mut zone = Self {
+            apex,
+            soa,
+            records: HashMap::new(),
+            names: HashSet::new(),
+        };
+        for record in records {
+            let mut name = record.name().to_lowercase();
+            if !zone.apex.zone_of(&name) {
+                return Err(eyre!(
+                    "record for {} is outside of the zone {}",
+                    name,
+                    zone.apex
+                ));
+            }
+            zone.records
+                .entry((name.clone(), record.record_type()))
+                .or_default()
+                .push(record);
+            while zone.names.insert(name.clone()) && name != zone.apex {
+                name = name.base_name();
+            }
+        }
+        Ok(zone)
+    }
+
+    /// Returns the records of `name` with the given type.
+    fn lookup(&self, name: &Name, record_type: RecordType) -> &[Record] {
+        self.records
+            .get(&(name.to_lowercase(), record_type))
+            .map_or(&[], Vec::as_slice)
+    }
+
+    /// Returns all records of `name`, as answered to ANY queries.
+    fn lookup_any(&self, name: &Name) -> Vec<Record> {
+        let name = name.to_lowercase();
+        self.records
+            .iter()
+            .filter(|((owner, _), _)| *owner == name)
+            .flat_map(|(_, records)| records.iter().cloned())
+            .collect()
+    }
+
+    /// Returns the SOA record for the authority section of negative answers, with the TTL lowered to the minimum
+    /// field of the SOA record (RFC 2308, Section 3).
+    fn negative_soa(&self) -> Record {
+        let mut soa = self.soa.clone();
+        if let Some(RData::SOA(rdata)) = self.soa.data() {
+            soa.set_ttl(self.soa.ttl().min(rdata.minimum()));
+        }
+        soa
+    }
+}
+
+/// Answers queries for names within the local zone authoritatively, following CNAME records within the zone.
+/// Names or types that don't exist in the zone are answered with NXDOMAIN or an empty response (NODATA), and CNAME
+/// loops within the zone with SERVFAIL.
+/// Returns `None` if the query is not for a name within the zone, so that it is forwarded.
+pub fn answer(query: &Message, zone: &LocalZone) -> Option<Result<BytesMut>> {
+    let [question] = query.queries() else {
+        return None;
+    };
+    if question.query_class() != DNSClass::IN || !zone.apex.zone_of(question.name()) {
+        return None;
+    }
+
+    let mut response = Message::new();
+    response
+        .set_id(query.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(query.op_code())
+        .set_recursion_desired(query.recursion_desired())
+        .set_checking_disabled(query.checking_disabled())
+        .set_recursion_available(true)
+        .set_authoritative(true)
+        .add_query(question.clone());
+
+    let query_type = question.query_type();
+    let mut name = question.name().clone();
+    let mut visited = HashSet::new();
+    for _ in 0..MAX_CNAME_CHAIN {
+        if !visited.insert(name.to_lowercase()) {
+            // The CNAME chain loops, so it never leads to an answer.
+            response.set_response_code(ResponseCode::ServFail);
+            break;
+        }
+        let answers = match query_type {
+            RecordType::ANY => zone.lookup_any(&name),
+            _ => zone.lookup(&name, query_type).to_vec(),
+        };
+        if !answers.is_empty() {
+            response.add_answers(answers);
+            break;
+        }
+        let [cname] = zone.lookup(&name, RecordType::CNAME) else {
+            // The response code refers to the last name of a CNAME chain (RFC 6604).
+            if !zone.names.contains(&name.to_lowercase()) {
+                response.set_response_code(ResponseCode::NXDomain);
+            }
+            response.add_name_server(zone.negative_soa());
+            break;
+        };
+        response.add_answer(cname.clone());
+        match cname.data() {
+            // Targets outside of the zone are left to the client to resolve.
+            Some(RData::CNAME(target)) if zone.apex.zone_of(target) => name = target.0.clone(),
+            _ => break,
+        }
+    }
+    Some(
+        response
+            .to_vec()
+            .map(|response| response.as_slice().into())
+            .map_err(Into::into),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use hickory_proto::{
+        op::Query,
+        rr::rdata::{A, CNAME, MX},
+    };
+
+    use super::*;
+
+    const ZONE: &str = "\
+$ORIGIN home.arpa.
+$TTL 3600
+@       IN SOA  ns.home.arpa. admin.home.arpa. 1 7200 3600 1209600 300
+        IN NS   ns
+        IN MX   10 mail
+ns      IN A    192.168.1.1
+mail    IN A    192.168.1.2
+nas.lab IN A    192.168.1.10
+        IN TXT  \"storage\"
+files   IN CNAME nas.lab
+www     IN CNAME www.example.com.
+loop1   IN CNAME loop2
+loop2   IN CNAME LOOP1
+";
+
+    fn zone() -> LocalZone {
+        LocalZone::parse(ZONE, Path::new("home.arpa.zone")).unwrap()
+    }
+
+    fn resolve(name: &str, query_type: RecordType) -> Option<Message> {
+        let mut query = Message::new();
+        query
+            .set_id(42)
+            .add_query(Query::query(name.parse().unwrap(), query_type));
+        let response = answer(&query, &zone())?.unwrap();
+        let response = Message::from_vec(&response).unwrap();
+        assert_eq!(response.id(), 42);
+        assert!(response.authoritative());
+        Some(response)
+    }
+
+    fn answers(response: &Message) -> Vec<RData> {
+        response
+            .answers()
+            .iter()
+            .filter_map(|record| record.data().cloned())
+            .collect()
+    }
+
+    #[test]
+    fn answer_records() {
+        let response = resolve("NAS.lab.home.arpa.", RecordType::A).unwrap();
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert_eq!(
+            answers(&response),
+            [RData::A(A(Ipv4Addr::new(192, 168, 1, 10)))]
+        );
+        assert_eq!(response.answers()[0].ttl(), 3600);
+
+        let response = resolve("home.arpa.", RecordType::MX).unwrap();
+        assert_eq!(
+            answers(&response),
+            [RData::MX(MX::new(10, "mail.home.arpa.".parse().unwrap()))]
+        );
+        let response = resolve("home.arpa.", RecordType::SOA).unwrap();
+        assert_eq!(response.answer_count(), 1);
+        let response = resolve("nas.lab.home.arpa.", RecordType::ANY).unwrap();
+        assert_eq!(response.answer_count(), 2);
+    }
+
+    #[test]
+    fn follow_cnames() {
+        let response = resolve("files.home.arpa.", RecordType::A).unwrap();
+        let answers = answers(&response);
+        assert_eq!(answers.len(), 2);
+        assert_eq!(
+            answers[0],
+            RData::CNAME(CNAME("nas.lab.home.arpa.".parse().unwrap()))
+        );
+        assert_eq!(answers[1], RData::A(A(Ipv4Addr::new(192, 168, 1, 10))));
+
+        // Targets outside of the zone are not resolved.
+        let response = resolve("www.home.arpa.", RecordType::A).unwrap();
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert_eq!(response.answer_count(), 1);
+        assert_eq!(response.name_server_count(), 0);
+    }
+
+    #[test]
+    fn stop_at_cname_loops() {
+        let response = resolve("loop1.home.arpa.", RecordType::A).unwrap();
+        assert_eq!(response.response_code(), ResponseCode::ServFail);
+        // Each CNAME record is only included once.
+        assert_eq!(response.answer_count(), 2);
+    }
+
+    #[test]
+    fn negative_answers() {
+        // NODATA, also for empty non-terminals.
+        for (name, query_type) in [
+            ("nas.lab.home.arpa.", RecordType::AAAA),
+            ("lab.home.arpa.", RecordType::A),
+        ] {
+            let response = resolve(name, query_type).unwrap();
+            assert_eq!(response.response_code(), ResponseCode::NoError, "{}", name);
+            assert_eq!(response.answer_count(), 0);
+            assert_eq!(response.name_servers()[0].record_type(), RecordType::SOA);
+            // The TTL is the minimum field of the SOA record.
+            assert_eq!(response.name_servers()[0].ttl(), 300);
+        }
+
+        let response = resolve("unknown.home.arpa.", RecordType::A).unwrap();
+        assert_eq!(response.response_code(), ResponseCode::NXDomain);
+        assert_eq!(response.name_servers()[0].record_type(), RecordType::SOA);
+    }
+
+    #[test]
+    fn ignore_other_names() {
+        assert!(resolve("www.example.com.", RecordType::A).is_none());
+        assert!(resolve("arpa.", RecordType::NS).is_none());
+    }
+
+    #[test]
+    fn reject_invalid_zones() {
+        let path = Path::new("invalid.zone");
+        // no SOA record
+        assert!(LocalZone::parse("www.home.arpa. 300 IN A 192.0.2.1\n", path).is_err());
+        // record outside of the zone
+        let zone = format!("{}\nwww.example.com. 300 IN A 192.0.2.1\n", ZONE);
+        assert!(LocalZone::parse(&zone, path).is_err());
+        assert!(LocalZone::parse("home.arpa. IN BOGUS\n", path).is_err());
+        assert!(LocalZone::load(Path::new("/nonexistent/home.arpa.zone")).is_err());
+    }
+}
@@ -2,4 +2,22 @@
 // SPDX-FileCopyrightText: 2025 Max Wipfli <mail@maxwipfli.ch>
 
 mod resolver;
-pub use resolver::run;
+pub use chaos::ServerIdentity;
+pub use dns64::discover_nat64_prefix;
+pub use resolver::{check_config, run, EcsPolicy, ResolverConfig};
+pub use stats::{Stats, StatsSnapshot};
+
+mod chaos;
+mod coalesce;
+mod dns64;
+mod health;
+mod histogram;
+mod inflight;
+mod local_zone;
+mod pcap;
+mod privacy;
+mod reverse;
+mod single_request;
+mod stats;
+mod trace;
+mod watchdog;
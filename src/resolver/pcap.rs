@@ -0,0 +1,242 @@
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Max Wipfli <mail@maxwipfli.ch>
+
+//! Capture of DNS messages to a file in pcap format, which can be inspected with e.g. Wireshark.
+//!
+//! Messages are written as synthetic UDP datagrams with raw IPv4 or IPv6 headers (link type `LINKTYPE_RAW`).
+
+use std::{
+    fs::File,
+    io::Write,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use eyre::{eyre, Result};
+
+const MAGIC: u32 = 0xa1b2c3d4;
+const VERSION_MAJOR: u16 = 2;
+const VERSION_MINOR: u16 = 4;
+const SNAPLEN: u32 = 65535;
+const LINKTYPE_RAW: u32 = 101;
+
+const IPV4_HEADER_LEN: usize = 20;
+const IPV6_HEADER_LEN: usize = 40;
+const UDP_HEADER_LEN: usize = 8;
+const IPPROTO_UDP: u8 = 17;
+const TTL: u8 = 64;
+
+/// Writer of DNS messages to a pcap file.
+pub struct PcapWriter {
+    file: Mutex<File>,
+}
+
+impl PcapWriter {
+    /// Creates the pcap file at `path` (truncating an existing file) and writes the global header.
+    pub fn create(path: &Path) -> Result<Self> {
+        let mut file = File::create(path)
+            .map_err(|err| eyre!("cannot create pcap file '{}': {}", path.display(), err))?;
+        let mut header = Vec::with_capacity(24);
+        header.extend_from_slice(&MAGIC.to_le_bytes());
+        header.extend_from_slice(&VERSION_MAJOR.to_le_bytes());
+        header.extend_from_slice(&VERSION_MINOR.to_le_bytes());
+        // Time zone offset and timestamp accuracy, which are always zero.
+        header.extend_from_slice(&[0; 8]);
+        header.extend_from_slice(&SNAPLEN.to_le_bytes());
+        header.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        file.write_all(&header)?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Writes `message` as a UDP datagram sent from `src` to `dst`, timestamped with the current time.
+    pub fn write(&self, src: SocketAddr, dst: SocketAddr, message: &[u8]) -> Result<()> {
+        let packet = udp_packet(src, dst, message)?;
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?;
+
+        let mut record = Vec::with_capacity(16 + packet.len());
+        record.extend_from_slice(&(timestamp.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&timestamp.subsec_micros().to_le_bytes());
+        // Captured and original length, as packets are never truncated.
+        let len = packet.len() as u32;
+        record.extend_from_slice(&len.to_le_bytes());
+        record.extend_from_slice(&len.to_le_bytes());
+        record.extend_from_slice(&packet);
+        // Write the record at once, so that records of concurrent requests aren't interleaved.
+        self.file.lock().unwrap().write_all(&record)?;
+        Ok(())
+    }
+}
+
+/// Builds an IP packet containing a UDP datagram with the given payload.
+/// Addresses of different families are written as IPv6 addresses (mapping the IPv4 address).
+fn udp_packet(src: SocketAddr, dst: SocketAddr, payload: &[u8]) -> Result<Vec<u8>> {
+    let udp_len = UDP_HEADER_LEN + payload.len();
+    let udp_len = u16::try_from(udp_len).map_err(|_| eyre!("message is too long to capture"))?;
+    let mut udp = Vec::with_capacity(udp_len.into());
+    udp.extend_from_slice(&src.port().to_be_bytes());
+    udp.extend_from_slice(&dst.port().to_be_bytes());
+    udp.extend_from_slice(&udp_len.to_be_bytes());
+    udp.extend_from_slice(&[0; 2]);
+    udp.extend_from_slice(payload);
+
+    let mut packet = match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+            let mut header = Vec::with_capacity(IPV4_HEADER_LEN);
+            header.push(0x45); // version 4, header length of 5 words
+            header.push(0);
+            let total_len = u16::try_from(IPV4_HEADER_LEN + udp.len())
+                .map_err(|_| eyre!("message is too long to capture"))?;
+            header.extend_from_slice(&total_len.to_be_bytes());
+            // Identification, flags, and fragment offset.
+            header.extend_from_slice(&[0; 4]);
+            header.extend_from_slice(&[TTL, IPPROTO_UDP, 0, 0]);
+            header.extend_from_slice(&src_ip.octets());
+            header.extend_from_slice(&dst_ip.octets());
+            let checksum = checksum(&[&header]);
+            header[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+            let pseudo_header = [
+                &src_ip.octets()[..],
+                &dst_ip.octets(),
+                &[0, IPPROTO_UDP],
+                &udp_len.to_be_bytes(),
+            ]
+            .concat();
+            set_udp_checksum(&mut udp, &pseudo_header);
+            header
+        }
+        (src_ip, dst_ip) => {
+            let src_ip = to_ipv6(src_ip).octets();
+            let dst_ip = to_ipv6(dst_ip).octets();
+            let mut header = Vec::with_capacity(IPV6_HEADER_LEN);
+            // Version 6, traffic class, and flow label.
+            header.extend_from_slice(&[0x60, 0, 0, 0]);
+            header.extend_from_slice(&udp_len.to_be_bytes());
+            header.extend_from_slice(&[IPPROTO_UDP, TTL]);
+            header.extend_from_slice(&src_ip);
+            header.extend_from_slice(&dst_ip);
+
+            let pseudo_header = [
+                &src_ip[..],
+                &dst_ip,
+                &u32::from(udp_len).to_be_bytes(),
+                &[0, 0, 0, IPPROTO_UDP],
+            ]
+            .concat();
+            set_udp_checksum(&mut udp, &pseudo_header);
+            header
+        }
+    };
+    packet.extend_from_slice(&udp);
+    Ok(packet)
+}
+
+fn to_ipv6(ip: IpAddr) -> std::net::Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+fn set_udp_checksum(udp: &mut [u8], pseudo_header: &[u8]) {
+    let checksum = match checksum(&[pseudo_header, udp]) {
+        // A checksum of zero means no checksum, so it is transmitted as all ones (RFC 768).
+        0 => 0xffff,
+        checksum => checksum,
+    };
+    udp[6..8].copy_from_slice(&checksum.to_be_bytes());
+}
+
+/// Computes the Internet checksum (RFC 1071) of the concatenation of `parts`, all of which (but the last) must have
+/// even length.
+fn checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum = 0u32;
+    for part in parts {
+        for chunk in part.chunks(2) {
+            let word = match chunk {
+                [high, low] => u16::from_be_bytes([*high, *low]),
+                [high] => u16::from_be_bytes([*high, 0]),
+                _ => unreachable!(),
+            };
+            sum += u32::from(word);
+        }
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::{
+        env, fs,
+        net::{Ipv4Addr, Ipv6Addr},
+        process,
+    };
+
+    use super::*;
+
+    /// Parses a pcap file written by `PcapWriter`, returning the packets it contains.
+    pub fn read_packets(path: &Path) -> Vec<Vec<u8>> {
+        let data = fs::read(path).unwrap();
+        assert_eq!(data[..4], MAGIC.to_le_bytes());
+        assert_eq!(data[20..24], LINKTYPE_RAW.to_le_bytes());
+        let mut packets = Vec::new();
+        let mut rest = &data[24..];
+        while !rest.is_empty() {
+            let len = u32::from_le_bytes(rest[8..12].try_into().unwrap()) as usize;
+            packets.push(rest[16..16 + len].to_vec());
+            rest = &rest[16 + len..];
+        }
+        packets
+    }
+
+    #[test]
+    fn write_packets() {
+        let path = env::temp_dir().join(format!("aufloes-pcap-{}.pcap", process::id()));
+        let writer = PcapWriter::create(&path).unwrap();
+        let v4 = |port| SocketAddr::new([192, 0, 2, 1].into(), port);
+        let v6 = |port| SocketAddr::new(Ipv6Addr::LOCALHOST.into(), port);
+        writer.write(v4(5353), v4(53), b"query").unwrap();
+        writer.write(v6(53), v6(5353), b"response").unwrap();
+        writer.write(v4(53), v6(5353), b"mapped").unwrap();
+
+        let packets = read_packets(&path);
+        assert_eq!(packets.len(), 3);
+
+        let ipv4 = &packets[0];
+        assert_eq!(ipv4.len(), IPV4_HEADER_LEN + UDP_HEADER_LEN + 5);
+        assert_eq!(ipv4[0], 0x45);
+        assert_eq!(checksum(&[&ipv4[..IPV4_HEADER_LEN]]), 0);
+        assert_eq!(ipv4[IPV4_HEADER_LEN..][..4], [0x14, 0xe9, 0, 53]);
+        assert_eq!(&ipv4[IPV4_HEADER_LEN + UDP_HEADER_LEN..], b"query");
+
+        let ipv6 = &packets[1];
+        assert_eq!(ipv6[0] >> 4, 6);
+        assert_eq!(&ipv6[IPV6_HEADER_LEN + UDP_HEADER_LEN..], b"response");
+
+        let mapped = &packets[2];
+        assert_eq!(mapped[0] >> 4, 6);
+        assert_eq!(
+            mapped[8..24],
+            Ipv4Addr::new(192, 0, 2, 1).to_ipv6_mapped().octets()
+        );
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn udp_checksum() {
+        let src = SocketAddr::new([192, 0, 2, 1].into(), 5353);
+        let dst = SocketAddr::new([192, 0, 2, 2].into(), 53);
+        let packet = udp_packet(src, dst, b"odd").unwrap();
+        let udp = &packet[IPV4_HEADER_LEN..];
+        let pseudo_header = [&packet[12..20], &[0, IPPROTO_UDP], &udp[4..6]].concat();
+        // The checksum over the pseudo header and the datagram (including the checksum) is zero.
+        assert_eq!(checksum(&[&pseudo_header, udp]), 0);
+    }
+}
@@ -0,0 +1,90 @@
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Max Wipfli <mail@maxwipfli.ch>
+
+//! Redaction of query names and peer addresses in log messages, so that logs don't reveal which names clients look
+//! up.
+
+use std::{
+    hash::{BuildHasher, RandomState},
+    net::SocketAddr,
+};
+
+use hickory_proto::rr::Name;
+
+/// Formats query names and peer addresses for log messages, replacing them by salted hashes if enabled.
+///
+/// The salt is chosen randomly when the redactor is created, so that hashes cannot be reversed by hashing candidate
+/// names, but a name (or address) logged multiple times within a run always has the same hash, which allows
+/// correlating log messages while debugging.
+pub struct LogRedactor {
+    /// The salted hasher, or `None` if redaction is disabled.
+    hasher: Option<RandomState>,
+}
+
+impl LogRedactor {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            hasher: enabled.then(RandomState::new),
+        }
+    }
+
+    /// Formats a query name for log messages.
+    pub fn name(&self, name: &Name) -> String {
+        match &self.hasher {
+            // Names are case-insensitive, so they are hashed in lowercase.
+            Some(hasher) => redacted(hasher.hash_one(name.to_lowercase().to_ascii())),
+            None => name.to_string(),
+        }
+    }
+
+    /// Formats the address of a downstream peer for log messages. Only the IP address is hashed, as the port is
+    /// typically chosen randomly for each query.
+    pub fn peer(&self, peer: SocketAddr) -> String {
+        match &self.hasher {
+            Some(hasher) => redacted(hasher.hash_one(peer.ip())),
+            None => peer.to_string(),
+        }
+    }
+}
+
+fn redacted(hash: u64) -> String {
+    format!("<redacted {:016x}>", hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redact_names_and_peers() {
+        let redactor = LogRedactor::new(true);
+        let name = "www.example.com.".parse::<Name>().unwrap();
+        let redacted = redactor.name(&name);
+        assert!(redacted.starts_with("<redacted "), "{}", redacted);
+        assert!(!redacted.contains("example"), "{}", redacted);
+        // Hashes are deterministic within a run, so log messages can be correlated.
+        assert_eq!(
+            redactor.name(&"WWW.example.COM.".parse().unwrap()),
+            redacted
+        );
+        assert_ne!(redactor.name(&"example.com.".parse().unwrap()), redacted);
+
+        let peer = "192.0.2.1:5353".parse().unwrap();
+        let redacted = redactor.peer(peer);
+        assert!(!redacted.contains("192.0.2.1"), "{}", redacted);
+        assert_eq!(redactor.peer("192.0.2.1:1234".parse().unwrap()), redacted);
+        assert_ne!(redactor.peer("192.0.2.2:5353".parse().unwrap()), redacted);
+
+        // Another run uses another salt.
+        assert_ne!(LogRedactor::new(true).peer(peer), redacted);
+    }
+
+    #[test]
+    fn disabled() {
+        let redactor = LogRedactor::new(false);
+        let name = "www.example.com.".parse::<Name>().unwrap();
+        assert_eq!(redactor.name(&name), "www.example.com.");
+        let peer = "[2001:db8::1]:5353".parse().unwrap();
+        assert_eq!(redactor.peer(peer), "[2001:db8::1]:5353");
+    }
+}
@@ -2,38 +2,898 @@
 // SPDX-FileCopyrightText: 2025 Max Wipfli <mail@maxwipfli.ch>
 
 use std::{
+    collections::{HashMap, HashSet},
+    io,
     net::SocketAddr,
+    num::NonZeroUsize,
+    path::PathBuf,
+    str::FromStr,
     sync::{
         atomic::{AtomicU64, Ordering},
-        Arc,
+        Arc, RwLock,
     },
-    time::Instant,
+    time::{Duration, Instant},
 };
 
 use bytes::BytesMut;
-use eyre::Result;
-use tokio::net::UdpSocket;
-use tracing::{info, warn};
+use eyre::{eyre, Result};
+use hickory_proto::{
+    op::{Message, ResponseCode},
+    rr::RecordType,
+};
+use ipnet::{IpNet, Ipv6Net};
+use rand::Rng;
+use socket2::{Domain, Protocol, Socket, Type};
+#[cfg(unix)]
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::{
+    net::{TcpListener, UdpSocket},
+    sync::{OwnedSemaphorePermit, Semaphore},
+    task::JoinSet,
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    client::{client::resolve_with, Client},
+    proto::{
+        edns::{self, ClientSubnet, EdnsOption, ExtendedError},
+        message::{
+            error_response, error_response_with_reason, first_question, map_ttls_by_type,
+            response_code, strip_record_types, truncate, MAX_UDP_MESSAGE_LEN, UDP_RECV_BUFFER_LEN,
+        },
+        set_txid_in_binary_message, txid_from_binary_message, Header,
+    },
+};
+
+use super::{
+    chaos::{self, ServerIdentity},
+    coalesce::Coalescer,
+    dns64,
+    health::{self, Health},
+    inflight::InflightTracker,
+    local_zone::{self, LocalZone},
+    pcap::PcapWriter,
+    privacy::LogRedactor,
+    reverse::{self, ReverseZone},
+    single_request::NameLocks,
+    stats::{self, Stats},
+    trace::{self, Path, Trace},
+    watchdog::Watchdog,
+};
+
+/// Configuration of the resolver.
+#[derive(Debug, Clone, Default)]
+pub struct ResolverConfig {
+    /// Answer queries for `<name>.__trace` from localhost with a TXT record describing how the query for `<name>`
+    /// was handled.
+    pub debug_trace: bool,
+
+    /// Warn if the listener hasn't received any datagram for this long, which may indicate a broken socket.
+    pub listener_idle_threshold: Option<Duration>,
+
+    /// Raise the TTLs of answer and authority records of upstream responses to at least this many seconds.
+    pub min_ttl: Option<u32>,
+
+    /// Lower the TTLs of answer and authority records of upstream responses to at most this many seconds.
+    pub max_ttl: Option<u32>,
+
+    /// Set the TTLs of answer and authority records of these types in upstream responses to the given number of
+    /// seconds, regardless of `min_ttl` and `max_ttl`.
+    pub ttl_overrides: HashMap<RecordType, u32>,
+
+    /// Remove records of these types from upstream responses, e.g. to work around clients that cannot handle them.
+    pub strip_types: HashSet<RecordType>,
+
+    /// How EDNS Client Subnet (ECS) options of queries are handled before forwarding them upstream.
+    pub ecs: EcsPolicy,
+
+    /// Add an OPT record (advertising a UDP payload size of 1232 bytes) to queries of downstream peers without EDNS
+    /// support before forwarding them upstream, and remove it from the response. Some upstreams return smaller
+    /// answers to queries without EDNS.
+    pub add_edns: bool,
+
+    /// Delay responses by a random duration of up to this long, which makes it harder to infer from the timing of
+    /// responses how they were handled.
+    pub response_jitter: Option<Duration>,
+
+    /// Warn about queries that take longer than this to answer.
+    pub slow_query_threshold: Option<Duration>,
+
+    /// Warn if a request has been pending for longer than this, which may indicate a stuck upstream.
+    pub stuck_request_threshold: Option<Duration>,
+
+    /// Give up on queries this long after receiving them, as the downstream client has likely stopped waiting for
+    /// the response by then. Upstream clients bound their own timeouts by the remaining time.
+    pub query_deadline: Option<Duration>,
+
+    /// Maximum number of concurrent upstream requests, which bounds resource usage and protects the upstream under
+    /// load. Requests over the limit wait for up to `inflight_queue_timeout`, and are then answered with SERVFAIL.
+    pub max_inflight: Option<usize>,
+
+    /// Time requests over the `max_inflight` limit wait for another upstream request to finish. If zero, they are
+    /// rejected immediately.
+    pub inflight_queue_timeout: Duration,
+
+    /// Only serve queries from peers in these networks, and answer queries from other peers with REFUSED. If empty,
+    /// all peers are served.
+    pub allowlist: Vec<IpNet>,
+
+    /// Periodically log the query rate and latency percentiles of the queries answered in each interval.
+    pub stats_interval: Option<Duration>,
+
+    /// Answer CHAOS-class TXT queries for the server version and hostname (e.g. `version.bind`) with this
+    /// information instead of forwarding them. Disabled by default, as it discloses information about the server.
+    pub identity: Option<ServerIdentity>,
+
+    /// Answer PTR queries for the addresses in the reverse zone (e.g. of a home network) in the file at this path
+    /// instead of forwarding them. The file is reloaded on SIGHUP.
+    pub reverse_zone: Option<PathBuf>,
+
+    /// Answer queries for names in the zone in the zone file at this path authoritatively instead of forwarding them,
+    /// e.g. to serve a small local zone. The file is reloaded on SIGHUP.
+    pub local_zone: Option<PathBuf>,
+
+    /// Bind the listening socket to this network interface (`SO_BINDTODEVICE`). Only supported on Linux.
+    pub interface: Option<String>,
+
+    /// Set `SO_REUSEPORT` on the listening sockets, so that other processes (e.g. further instances of the resolver)
+    /// can bind the same address, and the kernel balances queries between them. Only supported on Unix.
+    pub reuse_port: bool,
+
+    /// Synthesize AAAA records in this NAT64 prefix from the A records of names without AAAA records (DNS64), for
+    /// IPv6-only clients behind NAT64. The prefix length must be one of those supported by RFC 6052.
+    pub dns64: Option<Ipv6Net>,
+
+    /// Capture all queries and responses exchanged with downstream peers to a pcap file at this path. Disabled by
+    /// default, as it slows down the resolver and records the queries of all peers.
+    pub pcap: Option<PathBuf>,
+
+    /// Never forward queries upstream, and answer those that cannot be answered locally (e.g. with the server
+    /// identity) with REFUSED and without the RA flag, as a strictly local (non-recursive) server would.
+    pub no_recursion: bool,
+
+    /// Replace query names and peer addresses in log messages with salted hashes, so that logs don't reveal which
+    /// names peers look up.
+    pub log_privacy: bool,
+
+    /// Answer liveness (`/healthz`) and readiness (`/readyz`) probes over HTTP on this address. The resolver is
+    /// ready once its sockets are bound, and as long as the upstream hasn't failed several requests in a row.
+    pub health_addr: Option<SocketAddr>,
+
+    /// Name server identifier (NSID, RFC 5001) added to responses to queries requesting it, which identifies the
+    /// instance that answered in anycast or load-balanced setups. Disabled by default.
+    pub nsid: Option<String>,
+
+    /// Number of tasks receiving datagrams concurrently on each socket, which parallelizes ingestion across cores
+    /// under very high query rates. Defaults to one.
+    ///
+    /// Queries are handled concurrently regardless, so responses may be sent in a different order than the queries
+    /// were received. With multiple tasks, request IDs (and the order of log messages and captured queries) may
+    /// additionally not follow the order in which queries arrived.
+    pub recv_workers: Option<NonZeroUsize>,
+
+    /// Send upstream requests for the same name (e.g. for its A and AAAA records) one after another instead of
+    /// concurrently, like glibc's `single-request` resolver option, for upstreams that mishandle concurrent queries.
+    pub single_request: bool,
+}
+
+/// Handling of EDNS Client Subnet (ECS) options in queries forwarded to the upstream server.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EcsPolicy {
+    /// Remove ECS options, so that no information about the client's network is leaked upstream.
+    #[default]
+    Strip,
+    /// Forward ECS options sent by the downstream unchanged.
+    Keep,
+    /// Replace ECS options with the given subnet.
+    Set(ClientSubnet),
+}
+
+impl FromStr for EcsPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strip" => Ok(Self::Strip),
+            "keep" => Ok(Self::Keep),
+            _ => match s.strip_prefix("set=") {
+                Some(subnet) => Ok(Self::Set(subnet.parse()?)),
+                None => Err(format!(
+                    "invalid ECS policy '{}' (expected 'strip', 'keep', or 'set=<prefix>')",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+/// Checks that the configuration is valid, without binding any sockets or starting the resolver, e.g. so that
+/// operators can validate a configuration before deploying it. `run` performs the same checks.
+pub fn check_config(config: &ResolverConfig) -> Result<()> {
+    if let Some(prefix) = config.dns64 {
+        if !dns64::PREFIX_LENS.contains(&prefix.prefix_len()) {
+            return Err(eyre!(
+                "unsupported DNS64 prefix length /{} (expected one of {:?})",
+                prefix.prefix_len(),
+                dns64::PREFIX_LENS
+            ));
+        }
+    }
+    if let Some(path) = &config.reverse_zone {
+        ReverseZone::load(path)?;
+    }
+    if let Some(path) = &config.local_zone {
+        LocalZone::load(path)?;
+    }
+    if let Some(path) = &config.pcap {
+        // The capture file itself is only created when running, so that checking doesn't truncate it.
+        let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty());
+        if dir.is_some_and(|dir| !dir.is_dir()) {
+            return Err(eyre!(
+                "cannot create capture file {}: parent directory does not exist",
+                path.display()
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Runs the resolver, answering queries received on `bind_addrs`. Addresses that cannot be bound (e.g. because the
+/// address family is disabled) are skipped, as long as at least one can be bound.
+/// Statistics of the resolver are recorded in `stats`, which the caller can read while the resolver is running.
+pub async fn run(
+    upstream: Arc<dyn Client>,
+    bind_addrs: &[SocketAddr],
+    config: ResolverConfig,
+    stats: Arc<Stats>,
+) -> Result<()> {
+    check_config(&config)?;
+    let health_listener = match config.health_addr {
+        Some(addr) => Some(
+            TcpListener::bind(addr)
+                .await
+                .map_err(|err| eyre!("cannot bind health endpoint to {}: {}", addr, err))?,
+        ),
+        None => None,
+    };
+    let sockets = bind_sockets(bind_addrs, config.interface.as_deref(), config.reuse_port)?;
+    if let Some(interval) = config.stats_interval {
+        tokio::spawn(stats::report(stats.clone(), interval));
+    }
+    let pcap = config.pcap.as_deref().map(PcapWriter::create).transpose()?;
+    let mut resolver = Resolver::new(upstream, config, stats);
+    resolver.pcap = pcap;
+    resolver.load_local_zone()?;
+    resolver.load_reverse_zone()?;
+    let resolver = Arc::new(resolver);
+    if let Some(listener) = health_listener {
+        tokio::spawn(health::serve(listener, resolver.health.clone()));
+    }
+    resolver.health.record_sockets_bound();
+    #[cfg(unix)]
+    if resolver.config.local_zone.is_some() || resolver.config.reverse_zone.is_some() {
+        let hangups = signal(SignalKind::hangup())?;
+        tokio::spawn(reload_on_hangup(resolver.clone(), hangups));
+    }
+    if let Some(watchdog) = &resolver.watchdog {
+        tokio::spawn(watchdog.clone().run());
+    }
+    if let Some(inflight) = &resolver.inflight {
+        tokio::spawn(inflight.clone().run());
+    }
+
+    let recv_workers = resolver.config.recv_workers.map_or(1, NonZeroUsize::get);
+    let mut handlers = JoinSet::new();
+    for socket in sockets {
+        // All workers of a socket receive on it concurrently, a datagram is received by one of them.
+        let socket = Arc::new(socket);
+        for _ in 0..recv_workers {
+            handlers.spawn(socket_handler(socket.clone(), resolver.clone()));
+        }
+    }
+    // Socket handlers only return on error.
+    match handlers.join_next().await {
+        Some(result) => result?,
+        None => Ok(()),
+    }
+}
+
+/// Task that reloads the files of the resolver (i.e. the local and reverse zones) whenever the process receives
+/// SIGHUP. Settings that aren't loaded from files (e.g. the listen addresses) are not reloaded.
+#[cfg(unix)]
+async fn reload_on_hangup(resolver: Arc<Resolver>, mut hangups: Signal) {
+    while hangups.recv().await.is_some() {
+        if resolver.config.local_zone.is_some() {
+            match resolver.load_local_zone() {
+                Ok(()) => info!("reloaded local zone after SIGHUP"),
+                Err(err) => warn!("cannot reload local zone after SIGHUP, keeping it: {}", err),
+            }
+        }
+        if resolver.config.reverse_zone.is_some() {
+            match resolver.load_reverse_zone() {
+                Ok(()) => info!("reloaded reverse zone after SIGHUP"),
+                Err(err) => warn!(
+                    "cannot reload reverse zone after SIGHUP, keeping it: {}",
+                    err
+                ),
+            }
+        }
+    }
+}
+
+/// Binds a UDP socket to each of the given addresses, optionally restricted to a network interface and with
+/// `SO_REUSEPORT` set.
+/// Returns an error only if none of the addresses can be bound.
+fn bind_sockets(
+    bind_addrs: &[SocketAddr],
+    interface: Option<&str>,
+    reuse_port: bool,
+) -> Result<Vec<UdpSocket>> {
+    let mut sockets = Vec::new();
+    let mut last_err = eyre!("no address to bind to");
+    for addr in bind_addrs {
+        match bind_socket_to(*addr, interface, reuse_port) {
+            Ok(socket) => {
+                info!("listening on {}", addr);
+                sockets.push(socket);
+            }
+            Err(err) => {
+                warn!("cannot bind to {}: {}", addr, err);
+                last_err = err;
+            }
+        }
+    }
+    if sockets.is_empty() {
+        return Err(last_err);
+    }
+    Ok(sockets)
+}
+
+fn bind_socket_to(
+    addr: SocketAddr,
+    interface: Option<&str>,
+    reuse_port: bool,
+) -> Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if let Some(interface) = interface {
+        bind_device(&socket, interface)?;
+    }
+    if reuse_port {
+        set_reuse_port(&socket)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn bind_device(socket: &Socket, interface: &str) -> Result<()> {
+    socket
+        .bind_device(Some(interface.as_bytes()))
+        .map_err(|err| eyre!("cannot bind to interface '{}': {}", interface, err))
+}
 
-use crate::{client::Client, proto::txid_from_binary_message};
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn bind_device(_socket: &Socket, interface: &str) -> Result<()> {
+    Err(eyre!(
+        "cannot bind to interface '{}': binding to an interface is only supported on Linux",
+        interface
+    ))
+}
 
-pub async fn run(upstream: Arc<dyn Client>, bind_addrs: &[SocketAddr]) -> Result<()> {
-    let socket = Arc::new(UdpSocket::bind(bind_addrs).await?);
-    socket_handler(socket, upstream).await
+#[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+fn set_reuse_port(socket: &Socket) -> Result<()> {
+    socket
+        .set_reuse_port(true)
+        .map_err(|err| eyre!("cannot set SO_REUSEPORT: {}", err))
 }
 
-static REQUEST_ID: AtomicU64 = AtomicU64::new(0);
+#[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+fn set_reuse_port(_socket: &Socket) -> Result<()> {
+    Err(eyre!("SO_REUSEPORT is not supported on this platform"))
+}
 
 struct Request {
     id: u64,
     stamp: Instant,
+    /// Time after which resolving the query is pointless, as the downstream client won't wait for it.
+    deadline: Option<Instant>,
     peer: SocketAddr,
     data: BytesMut,
 }
 
-async fn socket_handler(socket: Arc<UdpSocket>, upstream: Arc<dyn Client>) -> Result<()> {
+struct Resolver {
+    upstream: Arc<dyn Client>,
+    config: ResolverConfig,
+    coalescer: Coalescer,
+    watchdog: Option<Arc<Watchdog>>,
+    inflight: Option<Arc<InflightTracker>>,
+    /// Permits for concurrent upstream requests, if limited.
+    upstream_permits: Option<Arc<Semaphore>>,
+    slow_queries: AtomicU64,
+    stats: Arc<Stats>,
+    health: Arc<Health>,
+    pcap: Option<PcapWriter>,
+    /// Formats query names and peer addresses in log messages.
+    redactor: LogRedactor,
+    /// Locks serializing upstream requests for the same name, if `config.single_request` is set.
+    name_locks: Option<NameLocks>,
+    /// Zone loaded from `config.local_zone`, which is replaced when reloading it.
+    local_zone: RwLock<Option<Arc<LocalZone>>>,
+    /// Reverse zone loaded from `config.reverse_zone`, which is replaced when reloading it.
+    reverse_zone: RwLock<Option<Arc<ReverseZone>>>,
+    /// ID of the next request, which identifies requests in log messages.
+    next_request_id: AtomicU64,
+}
+
+impl Resolver {
+    fn new(upstream: Arc<dyn Client>, config: ResolverConfig, stats: Arc<Stats>) -> Self {
+        let watchdog = config
+            .listener_idle_threshold
+            .map(|threshold| Arc::new(Watchdog::new(threshold)));
+        let inflight = config
+            .stuck_request_threshold
+            .map(|threshold| Arc::new(InflightTracker::new(threshold)));
+        let upstream_permits = config
+            .max_inflight
+            .map(|max_inflight| Arc::new(Semaphore::new(max_inflight)));
+        let redactor = LogRedactor::new(config.log_privacy);
+        let name_locks = config.single_request.then(NameLocks::default);
+        Self {
+            upstream,
+            config,
+            coalescer: Coalescer::default(),
+            watchdog,
+            inflight,
+            upstream_permits,
+            slow_queries: AtomicU64::new(0),
+            stats,
+            health: Arc::default(),
+            pcap: None,
+            redactor,
+            name_locks,
+            local_zone: RwLock::default(),
+            reverse_zone: RwLock::default(),
+            next_request_id: AtomicU64::new(0),
+        }
+    }
+
+    /// Loads the local zone from the configured file (if any), replacing the current one. Requests that are being
+    /// handled keep using the zone they started with. On error, the current zone is kept.
+    fn load_local_zone(&self) -> Result<()> {
+        let Some(path) = &self.config.local_zone else {
+            return Ok(());
+        };
+        let zone = LocalZone::load(path)?;
+        *self.local_zone.write().unwrap() = Some(Arc::new(zone));
+        Ok(())
+    }
+
+    /// Loads the reverse zone from the configured file (if any), replacing the current one. Requests that are being
+    /// handled keep using the zone they started with. On error, the current zone is kept.
+    fn load_reverse_zone(&self) -> Result<()> {
+        let Some(path) = &self.config.reverse_zone else {
+            return Ok(());
+        };
+        let zone = ReverseZone::load(path)?;
+        *self.reverse_zone.write().unwrap() = Some(Arc::new(zone));
+        Ok(())
+    }
+
+    /// Returns a new ID to identify a request with.
+    fn next_request_id(&self) -> u64 {
+        self.next_request_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Handles a request, returning the response to send to the downstream peer.
+    ///
+    /// Requests pass through the following steps in this order, and the first step answering the query ends the
+    /// handling:
+    ///
+    /// 1. Queries from peers outside of the allowlist are refused.
+    /// 2. Unsupported queries (e.g. with multiple questions) are answered with an error, see `check_query`.
+    /// 3. Trace queries (`<name>.__trace`) from localhost are answered with a trace of how the query for `<name>` is
+    ///    handled by the remaining steps.
+    /// 4. CHAOS-class identity queries (e.g. `version.bind`) are answered, if configured.
+    /// 5. Queries for names in the local zone are answered authoritatively.
+    /// 6. PTR queries for addresses in the reverse zone are answered.
+    /// 7. With `no_recursion`, all remaining queries are refused.
+    /// 8. All other queries are forwarded upstream (coalesced with identical concurrent queries), and AAAA records
+    ///    are synthesized with DNS64.
+    ///
+    /// Local answers thus take precedence over upstream answers for the same name, which are never requested.
+    async fn handle(&self, request: &Request) -> Result<BytesMut> {
+        if !self.is_allowed(request) {
+            debug!(
+                "request #{}: refusing query from {}, which is not in the allowlist",
+                request.id,
+                self.redactor.peer(request.peer)
+            );
+            let reason = ExtendedError::new(ExtendedError::PROHIBITED, "client not in allowlist");
+            return error_response_with_reason(&request.data, ResponseCode::Refused, &reason)
+                .map(|response| response.as_slice().into())
+                .ok_or_else(|| eyre!("cannot build response to malformed query"));
+        }
+        if let Some(response) = self.check_query(request) {
+            return response;
+        }
+        if self.config.debug_trace && request.peer.ip().is_loopback() {
+            if let Some(response) = self.trace(request).await? {
+                return Ok(response);
+            }
+        }
+        let (response, _) = self.answer(request).await?;
+        Ok(response)
+    }
+
+    /// Answers a query locally or from the upstream (steps 4 to 8 of `handle`), returning the response and how it
+    /// was answered.
+    async fn answer(&self, request: &Request) -> Result<(BytesMut, Path)> {
+        if let Some(identity) = &self.config.identity {
+            if let Ok(query) = Message::from_vec(&request.data) {
+                if let Some(response) = chaos::answer(&query, identity) {
+                    return Ok((response?, Path::Chaos));
+                }
+            }
+        }
+        let local_zone = self.local_zone.read().unwrap().clone();
+        if let Some(zone) = local_zone {
+            if let Ok(query) = Message::from_vec(&request.data) {
+                if let Some(response) = local_zone::answer(&query, &zone) {
+                    debug!("request #{}: answering query from local zone", request.id);
+                    return Ok((response?, Path::LocalZone));
+                }
+            }
+        }
+        let reverse_zone = self.reverse_zone.read().unwrap().clone();
+        if let Some(zone) = reverse_zone {
+            if let Ok(query) = Message::from_vec(&request.data) {
+                if let Some(response) = reverse::answer(&query, &zone) {
+                    debug!(
+                        "request #{}: answering PTR query from reverse zone",
+                        request.id
+                    );
+                    return Ok((response?, Path::ReverseZone));
+                }
+            }
+        }
+        if self.config.no_recursion {
+            debug!(
+                "request #{}: refusing query that cannot be answered locally",
+                request.id
+            );
+            let response = refused_response_without_recursion(&request.data)
+                .ok_or_else(|| eyre!("cannot build response to malformed query"))?;
+            return Ok((response, Path::Refused));
+        }
+        let (response, path) = self.forward(request, request.data.clone()).await?;
+        if let Some(prefix) = &self.config.dns64 {
+            if let Some(a_query) = dns64::a_query(&request.data, &response) {
+                debug!(
+                    "request #{}: synthesizing AAAA records from A records (DNS64)",
+                    request.id
+                );
+                let (a_response, _) = self.forward(request, a_query).await?;
+                if let Some(synthesized) = dns64::synthesize(&request.data, &a_response, prefix) {
+                    return Ok((synthesized, Path::Dns64));
+                }
+            }
+        }
+        Ok((response, path))
+    }
+
+    /// Checks that the query is one the resolver supports, i.e. a standard query with a single question.
+    /// Returns the error response to send otherwise.
+    ///
+    /// Queries with multiple questions are answered with FORMERR, as recommended by [RFC9619]. Handling of queries
+    /// (e.g. tracing and ECS rewriting) relies on queries having a single question. Queries without a question, which
+    /// some monitoring tools send as keepalives, are answered with an empty NOERROR response.
+    ///
+    /// [RFC9619]: https://datatracker.ietf.org/doc/html/rfc9619
+    fn check_query(&self, request: &Request) -> Option<Result<BytesMut>> {
+        let header = Header::parse(&request.data)?;
+        let (rcode, reason) = if header.opcode() != Header::OPCODE_QUERY {
+            let reason = ExtendedError::new(ExtendedError::NOT_SUPPORTED, "unsupported opcode");
+            (ResponseCode::NotImp, Some(reason))
+        } else if header.qdcount == 0 {
+            (ResponseCode::NoError, None)
+        } else if header.qdcount != 1 {
+            (ResponseCode::FormErr, None)
+        } else {
+            return None;
+        };
+        debug!(
+            "request #{}: answering query with opcode {} and {} questions with {}",
+            request.id,
+            header.opcode(),
+            header.qdcount,
+            rcode
+        );
+        let response = match reason {
+            Some(reason) => error_response_with_reason(&request.data, rcode, &reason),
+            None => error_response(&request.data, rcode),
+        };
+        Some(
+            response
+                .map(|response| response.as_slice().into())
+                .ok_or_else(|| eyre!("cannot build response to malformed query")),
+        )
+    }
+
+    /// Waits for a permit to send a request upstream, if the number of concurrent upstream requests is limited.
+    /// Fails if none becomes available within the configured queue timeout.
+    async fn acquire_upstream_permit(
+        &self,
+        request: &Request,
+    ) -> Result<Option<OwnedSemaphorePermit>> {
+        let Some(permits) = &self.upstream_permits else {
+            return Ok(None);
+        };
+        if let Ok(permit) = permits.clone().try_acquire_owned() {
+            return Ok(Some(permit));
+        }
+        debug!(
+            "request #{}: waiting for one of {} concurrent upstream requests to finish",
+            request.id,
+            self.config.max_inflight.unwrap_or_default()
+        );
+        match tokio::time::timeout(
+            self.config.inflight_queue_timeout,
+            permits.clone().acquire_owned(),
+        )
+        .await
+        {
+            Ok(permit) => Ok(Some(permit?)),
+            Err(_) => Err(eyre!("too many concurrent upstream requests")),
+        }
+    }
+
+    /// Checks whether the peer of a request is allowed to use the resolver.
+    fn is_allowed(&self, request: &Request) -> bool {
+        // Peers on dual-stack sockets are reported as IPv4-mapped IPv6 addresses.
+        let ip = request.peer.ip().to_canonical();
+        self.config.allowlist.is_empty()
+            || self.config.allowlist.iter().any(|net| net.contains(&ip))
+    }
+
+    /// Forwards a query to the upstream server. Returns the response, and whether it was requested from the upstream
+    /// or is the response to an identical concurrent query (`Path::Coalesced`).
+    async fn forward(&self, request: &Request, data: BytesMut) -> Result<(BytesMut, Path)> {
+        let data = self.apply_ecs_policy(request, data);
+        let (data, added_opt) = self.add_edns(request, data);
+
+        // store transaction ID for later
+        let txid = txid_from_binary_message(&data).ok_or_else(|| eyre!("query is too short"))?;
+
+        // Identical queries that are in flight concurrently only result in a single upstream request.
+        let query = data.clone();
+        let mut upstream_requested = false;
+        let resolve = async {
+            upstream_requested = true;
+            let _name_guard = match (&self.name_locks, first_question(&query)) {
+                (Some(locks), Some(question)) => Some(locks.lock(question.name()).await),
+                _ => None,
+            };
+            let _permit = self.acquire_upstream_permit(request).await?;
+            let result = resolve_with(&*self.upstream, data, request.deadline).await;
+            self.health.record_upstream(result.is_ok());
+            result
+        };
+        let mut data = self.coalescer.resolve(&query, resolve).await?;
+        let path = if upstream_requested {
+            Path::Upstream
+        } else {
+            self.stats.record_coalesced();
+            Path::Coalesced
+        };
+
+        if txid_from_binary_message(&data).is_none() {
+            return Err(eyre!(
+                "upstream response is too short ({} bytes)",
+                data.len()
+            ));
+        }
+        // restore transaction ID
+        set_txid_in_binary_message(&mut data, txid);
+
+        if added_opt {
+            // The downstream peer doesn't support EDNS, so it must not receive an OPT record (RFC 6891, Section 7).
+            if let Some(stripped) = edns::without_opt(&data) {
+                data = stripped.as_slice().into();
+            }
+        }
+        if self.config.min_ttl.is_some()
+            || self.config.max_ttl.is_some()
+            || !self.config.ttl_overrides.is_empty()
+        {
+            let min_ttl = self.config.min_ttl.unwrap_or(0);
+            let max_ttl = self.config.max_ttl.unwrap_or(u32::MAX);
+            let rewrite = |rtype, ttl: u32| match self.config.ttl_overrides.get(&rtype) {
+                Some(&ttl) => ttl,
+                None => ttl.max(min_ttl).min(max_ttl),
+            };
+            if map_ttls_by_type(&mut data, rewrite).is_none() {
+                debug!(
+                    "request #{}: cannot rewrite TTLs of malformed upstream response",
+                    request.id
+                );
+            }
+        }
+        if !self.config.strip_types.is_empty()
+            && strip_record_types(&mut data, &self.config.strip_types).is_none()
+        {
+            debug!(
+                "request #{}: cannot strip records from malformed upstream response",
+                request.id
+            );
+        }
+
+        debug!(
+            "request #{}: received {} bytes from upstream server (rcode {})",
+            request.id,
+            data.len(),
+            response_code(&data).map_or("unknown".to_string(), |rcode| rcode.to_string())
+        );
+        // Extended DNS Errors are passed through to the downstream unchanged, but logged for diagnostics.
+        for error in edns::extended_errors(&data) {
+            debug!(
+                "request #{}: upstream reported extended DNS error {} ({})",
+                request.id, error.info_code, error.extra_text
+            );
+        }
+        Ok((data, path))
+    }
+
+    /// Captures a message exchanged with a downstream peer, if enabled.
+    fn capture(&self, request: &Request, src: SocketAddr, dst: SocketAddr, data: &[u8]) {
+        let Some(pcap) = &self.pcap else {
+            return;
+        };
+        if let Err(err) = pcap.write(src, dst, data) {
+            debug!("request #{}: cannot capture message: {}", request.id, err);
+        }
+    }
+
+    /// Warns if answering the request took longer than the configured threshold.
+    fn check_latency(&self, request: &Request) {
+        let Some(threshold) = self.config.slow_query_threshold else {
+            return;
+        };
+        let latency = request.stamp.elapsed();
+        if latency <= threshold {
+            return;
+        }
+        let name = first_question(&request.data).map_or_else(
+            || "<malformed>".to_string(),
+            |question| self.redactor.name(question.name()),
+        );
+        warn!(
+            "request #{}: slow query for '{}' from {} took {} ms",
+            request.id,
+            name,
+            self.redactor.peer(request.peer),
+            latency.as_millis()
+        );
+        self.slow_queries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns a random delay to apply before sending a response, according to the configured jitter.
+    fn response_delay(&self) -> Duration {
+        match self.config.response_jitter {
+            Some(jitter) if !jitter.is_zero() => {
+                rand::thread_rng().gen_range(Duration::ZERO..=jitter)
+            }
+            _ => Duration::ZERO,
+        }
+    }
+
+    /// Strips, keeps, or sets the EDNS Client Subnet (ECS) option of a query according to the configured policy.
+    fn apply_ecs_policy(&self, request: &Request, data: BytesMut) -> BytesMut {
+        let subnet = match self.config.ecs {
+            EcsPolicy::Keep => return data,
+            EcsPolicy::Strip => None,
+            EcsPolicy::Set(subnet) => Some(subnet.to_option_data()),
+        };
+
+        let options = edns::options(&data).unwrap_or_default();
+        if subnet.is_none() && options.iter().all(|option| option.code != edns::ECS_CODE) {
+            return data;
+        }
+        let mut options = options
+            .into_iter()
+            .filter(|option| option.code != edns::ECS_CODE)
+            .collect::<Vec<_>>();
+        if let Some(subnet) = &subnet {
+            options.push(EdnsOption {
+                code: edns::ECS_CODE,
+                data: subnet,
+            });
+        }
+
+        match edns::with_options(&data, &options) {
+            Some(rewritten) => rewritten.as_slice().into(),
+            None => {
+                debug!(
+                    "request #{}: cannot rewrite EDNS client subnet of malformed query",
+                    request.id
+                );
+                data
+            }
+        }
+    }
+
+    /// Adds an OPT record to a query without one, if configured. Returns the query and whether an OPT record was
+    /// added.
+    fn add_edns(&self, request: &Request, data: BytesMut) -> (BytesMut, bool) {
+        if !self.config.add_edns || edns::find_opt(&data).is_some() {
+            return (data, false);
+        }
+        match edns::with_options(&data, &[]) {
+            Some(extended) => (extended.as_slice().into(), true),
+            None => {
+                debug!(
+                    "request #{}: cannot add OPT record to malformed query",
+                    request.id
+                );
+                (data, false)
+            }
+        }
+    }
+
+    /// Sets the NSID option of a response to the configured identifier, if enabled and requested by the query.
+    fn add_nsid(&self, request: &Request, data: BytesMut) -> BytesMut {
+        let Some(nsid) = &self.config.nsid else {
+            return data;
+        };
+        if !edns::requests_nsid(&request.data) {
+            return data;
+        }
+        match edns::with_nsid(&data, nsid.as_bytes()) {
+            Some(response) => response.as_slice().into(),
+            None => {
+                debug!(
+                    "request #{}: cannot add NSID to malformed response",
+                    request.id
+                );
+                data
+            }
+        }
+    }
+
+    /// Handles a query for `<name>.__trace` by answering the query for `<name>` and responding with a description of
+    /// how it was answered. Returns `None` if the request is not a trace request.
+    async fn trace(&self, request: &Request) -> Result<Option<BytesMut>> {
+        let Ok(query) = Message::from_vec(&request.data) else {
+            return Ok(None);
+        };
+        let Some(stripped) = trace::strip_trace_label(&query) else {
+            return Ok(None);
+        };
+
+        let stripped_request = Request {
+            id: request.id,
+            stamp: Instant::now(),
+            deadline: request.deadline,
+            peer: request.peer,
+            data: stripped.to_vec()?.as_slice().into(),
+        };
+        let (response, path) = self.answer(&stripped_request).await?;
+        let latency = stripped_request.stamp.elapsed();
+        let response = Message::from_vec(&response)?;
+
+        let trace = Trace {
+            path,
+            rcode: response.response_code(),
+            answers: response.answer_count(),
+            latency,
+        };
+        Ok(Some(trace::build_response(&query, &trace)?))
+    }
+}
+
+async fn socket_handler(socket: Arc<UdpSocket>, resolver: Arc<Resolver>) -> Result<()> {
     loop {
-        let mut buffer = BytesMut::zeroed(1024);
+        let mut buffer = BytesMut::zeroed(UDP_RECV_BUFFER_LEN);
         let result = socket.recv_from(&mut buffer).await;
         let stamp = Instant::now();
         if let Err(err) = result {
@@ -43,61 +903,2272 @@ async fn socket_handler(socket: Arc<UdpSocket>, upstream: Arc<dyn Client>) -> Re
         let (n, peer) = result.unwrap();
         let data = buffer.split_to(n);
 
+        if let Some(watchdog) = &resolver.watchdog {
+            watchdog.record_activity();
+        }
+
+        let Some(header) = Header::parse(&data) else {
+            debug!(
+                "dropping datagram from {} that is too short ({} bytes)",
+                resolver.redactor.peer(peer),
+                n
+            );
+            continue;
+        };
+        // Drop responses, forwarding them could cause loops between misconfigured resolvers.
+        if header.is_response() {
+            debug!(
+                "dropping datagram from {} with QR bit set",
+                resolver.redactor.peer(peer)
+            );
+            continue;
+        }
+
         let request = Request {
-            id: REQUEST_ID.fetch_add(1, Ordering::SeqCst),
+            id: resolver.next_request_id(),
             stamp,
+            deadline: resolver
+                .config
+                .query_deadline
+                .map(|deadline| stamp + deadline),
             peer,
             data,
         };
 
-        tokio::spawn(request_handler(socket.clone(), upstream.clone(), request));
+        tokio::spawn(request_handler(socket.clone(), resolver.clone(), request));
     }
 }
 
-async fn request_handler(
-    socket: Arc<UdpSocket>,
-    upstream_client: Arc<dyn Client>,
-    request: Request,
-) {
-    info!(
-        "request #{}: received {} bytes from downstream peer ({})",
-        request.id,
-        request.data.len(),
-        request.peer
+/// Builds the REFUSED response to a query that a non-recursive server cannot answer, which (unlike other error
+/// responses) doesn't offer recursion (RA flag cleared).
+fn refused_response_without_recursion(query: &[u8]) -> Option<BytesMut> {
+    let reason = ExtendedError::new(ExtendedError::NOT_AUTHORITATIVE, "recursion disabled");
+    let mut response = BytesMut::from(
+        error_response_with_reason(query, ResponseCode::Refused, &reason)?.as_slice(),
     );
-    // store transaction ID for later
-    let txid = txid_from_binary_message(&request.data);
+    let mut header = Header::parse(&response)?;
+    header.flags &= !Header::FLAG_RA;
+    response[..Header::LEN].copy_from_slice(&header.to_bytes());
+    Some(response)
+}
 
-    let result = upstream_client.resolve_raw(request.data).await;
-    if let Err(err) = result {
-        warn!(
-            "request #{}: error in upstream request: {}",
-            request.id, err
+/// Truncates responses to peers that don't support EDNS to the 512 bytes they accept over UDP, setting the TC bit so
+/// that they retry over TCP (RFC 1035, Section 4.2.1).
+fn limit_response_size(request: &Request, mut data: BytesMut) -> BytesMut {
+    if data.len() <= MAX_UDP_MESSAGE_LEN || edns::find_opt(&request.data).is_some() {
+        return data;
+    }
+    debug!(
+        "request #{}: truncating {}-byte response to peer without EDNS support",
+        request.id,
+        data.len()
+    );
+    if truncate(&mut data, MAX_UDP_MESSAGE_LEN).is_none() {
+        debug!(
+            "request #{}: cannot truncate malformed response",
+            request.id
         );
-        return;
     }
+    data
+}
+
+/// Time after which sending a response to a downstream peer is given up, e.g. because the socket buffer is full.
+const SEND_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Socket that responses are sent to downstream peers on, which tests replace with a mock.
+#[async_trait::async_trait]
+trait DownstreamSocket: Send + Sync {
+    async fn send_to(&self, data: &[u8], peer: SocketAddr) -> io::Result<usize>;
+}
+
+#[async_trait::async_trait]
+impl DownstreamSocket for UdpSocket {
+    async fn send_to(&self, data: &[u8], peer: SocketAddr) -> io::Result<usize> {
+        UdpSocket::send_to(self, data, peer).await
+    }
+}
 
-    // restore transaction ID
-    let mut data = result.unwrap().to_vec();
-    data[0..2].copy_from_slice(&txid.to_be_bytes());
+/// Sends the response to a request to the downstream peer, giving up after `SEND_TIMEOUT`.
+/// Returns whether the whole response was sent, failures are logged.
+async fn send_response(socket: &dyn DownstreamSocket, request: &Request, data: &[u8]) -> bool {
+    match tokio::time::timeout(SEND_TIMEOUT, socket.send_to(data, request.peer)).await {
+        Ok(Ok(n)) if n == data.len() => true,
+        Ok(Ok(n)) => {
+            warn!(
+                "request #{}: sent only {} of {} bytes of response",
+                request.id,
+                n,
+                data.len()
+            );
+            false
+        }
+        Ok(Err(err)) => {
+            warn!("request #{}: error in send_to(): {}", request.id, err);
+            false
+        }
+        Err(_) => {
+            warn!(
+                "request #{}: timeout sending response, dropping it",
+                request.id
+            );
+            false
+        }
+    }
+}
 
-    info!(
-        "request #{}: received {} bytes from upstream server",
+async fn request_handler(socket: Arc<UdpSocket>, resolver: Arc<Resolver>, request: Request) {
+    debug!(
+        "request #{}: received {} bytes from downstream peer ({})",
         request.id,
-        data.len()
+        request.data.len(),
+        resolver.redactor.peer(request.peer)
     );
 
-    let result = socket.send_to(&data, request.peer).await;
-    if let Err(err) = result {
-        warn!("request #{}: error in send_to(): {}", request.id, err);
+    let _inflight_guard = resolver
+        .inflight
+        .as_ref()
+        .map(|inflight| inflight.track(request.id));
+
+    let local_addr = socket.local_addr();
+    if let Ok(local_addr) = local_addr {
+        resolver.capture(&request, request.peer, local_addr, &request.data);
+    }
+
+    let data = match resolver.handle(&request).await {
+        Ok(data) => data,
+        Err(err) => {
+            warn!(
+                "request #{}: error in upstream request: {}",
+                request.id, err
+            );
+            resolver.stats.record_failure();
+            // Answer with SERVFAIL, so the downstream doesn't have to wait for its own timeout.
+            let reason =
+                ExtendedError::new(ExtendedError::NETWORK_ERROR, "upstream request failed");
+            let Some(data) =
+                error_response_with_reason(&request.data, ResponseCode::ServFail, &reason)
+            else {
+                debug!(
+                    "request #{}: cannot answer malformed query with SERVFAIL",
+                    request.id
+                );
+                return;
+            };
+            data.as_slice().into()
+        }
+    };
+    let data = resolver.add_nsid(&request, data);
+    let data = limit_response_size(&request, data);
+
+    let delay = resolver.response_delay();
+    if !delay.is_zero() {
+        tokio::time::sleep(delay).await;
+    }
+
+    if let Ok(local_addr) = local_addr {
+        resolver.capture(&request, local_addr, request.peer, &data);
+    }
+    if !send_response(&*socket, &request, &data).await {
         return;
     }
-    let n = result.unwrap();
-    assert_eq!(n, data.len());
 
-    info!(
+    debug!(
         "request #{}: finished in {} ms",
         request.id,
         request.stamp.elapsed().as_millis()
     );
+    resolver.stats.record_query(request.stamp.elapsed());
+    resolver.check_latency(&request);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env,
+        net::{IpAddr, Ipv4Addr, Ipv6Addr},
+        process,
+        time::Duration,
+    };
+
+    use hickory_proto::{
+        op::{Edns, MessageType, OpCode, Query, ResponseCode},
+        rr::{
+            rdata::{opt::EdnsOption, A, AAAA, NS, PTR, SOA},
+            DNSClass, Name, RData, Record, RecordType,
+        },
+    };
+
+    use super::*;
+    use crate::client::mock::MockClient;
+
+    const EDNS_PADDING_CODE: u16 = 12;
+
+    fn upstream_ecs_options(upstream: &MockClient) -> Vec<Vec<u8>> {
+        let queries = upstream.queries();
+        edns::options(&queries[0])
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|option| option.code == edns::ECS_CODE)
+            .map(|option| option.data.to_vec())
+            .collect()
+    }
+
+    fn build_request_with_ecs(peer: SocketAddr, name: &str) -> Request {
+        let mut request = build_request(peer, name);
+        let mut data = request.data.to_vec();
+        // 198.51.100.0/24
+        edns::tests::append_opt(
+            &mut data,
+            &[
+                (edns::ECS_CODE, &[0, 1, 24, 0, 198, 51, 100]),
+                (EDNS_PADDING_CODE, &[0; 8]),
+            ],
+        );
+        request.data = data.as_slice().into();
+        request
+    }
+
+    fn build_upstream() -> Arc<MockClient> {
+        let upstream = MockClient::new();
+        upstream.set_answer("www.example.com.", RData::A(A::new(192, 0, 2, 1)));
+        Arc::new(upstream)
+    }
+
+    fn build_resolver(upstream: Arc<MockClient>, config: ResolverConfig) -> Resolver {
+        Resolver::new(upstream, config, Arc::default())
+    }
+
+    fn build_query(id: u16, name: &str) -> Message {
+        let mut query = Message::new();
+        query
+            .set_id(id)
+            .add_query(Query::query(name.parse().unwrap(), RecordType::A));
+        query
+    }
+
+    fn build_request(peer: SocketAddr, name: &str) -> Request {
+        build_request_with_id(peer, 0x1234, name)
+    }
+
+    fn build_request_with_id(peer: SocketAddr, txid: u16, name: &str) -> Request {
+        Request {
+            id: 0,
+            stamp: Instant::now(),
+            deadline: None,
+            peer,
+            data: build_query(txid, name).to_vec().unwrap().as_slice().into(),
+        }
+    }
+
+    #[test]
+    fn independent_request_ids() {
+        let resolver = build_resolver(build_upstream(), ResolverConfig::default());
+        let other = build_resolver(build_upstream(), ResolverConfig::default());
+        assert_eq!(resolver.next_request_id(), 0);
+        assert_eq!(resolver.next_request_id(), 1);
+        assert_eq!(other.next_request_id(), 0);
+        assert_eq!(resolver.next_request_id(), 2);
+        assert_eq!(other.next_request_id(), 1);
+    }
+
+    #[tokio::test]
+    async fn debug_trace() {
+        let resolver = build_resolver(
+            build_upstream(),
+            ResolverConfig {
+                debug_trace: true,
+                ..Default::default()
+            },
+        );
+        let peer = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 5353);
+        let request = build_request(peer, "www.example.com.__trace.");
+
+        let response = resolver.handle(&request).await.unwrap();
+        let response = Message::from_vec(&response).unwrap();
+
+        assert_eq!(response.id(), 0x1234);
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert_eq!(response.answer_count(), 1);
+        let Some(RData::TXT(txt)) = response.answers()[0].data() else {
+            panic!("expected TXT record");
+        };
+        let strings = txt
+            .iter()
+            .map(|s| String::from_utf8_lossy(s).into_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            strings[..3],
+            ["path=upstream", "rcode=NoError", "answers=1"]
+        );
+        assert!(strings[3].starts_with("latency="));
+    }
+
+    /// Returns the path of the trace in a response to a trace query.
+    fn trace_path(response: &[u8]) -> String {
+        let response = Message::from_vec(response).unwrap();
+        let Some(RData::TXT(txt)) = response.answers()[0].data() else {
+            panic!("expected TXT record");
+        };
+        String::from_utf8_lossy(&txt.txt_data()[0]).into_owned()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn debug_trace_paths() {
+        let upstream = build_upstream();
+        upstream.set_delay(Duration::from_millis(100));
+        let zone = env::temp_dir().join(format!("aufloes-trace-zone-{}", process::id()));
+        std::fs::write(
+            &zone,
+            "$ORIGIN home.arpa.\n\
+             @   3600 IN SOA ns admin 1 7200 3600 1209600 300\n\
+             nas 3600 IN A   192.168.1.10\n",
+        )
+        .unwrap();
+        let mut resolver = build_resolver(
+            upstream.clone(),
+            ResolverConfig {
+                debug_trace: true,
+                identity: Some(ServerIdentity {
+                    version: Some("test".to_string()),
+                    hostname: None,
+                }),
+                local_zone: Some(zone.clone()),
+                ..Default::default()
+            },
+        );
+        resolver.load_local_zone().unwrap();
+        let _ = std::fs::remove_file(zone);
+        let peer = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 5353);
+
+        let request = build_request(peer, "nas.home.arpa.__trace.");
+        let response = resolver.handle(&request).await.unwrap();
+        assert_eq!(trace_path(&response), "path=local-zone");
+
+        let mut query = Message::new();
+        query.set_id(0x1234).add_query(
+            Query::query("version.bind.__trace.".parse().unwrap(), RecordType::TXT)
+                .set_query_class(DNSClass::CH)
+                .clone(),
+        );
+        let mut request = build_request(peer, "www.example.com.");
+        request.data = query.to_vec().unwrap().as_slice().into();
+        let response = resolver.handle(&request).await.unwrap();
+        assert_eq!(trace_path(&response), "path=chaos");
+
+        // Of two identical concurrent queries, one is answered by the upstream, the other with the same response.
+        let first = build_request_with_id(peer, 1, "www.example.com.__trace.");
+        let second = build_request_with_id(peer, 2, "www.example.com.__trace.");
+        let (first, second) = tokio::join!(resolver.handle(&first), resolver.handle(&second));
+        let mut paths = [trace_path(&first.unwrap()), trace_path(&second.unwrap())];
+        paths.sort();
+        assert_eq!(paths, ["path=coalesced", "path=upstream"]);
+        assert_eq!(upstream.calls(), 1);
+
+        resolver.config.no_recursion = true;
+        let request = build_request(peer, "www.example.com.__trace.");
+        let response = resolver.handle(&request).await.unwrap();
+        assert_eq!(trace_path(&response), "path=refused");
+    }
+
+    #[tokio::test]
+    async fn debug_trace_requires_local_peer() {
+        let upstream = build_upstream();
+        upstream.set_answer("www.example.com.__trace.", RData::A(A::new(192, 0, 2, 1)));
+        let resolver = build_resolver(
+            upstream,
+            ResolverConfig {
+                debug_trace: true,
+                ..Default::default()
+            },
+        );
+        let peer = SocketAddr::new(Ipv4Addr::new(192, 0, 2, 100).into(), 5353);
+        let request = build_request(peer, "www.example.com.__trace.");
+
+        let response = resolver.handle(&request).await.unwrap();
+        let response = Message::from_vec(&response).unwrap();
+
+        assert_eq!(response.answers()[0].record_type(), RecordType::A);
+    }
+
+    #[tokio::test]
+    async fn pass_through_extended_errors() {
+        let upstream = build_upstream();
+        let mut response = Message::new();
+        response.set_response_code(ResponseCode::ServFail);
+        // EDE 6 (DNSSEC Bogus)
+        response
+            .extensions_mut()
+            .get_or_insert_with(Edns::new)
+            .options_mut()
+            .insert(EdnsOption::Unknown(
+                edns::EDE_CODE,
+                b"\x00\x06signature expired".to_vec(),
+            ));
+        upstream.set_response("bogus.example.com.", RecordType::A, response);
+        let resolver = build_resolver(upstream, ResolverConfig::default());
+        let peer = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 5353);
+        let request = build_request(peer, "bogus.example.com.");
+
+        let response = resolver.handle(&request).await.unwrap();
+
+        assert_eq!(
+            Message::from_vec(&response).unwrap().response_code(),
+            ResponseCode::ServFail
+        );
+        assert_eq!(
+            edns::extended_errors(&response),
+            [edns::ExtendedError {
+                info_code: 6,
+                extra_text: "signature expired".to_string(),
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn reject_unsupported_queries() {
+        let upstream = build_upstream();
+        let resolver = build_resolver(upstream.clone(), ResolverConfig::default());
+        let peer = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 5353);
+
+        let mut update = build_query(1, "www.example.com.");
+        update.set_op_code(OpCode::Update);
+        let request = Request {
+            id: 0,
+            stamp: Instant::now(),
+            deadline: None,
+            peer,
+            data: update.to_vec().unwrap().as_slice().into(),
+        };
+        let response = resolver.handle(&request).await.unwrap();
+        let response = Message::from_vec(&response).unwrap();
+        assert_eq!(response.id(), update.id());
+        assert_eq!(response.message_type(), MessageType::Response);
+        assert_eq!(response.response_code(), ResponseCode::NotImp);
+        assert_eq!(upstream.calls(), 0);
+
+        // Standard queries are still forwarded.
+        let request = build_request(peer, "www.example.com.");
+        let response = resolver.handle(&request).await.unwrap();
+        assert_eq!(Message::from_vec(&response).unwrap().answer_count(), 1);
+        assert_eq!(upstream.calls(), 1);
+    }
+
+    #[tokio::test]
+    async fn answer_queries_without_question() {
+        let upstream = build_upstream();
+        let resolver = build_resolver(upstream.clone(), ResolverConfig::default());
+        let mut query = Message::new();
+        query.set_id(0x1234).set_recursion_desired(true);
+        let request = Request {
+            id: 0,
+            stamp: Instant::now(),
+            deadline: None,
+            peer: SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 5353),
+            data: query.to_vec().unwrap().as_slice().into(),
+        };
+
+        let response = resolver.handle(&request).await.unwrap();
+
+        let response = Message::from_vec(&response).unwrap();
+        assert_eq!(response.id(), 0x1234);
+        assert_eq!(response.message_type(), MessageType::Response);
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert!(response.recursion_desired());
+        assert_eq!(response.query_count(), 0);
+        assert_eq!(response.answer_count(), 0);
+        assert_eq!(upstream.calls(), 0);
+    }
+
+    #[tokio::test]
+    async fn reject_multiple_questions() {
+        let upstream = build_upstream();
+        upstream.set_answer("www.example.net.", RData::A(A::new(192, 0, 2, 2)));
+        let resolver = build_resolver(upstream.clone(), ResolverConfig::default());
+        let peer = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 5353);
+
+        let mut query = build_query(0x1234, "www.example.com.");
+        query.add_query(Query::query(
+            "www.example.net.".parse().unwrap(),
+            RecordType::A,
+        ));
+        let request = Request {
+            id: 0,
+            stamp: Instant::now(),
+            deadline: None,
+            peer,
+            data: query.to_vec().unwrap().as_slice().into(),
+        };
+
+        let response = resolver.handle(&request).await.unwrap();
+
+        let response = Message::from_vec(&response).unwrap();
+        assert_eq!(response.id(), 0x1234);
+        assert_eq!(response.response_code(), ResponseCode::FormErr);
+        assert_eq!(response.answer_count(), 0);
+        assert_eq!(upstream.calls(), 0);
+    }
+
+    #[tokio::test]
+    async fn answer_version_query() {
+        let upstream = build_upstream();
+        upstream.set_response("version.bind.", RecordType::TXT, Message::new());
+        let config = ResolverConfig {
+            identity: Some(ServerIdentity {
+                version: Some("aufloes 1.2.3".to_string()),
+                hostname: None,
+            }),
+            ..Default::default()
+        };
+        let resolver = build_resolver(upstream.clone(), config);
+        let peer = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 5353);
+
+        let mut responses = Vec::new();
+        for class in [DNSClass::CH, DNSClass::IN] {
+            let mut question = Query::query("version.bind.".parse().unwrap(), RecordType::TXT);
+            question.set_query_class(class);
+            let mut query = Message::new();
+            query.add_query(question);
+            let request = Request {
+                id: 0,
+                stamp: Instant::now(),
+                deadline: None,
+                peer,
+                data: query.to_vec().unwrap().as_slice().into(),
+            };
+            let response = resolver.handle(&request).await.unwrap();
+            responses.push(Message::from_vec(&response).unwrap());
+        }
+
+        let Some(RData::TXT(txt)) = responses[0].answers()[0].data() else {
+            panic!("expected TXT record");
+        };
+        assert_eq!(txt.to_string(), "aufloes 1.2.3");
+        // Only the IN-class query is forwarded.
+        assert_eq!(responses[1].answer_count(), 0);
+        assert_eq!(upstream.calls(), 1);
+    }
+
+    #[tokio::test]
+    async fn answer_from_reverse_zone() {
+        let upstream = build_upstream();
+        let zone = env::temp_dir().join(format!("aufloes-reverse-zone-{}", process::id()));
+        std::fs::write(
+            &zone,
+            "192.168.1.10 nas.home.arpa\nfd00::10 printer.home.arpa\n",
+        )
+        .unwrap();
+        let config = ResolverConfig {
+            reverse_zone: Some(zone.clone()),
+            ..Default::default()
+        };
+        let resolver = build_resolver(upstream.clone(), config);
+        resolver.load_reverse_zone().unwrap();
+        let _ = std::fs::remove_file(zone);
+        let peer = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 5353);
+
+        let ptr_request = |ip: IpAddr| {
+            let mut query = Message::new();
+            query
+                .set_id(0x1234)
+                .add_query(Query::query(ip.into(), RecordType::PTR));
+            let mut request = build_request(peer, "www.example.com.");
+            request.data = query.to_vec().unwrap().as_slice().into();
+            request
+        };
+        for (ip, name) in [
+            ("192.168.1.10", "nas.home.arpa."),
+            ("fd00::10", "printer.home.arpa."),
+        ] {
+            let request = ptr_request(ip.parse().unwrap());
+            let response = Message::from_vec(&resolver.handle(&request).await.unwrap()).unwrap();
+            assert_eq!(response.id(), 0x1234);
+            assert_eq!(
+                response.answers()[0].data(),
+                Some(&RData::PTR(PTR(name.parse().unwrap())))
+            );
+        }
+        assert_eq!(upstream.calls(), 0);
+
+        // Unknown addresses are forwarded.
+        let _ = resolver
+            .handle(&ptr_request("192.168.1.11".parse().unwrap()))
+            .await;
+        assert_eq!(upstream.calls(), 1);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn reload_reverse_zone_on_hangup() {
+        let zone = env::temp_dir().join(format!("aufloes-reload-zone-{}", process::id()));
+        std::fs::write(&zone, "192.168.1.10 nas.home.arpa\n").unwrap();
+        let config = ResolverConfig {
+            reverse_zone: Some(zone.clone()),
+            ..Default::default()
+        };
+        let resolver = Arc::new(build_resolver(build_upstream(), config));
+        resolver.load_reverse_zone().unwrap();
+        let hangups = signal(SignalKind::hangup()).unwrap();
+        tokio::spawn(reload_on_hangup(resolver.clone(), hangups));
+
+        let names = |ip: [u8; 4]| {
+            let name = Name::from(Ipv4Addr::from(ip));
+            resolver
+                .reverse_zone
+                .read()
+                .unwrap()
+                .as_ref()
+                .and_then(|zone| zone.lookup(&name).map(<[Name]>::to_vec))
+        };
+        assert!(names([192, 168, 1, 10]).is_some());
+        assert!(names([192, 168, 1, 11]).is_none());
+
+        // An address added to the file is answered after SIGHUP.
+        std::fs::write(
+            &zone,
+            "192.168.1.10 nas.home.arpa\n192.168.1.11 tv.home.arpa\n",
+        )
+        .unwrap();
+        let status = std::process::Command::new("kill")
+            .args(["-HUP", &process::id().to_string()])
+            .status()
+            .unwrap();
+        assert!(status.success());
+        for _ in 0..100 {
+            if names([192, 168, 1, 11]).is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(
+            names([192, 168, 1, 11]),
+            Some(vec!["tv.home.arpa.".parse().unwrap()])
+        );
+
+        // Invalid files are not loaded, keeping the current zone.
+        std::fs::write(&zone, "invalid\n").unwrap();
+        assert!(resolver.load_reverse_zone().is_err());
+        assert!(names([192, 168, 1, 11]).is_some());
+        let _ = std::fs::remove_file(zone);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn reload_local_zone_on_hangup() {
+        let zone = env::temp_dir().join(format!("aufloes-reload-local-zone-{}", process::id()));
+        let zone_file = |records: &str| {
+            format!(
+                "$ORIGIN home.arpa.\n\
+                 @   3600 IN SOA ns admin 1 7200 3600 1209600 300\n\
+                 {}",
+                records
+            )
+        };
+        std::fs::write(&zone, zone_file("nas 3600 IN A 192.168.1.10\n")).unwrap();
+        let config = ResolverConfig {
+            local_zone: Some(zone.clone()),
+            ..Default::default()
+        };
+        let resolver = Arc::new(build_resolver(build_upstream(), config));
+        resolver.load_local_zone().unwrap();
+        let hangups = signal(SignalKind::hangup()).unwrap();
+        tokio::spawn(reload_on_hangup(resolver.clone(), hangups));
+
+        let answers = |name: &str| {
+            let query = build_query(0x1234, name);
+            let zone = resolver.local_zone.read().unwrap().clone().unwrap();
+            let response = local_zone::answer(&query, &zone).unwrap().unwrap();
+            Message::from_vec(&response).unwrap().answer_count()
+        };
+        assert_eq!(answers("nas.home.arpa."), 1);
+        assert_eq!(answers("tv.home.arpa."), 0);
+
+        // A name added to the file is answered after SIGHUP.
+        std::fs::write(
+            &zone,
+            zone_file("nas 3600 IN A 192.168.1.10\ntv 3600 IN A 192.168.1.11\n"),
+        )
+        .unwrap();
+        let status = std::process::Command::new("kill")
+            .args(["-HUP", &process::id().to_string()])
+            .status()
+            .unwrap();
+        assert!(status.success());
+        for _ in 0..100 {
+            if answers("tv.home.arpa.") == 1 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(answers("tv.home.arpa."), 1);
+
+        // Invalid files are not loaded, keeping the current zone.
+        std::fs::write(&zone, "invalid\n").unwrap();
+        assert!(resolver.load_local_zone().is_err());
+        assert_eq!(answers("tv.home.arpa."), 1);
+        let _ = std::fs::remove_file(zone);
+    }
+
+    #[test]
+    fn check_configs() {
+        assert!(check_config(&ResolverConfig::default()).is_ok());
+
+        let config = ResolverConfig {
+            dns64: Some("64:ff9b::/50".parse().unwrap()),
+            ..Default::default()
+        };
+        let err = check_config(&config).unwrap_err();
+        assert!(
+            err.to_string().contains("DNS64 prefix length /50"),
+            "{}",
+            err
+        );
+
+        let zone = env::temp_dir().join(format!("aufloes-check-config-{}", process::id()));
+        let config = ResolverConfig {
+            reverse_zone: Some(zone.clone()),
+            ..Default::default()
+        };
+        assert!(check_config(&config).is_err());
+        std::fs::write(&zone, "192.168.1.10 nas.home.arpa\n").unwrap();
+        assert!(check_config(&config).is_ok());
+        let _ = std::fs::remove_file(zone);
+
+        let config = ResolverConfig {
+            pcap: Some(env::temp_dir().join("nonexistent-aufloes-dir/queries.pcap")),
+            ..Default::default()
+        };
+        assert!(check_config(&config).is_err());
+        let pcap = env::temp_dir().join(format!("aufloes-check-config-{}.pcap", process::id()));
+        let config = ResolverConfig {
+            pcap: Some(pcap.clone()),
+            ..Default::default()
+        };
+        assert!(check_config(&config).is_ok());
+        // Checking doesn't create the capture file.
+        assert!(!pcap.exists());
+    }
+
+    #[tokio::test]
+    async fn answer_from_local_zone() {
+        let upstream = build_upstream();
+        let zone = env::temp_dir().join(format!("aufloes-local-zone-{}", process::id()));
+        std::fs::write(
+            &zone,
+            "$ORIGIN home.arpa.\n\
+             @   3600 IN SOA ns admin 1 7200 3600 1209600 300\n\
+             nas 3600 IN A   192.168.1.10\n",
+        )
+        .unwrap();
+        let config = ResolverConfig {
+            local_zone: Some(zone.clone()),
+            ..Default::default()
+        };
+        assert!(check_config(&config).is_ok());
+        let resolver = build_resolver(upstream.clone(), config);
+        resolver.load_local_zone().unwrap();
+        let _ = std::fs::remove_file(zone);
+        let peer = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 5353);
+
+        let request = build_request(peer, "nas.home.arpa.");
+        let response = Message::from_vec(&resolver.handle(&request).await.unwrap()).unwrap();
+        assert_eq!(response.id(), 0x1234);
+        assert!(response.authoritative());
+        assert_eq!(
+            response.answers()[0].data(),
+            Some(&RData::A(A::new(192, 168, 1, 10)))
+        );
+        let request = build_request(peer, "unknown.home.arpa.");
+        let response = Message::from_vec(&resolver.handle(&request).await.unwrap()).unwrap();
+        assert_eq!(response.response_code(), ResponseCode::NXDomain);
+        assert_eq!(upstream.calls(), 0);
+
+        // Names outside of the zone are forwarded.
+        let request = build_request(peer, "www.example.com.");
+        let response = Message::from_vec(&resolver.handle(&request).await.unwrap()).unwrap();
+        assert!(!response.authoritative());
+        assert_eq!(response.answer_count(), 1);
+        assert_eq!(upstream.calls(), 1);
+    }
+
+    #[tokio::test]
+    async fn local_answers_shadow_upstream() {
+        let upstream = build_upstream();
+        for name in ["10.1.168.192.in-addr.arpa.", "11.2.168.192.in-addr.arpa."] {
+            upstream.set_answer(
+                name,
+                RData::PTR(PTR("upstream.example.com.".parse().unwrap())),
+            );
+        }
+        let local_zone = env::temp_dir().join(format!("aufloes-shadow-local-{}", process::id()));
+        std::fs::write(
+            &local_zone,
+            "$ORIGIN 1.168.192.in-addr.arpa.\n\
+             @  3600 IN SOA ns.home.arpa. admin.home.arpa. 1 7200 3600 1209600 300\n\
+             10 3600 IN PTR local.home.arpa.\n",
+        )
+        .unwrap();
+        let reverse_zone =
+            env::temp_dir().join(format!("aufloes-shadow-reverse-{}", process::id()));
+        std::fs::write(
+            &reverse_zone,
+            "192.168.1.10 reverse.home.arpa\n\
+             192.168.1.11 reverse.home.arpa\n\
+             192.168.2.11 reverse.home.arpa\n",
+        )
+        .unwrap();
+        let config = ResolverConfig {
+            local_zone: Some(local_zone.clone()),
+            reverse_zone: Some(reverse_zone.clone()),
+            ..Default::default()
+        };
+        let resolver = build_resolver(upstream.clone(), config);
+        resolver.load_local_zone().unwrap();
+        resolver.load_reverse_zone().unwrap();
+        let _ = std::fs::remove_file(local_zone);
+        let _ = std::fs::remove_file(reverse_zone);
+        let peer = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 5353);
+        let resolve = |ip: &str| {
+            let mut query = Message::new();
+            query.set_id(0x1234).add_query(Query::query(
+                ip.parse::<IpAddr>().unwrap().into(),
+                RecordType::PTR,
+            ));
+            let mut request = build_request(peer, "www.example.com.");
+            request.data = query.to_vec().unwrap().as_slice().into();
+            let resolver = &resolver;
+            async move { Message::from_vec(&resolver.handle(&request).await.unwrap()).unwrap() }
+        };
+        let ptr = |name: &str| Some(RData::PTR(PTR(name.parse().unwrap())));
+
+        // The local zone takes precedence over the reverse zone and the upstream, also for names without records.
+        let response = resolve("192.168.1.10").await;
+        assert_eq!(
+            response.answers()[0].data(),
+            ptr("local.home.arpa.").as_ref()
+        );
+        let response = resolve("192.168.1.11").await;
+        assert_eq!(response.response_code(), ResponseCode::NXDomain);
+        // The reverse zone takes precedence over the upstream.
+        let response = resolve("192.168.2.11").await;
+        assert_eq!(
+            response.answers()[0].data(),
+            ptr("reverse.home.arpa.").as_ref()
+        );
+        assert_eq!(upstream.calls(), 0);
+    }
+
+    /// Socket that sends at most `max_len` bytes per datagram, or blocks forever if `None`.
+    struct MockSocket {
+        max_len: Option<usize>,
+    }
+
+    #[async_trait::async_trait]
+    impl DownstreamSocket for MockSocket {
+        async fn send_to(&self, data: &[u8], _peer: SocketAddr) -> io::Result<usize> {
+            match self.max_len {
+                Some(max_len) => Ok(data.len().min(max_len)),
+                None => std::future::pending().await,
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn handle_failed_sends() {
+        let request = build_request(
+            SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 5353),
+            "www.example.com.",
+        );
+        let data = [0; 100];
+        assert!(send_response(&MockSocket { max_len: Some(100) }, &request, &data).await);
+        // Short writes and blocked sockets don't panic or hang.
+        assert!(!send_response(&MockSocket { max_len: Some(50) }, &request, &data).await);
+        let stamp = tokio::time::Instant::now();
+        assert!(!send_response(&MockSocket { max_len: None }, &request, &data).await);
+        assert_eq!(stamp.elapsed(), SEND_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn add_edns() {
+        let upstream = MockClient::new();
+        let mut response = Message::new();
+        response
+            .add_answer(Record::from_rdata(
+                "www.example.com.".parse().unwrap(),
+                300,
+                RData::A(A::new(192, 0, 2, 1)),
+            ))
+            .set_edns(Edns::new());
+        upstream.set_response("www.example.com.", RecordType::A, response);
+        let upstream = Arc::new(upstream);
+        let config = ResolverConfig {
+            add_edns: true,
+            ..Default::default()
+        };
+        let resolver = build_resolver(upstream.clone(), config);
+        let peer = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 5353);
+
+        let request = build_request(peer, "www.example.com.");
+        let response = resolver.handle(&request).await.unwrap();
+        let upstream_query = &upstream.queries()[0];
+        assert_eq!(
+            edns::opt_header(upstream_query).map(|opt| opt.udp_payload_size),
+            Some(edns::DEFAULT_UDP_PAYLOAD_SIZE)
+        );
+        assert!(edns::find_opt(&response).is_none());
+        let response = Message::from_vec(&response).unwrap();
+        assert_eq!(response.id(), 0x1234);
+        assert_eq!(response.answer_count(), 1);
+
+        // The OPT record of downstream peers with EDNS support is forwarded unchanged.
+        let mut request = build_request(peer, "www.example.com.");
+        let mut data = request.data.to_vec();
+        edns::tests::append_opt(&mut data, &[]);
+        request.data = data.as_slice().into();
+        let response = resolver.handle(&request).await.unwrap();
+        assert_eq!(upstream.queries()[1], request.data);
+        assert!(edns::find_opt(&response).is_some());
+    }
+
+    #[tokio::test]
+    async fn no_recursion() {
+        let upstream = build_upstream();
+        let config = ResolverConfig {
+            no_recursion: true,
+            identity: Some(ServerIdentity {
+                version: Some("aufloes 1.2.3".to_string()),
+                hostname: None,
+            }),
+            ..Default::default()
+        };
+        let resolver = build_resolver(upstream.clone(), config);
+        let peer = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 5353);
+
+        let response = resolver
+            .handle(&build_request(peer, "www.example.com."))
+            .await
+            .unwrap();
+        let response = Message::from_vec(&response).unwrap();
+        assert_eq!(response.id(), 0x1234);
+        assert_eq!(response.response_code(), ResponseCode::Refused);
+        assert!(!response.recursion_available());
+        assert_eq!(response.queries()[0].name().to_ascii(), "www.example.com.");
+
+        // Queries that can be answered locally are still answered.
+        let mut question = Query::query("version.bind.".parse().unwrap(), RecordType::TXT);
+        question.set_query_class(DNSClass::CH);
+        let mut query = Message::new();
+        query.add_query(question);
+        let mut request = build_request(peer, "www.example.com.");
+        request.data = query.to_vec().unwrap().as_slice().into();
+        let response = Message::from_vec(&resolver.handle(&request).await.unwrap()).unwrap();
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert_eq!(response.answer_count(), 1);
+        assert_eq!(upstream.calls(), 0);
+    }
+
+    #[tokio::test]
+    async fn forward_root_queries() {
+        let upstream = build_upstream();
+        let mut soa_response = Message::new();
+        soa_response.add_answer(Record::from_rdata(
+            Name::root(),
+            86400,
+            RData::SOA(SOA::new(
+                "a.root-servers.net.".parse().unwrap(),
+                "nstld.verisign-grs.com.".parse().unwrap(),
+                1,
+                1800,
+                900,
+                604800,
+                86400,
+            )),
+        ));
+        upstream.set_response(".", RecordType::SOA, soa_response);
+        upstream.set_answer(".", RData::NS(NS("a.root-servers.net.".parse().unwrap())));
+        // There are no A records at the root, the upstream answers NODATA.
+        upstream.set_response(".", RecordType::A, Message::new());
+        let resolver = build_resolver(upstream.clone(), ResolverConfig::default());
+        let peer = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 5353);
+
+        for query_type in [RecordType::NS, RecordType::SOA, RecordType::A] {
+            let mut query = Message::new();
+            query
+                .set_id(42)
+                .add_query(Query::query(Name::root(), query_type));
+            let request = Request {
+                id: 0,
+                stamp: Instant::now(),
+                deadline: None,
+                peer,
+                data: query.to_vec().unwrap().as_slice().into(),
+            };
+
+            let response = resolver.handle(&request).await.unwrap();
+            let response = Message::from_vec(&response).unwrap();
+            assert_eq!(response.id(), 42);
+            assert_eq!(response.queries(), query.queries());
+            let expected_answers = usize::from(query_type != RecordType::A);
+            assert_eq!(response.answers().len(), expected_answers);
+            if let Some(answer) = response.answers().first() {
+                assert!(answer.name().is_root());
+                assert_eq!(answer.record_type(), query_type);
+            }
+        }
+        assert_eq!(upstream.calls(), 3);
+    }
+
+    #[tokio::test]
+    async fn preserve_edns() {
+        let upstream = build_upstream();
+        let mut response = Message::new();
+        response.add_answer(Record::from_rdata(
+            "www.example.com.".parse().unwrap(),
+            300,
+            RData::A(A::new(192, 0, 2, 1)),
+        ));
+        let edns = response.extensions_mut().get_or_insert_with(Edns::new);
+        edns.set_dnssec_ok(true).set_max_payload(1232);
+        edns.options_mut()
+            .insert(EdnsOption::Unknown(EDNS_PADDING_CODE, vec![0; 64]));
+        upstream.set_response("www.example.com.", RecordType::A, response);
+        let resolver = build_resolver(upstream.clone(), ResolverConfig::default());
+
+        let mut request = build_request(
+            SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 5353),
+            "www.example.com.",
+        );
+        let mut data = request.data.to_vec();
+        edns::tests::append_opt(&mut data, &[(EDNS_PADDING_CODE, &[0; 32])]);
+        request.data = data.as_slice().into();
+
+        let response = resolver.handle(&request).await.unwrap();
+
+        // The OPT record of the query is forwarded unmodified.
+        let query_opt = edns::find_opt(&request.data).unwrap();
+        let upstream_query = &upstream.queries()[0];
+        let upstream_opt = edns::find_opt(upstream_query).unwrap();
+        assert_eq!(
+            request.data[query_opt.range],
+            upstream_query[upstream_opt.range]
+        );
+
+        // The OPT record of the response is returned unmodified.
+        assert_eq!(txid_from_binary_message(&response), Some(0x1234));
+        let opt = edns::opt_header(&response).unwrap();
+        assert!(opt.dnssec_ok);
+        assert_eq!(opt.udp_payload_size, 1232);
+        assert_eq!(
+            edns::options(&response).unwrap(),
+            [edns::EdnsOption {
+                code: EDNS_PADDING_CODE,
+                data: &[0; 64],
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn ecs_strip() {
+        let upstream = build_upstream();
+        let resolver = build_resolver(upstream.clone(), ResolverConfig::default());
+        let peer = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 5353);
+
+        let response = resolver
+            .handle(&build_request_with_ecs(peer, "www.example.com."))
+            .await
+            .unwrap();
+
+        assert_eq!(Message::from_vec(&response).unwrap().answer_count(), 1);
+        assert!(upstream_ecs_options(&upstream).is_empty());
+        // Other options are retained.
+        let queries = upstream.queries();
+        let options = edns::options(&queries[0]).unwrap();
+        assert_eq!(options.len(), 1);
+        assert_eq!(options[0].code, EDNS_PADDING_CODE);
+    }
+
+    #[tokio::test]
+    async fn ecs_keep() {
+        let upstream = build_upstream();
+        let config = ResolverConfig {
+            ecs: EcsPolicy::Keep,
+            ..Default::default()
+        };
+        let resolver = build_resolver(upstream.clone(), config);
+        let peer = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 5353);
+
+        resolver
+            .handle(&build_request_with_ecs(peer, "www.example.com."))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            upstream_ecs_options(&upstream),
+            [vec![0, 1, 24, 0, 198, 51, 100]]
+        );
+    }
+
+    #[tokio::test]
+    async fn ecs_set() {
+        let config = ResolverConfig {
+            ecs: "set=192.0.2.0/24".parse().unwrap(),
+            ..Default::default()
+        };
+        let peer = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 5353);
+
+        // The configured subnet replaces the one sent by the downstream, and is added to queries without EDNS.
+        for request in [
+            build_request_with_ecs(peer, "www.example.com."),
+            build_request(peer, "www.example.com."),
+        ] {
+            let upstream = build_upstream();
+            let resolver = build_resolver(upstream.clone(), config.clone());
+            resolver.handle(&request).await.unwrap();
+            assert_eq!(
+                upstream_ecs_options(&upstream),
+                [vec![0, 1, 24, 0, 192, 0, 2]]
+            );
+        }
+    }
+
+    #[test]
+    fn parse_ecs_policy() {
+        assert_eq!("strip".parse(), Ok(EcsPolicy::Strip));
+        assert_eq!("keep".parse(), Ok(EcsPolicy::Keep));
+        assert_eq!(
+            "set=2001:db8::/48".parse(),
+            Ok(EcsPolicy::Set(ClientSubnet {
+                addr: "2001:db8::".parse().unwrap(),
+                prefix_len: 48,
+            }))
+        );
+        assert!("set=2001:db8::".parse::<EcsPolicy>().is_err());
+        assert!("drop".parse::<EcsPolicy>().is_err());
+    }
+
+    #[test]
+    fn response_jitter() {
+        let resolver = build_resolver(build_upstream(), ResolverConfig::default());
+        assert_eq!(resolver.response_delay(), Duration::ZERO);
+
+        let jitter = Duration::from_millis(20);
+        let resolver = build_resolver(
+            build_upstream(),
+            ResolverConfig {
+                response_jitter: Some(jitter),
+                ..Default::default()
+            },
+        );
+        let delays = (0..1000)
+            .map(|_| resolver.response_delay())
+            .collect::<Vec<_>>();
+        assert!(delays.iter().all(|delay| *delay <= jitter));
+        // The delays are actually randomized.
+        assert!(delays.iter().any(|delay| *delay < jitter / 2));
+        assert!(delays.iter().any(|delay| *delay > jitter / 2));
+    }
+
+    #[tokio::test]
+    async fn min_ttl() {
+        let upstream = build_upstream();
+        let mut response = Message::new();
+        for (ttl, ip) in [(1, 1), (0, 2), (3600, 3)] {
+            response.add_answer(Record::from_rdata(
+                "short.example.com.".parse().unwrap(),
+                ttl,
+                RData::A(A::new(192, 0, 2, ip)),
+            ));
+        }
+        upstream.set_response("short.example.com.", RecordType::A, response);
+        let config = ResolverConfig {
+            min_ttl: Some(60),
+            ..Default::default()
+        };
+        let resolver = build_resolver(upstream, config);
+        let peer = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 5353);
+        let request = build_request(peer, "short.example.com.");
+
+        let response = resolver.handle(&request).await.unwrap();
+        let response = Message::from_vec(&response).unwrap();
+
+        assert_eq!(response.id(), 0x1234);
+        let ttls = response
+            .answers()
+            .iter()
+            .map(|record| record.ttl())
+            .collect::<Vec<_>>();
+        assert_eq!(ttls, [60, 60, 3600]);
+    }
+
+    #[tokio::test]
+    async fn track_upstream_health() {
+        let upstream = build_upstream();
+        let resolver = build_resolver(upstream.clone(), ResolverConfig::default());
+        resolver.health.record_sockets_bound();
+        let peer = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 5353);
+
+        upstream.set_failing(true);
+        for id in 0..3 {
+            let request = build_request_with_id(peer, id, "www.example.com.");
+            assert!(resolver.handle(&request).await.is_err());
+        }
+        assert!(!resolver.health.is_ready());
+
+        upstream.set_failing(false);
+        resolver
+            .handle(&build_request(peer, "www.example.com."))
+            .await
+            .unwrap();
+        assert!(resolver.health.is_ready());
+    }
+
+    #[tokio::test]
+    async fn pass_through_checking_disabled() {
+        let upstream = build_upstream();
+        let resolver = build_resolver(upstream.clone(), ResolverConfig::default());
+        let peer = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 5353);
+        let mut request = build_request(peer, "www.example.com.");
+        let mut query = Message::from_vec(&request.data).unwrap();
+        query.set_checking_disabled(true);
+        request.data = query.to_vec().unwrap().as_slice().into();
+
+        resolver.handle(&request).await.unwrap();
+
+        // The upstream validates (or not) as requested by the client, so the CD bit is forwarded as is.
+        let forwarded = Header::parse(&upstream.queries()[0]).unwrap();
+        assert!(forwarded.checking_disabled());
+    }
+
+    #[tokio::test]
+    async fn max_ttl() {
+        let upstream = build_upstream();
+        let mut response = Message::new();
+        for (ttl, ip) in [(604800, 1), (300, 2)] {
+            response.add_answer(Record::from_rdata(
+                "long.example.com.".parse().unwrap(),
+                ttl,
+                RData::A(A::new(192, 0, 2, ip)),
+            ));
+        }
+        upstream.set_response("long.example.com.", RecordType::A, response);
+        let config = ResolverConfig {
+            max_ttl: Some(3600),
+            ..Default::default()
+        };
+        let resolver = build_resolver(upstream, config);
+        let peer = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 5353);
+        let request = build_request(peer, "long.example.com.");
+
+        let response = resolver.handle(&request).await.unwrap();
+        let response = Message::from_vec(&response).unwrap();
+
+        let ttls = response
+            .answers()
+            .iter()
+            .map(|record| record.ttl())
+            .collect::<Vec<_>>();
+        assert_eq!(ttls, [3600, 300]);
+    }
+
+    #[tokio::test]
+    async fn ttl_overrides() {
+        let upstream = build_upstream();
+        let mut response = Message::new();
+        response
+            .add_answer(Record::from_rdata(
+                "cdn.example.com.".parse().unwrap(),
+                3600,
+                RData::A(A::new(192, 0, 2, 1)),
+            ))
+            .add_name_server(Record::from_rdata(
+                "example.com.".parse().unwrap(),
+                86400,
+                RData::NS(NS("ns.example.com.".parse().unwrap())),
+            ));
+        upstream.set_response("cdn.example.com.", RecordType::A, response);
+        let config = ResolverConfig {
+            // The override takes precedence over the minimum TTL.
+            min_ttl: Some(120),
+            ttl_overrides: HashMap::from([(RecordType::A, 60), (RecordType::MX, 3600)]),
+            ..Default::default()
+        };
+        let resolver = build_resolver(upstream, config);
+        let peer = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 5353);
+        let request = build_request(peer, "cdn.example.com.");
+
+        let response = resolver.handle(&request).await.unwrap();
+        let response = Message::from_vec(&response).unwrap();
+        assert_eq!(response.answers()[0].ttl(), 60);
+        // Records of other types keep their TTL.
+        assert_eq!(response.name_servers()[0].ttl(), 86400);
+    }
+
+    #[tokio::test]
+    async fn strip_types() {
+        let upstream = build_upstream();
+        let mut response = Message::new();
+        response
+            .add_answer(Record::from_rdata(
+                "dual.example.com.".parse().unwrap(),
+                300,
+                RData::A(A::new(192, 0, 2, 1)),
+            ))
+            .add_answer(Record::from_rdata(
+                "dual.example.com.".parse().unwrap(),
+                300,
+                RData::AAAA(AAAA::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)),
+            ));
+        upstream.set_response("dual.example.com.", RecordType::A, response);
+        let config = ResolverConfig {
+            strip_types: HashSet::from([RecordType::AAAA]),
+            ..Default::default()
+        };
+        let resolver = build_resolver(upstream, config);
+        let peer = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 5353);
+        let request = build_request(peer, "dual.example.com.");
+
+        let response = resolver.handle(&request).await.unwrap();
+        let response = Message::from_vec(&response).unwrap();
+
+        assert_eq!(response.id(), 0x1234);
+        assert_eq!(response.answer_count(), 1);
+        assert_eq!(
+            response.answers()[0].data(),
+            Some(&RData::A(A::new(192, 0, 2, 1)))
+        );
+    }
+
+    #[tokio::test]
+    async fn coalesce_identical_queries() {
+        let upstream = build_upstream();
+        upstream.set_delay(Duration::from_millis(100));
+        let resolver = Arc::new(build_resolver(upstream.clone(), ResolverConfig::default()));
+        let peer = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 5353);
+
+        let tasks = (0..50)
+            .map(|txid| {
+                let resolver = resolver.clone();
+                let request = build_request_with_id(peer, txid, "www.example.com.");
+                tokio::spawn(async move { resolver.handle(&request).await })
+            })
+            .collect::<Vec<_>>();
+
+        for (txid, task) in tasks.into_iter().enumerate() {
+            let response = task.await.unwrap().unwrap();
+            let response = Message::from_vec(&response).unwrap();
+            assert_eq!(response.id(), txid as u16);
+            assert_eq!(response.answer_count(), 1);
+        }
+        assert_eq!(upstream.calls(), 1);
+        assert_eq!(resolver.stats.snapshot().coalesced, 49);
+    }
+
+    #[tokio::test]
+    async fn dont_coalesce_different_dnssec_flags() {
+        let upstream = build_upstream();
+        upstream.set_delay(Duration::from_millis(100));
+        let resolver = Arc::new(build_resolver(upstream.clone(), ResolverConfig::default()));
+        let peer = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 5353);
+
+        // Responses to queries with the DO or CD bit set may differ (e.g. include DNSSEC records), so they must not be
+        // shared with queries without them.
+        let mut queries = Vec::new();
+        for (dnssec_ok, checking_disabled) in [(false, false), (true, false), (false, true)] {
+            let mut query = build_query(1, "www.example.com.");
+            let mut edns = Edns::new();
+            edns.set_dnssec_ok(dnssec_ok);
+            query
+                .set_edns(edns)
+                .set_checking_disabled(checking_disabled);
+            queries.push(query);
+        }
+        let tasks = queries
+            .iter()
+            .map(|query| {
+                let resolver = resolver.clone();
+                let mut request = build_request(peer, "www.example.com.");
+                request.data = query.to_vec().unwrap().as_slice().into();
+                tokio::spawn(async move { resolver.handle(&request).await })
+            })
+            .collect::<Vec<_>>();
+        for task in tasks {
+            assert!(task.await.unwrap().is_ok());
+        }
+        assert_eq!(upstream.calls(), 3);
+        assert_eq!(resolver.stats.snapshot().coalesced, 0);
+
+        let mut flags = upstream
+            .queries()
+            .iter()
+            .map(|query| {
+                let header = Header::parse(query).unwrap();
+                let opt = edns::opt_header(query).unwrap();
+                (opt.dnssec_ok, header.checking_disabled())
+            })
+            .collect::<Vec<_>>();
+        flags.sort();
+        assert_eq!(flags, [(false, false), (false, true), (true, false)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn single_request() {
+        for single_request in [false, true] {
+            let upstream = build_upstream();
+            upstream.set_answer(
+                "www.example.com.",
+                RData::AAAA(AAAA::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)),
+            );
+            upstream.set_delay(Duration::from_millis(100));
+            let config = ResolverConfig {
+                single_request,
+                ..Default::default()
+            };
+            let resolver = Arc::new(build_resolver(upstream.clone(), config));
+            let peer = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 5353);
+
+            let start = tokio::time::Instant::now();
+            let tasks = [RecordType::A, RecordType::AAAA]
+                .into_iter()
+                .map(|query_type| {
+                    let resolver = resolver.clone();
+                    let mut query = build_query(1, "www.example.com.");
+                    query.queries_mut()[0].set_query_type(query_type);
+                    let mut request = build_request(peer, "www.example.com.");
+                    request.data = query.to_vec().unwrap().as_slice().into();
+                    tokio::spawn(async move { resolver.handle(&request).await })
+                })
+                .collect::<Vec<_>>();
+            for task in tasks {
+                let response = Message::from_vec(&task.await.unwrap().unwrap()).unwrap();
+                assert_eq!(response.answer_count(), 1);
+            }
+            assert_eq!(upstream.calls(), 2);
+            // With the option, the AAAA query is only sent once the A query has been answered.
+            let expected = if single_request { 200 } else { 100 };
+            assert_eq!(start.elapsed(), Duration::from_millis(expected));
+        }
+    }
+
+    #[tokio::test]
+    async fn reject_short_upstream_responses() {
+        let peer = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 5353);
+        for len in [0, 1] {
+            let upstream = build_upstream();
+            upstream.set_raw_response("www.example.com.", RecordType::A, &[0; 1][..len]);
+            let resolver = build_resolver(upstream, ResolverConfig::default());
+            let request = build_request(peer, "www.example.com.");
+            let err = resolver.handle(&request).await.unwrap_err();
+            assert!(err.to_string().contains("too short"), "{}", err);
+        }
+    }
+
+    #[tokio::test]
+    async fn coalesce_failures() {
+        let upstream = build_upstream();
+        upstream.set_delay(Duration::from_millis(100));
+        upstream.set_failing(true);
+        let resolver = Arc::new(build_resolver(upstream.clone(), ResolverConfig::default()));
+        let peer = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 5353);
+
+        let tasks = (0..2)
+            .map(|txid| {
+                let resolver = resolver.clone();
+                let request = build_request_with_id(peer, txid, "www.example.com.");
+                tokio::spawn(async move { resolver.handle(&request).await })
+            })
+            .collect::<Vec<_>>();
+        for task in tasks {
+            assert!(task.await.unwrap().is_err());
+        }
+        assert_eq!(upstream.calls(), 1);
+
+        // Once the failed query is no longer in flight, it is retried.
+        upstream.set_failing(false);
+        let request = build_request(peer, "www.example.com.");
+        assert!(resolver.handle(&request).await.is_ok());
+        assert_eq!(upstream.calls(), 2);
+    }
+
+    #[tokio::test]
+    async fn drop_responses() {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let server_addr = socket.local_addr().unwrap();
+        let resolver = Arc::new(build_resolver(build_upstream(), ResolverConfig::default()));
+        tokio::spawn(socket_handler(Arc::new(socket), resolver));
+
+        let client = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        client.connect(server_addr).await.unwrap();
+        let mut response = build_query(1, "www.example.com.");
+        response.set_message_type(MessageType::Response);
+        client.send(&response.to_vec().unwrap()).await.unwrap();
+        let query = build_query(2, "www.example.com.");
+        client.send(&query.to_vec().unwrap()).await.unwrap();
+
+        // Only the query is answered.
+        let mut buffer = [0; 512];
+        let n = client.recv(&mut buffer).await.unwrap();
+        assert_eq!(Message::from_vec(&buffer[..n]).unwrap().id(), 2);
+        let result =
+            tokio::time::timeout(Duration::from_millis(100), client.recv(&mut buffer)).await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn drop_short_datagrams() {
+        let upstream = build_upstream();
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let server_addr = socket.local_addr().unwrap();
+        let resolver = Arc::new(build_resolver(upstream.clone(), ResolverConfig::default()));
+        tokio::spawn(socket_handler(Arc::new(socket), resolver));
+
+        let client = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        client.connect(server_addr).await.unwrap();
+        client.send(&[0x12]).await.unwrap();
+        client.send(&[0; Header::LEN - 1]).await.unwrap();
+        let query = build_query(2, "www.example.com.");
+        client.send(&query.to_vec().unwrap()).await.unwrap();
+
+        // The resolver is still running, and only the query is answered.
+        let mut buffer = [0; 512];
+        let n = client.recv(&mut buffer).await.unwrap();
+        assert_eq!(Message::from_vec(&buffer[..n]).unwrap().id(), 2);
+        assert_eq!(upstream.calls(), 1);
+        let result =
+            tokio::time::timeout(Duration::from_millis(100), client.recv(&mut buffer)).await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn dns64() {
+        let upstream = build_upstream();
+        upstream.set_response("www.example.com.", RecordType::AAAA, Message::new());
+        upstream.set_answer(
+            "ipv6.example.com.",
+            RData::AAAA(AAAA::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)),
+        );
+        let resolver = build_resolver(
+            upstream.clone(),
+            ResolverConfig {
+                dns64: Some("64:ff9b::/96".parse().unwrap()),
+                ..Default::default()
+            },
+        );
+        let peer = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 5353);
+
+        let mut query = build_query(0x1234, "www.example.com.");
+        query.queries_mut()[0].set_query_type(RecordType::AAAA);
+        let request = Request {
+            data: query.to_vec().unwrap().as_slice().into(),
+            ..build_request(peer, "www.example.com.")
+        };
+        let response = resolver.handle(&request).await.unwrap();
+        let response = Message::from_vec(&response).unwrap();
+        assert_eq!(response.id(), 0x1234);
+        assert_eq!(response.queries()[0].query_type(), RecordType::AAAA);
+        assert_eq!(
+            response.answers()[0].data(),
+            Some(&RData::AAAA(
+                "64:ff9b::192.0.2.1".parse::<Ipv6Addr>().unwrap().into()
+            ))
+        );
+        assert_eq!(upstream.calls(), 2);
+
+        // Names with AAAA records are answered as is.
+        let mut query = build_query(0x1234, "ipv6.example.com.");
+        query.queries_mut()[0].set_query_type(RecordType::AAAA);
+        let request = Request {
+            data: query.to_vec().unwrap().as_slice().into(),
+            ..build_request(peer, "ipv6.example.com.")
+        };
+        let response = resolver.handle(&request).await.unwrap();
+        let response = Message::from_vec(&response).unwrap();
+        assert_eq!(
+            response.answers()[0].data(),
+            Some(&RData::AAAA(AAAA::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)))
+        );
+        assert_eq!(upstream.calls(), 3);
+    }
+
+    #[tokio::test]
+    async fn dns64_with_discovered_prefix() {
+        let upstream = build_upstream();
+        upstream.set_response("www.example.com.", RecordType::AAAA, Message::new());
+        upstream.set_answer(
+            "ipv4only.arpa.",
+            RData::AAAA(
+                "2001:db8:64::192.0.0.170"
+                    .parse::<Ipv6Addr>()
+                    .unwrap()
+                    .into(),
+            ),
+        );
+        let prefix = dns64::discover_nat64_prefix(&*upstream).await.unwrap();
+        assert_eq!(prefix, "2001:db8:64::/96".parse().unwrap());
+        let query = Message::from_vec(&upstream.queries()[0]).unwrap();
+        assert_eq!(query.queries()[0].name().to_ascii(), "ipv4only.arpa.");
+        assert_eq!(query.queries()[0].query_type(), RecordType::AAAA);
+
+        let resolver = build_resolver(
+            upstream.clone(),
+            ResolverConfig {
+                dns64: Some(prefix),
+                ..Default::default()
+            },
+        );
+        let peer = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 5353);
+        let mut query = build_query(0x1234, "www.example.com.");
+        query.queries_mut()[0].set_query_type(RecordType::AAAA);
+        let request = Request {
+            data: query.to_vec().unwrap().as_slice().into(),
+            ..build_request(peer, "www.example.com.")
+        };
+        let response = resolver.handle(&request).await.unwrap();
+        let response = Message::from_vec(&response).unwrap();
+        assert_eq!(
+            response.answers()[0].data(),
+            Some(&RData::AAAA(
+                "2001:db8:64::192.0.2.1".parse::<Ipv6Addr>().unwrap().into()
+            ))
+        );
+
+        // Without a DNS64 upstream, there is no prefix to discover.
+        let upstream = build_upstream();
+        assert!(dns64::discover_nat64_prefix(&*upstream).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn capture_to_pcap() {
+        let path = env::temp_dir().join(format!("aufloes-resolver-{}.pcap", process::id()));
+        let mut resolver = build_resolver(build_upstream(), ResolverConfig::default());
+        resolver.pcap = Some(PcapWriter::create(&path).unwrap());
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let server_addr = socket.local_addr().unwrap();
+        tokio::spawn(socket_handler(Arc::new(socket), Arc::new(resolver)));
+
+        let client = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        client.connect(server_addr).await.unwrap();
+        let mut buffer = [0; 512];
+        let mut exchanged = Vec::new();
+        for txid in 0..2 {
+            let query = build_query(txid, "www.example.com.").to_vec().unwrap();
+            client.send(&query).await.unwrap();
+            let n = client.recv(&mut buffer).await.unwrap();
+            exchanged.push(query);
+            exchanged.push(buffer[..n].to_vec());
+        }
+
+        // Each packet is a UDP datagram with an IPv4 header.
+        let packets = crate::resolver::pcap::tests::read_packets(&path);
+        assert_eq!(packets.len(), 4);
+        for (packet, message) in packets.iter().zip(exchanged) {
+            assert_eq!(packet[28..], message);
+        }
+        let client_port = client.local_addr().unwrap().port();
+        assert_eq!(packets[0][20..22], client_port.to_be_bytes());
+        assert_eq!(packets[1][22..24], client_port.to_be_bytes());
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn truncate_large_responses() {
+        let upstream = build_upstream();
+        let mut response = Message::new();
+        for i in 0..40 {
+            response.add_answer(Record::from_rdata(
+                "large.example.com.".parse().unwrap(),
+                300,
+                RData::A(A::new(192, 0, 2, i)),
+            ));
+        }
+        upstream.set_response("large.example.com.", RecordType::A, response);
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let server_addr = socket.local_addr().unwrap();
+        let resolver = Arc::new(build_resolver(upstream, ResolverConfig::default()));
+        tokio::spawn(socket_handler(Arc::new(socket), resolver));
+
+        let client = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        client.connect(server_addr).await.unwrap();
+        let mut buffer = [0; 4096];
+
+        // Without EDNS, the response is truncated to 512 bytes.
+        let query = build_query(0x1234, "large.example.com.");
+        client.send(&query.to_vec().unwrap()).await.unwrap();
+        let n = client.recv(&mut buffer).await.unwrap();
+        assert!(n <= 512);
+        let response = Message::from_vec(&buffer[..n]).unwrap();
+        assert_eq!(response.id(), 0x1234);
+        assert!(response.truncated());
+        assert!(response.answer_count() < 40);
+
+        // With EDNS, the response is forwarded as is.
+        let mut query = build_query(0x1235, "large.example.com.");
+        query.set_edns(Edns::new());
+        client.send(&query.to_vec().unwrap()).await.unwrap();
+        let n = client.recv(&mut buffer).await.unwrap();
+        assert!(n > 512);
+        let response = Message::from_vec(&buffer[..n]).unwrap();
+        assert!(!response.truncated());
+        assert_eq!(response.answer_count(), 40);
+    }
+
+    #[tokio::test]
+    async fn servfail_on_upstream_error() {
+        let upstream = build_upstream();
+        upstream.set_failing(true);
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let server_addr = socket.local_addr().unwrap();
+        let resolver = Arc::new(build_resolver(upstream, ResolverConfig::default()));
+        tokio::spawn(socket_handler(Arc::new(socket), resolver));
+
+        let client = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        client.connect(server_addr).await.unwrap();
+        let query = build_query(0x1234, "www.example.com.");
+        client.send(&query.to_vec().unwrap()).await.unwrap();
+
+        let mut buffer = [0; 512];
+        let n = tokio::time::timeout(Duration::from_secs(1), client.recv(&mut buffer))
+            .await
+            .unwrap()
+            .unwrap();
+        let response = Message::from_vec(&buffer[..n]).unwrap();
+        assert_eq!(response.id(), 0x1234);
+        assert_eq!(response.message_type(), MessageType::Response);
+        assert_eq!(response.response_code(), ResponseCode::ServFail);
+        assert_eq!(response.queries(), query.queries());
+        // The peer doesn't support EDNS, so the response has no OPT record (and no Extended DNS Error).
+        assert!(response.extensions().is_none());
+
+        let mut data = query.to_vec().unwrap();
+        edns::tests::append_opt(&mut data, &[]);
+        client.send(&data).await.unwrap();
+        let n = tokio::time::timeout(Duration::from_secs(1), client.recv(&mut buffer))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            edns::extended_errors(&buffer[..n]),
+            [ExtendedError::new(
+                ExtendedError::NETWORK_ERROR,
+                "upstream request failed"
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn query_deadline() {
+        let upstream = build_upstream();
+        upstream.set_delay(Duration::from_secs(5));
+        let config = ResolverConfig {
+            query_deadline: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let server_addr = socket.local_addr().unwrap();
+        let resolver = Arc::new(build_resolver(upstream, config));
+        tokio::spawn(socket_handler(Arc::new(socket), resolver));
+
+        let client = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        client.connect(server_addr).await.unwrap();
+        let query = build_query(0x1234, "www.example.com.");
+        client.send(&query.to_vec().unwrap()).await.unwrap();
+
+        // The slow upstream request is abandoned at the deadline.
+        let mut buffer = [0; 512];
+        let n = tokio::time::timeout(Duration::from_secs(1), client.recv(&mut buffer))
+            .await
+            .unwrap()
+            .unwrap();
+        let response = Message::from_vec(&buffer[..n]).unwrap();
+        assert_eq!(response.response_code(), ResponseCode::ServFail);
+    }
+
+    #[tokio::test]
+    async fn limit_inflight_requests() {
+        let upstream = build_upstream();
+        upstream.set_answer("other.example.com.", RData::A(A::new(192, 0, 2, 2)));
+        upstream.set_delay(Duration::from_millis(100));
+        let peer = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 5353);
+        let first = build_request(peer, "www.example.com.");
+        let second = build_request(peer, "other.example.com.");
+
+        // Requests over the limit are rejected.
+        let config = ResolverConfig {
+            max_inflight: Some(1),
+            ..Default::default()
+        };
+        let resolver = build_resolver(upstream.clone(), config);
+        let (first_result, second_result) =
+            tokio::join!(resolver.handle(&first), resolver.handle(&second));
+        assert!(first_result.is_ok());
+        assert!(second_result
+            .unwrap_err()
+            .to_string()
+            .contains("concurrent"));
+
+        // Requests over the limit are queued until a permit is available.
+        let config = ResolverConfig {
+            max_inflight: Some(1),
+            inflight_queue_timeout: Duration::from_secs(1),
+            ..Default::default()
+        };
+        let resolver = build_resolver(upstream, config);
+        let stamp = Instant::now();
+        let (first_result, second_result) =
+            tokio::join!(resolver.handle(&first), resolver.handle(&second));
+        assert!(first_result.is_ok());
+        assert!(second_result.is_ok());
+        // The requests were sent one after the other.
+        assert!(stamp.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[tokio::test]
+    async fn allowlist() {
+        let upstream = build_upstream();
+        let config = ResolverConfig {
+            allowlist: vec![
+                "192.0.2.0/24".parse().unwrap(),
+                "2001:db8::/32".parse().unwrap(),
+            ],
+            ..Default::default()
+        };
+        let resolver = build_resolver(upstream.clone(), config);
+
+        for (peer, rcode) in [
+            ("192.0.2.1:5353", ResponseCode::NoError),
+            ("[::ffff:192.0.2.1]:5353", ResponseCode::NoError),
+            ("[2001:db8::1]:5353", ResponseCode::NoError),
+            ("198.51.100.1:5353", ResponseCode::Refused),
+            ("[::1]:5353", ResponseCode::Refused),
+        ] {
+            let request = build_request(peer.parse().unwrap(), "www.example.com.");
+            let response = resolver.handle(&request).await.unwrap();
+            let response = Message::from_vec(&response).unwrap();
+            assert_eq!(response.id(), 0x1234);
+            assert_eq!(response.response_code(), rcode, "peer {}", peer);
+        }
+        assert_eq!(upstream.calls(), 3);
+
+        // Peers supporting EDNS are told why their query was refused.
+        let mut request = build_request("198.51.100.1:5353".parse().unwrap(), "www.example.com.");
+        let mut data = request.data.to_vec();
+        edns::tests::append_opt(&mut data, &[]);
+        request.data = data.as_slice().into();
+        let response = resolver.handle(&request).await.unwrap();
+        let errors = edns::extended_errors(&response);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].info_code, ExtendedError::PROHIBITED);
+    }
+
+    #[tokio::test]
+    async fn warn_on_slow_queries() {
+        let upstream = build_upstream();
+        let config = ResolverConfig {
+            slow_query_threshold: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let server_addr = socket.local_addr().unwrap();
+        let resolver = Arc::new(build_resolver(upstream.clone(), config));
+        tokio::spawn(socket_handler(Arc::new(socket), resolver.clone()));
+
+        let client = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        client.connect(server_addr).await.unwrap();
+        let mut buffer = [0; 512];
+
+        client
+            .send(&build_query(1, "www.example.com.").to_vec().unwrap())
+            .await
+            .unwrap();
+        client.recv(&mut buffer).await.unwrap();
+
+        upstream.set_delay(Duration::from_millis(100));
+        client
+            .send(&build_query(2, "www.example.com.").to_vec().unwrap())
+            .await
+            .unwrap();
+        client.recv(&mut buffer).await.unwrap();
+
+        // The latency is checked just after sending the response.
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(resolver.slow_queries.load(Ordering::Relaxed), 1);
+    }
+
+    /// Log messages written while the subscriber is set as the default for the current thread.
+    #[derive(Clone, Default)]
+    struct CapturedLogs(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn redact_logs() {
+        let logs = CapturedLogs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::DEBUG)
+            .with_writer(move || writer.clone())
+            .finish();
+        // The test runtime is single-threaded, so the subscriber also captures the logs of spawned tasks.
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let upstream = build_upstream();
+        let config = ResolverConfig {
+            slow_query_threshold: Some(Duration::ZERO),
+            log_privacy: true,
+            ..Default::default()
+        };
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let server_addr = socket.local_addr().unwrap();
+        let resolver = Arc::new(build_resolver(upstream, config));
+        tokio::spawn(socket_handler(Arc::new(socket), resolver.clone()));
+
+        let client = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        client.connect(server_addr).await.unwrap();
+        client
+            .send(&build_query(1, "www.example.com.").to_vec().unwrap())
+            .await
+            .unwrap();
+        let mut buffer = [0; 512];
+        let n = client.recv(&mut buffer).await.unwrap();
+        let response = Message::from_vec(&buffer[..n]).unwrap();
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert_eq!(response.answer_count(), 1);
+        // The latency is checked just after sending the response.
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        assert!(logs.contains("slow query for '<redacted "), "{}", logs);
+        assert!(
+            logs.contains("from downstream peer (<redacted "),
+            "{}",
+            logs
+        );
+        assert!(!logs.contains("example.com"), "{}", logs);
+        assert!(!logs.contains("127.0.0.1"), "{}", logs);
+    }
+
+    #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+    #[tokio::test]
+    async fn bind_to_interface() {
+        let bind_addrs = [SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0)];
+        let sockets = bind_sockets(&bind_addrs, Some("lo"), false).unwrap();
+        assert!(sockets[0].local_addr().unwrap().ip().is_loopback());
+        assert!(bind_sockets(&bind_addrs, Some("nonexistent0"), false).is_err());
+    }
+
+    #[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+    #[tokio::test]
+    async fn bind_to_interface_unsupported() {
+        let bind_addrs = [SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0)];
+        assert!(bind_sockets(&bind_addrs, Some("lo0"), false).is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn bind_with_reuse_port() {
+        let bind_addrs = [SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0)];
+        let first = bind_sockets(&bind_addrs, None, true).unwrap();
+        let bind_addrs = [first[0].local_addr().unwrap()];
+
+        // Another socket can bind the same address only if both set SO_REUSEPORT.
+        let second = bind_sockets(&bind_addrs, None, true).unwrap();
+        assert_eq!(second[0].local_addr().unwrap(), bind_addrs[0]);
+        assert!(bind_sockets(&bind_addrs, None, false).is_err());
+    }
+
+    #[tokio::test]
+    async fn track_inflight_requests() {
+        let upstream = build_upstream();
+        upstream.set_delay(Duration::from_millis(200));
+        let config = ResolverConfig {
+            stuck_request_threshold: Some(Duration::from_secs(10)),
+            ..Default::default()
+        };
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let server_addr = socket.local_addr().unwrap();
+        let resolver = Arc::new(build_resolver(upstream, config));
+        tokio::spawn(socket_handler(Arc::new(socket), resolver.clone()));
+
+        let client = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        client.connect(server_addr).await.unwrap();
+        client
+            .send(&build_query(1, "www.example.com.").to_vec().unwrap())
+            .await
+            .unwrap();
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let inflight = resolver.inflight.as_ref().unwrap();
+        let (_, age) = inflight.oldest().unwrap();
+        assert!(age >= Duration::from_millis(50));
+
+        let mut buffer = [0; 512];
+        client.recv(&mut buffer).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(inflight.oldest(), None);
+    }
+
+    #[tokio::test]
+    async fn record_stats() {
+        let upstream = build_upstream();
+        let stats = Arc::new(Stats::default());
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let server_addr = socket.local_addr().unwrap();
+        let resolver = Arc::new(Resolver::new(
+            upstream.clone(),
+            ResolverConfig::default(),
+            stats.clone(),
+        ));
+        tokio::spawn(socket_handler(Arc::new(socket), resolver));
+
+        let client = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        client.connect(server_addr).await.unwrap();
+        let mut buffer = [0; 512];
+        for id in 0..3 {
+            let query = build_query(id, "www.example.com.");
+            client.send(&query.to_vec().unwrap()).await.unwrap();
+            client.recv(&mut buffer).await.unwrap();
+        }
+        upstream.set_failing(true);
+        let query = build_query(3, "www.example.com.");
+        client.send(&query.to_vec().unwrap()).await.unwrap();
+        client.recv(&mut buffer).await.unwrap();
+
+        // Statistics are recorded just after sending the response.
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.queries, 4);
+        assert_eq!(snapshot.failed, 1);
+        assert_eq!(snapshot.coalesced, 0);
+        assert!(snapshot.max_latency >= snapshot.mean_latency);
+    }
+
+    #[tokio::test]
+    async fn record_latency_percentiles() {
+        let upstream = build_upstream();
+        upstream.set_delay(Duration::from_millis(20));
+        let stats = Arc::new(Stats::default());
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let server_addr = socket.local_addr().unwrap();
+        let resolver = Arc::new(Resolver::new(
+            upstream.clone(),
+            ResolverConfig::default(),
+            stats.clone(),
+        ));
+        tokio::spawn(socket_handler(Arc::new(socket), resolver));
+
+        let client = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        client.connect(server_addr).await.unwrap();
+        let mut buffer = [0; 512];
+        for id in 0..5 {
+            let query = build_query(id, "www.example.com.");
+            client.send(&query.to_vec().unwrap()).await.unwrap();
+            client.recv(&mut buffer).await.unwrap();
+        }
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let latencies = stats.take_recent_latencies();
+        assert_eq!(latencies.count(), 5);
+        for quantile in [0.5, 0.9, 0.99] {
+            let latency = latencies.percentile(quantile).unwrap();
+            assert!(latency >= Duration::from_millis(20), "{:?}", latency);
+            assert!(latency <= Duration::from_millis(100), "{:?}", latency);
+        }
+    }
+
+    #[tokio::test]
+    async fn partial_bind_failure() {
+        // 192.0.2.1 (TEST-NET-1) is not assigned to any local interface, so it cannot be bound.
+        let unavailable = SocketAddr::new(Ipv4Addr::new(192, 0, 2, 1).into(), 0);
+        let available = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0);
+
+        let sockets = bind_sockets(&[unavailable, available], None, false).unwrap();
+        assert_eq!(sockets.len(), 1);
+        assert!(sockets[0].local_addr().unwrap().ip().is_loopback());
+        assert!(bind_sockets(&[unavailable], None, false).is_err());
+        assert!(bind_sockets(&[], None, false).is_err());
+
+        // The resolver starts and answers queries on the address that could be bound.
+        let port = UdpSocket::bind(available)
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let bind_addrs = [
+            unavailable,
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port),
+        ];
+        let upstream = build_upstream();
+        tokio::spawn(async move {
+            run(
+                upstream,
+                &bind_addrs,
+                ResolverConfig::default(),
+                Arc::default(),
+            )
+            .await
+        });
+
+        let response = exchange(bind_addrs[1], &build_query(1, "www.example.com.")).await;
+        assert_eq!(response.id(), 1);
+    }
+
+    /// Starts the resolver with `run` on a free port on localhost, forwarding queries to `upstream`.
+    /// Returns the address it listens on.
+    async fn spawn_server(upstream: Arc<MockClient>, config: ResolverConfig) -> SocketAddr {
+        // `run` binds the sockets itself, so find a free port first.
+        let port = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let server_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port);
+        tokio::spawn(async move { run(upstream, &[server_addr], config, Arc::default()).await });
+        server_addr
+    }
+
+    /// Sends `query` to the resolver at `server_addr` over UDP, and returns the response.
+    /// The query is resent until answered, as the resolver may still be starting.
+    async fn exchange(server_addr: SocketAddr, query: &Message) -> Message {
+        let client = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        client.connect(server_addr).await.unwrap();
+        let mut buffer = [0; 4096];
+        for _ in 0..20 {
+            // Sending fails if a previous query was rejected because the socket wasn't bound yet.
+            let _ = client.send(&query.to_vec().unwrap()).await;
+            let received =
+                tokio::time::timeout(Duration::from_millis(100), client.recv(&mut buffer)).await;
+            if let Ok(Ok(n)) = received {
+                return Message::from_vec(&buffer[..n]).unwrap();
+            }
+        }
+        panic!("no response from resolver at {}", server_addr);
+    }
+
+    #[tokio::test]
+    async fn receive_large_queries() {
+        let upstream = build_upstream();
+        let server_addr = spawn_server(upstream.clone(), ResolverConfig::default()).await;
+
+        // Padded to more than the EDNS payload size that many clients advertise.
+        let mut query = build_query(0xbeef, "www.example.com.");
+        query
+            .extensions_mut()
+            .get_or_insert_with(Edns::new)
+            .options_mut()
+            .insert(EdnsOption::Unknown(EDNS_PADDING_CODE, vec![0; 2000]));
+        assert!(query.to_vec().unwrap().len() > 2000);
+        let response = exchange(server_addr, &query).await;
+
+        assert_eq!(response.id(), 0xbeef);
+        assert_eq!(response.answer_count(), 1);
+        assert_eq!(upstream.queries()[0].len(), query.to_vec().unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn end_to_end() {
+        let upstream = build_upstream();
+        let server_addr = spawn_server(upstream.clone(), ResolverConfig::default()).await;
+
+        let mut query = build_query(0xbeef, "www.example.com.");
+        query.set_recursion_desired(true);
+        let response = exchange(server_addr, &query).await;
+
+        assert_eq!(response.id(), 0xbeef);
+        assert_eq!(response.message_type(), MessageType::Response);
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert!(response.recursion_desired());
+        assert_eq!(response.queries(), query.queries());
+        let answers = response
+            .answers()
+            .iter()
+            .map(|record| record.data().cloned())
+            .collect::<Vec<_>>();
+        assert_eq!(answers, [Some(RData::A(A::new(192, 0, 2, 1)))]);
+        assert!(upstream.calls() >= 1);
+
+        // Unknown names are answered as determined by the upstream.
+        let response = exchange(server_addr, &build_query(2, "unknown.example.com.")).await;
+        assert_eq!(response.id(), 2);
+        assert_eq!(response.answer_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn nsid() {
+        let config = ResolverConfig {
+            nsid: Some("ns1.example.net".to_string()),
+            ..Default::default()
+        };
+        let server_addr = spawn_server(build_upstream(), config).await;
+
+        let mut query = build_query(1, "www.example.com.");
+        let mut edns = Edns::new();
+        edns.options_mut()
+            .insert(EdnsOption::Unknown(edns::NSID_CODE, Vec::new()));
+        query.set_edns(edns);
+        let response = exchange(server_addr, &query).await;
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        let nsid = response
+            .extensions()
+            .as_ref()
+            .and_then(|edns| edns.option(edns::NSID_CODE.into()))
+            .cloned();
+        assert_eq!(
+            nsid,
+            Some(EdnsOption::Unknown(
+                edns::NSID_CODE,
+                b"ns1.example.net".to_vec()
+            ))
+        );
+
+        // Queries that don't request the NSID don't receive it.
+        let mut query = build_query(2, "www.example.com.");
+        query.set_edns(Edns::new());
+        let response = exchange(server_addr, &query).await;
+        assert!(response
+            .extensions()
+            .as_ref()
+            .and_then(|edns| edns.option(edns::NSID_CODE.into()))
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn multiple_recv_workers() {
+        let config = ResolverConfig {
+            recv_workers: NonZeroUsize::new(4),
+            ..Default::default()
+        };
+        let server_addr = spawn_server(build_upstream(), config).await;
+        // Wait for the resolver to be started.
+        exchange(server_addr, &build_query(0, "www.example.com.")).await;
+
+        // Send a burst of queries, all of which must be answered (in any order).
+        let client = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        client.connect(server_addr).await.unwrap();
+        const QUERIES: u16 = 200;
+        for id in 1..=QUERIES {
+            let query = build_query(id, "www.example.com.");
+            client.send(&query.to_vec().unwrap()).await.unwrap();
+        }
+        let mut ids = HashSet::new();
+        let mut buffer = [0; 4096];
+        while ids.len() < QUERIES as usize {
+            let n = tokio::time::timeout(Duration::from_secs(5), client.recv(&mut buffer))
+                .await
+                .expect("not all queries were answered")
+                .unwrap();
+            let response = Message::from_vec(&buffer[..n]).unwrap();
+            assert_eq!(response.response_code(), ResponseCode::NoError);
+            ids.insert(response.id());
+        }
+        assert_eq!(ids, (1..=QUERIES).collect());
+    }
 }
@@ -0,0 +1,192 @@
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Max Wipfli <mail@maxwipfli.ch>
+
+//! Local answers to reverse DNS (PTR) queries, e.g. for addresses in private networks (RFC 1918), which public
+//! resolvers cannot answer.
+
+use std::{collections::HashMap, fs, net::IpAddr, path::Path};
+
+use bytes::BytesMut;
+use eyre::{eyre, Result};
+use hickory_proto::{
+    op::{Message, MessageType},
+    rr::{rdata::PTR, DNSClass, Name, RData, Record, RecordType},
+};
+
+/// TTL of PTR records answered from the reverse zone.
+const TTL: u32 = 300;
+
+/// Names of local IP addresses, answered to PTR queries instead of forwarding them.
+#[derive(Debug, Clone, Default)]
+pub struct ReverseZone {
+    names: HashMap<IpAddr, Vec<Name>>,
+}
+
+impl ReverseZone {
+    /// Loads the reverse zone from a file in the format of `/etc/hosts`, i.e. lines with an IP address followed by
+    /// one or more names. Empty lines and comments (starting with `#`) are ignored.
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .map_err(|err| eyre!("cannot read reverse zone '{}': {}", path.display(), err))?;
+        Self::parse(&contents)
+            .map_err(|err| eyre!("invalid reverse zone '{}': {}", path.display(), err))
+    }
+
+    fn parse(contents: &str) -> Result<Self> {
+        let mut names: HashMap<IpAddr, Vec<Name>> = HashMap::new();
+        for (index, line) in contents.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut fields = line.split_whitespace();
+            let Some(ip) = fields.next() else {
+                continue;
+            };
+            let ip = ip
+                .parse::<IpAddr>()
+                .map_err(|_| eyre!("line {}: invalid IP address '{}'", index + 1, ip))?;
+            let entry = names.entry(ip).or_default();
+            for name in fields {
+                let mut name = Name::from_ascii(name)
+                    .map_err(|err| eyre!("line {}: invalid name '{}': {}", index + 1, name, err))?;
+                name.set_fqdn(true);
+                entry.push(name);
+            }
+            if entry.is_empty() {
+                return Err(eyre!("line {}: no name for {}", index + 1, ip));
+            }
+        }
+        Ok(Self { names })
+    }
+
+    /// Returns the names of the address that `name` is the reverse name of (in `in-addr.arpa` or `ip6.arpa`).
+    pub fn lookup(&self, name: &Name) -> Option<&[Name]> {
+        let net = name.to_lowercase().parse_arpa_name().ok()?;
+        // Reverse names of networks (with fewer labels) don't name an address.
+        if net.prefix_len() != net.max_prefix_len() {
+            return None;
+        }
+        let ip = match net.addr() {
+            // IPv4-mapped addresses are stored as IPv4 addresses.
+            IpAddr::V6(ip) => ip.to_ipv4_mapped().map_or(IpAddr::V6(ip), IpAddr::V4),
+            ip => ip,
+        };
+        self.names.get(&ip).map(Vec::as_slice)
+    }
+}
+
+/// Answers PTR queries for addresses in the reverse zone.
+/// Returns `None` if the query is not such a query, so that it is forwarded.
+pub fn answer(query: &Message, zone: &ReverseZone) -> Option<Result<BytesMut>> {
+    let [question] = query.queries() else {
+        return None;
+    };
+    if question.query_class() != DNSClass::IN || question.query_type() != RecordType::PTR {
+        return None;
+    }
+    let names = zone.lookup(question.name())?;
+
+    let mut response = Message::new();
+    response
+        .set_id(query.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(query.op_code())
+        .set_recursion_desired(query.recursion_desired())
+        .set_checking_disabled(query.checking_disabled())
+        .set_recursion_available(true)
+        .set_authoritative(true)
+        .add_query(question.clone());
+    for name in names {
+        response.add_answer(Record::from_rdata(
+            question.name().clone(),
+            TTL,
+            RData::PTR(PTR(name.clone())),
+        ));
+    }
+    Some(
+        response
+            .to_vec()
+            .map(|response| response.as_slice().into())
+            .map_err(Into::into),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use hickory_proto::op::Query;
+
+    use super::*;
+
+    fn zone() -> ReverseZone {
+        ReverseZone::parse(
+            "# home lab\n\
+             192.168.1.10   nas.home.arpa  nas-alias.home.arpa\n\
+             \n\
+             fd00::10       printer.home.arpa.  # IPv6\n",
+        )
+        .unwrap()
+    }
+
+    fn query(name: Name, query_type: RecordType) -> Message {
+        let mut query = Message::new();
+        query.set_id(42).add_query(Query::query(name, query_type));
+        query
+    }
+
+    fn ptr_names(response: &[u8]) -> Vec<String> {
+        let response = Message::from_vec(response).unwrap();
+        assert_eq!(response.id(), 42);
+        response
+            .answers()
+            .iter()
+            .map(|record| match record.data() {
+                Some(RData::PTR(ptr)) => ptr.0.to_ascii(),
+                data => panic!("expected PTR record, got {:?}", data),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn answer_ptr_queries() {
+        let zone = zone();
+        let name = Name::from(Ipv4Addr::new(192, 168, 1, 10));
+        let response = answer(&query(name, RecordType::PTR), &zone)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            ptr_names(&response),
+            ["nas.home.arpa.", "nas-alias.home.arpa."]
+        );
+
+        let name = Name::from("fd00::10".parse::<Ipv6Addr>().unwrap());
+        let response = answer(&query(name, RecordType::PTR), &zone)
+            .unwrap()
+            .unwrap();
+        assert_eq!(ptr_names(&response), ["printer.home.arpa."]);
+
+        // Reverse names are case-insensitive.
+        let name = Name::from_ascii("10.1.168.192.IN-ADDR.ARPA.").unwrap();
+        assert!(answer(&query(name, RecordType::PTR), &zone).is_some());
+    }
+
+    #[test]
+    fn forward_other_queries() {
+        let zone = zone();
+        for name in [
+            Name::from(Ipv4Addr::new(192, 168, 1, 11)),
+            Name::from_ascii("1.168.192.in-addr.arpa.").unwrap(),
+            Name::from_ascii("www.example.com.").unwrap(),
+        ] {
+            assert!(answer(&query(name, RecordType::PTR), &zone).is_none());
+        }
+        let name = Name::from(Ipv4Addr::new(192, 168, 1, 10));
+        assert!(answer(&query(name, RecordType::TXT), &zone).is_none());
+    }
+
+    #[test]
+    fn reject_invalid_zones() {
+        assert!(ReverseZone::parse("192.168.1.300 nas.home.arpa").is_err());
+        assert!(ReverseZone::parse("192.168.1.10").is_err());
+        assert!(ReverseZone::parse("192.168.1.10 nas..home").is_err());
+    }
+}
@@ -0,0 +1,116 @@
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Max Wipfli <mail@maxwipfli.ch>
+
+//! Serialization of upstream requests for the same name, as a workaround for upstreams that mishandle concurrent
+//! queries for a name (e.g. A and AAAA), similar to glibc's `single-request` resolver option.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use hickory_proto::rr::Name;
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard};
+
+/// Lock of a name, and the number of requests holding or waiting for it.
+type NameLock = (Arc<AsyncMutex<()>>, usize);
+
+/// Locks serializing upstream requests per name. Locks are created when first needed, and removed once no request
+/// holds or waits for them.
+#[derive(Default)]
+pub struct NameLocks {
+    locks: Mutex<HashMap<Name, NameLock>>,
+}
+
+impl NameLocks {
+    /// Waits until no other request for `name` holds the lock, and returns a guard holding it.
+    pub async fn lock(&self, name: &Name) -> NameGuard<'_> {
+        // Names are case-insensitive.
+        let name = name.to_lowercase();
+        let lock = {
+            let mut locks = self.locks.lock().unwrap();
+            let (lock, users) = locks.entry(name.clone()).or_default();
+            *users += 1;
+            lock.clone()
+        };
+        // The guard is created before waiting, so that the lock is cleaned up even if this future is dropped.
+        let mut guard = NameGuard {
+            locks: self,
+            name,
+            guard: None,
+        };
+        guard.guard = Some(lock.lock_owned().await);
+        guard
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.locks.lock().unwrap().len()
+    }
+}
+
+/// Holds the lock of a name until dropped.
+pub struct NameGuard<'a> {
+    locks: &'a NameLocks,
+    name: Name,
+    /// The held lock, or `None` while waiting for it.
+    guard: Option<OwnedMutexGuard<()>>,
+}
+
+impl Drop for NameGuard<'_> {
+    fn drop(&mut self) {
+        drop(self.guard.take());
+        let mut locks = self.locks.locks.lock().unwrap();
+        if let Some((_, users)) = locks.get_mut(&self.name) {
+            *users -= 1;
+            if *users == 0 {
+                locks.remove(&self.name);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn serialize_per_name() {
+        let locks = Arc::new(NameLocks::default());
+        let name = "www.example.com.".parse::<Name>().unwrap();
+
+        let guard = locks.lock(&name).await;
+        // Other names are not affected.
+        drop(locks.lock(&"example.com.".parse().unwrap()).await);
+
+        let waiter = tokio::spawn({
+            let locks = locks.clone();
+            async move {
+                drop(locks.lock(&"WWW.example.com.".parse().unwrap()).await);
+            }
+        });
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(!waiter.is_finished());
+
+        drop(guard);
+        waiter.await.unwrap();
+        assert_eq!(locks.len(), 0);
+
+        // Locks are also removed if a request stops waiting for them.
+        let guard = locks.lock(&name).await;
+        let waiter = tokio::spawn({
+            let locks = locks.clone();
+            let name = name.clone();
+            async move {
+                drop(locks.lock(&name).await);
+            }
+        });
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        waiter.abort();
+        let _ = waiter.await;
+        drop(guard);
+        assert_eq!(locks.len(), 0);
+    }
+}
@@ -0,0 +1,236 @@
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Max Wipfli <mail@maxwipfli.ch>
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::time::{interval, Instant, MissedTickBehavior};
+use tracing::info;
+
+use super::histogram::LatencyHistogram;
+use crate::client::{
+    labeled::{LabeledClient, UpstreamStats},
+    udp::{EntropySnapshot, EntropyStats},
+};
+
+/// Live statistics of a resolver, which embedders can read while the resolver is running.
+#[derive(Debug, Default)]
+pub struct Stats {
+    queries: AtomicU64,
+    coalesced: AtomicU64,
+    failed: AtomicU64,
+    latency_total_us: AtomicU64,
+    latency_max_us: AtomicU64,
+    /// Latencies of queries answered since the last periodic report.
+    recent_latencies: Mutex<LatencyHistogram>,
+    /// Upstreams whose statistics are included in snapshots and reports.
+    upstreams: Mutex<Vec<Arc<LabeledClient>>>,
+    /// Randomization statistics of DNS-over-UDP upstreams included in snapshots and reports, by label.
+    entropy: Mutex<Vec<(String, Arc<EntropyStats>)>>,
+}
+
+/// Point-in-time copy of `Stats`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatsSnapshot {
+    /// Number of queries answered.
+    pub queries: u64,
+    /// Number of queries answered with the response to an identical concurrent query, without an upstream request.
+    pub coalesced: u64,
+    /// Number of queries answered with SERVFAIL because the upstream request failed.
+    pub failed: u64,
+    /// Mean time to answer a query.
+    pub mean_latency: Duration,
+    /// Maximum time to answer a query.
+    pub max_latency: Duration,
+    /// Statistics of the upstreams added with `Stats::add_upstream`, by label.
+    pub upstreams: Vec<(String, UpstreamStats)>,
+    /// Randomization statistics added with `Stats::add_entropy`, by label.
+    pub entropy: Vec<(String, EntropySnapshot)>,
+}
+
+impl Stats {
+    /// Records that a query was answered after `latency`.
+    pub(super) fn record_query(&self, latency: Duration) {
+        let latency_us = latency.as_micros().try_into().unwrap_or(u64::MAX);
+        self.queries.fetch_add(1, Ordering::Relaxed);
+        self.latency_total_us
+            .fetch_add(latency_us, Ordering::Relaxed);
+        self.latency_max_us.fetch_max(latency_us, Ordering::Relaxed);
+        self.recent_latencies.lock().unwrap().record(latency);
+    }
+
+    /// Records that a query was coalesced with an identical concurrent query.
+    pub(super) fn record_coalesced(&self) {
+        self.coalesced.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records that the upstream request for a query failed.
+    pub(super) fn record_failure(&self) {
+        self.failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Adds an upstream, whose statistics are then included in snapshots and periodic reports.
+    pub fn add_upstream(&self, upstream: Arc<LabeledClient>) {
+        self.upstreams.lock().unwrap().push(upstream);
+    }
+
+    /// Adds the randomization statistics of a DNS-over-UDP upstream, which are then included in snapshots and
+    /// periodic reports, so that operators can verify that source ports and TXIDs are randomized.
+    pub fn add_entropy(&self, label: impl Into<String>, entropy: Arc<EntropyStats>) {
+        self.entropy.lock().unwrap().push((label.into(), entropy));
+    }
+
+    /// Returns the latencies of queries answered since the last call, and starts over.
+    pub(super) fn take_recent_latencies(&self) -> LatencyHistogram {
+        std::mem::take(&mut *self.recent_latencies.lock().unwrap())
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        let queries = self.queries.load(Ordering::Relaxed);
+        let latency_total_us = self.latency_total_us.load(Ordering::Relaxed);
+        StatsSnapshot {
+            queries,
+            coalesced: self.coalesced.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            mean_latency: Duration::from_micros(latency_total_us.checked_div(queries).unwrap_or(0)),
+            max_latency: Duration::from_micros(self.latency_max_us.load(Ordering::Relaxed)),
+            upstreams: self
+                .upstreams
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|upstream| (upstream.label().to_string(), upstream.stats()))
+                .collect(),
+            entropy: self
+                .entropy
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(label, entropy)| (label.clone(), entropy.snapshot()))
+                .collect(),
+        }
+    }
+}
+
+/// Task that logs the query rate and latency percentiles of the queries answered in each `period`.
+pub(super) async fn report(stats: Arc<Stats>, period: Duration) {
+    let mut ticks = interval(period);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // The first tick completes immediately.
+    ticks.tick().await;
+    let mut last_report = Instant::now();
+    loop {
+        ticks.tick().await;
+        let latencies = stats.take_recent_latencies();
+        let elapsed = last_report.elapsed();
+        last_report = Instant::now();
+        let qps = latencies.count() as f64 / elapsed.as_secs_f64();
+        match (
+            latencies.percentile(0.5),
+            latencies.percentile(0.9),
+            latencies.percentile(0.99),
+        ) {
+            (Some(p50), Some(p90), Some(p99)) => info!(
+                "stats: {} queries ({:.1}/s), latency p50 {} ms, p90 {} ms, p99 {} ms",
+                latencies.count(),
+                qps,
+                p50.as_millis(),
+                p90.as_millis(),
+                p99.as_millis()
+            ),
+            _ => info!("stats: no queries in the last {} s", elapsed.as_secs()),
+        }
+        let snapshot = stats.snapshot();
+        for (label, upstream) in snapshot.upstreams {
+            info!(
+                "stats: upstream '{}': {} queries ({} failed) in total, mean latency {} ms, max latency {} ms",
+                label,
+                upstream.queries,
+                upstream.errors,
+                upstream.mean_latency.as_millis(),
+                upstream.max_latency.as_millis()
+            );
+        }
+        for (label, entropy) in snapshot.entropy {
+            let (min, max) = entropy.txid_spread();
+            info!(
+                "stats: upstream '{}': {} queries sent from {} distinct source ports, TXID histogram buckets of {} to {} \
+                 queries",
+                label, entropy.queries, entropy.distinct_source_ports, min, max
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hickory_proto::rr::{rdata::A, RData};
+
+    use super::*;
+    use crate::client::{client::tests as client_tests, mock::MockClient};
+
+    #[test]
+    fn record() {
+        let stats = Stats::default();
+        assert_eq!(stats.snapshot().mean_latency, Duration::ZERO);
+
+        stats.record_query(Duration::from_millis(10));
+        stats.record_query(Duration::from_millis(30));
+        stats.record_coalesced();
+        stats.record_failure();
+
+        assert_eq!(
+            stats.snapshot(),
+            StatsSnapshot {
+                queries: 2,
+                coalesced: 1,
+                failed: 1,
+                mean_latency: Duration::from_millis(20),
+                max_latency: Duration::from_millis(30),
+                upstreams: Vec::new(),
+                entropy: Vec::new(),
+            }
+        );
+
+        // Recent latencies are reset when taken, the totals are not.
+        assert_eq!(stats.take_recent_latencies().count(), 2);
+        assert_eq!(stats.take_recent_latencies().count(), 0);
+        assert_eq!(stats.snapshot().queries, 2);
+    }
+
+    #[tokio::test]
+    async fn include_upstreams() {
+        let stats = Stats::default();
+        let upstream = MockClient::new();
+        upstream.set_answer("www.example.com.", RData::A(A::new(192, 0, 2, 1)));
+        let upstream = Arc::new(LabeledClient::new("udp://192.0.2.53", Arc::new(upstream)));
+        stats.add_upstream(upstream.clone());
+        client_tests::basic_a(upstream.clone()).await;
+
+        let upstreams = stats.snapshot().upstreams;
+        assert_eq!(upstreams.len(), 1);
+        assert_eq!(upstreams[0].0, "udp://192.0.2.53");
+        assert_eq!(upstreams[0].1.queries, 1);
+        assert_eq!(upstreams[0].1.errors, 0);
+    }
+
+    #[test]
+    fn include_entropy() {
+        let stats = Stats::default();
+        let entropy = Arc::new(EntropyStats::default());
+        stats.add_entropy("udp://192.0.2.53", entropy.clone());
+        entropy.record(1234, 0x0000);
+        entropy.record(5678, 0xffff);
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.entropy.len(), 1);
+        assert_eq!(snapshot.entropy[0].0, "udp://192.0.2.53");
+        assert_eq!(snapshot.entropy[0].1.queries, 2);
+        assert_eq!(snapshot.entropy[0].1.distinct_source_ports, 2);
+    }
+}
@@ -0,0 +1,146 @@
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Max Wipfli <mail@maxwipfli.ch>
+
+use std::{fmt, time::Duration};
+
+use bytes::BytesMut;
+use eyre::Result;
+use hickory_proto::{
+    op::{Message, MessageType, ResponseCode},
+    rr::{rdata::TXT, Name, RData, Record},
+};
+
+/// Label that, appended to a query name, requests a trace of how the query is handled.
+const TRACE_LABEL: &[u8] = b"__trace";
+
+/// How a query was answered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Path {
+    /// From the configured server identity (CHAOS-class query).
+    Chaos,
+    /// From the local zone.
+    LocalZone,
+    /// From the reverse zone.
+    ReverseZone,
+    /// Refused, as recursion is disabled.
+    Refused,
+    /// With the response of the upstream.
+    Upstream,
+    /// With the upstream response to an identical concurrent query.
+    Coalesced,
+    /// With AAAA records synthesized from the A records of the upstream response (DNS64).
+    Dns64,
+}
+
+impl fmt::Display for Path {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Chaos => "chaos",
+            Self::LocalZone => "local-zone",
+            Self::ReverseZone => "reverse-zone",
+            Self::Refused => "refused",
+            Self::Upstream => "upstream",
+            Self::Coalesced => "coalesced",
+            Self::Dns64 => "dns64",
+        })
+    }
+}
+
+/// Description of how a traced query was handled.
+pub struct Trace {
+    pub path: Path,
+    pub rcode: ResponseCode,
+    pub answers: u16,
+    pub latency: Duration,
+}
+
+impl Trace {
+    fn to_txt(&self) -> TXT {
+        TXT::new(vec![
+            format!("path={}", self.path),
+            format!("rcode={:?}", self.rcode),
+            format!("answers={}", self.answers),
+            format!("latency={}ms", self.latency.as_millis()),
+        ])
+    }
+}
+
+/// Checks whether `query` requests a trace (i.e. its name ends in the trace label).
+/// Returns a copy of the query with the trace label removed, which is the query to actually resolve.
+pub fn strip_trace_label(query: &Message) -> Option<Message> {
+    let [question] = query.queries() else {
+        return None;
+    };
+    let name = question.name();
+    let labels = name.iter().collect::<Vec<_>>();
+    let (last, rest) = labels.split_last()?;
+    if !last.eq_ignore_ascii_case(TRACE_LABEL) {
+        return None;
+    }
+
+    let mut stripped_question = question.clone();
+    stripped_question.set_name(Name::from_labels(rest.iter().copied()).ok()?);
+    let mut stripped = query.clone();
+    stripped.take_queries();
+    stripped.add_query(stripped_question);
+    Some(stripped)
+}
+
+/// Builds the response to a traced query, answering with a single TXT record describing the trace.
+pub fn build_response(query: &Message, trace: &Trace) -> Result<BytesMut> {
+    let mut response = Message::new();
+    response
+        .set_id(query.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(query.op_code())
+        .set_recursion_desired(query.recursion_desired())
+        .set_checking_disabled(query.checking_disabled())
+        .set_recursion_available(true)
+        .add_queries(query.queries().to_vec());
+    for question in query.queries() {
+        let record = Record::from_rdata(question.name().clone(), 0, RData::TXT(trace.to_txt()));
+        response.add_answer(record);
+    }
+    Ok(response.to_vec()?.as_slice().into())
+}
+
+#[cfg(test)]
+mod tests {
+    use hickory_proto::{op::Query, rr::RecordType};
+
+    use super::*;
+
+    fn query(name: &str) -> Message {
+        let mut query = Message::new();
+        query.add_query(Query::query(name.parse().unwrap(), RecordType::A));
+        query
+    }
+
+    #[test]
+    fn strips_trace_label() {
+        let stripped = strip_trace_label(&query("www.example.com.__trace.")).unwrap();
+        assert_eq!(stripped.queries().len(), 1);
+        assert_eq!(
+            stripped.queries()[0].name(),
+            &"www.example.com.".parse::<Name>().unwrap()
+        );
+        assert_eq!(stripped.queries()[0].query_type(), RecordType::A);
+    }
+
+    #[test]
+    fn strips_trace_label_to_root() {
+        let stripped = strip_trace_label(&query("__trace.")).unwrap();
+        assert!(stripped.queries()[0].name().is_root());
+        let data = stripped.to_vec().unwrap();
+        assert!(Message::from_vec(&data).unwrap().queries()[0]
+            .name()
+            .is_root());
+    }
+
+    #[test]
+    fn ignores_untraced_names() {
+        assert!(strip_trace_label(&query("www.example.com.")).is_none());
+        assert!(strip_trace_label(&query("__trace.example.com.")).is_none());
+        assert!(strip_trace_label(&query(".")).is_none());
+    }
+}
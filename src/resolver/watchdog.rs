@@ -0,0 +1,95 @@
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Max Wipfli <mail@maxwipfli.ch>
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::time::{sleep, sleep_until, Instant};
+use tracing::warn;
+
+/// Watchdog warning if the listener hasn't received any datagram for an unusually long time.
+///
+/// On a normally busy resolver, this may indicate a problem with the listening socket.
+pub struct Watchdog {
+    threshold: Duration,
+    last_activity: Mutex<Instant>,
+    warnings: AtomicU64,
+}
+
+impl Watchdog {
+    pub fn new(threshold: Duration) -> Self {
+        Self {
+            threshold,
+            last_activity: Mutex::new(Instant::now()),
+            warnings: AtomicU64::new(0),
+        }
+    }
+
+    /// Records that a datagram was received.
+    pub fn record_activity(&self) {
+        *self.last_activity.lock().unwrap() = Instant::now();
+    }
+
+    /// Returns how many times the watchdog has warned about inactivity.
+    #[cfg(test)]
+    pub fn warnings(&self) -> u64 {
+        self.warnings.load(Ordering::Relaxed)
+    }
+
+    /// Task that periodically checks for inactivity. Warns once per period of inactivity.
+    pub async fn run(self: Arc<Self>) {
+        let mut warned_for = None;
+        loop {
+            let last_activity = *self.last_activity.lock().unwrap();
+            let deadline = last_activity + self.threshold;
+            if Instant::now() < deadline {
+                sleep_until(deadline).await;
+                continue;
+            }
+
+            if warned_for != Some(last_activity) {
+                warn!(
+                    "listener has not received any datagram for {} s, the socket may be broken",
+                    last_activity.elapsed().as_secs()
+                );
+                self.warnings.fetch_add(1, Ordering::Relaxed);
+                warned_for = Some(last_activity);
+            }
+            sleep(self.threshold).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn warn_on_inactivity() {
+        let watchdog = Arc::new(Watchdog::new(Duration::from_secs(60)));
+        tokio::spawn(watchdog.clone().run());
+
+        sleep(Duration::from_secs(30)).await;
+        assert_eq!(watchdog.warnings(), 0);
+        sleep(Duration::from_secs(40)).await;
+        assert_eq!(watchdog.warnings(), 1);
+
+        // Only warns once per period of inactivity.
+        sleep(Duration::from_secs(600)).await;
+        assert_eq!(watchdog.warnings(), 1);
+
+        watchdog.record_activity();
+        sleep(Duration::from_secs(50)).await;
+        assert_eq!(watchdog.warnings(), 1);
+        watchdog.record_activity();
+        sleep(Duration::from_secs(50)).await;
+        assert_eq!(watchdog.warnings(), 1);
+        sleep(Duration::from_secs(100)).await;
+        assert_eq!(watchdog.warnings(), 2);
+    }
+}